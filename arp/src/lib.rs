@@ -1,5 +1,3 @@
-#![feature(try_from)]
-
 extern crate libc;
 extern crate byteorder;
 extern crate ethernet;
@@ -7,8 +5,9 @@ extern crate common;
 extern crate vlan;
 extern crate ethertype;
 
-use byteorder::{ByteOrder, NetworkEndian, BigEndian};
 use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use common::{Emit, Layer, ParseError, Reason};
 
 #[derive(Debug,PartialEq)]
 pub struct ArpHdr {
@@ -21,10 +20,30 @@ pub struct ArpHdr {
 
 #[derive(Debug,PartialEq)]
 pub struct ArpInfo <'a> {
-    pub ar_ha: &'a [u8],
+    pub ar_ha: ethernet::MacAddr,
     pub addr: &'a [u8]
 }
 
+impl <'a> ArpInfo<'a> {
+    /// Protocol address as an IP address: 4 bytes give IPv4, 16 bytes IPv6,
+    /// `None` for other lengths
+    pub fn ip_addr(&self) -> Option<IpAddr> {
+        match self.addr.len() {
+            4 => {
+                let mut a = [0u8; 4];
+                a.copy_from_slice(self.addr);
+                Some(IpAddr::V4(Ipv4Addr::from(a)))
+            }
+            16 => {
+                let mut a = [0u8; 16];
+                a.copy_from_slice(self.addr);
+                Some(IpAddr::V6(Ipv6Addr::from(a)))
+            }
+            _ => None
+        }
+    }
+}
+
 #[derive(Debug,PartialEq)]
 pub struct ArpBody <'a> {
     pub source: ArpInfo<'a>,
@@ -33,16 +52,19 @@ pub struct ArpBody <'a> {
 
 #[derive(Debug,PartialEq)]
 pub struct ArpPacket <'a>{
-    pub eth_header: ethernet::EthHeader<'a>,
+    pub eth_header: ethernet::EthHeader,
     pub vlans : Option<Vec<vlan::Vlan>>,
     pub arp_hdr : ArpHdr,
     pub arp_body : ArpBody<'a>
 }
 
 #[no_mangle]
-pub extern "C" fn arp_recv(port: u32, vid: u16, buf: *mut libc::c_void, len: u32) -> i32 {
+pub extern "C" fn arp_recv(_port: u32, _vid: u16, buf: *mut libc::c_void, len: u32) -> i32 {
+    if buf.is_null() {
+        return -1;
+    }
     let len: usize = len as usize;
-    let mut buf_vector : &[u8];
+    let buf_vector : &[u8];
     unsafe {
         buf_vector = std::slice::from_raw_parts(buf as *mut u8, len);
     }
@@ -51,32 +73,27 @@ pub extern "C" fn arp_recv(port: u32, vid: u16, buf: *mut libc::c_void, len: u32
     0
 }
 
-fn parse_arp_header(buf: &[u8]) -> ArpHdr {
-    ArpHdr{ ar_hrd: common::u16_parse(&buf[0..2]), ar_pro: common::u16_parse(&buf[2..4]), ar_hln: buf[4], ar_pln: buf[5], ar_op: common::u16_parse(&buf[6..8])}
-}
-
-fn parse_arp_body<'a>(typ: u16, buf: &'a[u8]) -> ArpBody {
+const ARP_HDR_LEN: usize = 8;
 
-    let src_ar_ha = &buf[0..6];
-    let source_addr = if typ == 0x0800 {
-        &buf[6..10]
-    } else {
-        &buf[6..12]
-    };
+fn parse_arp_header(buf: &[u8]) -> Result<ArpHdr, ParseError> {
+    if buf.len() < ARP_HDR_LEN {
+        return Err(ParseError::truncated(Layer::Arp, 0, ARP_HDR_LEN, buf.len()));
+    }
+    Ok(ArpHdr{ ar_hrd: common::u16_parse(&buf[0..2]), ar_pro: common::u16_parse(&buf[2..4]), ar_hln: buf[4], ar_pln: buf[5], ar_op: common::u16_parse(&buf[6..8])})
+}
 
-    let target_ar_ha = if typ == 0x0800 {
-        &buf[10..16]
-    } else {
-        &buf[12..18]
-    };
+fn parse_arp_body(addr_len: usize, buf: &[u8]) -> Result<ArpBody<'_>, ParseError> {
+    let body_len = 2 * (6 + addr_len);
+    if buf.len() < body_len {
+        return Err(ParseError::truncated(Layer::Arp, 0, body_len, buf.len()));
+    }
 
-    let target_addr = if typ == 0x0800 {
-        &buf[16..20]
-    } else {
-        &buf[18..24]
-    };
+    let src_ar_ha = ethernet::MacAddr::try_from(&buf[0..6])?;
+    let source_addr = &buf[6..6 + addr_len];
+    let target_ar_ha = ethernet::MacAddr::try_from(&buf[6 + addr_len..12 + addr_len])?;
+    let target_addr = &buf[12 + addr_len..body_len];
 
-    ArpBody{source:ArpInfo{ar_ha:src_ar_ha, addr:source_addr}, target: ArpInfo{ar_ha: target_ar_ha, addr: target_addr}}
+    Ok(ArpBody{source:ArpInfo{ar_ha:src_ar_ha, addr:source_addr}, target: ArpInfo{ar_ha: target_ar_ha, addr: target_addr}})
 }
 
 
@@ -89,37 +106,85 @@ fn parse_arp_body<'a>(typ: u16, buf: &'a[u8]) -> ArpBody {
 }*/
 
 impl <'a> TryFrom<&'a [u8]> for ArpPacket<'a> {
-    type Error = ();
-    fn try_from(buf: &'a [u8]) -> Result<Self, ()> {
+    type Error = ParseError;
+    fn try_from(buf: &'a [u8]) -> Result<Self, ParseError> {
         let eth_end = 12;
 
-        let (v, ptr) = vlan::parse_vlans(&buf[eth_end..buf.len()]).unwrap();
+        let eth_header = ethernet::EthHeader::try_from(buf)?;
+
+        let (v, ptr) = vlan::parse_vlans(&buf[eth_end..buf.len()]).map_err(|e| e.shift(eth_end))?;
 
-        let ethtype = ethertype::parse_ethtype(&buf[eth_end + ptr..eth_end + ptr + 2]);
+        let ethtype = ethertype::parse_ethtype(&buf[eth_end + ptr..buf.len()]).map_err(|e| e.shift(eth_end + ptr))?;
         if ethtype != ethertype::EtherTypes::Arp {
-            return Err(());
+            return Err(ParseError::new(Layer::Arp, eth_end + ptr, Reason::WrongEtherType(ethtype.0)));
         }
 
-        let arp_hdr = parse_arp_header(&buf[eth_end + ptr + 2..buf.len()]);
-
-        let arp_body = parse_arp_body(arp_hdr.ar_pro, &buf[eth_end + ptr + 2 + 8..buf.len()]);
-
-        Ok(ArpPacket { eth_header: ethernet::EthHeader::try_from(buf)?, vlans: v, arp_hdr: arp_hdr, arp_body: arp_body })
+        let arp_start = eth_end + ptr + 2;
+        ArpPacket::try_from((eth_header, v, &buf[arp_start..buf.len()])).map_err(|e| e.shift(arp_start))
     }
 }
 
-impl <'a> TryFrom<(ethernet::EthHeader<'a>, Option<Vec<vlan::Vlan>>, &'a [u8])> for ArpPacket<'a> {
-    type Error = ();
-    fn try_from(src: (ethernet::EthHeader<'a>, Option<Vec<vlan::Vlan>>, &'a [u8])) -> Result<ArpPacket, ()> {
+impl <'a> TryFrom<(ethernet::EthHeader, Option<Vec<vlan::Vlan>>, &'a [u8])> for ArpPacket<'a> {
+    type Error = ParseError;
+    fn try_from(src: (ethernet::EthHeader, Option<Vec<vlan::Vlan>>, &'a [u8])) -> Result<ArpPacket<'a>, ParseError> {
         let (eth_header, vlans, payload) = src;
-        let arp_hdr = parse_arp_header(&payload[0..payload.len()]);
+        let arp_hdr = parse_arp_header(payload)?;
+        if arp_hdr.ar_hln != 6 {
+            return Err(ParseError::new(Layer::Arp, 4, Reason::BadField("ar_hln")));
+        }
 
-        let arp_body = parse_arp_body(arp_hdr.ar_pro, &payload[8..payload.len()]);
+        let arp_body = parse_arp_body(arp_hdr.ar_pln as usize, &payload[ARP_HDR_LEN..payload.len()]).map_err(|e| e.shift(ARP_HDR_LEN))?;
 
         Ok(ArpPacket{eth_header, vlans, arp_hdr, arp_body})
     }
 }
+fn emit_arp_info(info: &ArpInfo, addr_len: usize, buf: &mut [u8]) -> Result<usize, ParseError> {
+    if info.addr.len() != addr_len {
+        return Err(ParseError::new(Layer::Arp, 6, Reason::BadField("addr")));
+    }
+    buf[0..6].copy_from_slice(&info.ar_ha.0);
+    buf[6..6 + addr_len].copy_from_slice(info.addr);
+    Ok(6 + addr_len)
+}
+
+impl <'a> Emit for ArpPacket<'a> {
+    fn emit_len(&self) -> usize {
+        let vlans = self.vlans.as_ref().map_or(0, |v| v.len());
+        12 + 4 * vlans + 2 + ARP_HDR_LEN + 2 * (6 + self.arp_hdr.ar_pln as usize)
+    }
+
+    /// Write the whole frame: Ethernet header, tags, EtherType and the ARP message
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        let mut ptr = self.eth_header.emit(buf)?;
+        if let Some(ref vlans) = self.vlans {
+            ptr += vlan::emit_vlans(vlans, &mut buf[ptr..]).map_err(|e| e.shift(ptr))?;
+        }
+        ptr += ethertype::EtherTypes::Arp.emit(&mut buf[ptr..]).map_err(|e| e.shift(ptr))?;
+
+        if self.arp_hdr.ar_hln != 6 {
+            return Err(ParseError::new(Layer::Arp, ptr + 4, Reason::BadField("ar_hln")));
+        }
+        let addr_len = self.arp_hdr.ar_pln as usize;
+        let arp_len = ARP_HDR_LEN + 2 * (6 + addr_len);
+        if buf.len() < ptr + arp_len {
+            return Err(ParseError::truncated(Layer::Arp, ptr, arp_len, buf.len() - ptr));
+        }
+        let hdr = &self.arp_hdr;
+        common::u16_write(&mut buf[ptr..], hdr.ar_hrd);
+        common::u16_write(&mut buf[ptr + 2..], hdr.ar_pro);
+        buf[ptr + 4] = hdr.ar_hln;
+        buf[ptr + 5] = hdr.ar_pln;
+        common::u16_write(&mut buf[ptr + 6..], hdr.ar_op);
+        ptr += ARP_HDR_LEN;
+
+        ptr += emit_arp_info(&self.arp_body.source, addr_len, &mut buf[ptr..]).map_err(|e| e.shift(ptr))?;
+        ptr += emit_arp_info(&self.arp_body.target, addr_len, &mut buf[ptr..]).map_err(|e| e.shift(ptr))?;
+        Ok(ptr)
+    }
+}
+
 #[cfg(test)]
+#[allow(unused_mut, clippy::unnecessary_cast)]
 mod tests {
     use super::*;
 
@@ -129,23 +194,80 @@ mod tests {
         let res = ArpPacket::try_from(&test_buf[0..test_buf.len()]).unwrap();
         assert_eq!(res, ArpPacket {
             eth_header: ethernet::EthHeader {
-                dst_mac: &[17, 18, 19, 20, 21, 22], src_mac: &[1, 2, 3, 4, 5, 6] },
-            vlans: Some(vec!(vlan::Vlan{priority_code_point:vlan::ClassesOfService::BE, drop_eligible_indicator:false, vid:1})),
+                dst_mac: ethernet::MacAddr([17, 18, 19, 20, 21, 22]), src_mac: ethernet::MacAddr([1, 2, 3, 4, 5, 6]) },
+            vlans: Some(vec!(vlan::Vlan{tpid:ethertype::EtherTypes::Vlan, priority_code_point:vlan::ClassesOfService::BE, drop_eligible_indicator:false, vid:1})),
             arp_hdr: ArpHdr {
                 ar_hrd: 1, ar_pro: 2048, ar_hln: 6, ar_pln: 4, ar_op: 1 },
             arp_body: ArpBody {
                 source: ArpInfo {
-                    ar_ha: &[1, 2, 3, 4, 5, 6], addr: &[1, 1, 1, 1] },
+                    ar_ha: ethernet::MacAddr([1, 2, 3, 4, 5, 6]), addr: &[1, 1, 1, 1] },
                 target: ArpInfo {
-                    ar_ha: &[0, 0, 0, 0, 0, 0], addr: &[2, 2, 2, 2] } } } );
+                    ar_ha: ethernet::MacAddr([0, 0, 0, 0, 0, 0]), addr: &[2, 2, 2, 2] } } } );
     }
     #[test]
     #[should_panic(expected="Not ARP")]
     fn check_not_arp() {
         let mut test_buf = [0x11 as u8,0x12,0x13,0x14,0x15,0x16,0x01,0x02,0x03,0x04,0x05,0x06,0x81,0x00,0x00,0x01,0x08,0x08,0x0,0x1,0x08,0,6,4,0,1,0x01,0x02,0x03,0x04,0x05,0x06,0x1,0x1,0x1,0x1,0,0,0,0,0,0,0x2,0x2,0x2,0x2];
-        let res = match ArpPacket::try_from(&test_buf[0..test_buf.len()]) {
-            Err(_) => panic!("Not ARP"),
-            _ => 0
-        };
+        if let Err(e) = ArpPacket::try_from(&test_buf[0..test_buf.len()]) {
+            assert_eq!(e, ParseError::new(Layer::Arp, 16, Reason::WrongEtherType(0x0808)));
+            panic!("Not ARP")
+        }
+    }
+    #[test]
+    fn check_truncated() {
+        let test_buf = [0x11u8,0x12,0x13,0x14,0x15,0x16,0x01,0x02,0x03,0x04,0x05,0x06,0x81,0x00,0x00,0x01,0x08,0x06,0x0,0x1,0x08,0,6,4,0,1,0x01,0x02,0x03,0x04,0x05,0x06,0x1,0x1,0x1,0x1,0,0,0,0,0,0,0x2,0x2,0x2,0x2];
+        for len in 0..test_buf.len() {
+            assert!(ArpPacket::try_from(&test_buf[..len]).is_err(), "len {}", len);
+        }
+        assert_eq!(ArpPacket::try_from(&test_buf[..30]), Err(ParseError::truncated(Layer::Arp, 26, 20, 4)));
+    }
+    #[test]
+    fn check_emit() {
+        let test_buf = [0x11u8,0x12,0x13,0x14,0x15,0x16,0x01,0x02,0x03,0x04,0x05,0x06,0x81,0x00,0x00,0x01,0x08,0x06,0x0,0x1,0x08,0,6,4,0,1,0x01,0x02,0x03,0x04,0x05,0x06,0x1,0x1,0x1,0x1,0,0,0,0,0,0,0x2,0x2,0x2,0x2];
+        let res = ArpPacket::try_from(&test_buf[..]).unwrap();
+        let mut out = vec!();
+        assert_eq!(res.emit_vec(&mut out), Ok(test_buf.len()));
+        assert_eq!(&out[..], &test_buf[..]);
+        assert_eq!(ArpPacket::try_from(&out[..]), Ok(res));
+    }
+    #[test]
+    fn check_emit_errors() {
+        let packet = ArpPacket {
+            eth_header: ethernet::EthHeader {
+                dst_mac: ethernet::MacAddr([17, 18, 19, 20, 21, 22]), src_mac: ethernet::MacAddr([1, 2, 3, 4, 5, 6]) },
+            vlans: None,
+            arp_hdr: ArpHdr {
+                ar_hrd: 1, ar_pro: 2048, ar_hln: 6, ar_pln: 4, ar_op: 2 },
+            arp_body: ArpBody {
+                source: ArpInfo {
+                    ar_ha: ethernet::MacAddr([1, 2, 3, 4, 5, 6]), addr: &[1, 1, 1, 1] },
+                target: ArpInfo {
+                    ar_ha: ethernet::MacAddr([0, 0, 0, 0, 0, 0]), addr: &[2, 2, 2] } } };
+        assert_eq!(packet.emit(&mut [0; 42]), Err(ParseError::new(Layer::Arp, 38, Reason::BadField("addr"))));
+        assert_eq!(packet.emit(&mut [0; 41]), Err(ParseError::truncated(Layer::Arp, 14, 28, 27)));
+    }
+    #[test]
+    fn check_ip_addr() {
+        let test_buf = [0x11u8,0x12,0x13,0x14,0x15,0x16,0x01,0x02,0x03,0x04,0x05,0x06,0x08,0x06,0x0,0x1,0x08,0,6,4,0,1,0x01,0x02,0x03,0x04,0x05,0x06,10,0,0,1,0,0,0,0,0,0,10,0,0,2];
+        let res = ArpPacket::try_from(&test_buf[..]).unwrap();
+        assert_eq!(res.arp_body.source.ip_addr(), Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))));
+        assert_eq!(res.arp_body.target.ip_addr(), Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2))));
+    }
+    #[test]
+    fn check_long_addr() {
+        let mut test_buf = vec!(0x11u8,0x12,0x13,0x14,0x15,0x16,0x01,0x02,0x03,0x04,0x05,0x06,0x08,0x06,0x0,0x1,0x86,0xdd,6,16,0,1);
+        test_buf.extend_from_slice(&[1, 2, 3, 4, 5, 6]);
+        test_buf.extend_from_slice(&Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1).octets());
+        test_buf.extend_from_slice(&[0; 6]);
+        test_buf.extend_from_slice(&Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 2).octets());
+        let res = ArpPacket::try_from(&test_buf[..]).unwrap();
+        assert_eq!(res.arp_body.source.ip_addr(), Some(IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1))));
+        assert_eq!(res.arp_body.target.ip_addr(), Some(IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 2))));
+        let mut out = vec!();
+        res.emit_vec(&mut out).unwrap();
+        assert_eq!(out, test_buf);
+
+        test_buf[18] = 8;
+        assert_eq!(ArpPacket::try_from(&test_buf[..]), Err(ParseError::new(Layer::Arp, 18, Reason::BadField("ar_hln"))));
     }
 }
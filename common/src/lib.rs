@@ -1,14 +1,239 @@
 extern crate byteorder;
-use byteorder::{ByteOrder, NetworkEndian, BigEndian};
+use byteorder::{ByteOrder, NetworkEndian};
 
+use std::error::Error;
+use std::fmt;
+
+/// Read a network order u16 from the first two bytes of `buf`.
+/// The caller checks the length, shorter buffers panic.
 pub fn u16_parse (buf: &[u8]) -> u16 {
     NetworkEndian::read_u16(&buf[0..2])
 }
 
+/// Write `val` in network order to the first two bytes of `buf`.
+/// The caller checks the length, shorter buffers panic.
+pub fn u16_write (buf: &mut [u8], val: u16) {
+    NetworkEndian::write_u16(&mut buf[0..2], val)
+}
+
+/// Read a network order u32 from the first four bytes of `buf`.
+/// The caller checks the length, shorter buffers panic.
+pub fn u32_parse (buf: &[u8]) -> u32 {
+    NetworkEndian::read_u32(&buf[0..4])
+}
+
+/// Write `val` in network order to the first four bytes of `buf`.
+/// The caller checks the length, shorter buffers panic.
+pub fn u32_write (buf: &mut [u8], val: u32) {
+    NetworkEndian::write_u32(&mut buf[0..4], val)
+}
+
+fn sum_words (buf: &[u8], mut sum: u32) -> u32 {
+    for chunk in buf.chunks(2) {
+        sum += if chunk.len() == 2 { u16_parse(chunk) as u32 } else { (chunk[0] as u32) << 8 };
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum
+}
+
+/// Internet checksum (RFC 1071) of `buf`, odd length is padded with zero
+pub fn checksum (buf: &[u8]) -> u16 {
+    !(sum_words(buf, 0) as u16)
+}
+
+/// Internet checksum of an IP pseudo-header followed by `buf`,
+/// `pseudo` has an even length
+pub fn checksum_pseudo (pseudo: &[u8], buf: &[u8]) -> u16 {
+    !(sum_words(buf, sum_words(pseudo, 0)) as u16)
+}
+
+/// Incremental checksum update (RFC 1624, eqn. 3) after a 16-bit word
+/// of the covered data changed from `old` to `new`
+pub fn checksum_update (crc: u16, old: u16, new: u16) -> u16 {
+    let mut sum = (!crc as u32) + (!old as u32) + (new as u32);
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Protocol layer at which a parse error happened
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Layer {
+    Ethernet,
+    Vlan,
+    EtherType,
+    Llc,
+    Arp,
+    IpV4,
+    IpV6,
+    Tcp,
+    Udp,
+    Icmp,
+    IcmpV6,
+    Igmp,
+    /// Link header other than Ethernet, such as Linux cooked or BSD loopback
+    Link,
+    /// Capture file format
+    Pcap,
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{}",
+               match *self {
+                   Layer::Ethernet => "Ethernet",
+                   Layer::Vlan => "VLAN",
+                   Layer::EtherType => "EtherType",
+                   Layer::Llc => "LLC",
+                   Layer::Arp => "ARP",
+                   Layer::IpV4 => "IPv4",
+                   Layer::IpV6 => "IPv6",
+                   Layer::Tcp => "TCP",
+                   Layer::Udp => "UDP",
+                   Layer::Icmp => "ICMP",
+                   Layer::IcmpV6 => "ICMPv6",
+                   Layer::Igmp => "IGMP",
+                   Layer::Link => "Link",
+                   Layer::Pcap => "PCAP",
+               })
+    }
+}
+
+/// Reason of a parse error
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Reason {
+    /// Buffer is shorter than the layer requires
+    Truncated { expected: usize, actual: usize },
+    /// Unsupported protocol version
+    BadVersion(u8),
+    /// IPv4 IHL out of range or bigger than the buffer
+    BadHeaderLength(u8),
+    /// Options do not fit into the header
+    TruncatedOptions,
+    /// EtherType is not the one the parser handles
+    WrongEtherType(u16),
+    /// IP protocol / next header is not the one the parser handles
+    WrongProtocol(u8),
+    /// Field value does not fit its wire format
+    BadField(&'static str),
+    /// Stored checksum differs from the computed one
+    BadChecksum { expected: u16, actual: u16 },
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Reason::Truncated { expected, actual } => write!(f, "truncated: expected {} bytes, got {}", expected, actual),
+            Reason::BadVersion(v) => write!(f, "bad version {}", v),
+            Reason::BadHeaderLength(l) => write!(f, "bad header length {}", l),
+            Reason::TruncatedOptions => write!(f, "truncated options"),
+            Reason::WrongEtherType(t) => write!(f, "wrong ethertype 0x{:04x}", t),
+            Reason::WrongProtocol(p) => write!(f, "wrong protocol {}", p),
+            Reason::BadField(name) => write!(f, "bad value of {}", name),
+            Reason::BadChecksum { expected, actual } => write!(f, "bad checksum: expected 0x{:04x}, got 0x{:04x}", expected, actual),
+        }
+    }
+}
+
+/// Error returned by every parser in the crate family.
+///
+/// `offset` is the position of the failed layer from the start of the buffer
+/// given to the outermost parser.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ParseError {
+    pub layer: Layer,
+    pub offset: usize,
+    pub reason: Reason,
+}
+
+impl ParseError {
+    pub fn new(layer: Layer, offset: usize, reason: Reason) -> ParseError {
+        ParseError { layer, offset, reason }
+    }
+
+    /// Shortcut for a `Reason::Truncated` error
+    pub fn truncated(layer: Layer, offset: usize, expected: usize, actual: usize) -> ParseError {
+        ParseError::new(layer, offset, Reason::Truncated { expected, actual })
+    }
+
+    /// Move the error by `base` bytes, used when a layer was parsed from a sub-slice
+    pub fn shift(self, base: usize) -> ParseError {
+        ParseError { offset: self.offset + base, ..self }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at offset {}: {}", self.layer, self.offset, self.reason)
+    }
+}
+
+impl Error for ParseError {}
+
+/// Types that can be written back to the wire format.
+///
+/// Errors reuse `ParseError`: a short output buffer is reported as
+/// `Reason::Truncated`, an unrepresentable field as `Reason::BadField`.
+pub trait Emit {
+    /// Number of bytes `emit` writes
+    fn emit_len(&self) -> usize;
+
+    /// Write the wire format to the start of `buf`, returns the number of bytes written
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError>;
+
+    /// Append the wire format to `out`, returns the number of bytes written
+    fn emit_vec(&self, out: &mut Vec<u8>) -> Result<usize, ParseError> {
+        let start = out.len();
+        out.resize(start + self.emit_len(), 0);
+        match self.emit(&mut out[start..]) {
+            Ok(n) => {
+                out.truncate(start + n);
+                Ok(n)
+            }
+            Err(e) => {
+                out.truncate(start);
+                Err(e)
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::*;
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+    #[test]
+    fn check_display() {
+        let e = ParseError::truncated(Layer::IpV4, 14, 20, 18);
+        assert_eq!(format!("{}", e), "IPv4 at offset 14: truncated: expected 20 bytes, got 18");
+        assert_eq!((Layer::Link.to_string(), Layer::Pcap.to_string()), ("Link".to_string(), "PCAP".to_string()));
+    }
+    #[test]
+    fn check_shift() {
+        let e = ParseError::new(Layer::Vlan, 2, Reason::WrongEtherType(0x0800)).shift(12);
+        assert_eq!(e.offset, 14);
+    }
+    #[test]
+    fn check_checksum() {
+        let hdr = [0x45u8,0x00,0x05,0xbf,0xe5,0x46,0x40,0x00,0x3e,0x06,0x00,0x00,0xac,0x10,0x00,0x53,0xc0,0xa8,0x16,0x47];
+        assert_eq!(checksum(&hdr), 0xce9f);
+        assert_eq!(checksum(&[0x01, 0x02, 0x03]), !0x0402);
+        assert_eq!(checksum_pseudo(&hdr[..10], &hdr[10..]), 0xce9f);
+        assert_eq!(checksum(&[0xffu8; 40000]), 0);
+    }
+    #[test]
+    fn check_checksum_update() {
+        let mut hdr = [0x45u8,0x00,0x05,0xbf,0xe5,0x46,0x40,0x00,0x3e,0x06,0xce,0x9f,0xac,0x10,0x00,0x53,0xc0,0xa8,0x16,0x47];
+        let old = u16_parse(&hdr[8..10]);
+        hdr[8] -= 1;
+        let crc = checksum_update(0xce9f, old, u16_parse(&hdr[8..10]));
+        hdr[10] = 0;
+        hdr[11] = 0;
+        assert_eq!(crc, checksum(&hdr));
+    }
 }
@@ -0,0 +1,491 @@
+extern crate common;
+extern crate ethernet;
+extern crate vlan;
+extern crate ip;
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::time::Duration;
+use common::{Emit, Layer, ParseError, Reason};
+use ip::{Flags, IpNextHeaderProtocol};
+
+/// Largest IPv4 datagram, header included
+const MAX_DATAGRAM: usize = 65535;
+
+/// Fragments belong to the same datagram when these fields match [RFC 791]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FragmentKey {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: IpNextHeaderProtocol,
+    pub id: u16,
+}
+
+/// Which data is kept where fragments overlap, after the target-based policies of IDSes
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum OverlapPolicy {
+    /// Data received first wins (Windows, Solaris)
+    First,
+    /// Data received last wins (Cisco IOS)
+    Last,
+    /// Later data wins where its fragment starts before the one it overlaps
+    Bsd,
+    /// Later data wins where its fragment starts before or at the start of the one it overlaps
+    Linux,
+}
+
+impl OverlapPolicy {
+    /// Whether `new` replaces the bytes of `old`, both being (start, end) payload offsets
+    fn new_wins(self, old: (usize, usize), new: (usize, usize)) -> bool {
+        match self {
+            OverlapPolicy::First => false,
+            OverlapPolicy::Last => true,
+            OverlapPolicy::Bsd => new.0 < old.0,
+            OverlapPolicy::Linux => new.0 <= old.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DefragConfig {
+    /// Incomplete datagrams older than this are dropped
+    pub timeout: Duration,
+    /// Bytes held for incomplete datagrams, the oldest are dropped beyond it
+    pub max_memory: usize,
+    /// Incomplete datagrams held at once, the oldest are dropped beyond it
+    pub max_datagrams: usize,
+    /// Fragments of a datagram, it is dropped when more arrive. At most 65535.
+    pub max_fragments: usize,
+    pub policy: OverlapPolicy,
+}
+
+/// Timeout, memory and overlap policy of Linux, 4096 datagrams of at most 64 fragments
+impl Default for DefragConfig {
+    fn default() -> DefragConfig {
+        DefragConfig {
+            timeout: Duration::from_secs(30),
+            max_memory: 4 << 20,
+            max_datagrams: 1 << 12,
+            max_fragments: 64,
+            policy: OverlapPolicy::Linux,
+        }
+    }
+}
+
+/// Datagram rebuilt from its fragments
+#[derive(Debug,PartialEq)]
+pub struct Datagram {
+    pub key: FragmentKey,
+    /// Link-layer headers of the first fragment received
+    pub eth_header: ethernet::EthHeader,
+    pub vlans: Option<Vec<vlan::Vlan>>,
+    /// IPv4 header of the first fragment with length, offset and checksum fixed, then the payload
+    pub data: Vec<u8>,
+    /// Number of fragments received, duplicates excluded
+    pub fragments: usize,
+    /// Some fragments overlapped, see `OverlapPolicy`
+    pub overlapping: bool,
+}
+
+impl Datagram {
+    /// The datagram as a packet the upper-layer parsers accept
+    pub fn packet(&self) -> Result<ip::IpV4Packet<'_>, ParseError> {
+        let ip_hdr = ip::IpV4Header::try_from(&self.data[..])?;
+        let payload = &self.data[ip_hdr.hdr_length as usize * 4..];
+        Ok(ip::IpV4Packet { eth_header: self.eth_header.clone(), vlans: self.vlans.clone(), ip_hdr, payload })
+    }
+}
+
+struct Pending {
+    id: u64,
+    eth_header: ethernet::EthHeader,
+    vlans: Option<Vec<vlan::Vlan>>,
+    /// Header of the fragment at offset 0
+    header: Option<Vec<u8>>,
+    /// Payload length, known once the last fragment arrived
+    total: Option<usize>,
+    data: Vec<u8>,
+    /// Index + 1 in `fragments` of the fragment each byte of `data` comes from, 0 for holes
+    owner: Vec<u16>,
+    fragments: Vec<(usize, usize)>,
+    overlapping: bool,
+}
+
+impl Pending {
+    /// Bytes held for the datagram, its entry in the table included
+    fn memory(&self) -> usize {
+        mem::size_of::<(FragmentKey, Pending)>()
+            + self.header.as_ref().map_or(0, |h| h.len())
+            + self.data.len()
+            + self.owner.len() * mem::size_of::<u16>()
+            + self.fragments.len() * mem::size_of::<(usize, usize)>()
+    }
+
+    fn is_complete(&self) -> bool {
+        match (&self.header, self.total) {
+            (&Some(_), Some(total)) => self.owner.len() >= total && self.owner[..total].iter().all(|&o| o != 0),
+            _ => false,
+        }
+    }
+
+    fn into_datagram(self, key: FragmentKey) -> Datagram {
+        let total = self.total.unwrap_or(0);
+        let mut data = self.header.unwrap_or_default();
+        let hdr_len = data.len();
+        data.extend_from_slice(&self.data[..total]);
+        common::u16_write(&mut data[2..], (hdr_len + total) as u16);
+        let flags = data[6] >> 5 & Flags::DONT_FRAGMENT;
+        common::u16_write(&mut data[6..], (flags as u16) << 13);
+        common::u16_write(&mut data[10..], 0);
+        let crc = common::checksum(&data[..hdr_len]);
+        common::u16_write(&mut data[10..], crc);
+        Datagram {
+            key,
+            eth_header: self.eth_header,
+            vlans: self.vlans,
+            data,
+            fragments: self.fragments.len(),
+            overlapping: self.overlapping,
+        }
+    }
+}
+
+/// Reassembles IPv4 datagrams from their fragments
+pub struct Defragmenter {
+    config: DefragConfig,
+    pending: HashMap<FragmentKey, Pending>,
+    /// Keys by the arrival of their first fragment, entries of datagrams gone are skipped
+    queue: VecDeque<(FragmentKey, u64, Duration)>,
+    next_id: u64,
+    memory: usize,
+}
+
+impl Defragmenter {
+    pub fn new(config: DefragConfig) -> Defragmenter {
+        Defragmenter { config, pending: HashMap::new(), queue: VecDeque::new(), next_id: 0, memory: 0 }
+    }
+
+    /// Number of incomplete datagrams
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Bytes held for incomplete datagrams
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
+
+    /// Drop the incomplete datagrams whose first fragment arrived more than `timeout` before `now`,
+    /// returns their number
+    pub fn expire(&mut self, now: Duration) -> usize {
+        let timeout = self.config.timeout;
+        let mut expired = 0;
+        while self.queue.front().is_some_and(|&(_, _, first_seen)| now.saturating_sub(first_seen) > timeout) {
+            let (key, id, _) = self.queue.pop_front().unwrap();
+            if self.is_queued(&key, id) {
+                self.remove(&key);
+                expired += 1;
+            }
+        }
+        expired
+    }
+
+    /// Whether the datagram queued as `key` is still pending
+    fn is_queued(&self, key: &FragmentKey, id: u64) -> bool {
+        self.pending.get(key).is_some_and(|p| p.id == id)
+    }
+
+    fn remove(&mut self, key: &FragmentKey) -> Option<Pending> {
+        let pending = self.pending.remove(key)?;
+        self.memory -= pending.memory();
+        Some(pending)
+    }
+
+    /// Drop the oldest incomplete datagram, false if there is none
+    fn evict(&mut self) -> bool {
+        while let Some((key, id, _)) = self.queue.pop_front() {
+            if self.is_queued(&key, id) {
+                self.remove(&key);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Drop the entries of removed datagrams once they outnumber the pending ones
+    fn compact(&mut self) {
+        if self.queue.len() > 2 * self.pending.len() + 64 {
+            let pending = &self.pending;
+            self.queue.retain(|&(ref key, id, _)| pending.get(key).is_some_and(|p| p.id == id));
+        }
+    }
+
+    /// Add a packet captured at `now`. Returns the datagram once all its fragments arrived,
+    /// packets that are not fragments are returned at once. Fragments repeating the range of
+    /// an earlier one are ignored, datagrams with more than `max_fragments` are dropped.
+    ///
+    /// Fails on truncated captures and on fragments inconsistent with the datagram, which is
+    /// then dropped. Offsets of errors count from the IPv4 header.
+    pub fn process(&mut self, packet: &ip::IpV4Packet, now: Duration) -> Result<Option<Datagram>, ParseError> {
+        self.expire(now);
+        let hdr = &packet.ip_hdr;
+        let hdr_len = hdr.hdr_length as usize * 4;
+        let payload = packet.complete_payload()?;
+        let more = hdr.flags & Flags::MORE_FRAGMENTS != 0;
+        let start = hdr.offset as usize * 8;
+        let end = start + payload.len();
+        if more && !payload.len().is_multiple_of(8) {
+            return Err(ParseError::new(Layer::IpV4, 2, Reason::BadField("packet_length")));
+        }
+        if hdr_len + end > MAX_DATAGRAM {
+            return Err(ParseError::new(Layer::IpV4, 6, Reason::BadField("offset")));
+        }
+        let key = FragmentKey { src: hdr.src_addr(), dst: hdr.dst_addr(), protocol: hdr.protocol, id: hdr.id };
+        let header = match start {
+            0 => {
+                let mut header = vec!();
+                hdr.emit_vec(&mut header)?;
+                Some(header)
+            }
+            _ => None,
+        };
+
+        if !more && start == 0 {
+            let mut data = header.unwrap_or_default();
+            data.extend_from_slice(payload);
+            return Ok(Some(Datagram {
+                key,
+                eth_header: packet.eth_header.clone(),
+                vlans: packet.vlans.clone(),
+                data,
+                fragments: 1,
+                overlapping: false,
+            }));
+        }
+
+        if !self.pending.contains_key(&key) {
+            while self.pending.len() >= self.config.max_datagrams.max(1) && self.evict() {}
+            let id = self.next_id;
+            self.next_id += 1;
+            let pending = Pending {
+                id,
+                eth_header: packet.eth_header.clone(),
+                vlans: packet.vlans.clone(),
+                header: None,
+                total: None,
+                data: vec!(),
+                owner: vec!(),
+                fragments: vec!(),
+                overlapping: false,
+            };
+            self.memory += pending.memory();
+            self.pending.insert(key, pending);
+            self.queue.push_back((key, id, now));
+            self.compact();
+        }
+
+        let policy = self.config.policy;
+        let max_fragments = self.config.max_fragments.min(u16::MAX as usize);
+        let pending = self.pending.get_mut(&key).unwrap();
+        let beyond_end = |total: usize| end > total || pending.owner.iter().skip(total).any(|&o| o != 0);
+        let consistent = match (more, pending.total) {
+            (false, Some(total)) => total == end,
+            (false, None) => !beyond_end(end),
+            (true, Some(total)) => !beyond_end(total),
+            (true, None) => true,
+        };
+        if !consistent {
+            self.remove(&key);
+            return Err(ParseError::new(Layer::IpV4, 6, Reason::BadField("offset")));
+        }
+        if pending.fragments.contains(&(start, end)) {
+            return Ok(None);
+        }
+        if pending.fragments.len() >= max_fragments {
+            self.remove(&key);
+            return Ok(None);
+        }
+        let before = pending.memory();
+        if !more {
+            pending.total = Some(end);
+        }
+        if pending.header.is_none() {
+            pending.header = header;
+        }
+
+        if end > pending.data.len() {
+            pending.data.resize(end, 0);
+            pending.owner.resize(end, 0);
+        }
+        pending.fragments.push((start, end));
+        let index = pending.fragments.len() as u16;
+        for (i, &b) in payload.iter().enumerate() {
+            let owner = pending.owner[start + i];
+            if owner != 0 {
+                pending.overlapping = true;
+                if !policy.new_wins(pending.fragments[owner as usize - 1], (start, end)) {
+                    continue;
+                }
+            }
+            pending.data[start + i] = b;
+            pending.owner[start + i] = index;
+        }
+        self.memory += pending.memory() - before;
+
+        if pending.is_complete() {
+            let pending = self.remove(&key).unwrap();
+            return Ok(Some(pending.into_datagram(key)));
+        }
+        while self.memory > self.config.max_memory && self.evict() {}
+        Ok(None)
+    }
+}
+
+impl Default for Defragmenter {
+    fn default() -> Defragmenter {
+        Defragmenter::new(DefragConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SRC: [u8; 4] = [10, 0, 0, 1];
+    const DST: [u8; 4] = [10, 0, 0, 2];
+
+    fn fragment(id: u16, offset: u16, more: bool, payload: &[u8]) -> Vec<u8> {
+        let hdr = ip::IpV4Header {
+            version: 4,
+            hdr_length: 5,
+            dscp: 0,
+            ecn: 0,
+            packet_length: 20 + payload.len() as u16,
+            id,
+            flags: if more { Flags::MORE_FRAGMENTS } else { 0 },
+            offset,
+            ttl: 64,
+            protocol: ip::IpNextHeaderProtocols::Udp,
+            crc: 0,
+            checksum_ok: None,
+            src_ip: &SRC,
+            dst_ip: &DST,
+            options: None,
+        };
+        let mut frame = vec!(0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x08, 0x00);
+        hdr.emit_vec(&mut frame).unwrap();
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Memory of a pending datagram with `payload` bytes in `fragments`
+    fn held(header: usize, payload: usize, fragments: usize) -> usize {
+        mem::size_of::<(FragmentKey, Pending)>() + header + payload * 3 + fragments * 16
+    }
+
+    fn add(defrag: &mut Defragmenter, frame: &[u8], secs: u64) -> Result<Option<Datagram>, ParseError> {
+        let packet = ip::IpV4Packet::try_from(frame).unwrap();
+        defrag.process(&packet, Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn test_reassembly() {
+        let payload: Vec<u8> = (0..40).collect();
+        let mut defrag = Defragmenter::default();
+        assert_eq!(add(&mut defrag, &fragment(7, 3, false, &payload[24..]), 0), Ok(None));
+        assert_eq!(add(&mut defrag, &fragment(7, 0, true, &payload[..16]), 0), Ok(None));
+        assert_eq!(add(&mut defrag, &fragment(8, 0, true, &payload[..16]), 0), Ok(None));
+        assert_eq!((defrag.pending(), defrag.memory()), (2, held(20, 40, 2) + held(20, 16, 1)));
+
+        let res = add(&mut defrag, &fragment(7, 2, true, &payload[16..24]), 1).unwrap().unwrap();
+        assert_eq!((res.fragments, res.overlapping), (3, false));
+        assert_eq!(res.key, FragmentKey { src: Ipv4Addr::from(SRC), dst: Ipv4Addr::from(DST), protocol: ip::IpNextHeaderProtocols::Udp, id: 7 });
+        assert_eq!(res.data, &fragment(7, 0, false, &payload)[14..]);
+        let packet = res.packet().unwrap();
+        assert_eq!(packet.payload, &payload[..]);
+        assert_eq!(ip::IpV4Header::parse(&res.data, ip::ChecksumCheck::Reject).map(|h| h.packet_length), Ok(60));
+        assert_eq!((defrag.pending(), defrag.memory()), (1, held(20, 16, 1)));
+
+        let whole = add(&mut defrag, &fragment(9, 0, false, &payload), 1).unwrap().unwrap();
+        assert_eq!(whole.data, &fragment(9, 0, false, &payload)[14..]);
+    }
+    #[test]
+    fn test_overlap_policies() {
+        let expected: [(OverlapPolicy, &[u8]); 4] = [
+            (OverlapPolicy::First, b"aaaaaaaabbbbbbbbbbbbbbbbcccccccc"),
+            (OverlapPolicy::Last, b"ddddddddddddddddeeeeeeeecccccccc"),
+            (OverlapPolicy::Bsd, b"aaaaaaaaddddddddbbbbbbbbcccccccc"),
+            (OverlapPolicy::Linux, b"ddddddddddddddddbbbbbbbbcccccccc"),
+        ];
+        for &(policy, data) in expected.iter() {
+            let mut defrag = Defragmenter::new(DefragConfig { policy, ..DefragConfig::default() });
+            assert_eq!(add(&mut defrag, &fragment(1, 0, true, b"aaaaaaaa"), 0), Ok(None));
+            assert_eq!(add(&mut defrag, &fragment(1, 1, true, b"bbbbbbbbbbbbbbbb"), 0), Ok(None));
+            assert_eq!(add(&mut defrag, &fragment(1, 0, true, b"dddddddddddddddd"), 0), Ok(None));
+            assert_eq!(add(&mut defrag, &fragment(1, 2, true, b"eeeeeeee"), 0), Ok(None));
+            let res = add(&mut defrag, &fragment(1, 3, false, b"cccccccc"), 0).unwrap().unwrap();
+            assert_eq!((res.fragments, res.overlapping), (5, true));
+            assert_eq!(res.packet().unwrap().payload, data, "{:?}", policy);
+        }
+    }
+    #[test]
+    fn test_limits() {
+        let max_memory = 2 * held(20, 16, 1);
+        let mut defrag = Defragmenter::new(DefragConfig { max_memory, ..DefragConfig::default() });
+        assert_eq!(add(&mut defrag, &fragment(1, 0, true, &[0; 16]), 0), Ok(None));
+        assert_eq!(add(&mut defrag, &fragment(2, 0, true, &[0; 16]), 1), Ok(None));
+        assert_eq!(add(&mut defrag, &fragment(3, 0, true, &[0; 16]), 2), Ok(None));
+        assert_eq!((defrag.pending(), defrag.memory()), (2, max_memory));
+        assert_eq!(add(&mut defrag, &fragment(1, 2, false, &[0; 8]), 3), Ok(None));
+        assert_eq!((defrag.pending(), defrag.memory()), (1, held(0, 24, 1)));
+
+        assert_eq!(defrag.expire(Duration::from_secs(33)), 0);
+        assert_eq!(defrag.expire(Duration::from_secs(34)), 1);
+        assert_eq!(defrag.memory(), 0);
+        assert_eq!(add(&mut defrag, &fragment(2, 2, false, &[0; 8]), 40), Ok(None));
+        assert_eq!((defrag.pending(), defrag.memory()), (1, held(0, 24, 1)));
+
+        let config = DefragConfig { max_datagrams: 2, max_fragments: 2, ..DefragConfig::default() };
+        let mut defrag = Defragmenter::new(config);
+        for id in 0..1000 {
+            assert_eq!(add(&mut defrag, &fragment(id, 0, true, &[0; 8]), 0), Ok(None));
+            assert!(defrag.pending() <= 2 && defrag.queue.len() <= 2 * 2 + 64);
+        }
+        assert_eq!(add(&mut defrag, &fragment(999, 1, true, &[0; 8]), 0), Ok(None));
+        assert_eq!(defrag.memory(), held(20, 8, 1) + held(20, 16, 2));
+        // a third fragment drops the datagram
+        assert_eq!(add(&mut defrag, &fragment(999, 2, false, &[0; 8]), 0), Ok(None));
+        assert_eq!((defrag.pending(), defrag.memory()), (1, held(20, 8, 1)));
+    }
+    #[test]
+    fn test_duplicates() {
+        let mut defrag = Defragmenter::default();
+        assert_eq!(add(&mut defrag, &fragment(1, 0, true, &[1; 16]), 0), Ok(None));
+        let memory = defrag.memory();
+        for _ in 0..100 {
+            assert_eq!(add(&mut defrag, &fragment(1, 0, true, &[2; 16]), 0), Ok(None));
+        }
+        assert_eq!(defrag.memory(), memory);
+        let res = add(&mut defrag, &fragment(1, 2, false, &[3; 8]), 0).unwrap().unwrap();
+        assert_eq!((res.fragments, res.overlapping), (2, false));
+        assert_eq!(res.packet().unwrap().payload, &[1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 3, 3, 3, 3, 3, 3, 3, 3][..]);
+    }
+    #[test]
+    fn test_inconsistent() {
+        let mut defrag = Defragmenter::default();
+        assert_eq!(add(&mut defrag, &fragment(1, 0, true, &[0; 12]), 0),
+                   Err(ParseError::new(Layer::IpV4, 2, Reason::BadField("packet_length"))));
+        assert_eq!(add(&mut defrag, &fragment(1, 2, true, &[0; 16]), 0), Ok(None));
+        assert_eq!(add(&mut defrag, &fragment(1, 1, false, &[0; 8]), 0),
+                   Err(ParseError::new(Layer::IpV4, 6, Reason::BadField("offset"))));
+        assert_eq!(defrag.pending(), 0);
+        assert_eq!(add(&mut defrag, &fragment(1, 8190, true, &[0; 16]), 0),
+                   Err(ParseError::new(Layer::IpV4, 6, Reason::BadField("offset"))));
+
+        let frame = fragment(1, 0, true, &[0; 16]);
+        let packet = ip::IpV4Packet::try_from(&frame[..44]).unwrap();
+        assert_eq!(defrag.process(&packet, Duration::from_secs(0)), Err(ParseError::truncated(Layer::IpV4, 20, 16, 10)));
+    }
+}
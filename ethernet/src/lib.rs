@@ -1,36 +1,259 @@
-#![feature(try_from)]
+extern crate common;
+
 use std::convert::TryFrom;
+use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use common::{Emit, Layer, ParseError, Reason};
+
+/// IEEE 802 MAC address
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub fn new(a: u8, b: u8, c: u8, d: u8, e: u8, f: u8) -> MacAddr {
+        MacAddr([a, b, c, d, e, f])
+    }
+
+    /// ff:ff:ff:ff:ff:ff
+    pub fn broadcast() -> MacAddr {
+        MacAddr([0xff; 6])
+    }
+
+    /// 00:00:00:00:00:00
+    pub fn zero() -> MacAddr {
+        MacAddr([0; 6])
+    }
+
+    pub fn octets(&self) -> [u8; 6] {
+        self.0
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        *self == MacAddr::broadcast()
+    }
+
+    pub fn is_zero(&self) -> bool {
+        *self == MacAddr::zero()
+    }
+
+    /// Group address, the I/G bit is set. Broadcast is multicast too.
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 0x01 != 0
+    }
+
+    pub fn is_unicast(&self) -> bool {
+        !self.is_multicast()
+    }
+
+    /// The U/L bit is set
+    pub fn is_locally_administered(&self) -> bool {
+        self.0[0] & 0x02 != 0
+    }
+
+    pub fn is_universal(&self) -> bool {
+        !self.is_locally_administered()
+    }
+
+    /// MAC address of an IPv4 multicast group (RFC 1112), `None` for other addresses
+    pub fn from_ipv4_multicast(ip: Ipv4Addr) -> Option<MacAddr> {
+        if !ip.is_multicast() {
+            return None;
+        }
+        let o = ip.octets();
+        Some(MacAddr([0x01, 0x00, 0x5e, o[1] & 0x7f, o[2], o[3]]))
+    }
+
+    /// MAC address of an IPv6 multicast group (RFC 2464), `None` for other addresses
+    pub fn from_ipv6_multicast(ip: Ipv6Addr) -> Option<MacAddr> {
+        if !ip.is_multicast() {
+            return None;
+        }
+        let o = ip.octets();
+        Some(MacAddr([0x33, 0x33, o[12], o[13], o[14], o[15]]))
+    }
+}
+
+impl From<[u8; 6]> for MacAddr {
+    fn from(octets: [u8; 6]) -> MacAddr {
+        MacAddr(octets)
+    }
+}
+
+impl <'a> TryFrom<&'a [u8]> for MacAddr {
+    type Error = ParseError;
+    fn try_from(buf: &'a [u8]) -> Result<MacAddr, ParseError> {
+        if buf.len() != 6 {
+            return Err(ParseError::new(Layer::Ethernet, 0, Reason::BadField("mac address")));
+        }
+        let mut mac = [0u8; 6];
+        mac.copy_from_slice(buf);
+        Ok(MacAddr(mac))
+    }
+}
+
+impl fmt::Display for MacAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let o = &self.0;
+        write!(f, "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", o[0], o[1], o[2], o[3], o[4], o[5])
+    }
+}
+
+/// Parse `aa:bb:cc:dd:ee:ff`, dashes are accepted as separators too
+impl FromStr for MacAddr {
+    type Err = ParseError;
+    fn from_str(s: &str) -> Result<MacAddr, ParseError> {
+        let err = ParseError::new(Layer::Ethernet, 0, Reason::BadField("mac address"));
+        let mut mac = [0u8; 6];
+        let mut parts = s.split(&[':', '-'][..]);
+        for octet in mac.iter_mut() {
+            match parts.next() {
+                Some(p) if p.len() == 2 && p.bytes().all(|b| b.is_ascii_hexdigit()) => {
+                    *octet = u8::from_str_radix(p, 16).map_err(|_| err)?
+                }
+                _ => return Err(err),
+            }
+        }
+        if parts.next().is_some() {
+            return Err(err);
+        }
+        Ok(MacAddr(mac))
+    }
+}
 
-#[derive(Debug,PartialEq)]
-pub struct EthHeader <'a>{
-    pub dst_mac: &'a[u8],
-    pub src_mac: &'a[u8]
+#[derive(Clone,Debug,PartialEq)]
+pub struct EthHeader {
+    pub dst_mac: MacAddr,
+    pub src_mac: MacAddr
 }
 
-impl <'a>TryFrom<&'a [u8]> for EthHeader<'a> {
-    type Error = ();
-    fn try_from(buf: &'a [u8]) -> Result<EthHeader, ()> {
+impl <'a>TryFrom<&'a [u8]> for EthHeader {
+    type Error = ParseError;
+    fn try_from(buf: &'a [u8]) -> Result<EthHeader, ParseError> {
         if buf.len()>=12 {
-            Ok(EthHeader { dst_mac: &buf[0..6], src_mac: &buf[6..12] })
+            Ok(EthHeader { dst_mac: MacAddr::try_from(&buf[0..6])?, src_mac: MacAddr::try_from(&buf[6..12])? })
         } else {
-            Err(())
+            Err(ParseError::truncated(Layer::Ethernet, 0, 12, buf.len()))
         }
     }
 }
 
+impl Emit for EthHeader {
+    fn emit_len(&self) -> usize {
+        12
+    }
+
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        if buf.len() < 12 {
+            return Err(ParseError::truncated(Layer::Ethernet, 0, 12, buf.len()));
+        }
+        buf[0..6].copy_from_slice(&self.dst_mac.0);
+        buf[6..12].copy_from_slice(&self.src_mac.0);
+        Ok(12)
+    }
+}
+
+/// Mutable view of the Ethernet header inside a received frame
+#[derive(Debug)]
+pub struct EthHeaderMut <'a> {
+    buf: &'a mut [u8]
+}
+
+impl <'a> TryFrom<&'a mut [u8]> for EthHeaderMut<'a> {
+    type Error = ParseError;
+    fn try_from(buf: &'a mut [u8]) -> Result<EthHeaderMut<'a>, ParseError> {
+        EthHeader::try_from(&buf[..])?;
+        Ok(EthHeaderMut { buf })
+    }
+}
+
+impl <'a> EthHeaderMut<'a> {
+    /// Copy of the current header
+    pub fn header(&self) -> EthHeader {
+        let mut dst_mac = [0; 6];
+        let mut src_mac = [0; 6];
+        dst_mac.copy_from_slice(&self.buf[0..6]);
+        src_mac.copy_from_slice(&self.buf[6..12]);
+        EthHeader { dst_mac: MacAddr(dst_mac), src_mac: MacAddr(src_mac) }
+    }
+
+    pub fn set_dst_mac(&mut self, mac: MacAddr) {
+        self.buf[0..6].copy_from_slice(&mac.0);
+    }
+
+    pub fn set_src_mac(&mut self, mac: MacAddr) {
+        self.buf[6..12].copy_from_slice(&mac.0);
+    }
+}
+
 #[cfg(test)]
+#[allow(clippy::unnecessary_cast)]
 mod tests {
     use super::*;
     #[test]
     fn test_ok() {
         let buf = [11 as u8,22, 33,44,55,66,21,22,23,24,25,26,2];
         let eth = EthHeader::try_from(&buf[..]);
-        assert!(eth == Ok(EthHeader{dst_mac: &[11 as u8, 22,33,44,55,66], src_mac: &[21 as u8,22,23,24,25,26]}));
+        assert!(eth == Ok(EthHeader{dst_mac: MacAddr([11 as u8, 22,33,44,55,66]), src_mac: MacAddr([21 as u8,22,23,24,25,26])}));
     }
     #[test]
     fn test_too_short() {
         let buf = [11 as u8,22, 33,44,55,66,21,22,23,24,25];
         let eth = EthHeader::try_from(&buf[..]);
-        assert!(eth == Err(()));
+        assert!(eth == Err(ParseError::truncated(Layer::Ethernet, 0, 12, 11)));
     }
-}
\ No newline at end of file
+    #[test]
+    fn test_emit() {
+        let buf = [11u8,22, 33,44,55,66,21,22,23,24,25,26];
+        let eth = EthHeader::try_from(&buf[..]).unwrap();
+        let mut out = vec!();
+        assert_eq!(eth.emit_vec(&mut out), Ok(12));
+        assert_eq!(&out[..], &buf[..]);
+        assert_eq!(EthHeader::try_from(&out[..]), Ok(eth));
+    }
+    #[test]
+    fn test_emit_errors() {
+        let eth = EthHeader{dst_mac: MacAddr([1, 2, 3, 4, 5, 6]), src_mac: MacAddr([1, 2, 3, 4, 5, 6])};
+        assert_eq!(eth.emit(&mut [0; 11]), Err(ParseError::truncated(Layer::Ethernet, 0, 12, 11)));
+    }
+    #[test]
+    fn test_mut() {
+        let mut buf = [11u8,22, 33,44,55,66,21,22,23,24,25,26,2];
+        {
+            let mut eth = EthHeaderMut::try_from(&mut buf[..]).unwrap();
+            eth.set_dst_mac(MacAddr([1, 2, 3, 4, 5, 6]));
+            eth.set_src_mac(MacAddr([6, 5, 4, 3, 2, 1]));
+            assert_eq!(eth.header(), EthHeader{dst_mac: MacAddr([1, 2, 3, 4, 5, 6]), src_mac: MacAddr([6, 5, 4, 3, 2, 1])});
+        }
+        assert_eq!(buf, [1, 2, 3, 4, 5, 6, 6, 5, 4, 3, 2, 1, 2]);
+        assert_eq!(EthHeaderMut::try_from(&mut buf[..11]).unwrap_err(), ParseError::truncated(Layer::Ethernet, 0, 12, 11));
+    }
+    #[test]
+    fn test_mac_format() {
+        let mac = MacAddr::new(0xd0, 0x17, 0xc2, 0x97, 0x48, 0xb2);
+        assert_eq!(mac.to_string(), "d0:17:c2:97:48:b2");
+        assert_eq!("d0:17:c2:97:48:b2".parse(), Ok(mac));
+        assert_eq!("D0-17-C2-97-48-B2".parse(), Ok(mac));
+        let err = Err(ParseError::new(Layer::Ethernet, 0, Reason::BadField("mac address")));
+        assert_eq!("d0:17:c2:97:48".parse::<MacAddr>(), err);
+        assert_eq!("d0:17:c2:97:48:b2:00".parse::<MacAddr>(), err);
+        assert_eq!("d0:17:c2:97:48:g2".parse::<MacAddr>(), err);
+        assert_eq!("d0:17:c2:97:48:+2".parse::<MacAddr>(), err);
+        assert_eq!(MacAddr::try_from(&[1u8, 2, 3][..]), err);
+    }
+    #[test]
+    fn test_mac_classes() {
+        assert!(MacAddr::broadcast().is_broadcast() && MacAddr::broadcast().is_multicast());
+        assert!(MacAddr::zero().is_zero() && MacAddr::zero().is_unicast() && MacAddr::zero().is_universal());
+        assert!(MacAddr::new(0x01, 0x00, 0x5e, 0, 0, 1).is_multicast());
+        assert!(MacAddr::new(0x02, 0, 0, 0, 0, 1).is_locally_administered());
+        assert!(!MacAddr::new(0xd0, 0x17, 0xc2, 0x97, 0x48, 0xb2).is_locally_administered());
+    }
+    #[test]
+    fn test_mac_multicast() {
+        assert_eq!(MacAddr::from_ipv4_multicast(Ipv4Addr::new(239, 129, 2, 3)), Some(MacAddr::new(0x01, 0x00, 0x5e, 0x01, 0x02, 0x03)));
+        assert_eq!(MacAddr::from_ipv4_multicast(Ipv4Addr::new(10, 0, 0, 1)), None);
+        assert_eq!(MacAddr::from_ipv6_multicast("ff02::1:ff00:1234".parse().unwrap()), Some(MacAddr::new(0x33, 0x33, 0xff, 0x00, 0x12, 0x34)));
+        assert_eq!(MacAddr::from_ipv6_multicast(Ipv6Addr::LOCALHOST), None);
+    }
+}
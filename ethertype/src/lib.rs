@@ -1,6 +1,7 @@
 extern crate common;
 
 use std::fmt;
+use common::{Emit, Layer, ParseError};
 
 #[allow(non_snake_case)]
 #[allow(non_upper_case_globals)]
@@ -57,42 +58,52 @@ pub mod EtherTypes {
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct EtherType(pub u16);
 
+/// Biggest value of the type field that is an IEEE 802.3 length
+pub const MAX_LENGTH: u16 = 1500;
+
+impl EtherType {
+    /// The field is an 802.3 length followed by an LLC header, not an EtherType
+    pub fn is_length(&self) -> bool {
+        self.0 <= MAX_LENGTH
+    }
+}
+
 impl fmt::Display for EtherType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f,
                "{}",
-               match self {
-                   &EtherTypes::Ipv4 => "Ipv4", //(0x0800)
-                   &EtherTypes::Arp => "Arp", //(0x0806)
-                   &EtherTypes::WakeOnLan => "WakeOnLan", //(0x0842)
-                   &EtherTypes::Trill => "Trill", //(0x22F3)
-                   &EtherTypes::DECnet => "DECnet", //(0x6003)
-                   &EtherTypes::Rarp => "Rarp", //(0x8035)
-                   &EtherTypes::AppleTalk => "AppleTalk", //(0x809B)
-                   &EtherTypes::Aarp => "Aarp", //(0x80F3)
-                   &EtherTypes::Ipx => "Ipx", //(0x8137)
-                   &EtherTypes::Qnx => "Qnx", //(0x8204)
-                   &EtherTypes::Ipv6 => "Ipv6", //(0x86DD)
-                   &EtherTypes::FlowControl => "FlowControl", //(0x8808)
-                   &EtherTypes::CobraNet => "CobraNet", //(0x8819)
-                   &EtherTypes::Mpls => "Mpls", //(0x8847)
-                   &EtherTypes::MplsMcast => "MplsMcast", //(0x8848)
-                   &EtherTypes::PppoeDiscovery => "PppoeDiscovery", //(0x8863)
-                   &EtherTypes::PppoeSession => "PppoeSession", //(0x8864)
-                   &EtherTypes::Vlan => "Vlan", //(0x8100)
-                   &EtherTypes::PBridge => "PBridge", //(0x88a8)
-                   &EtherTypes::Lldp => "Lldp", //(0x88cc)
-                   &EtherTypes::Ptp => "Ptp", //(0x88f7)
-                   &EtherTypes::Cfm => "Cfm", //(0x8902)
-                   &EtherTypes::QinQ => "QinQ", //(0x9100)
+               match *self {
+                   EtherTypes::Ipv4 => "Ipv4", //(0x0800)
+                   EtherTypes::Arp => "Arp", //(0x0806)
+                   EtherTypes::WakeOnLan => "WakeOnLan", //(0x0842)
+                   EtherTypes::Trill => "Trill", //(0x22F3)
+                   EtherTypes::DECnet => "DECnet", //(0x6003)
+                   EtherTypes::Rarp => "Rarp", //(0x8035)
+                   EtherTypes::AppleTalk => "AppleTalk", //(0x809B)
+                   EtherTypes::Aarp => "Aarp", //(0x80F3)
+                   EtherTypes::Ipx => "Ipx", //(0x8137)
+                   EtherTypes::Qnx => "Qnx", //(0x8204)
+                   EtherTypes::Ipv6 => "Ipv6", //(0x86DD)
+                   EtherTypes::FlowControl => "FlowControl", //(0x8808)
+                   EtherTypes::CobraNet => "CobraNet", //(0x8819)
+                   EtherTypes::Mpls => "Mpls", //(0x8847)
+                   EtherTypes::MplsMcast => "MplsMcast", //(0x8848)
+                   EtherTypes::PppoeDiscovery => "PppoeDiscovery", //(0x8863)
+                   EtherTypes::PppoeSession => "PppoeSession", //(0x8864)
+                   EtherTypes::Vlan => "Vlan", //(0x8100)
+                   EtherTypes::PBridge => "PBridge", //(0x88a8)
+                   EtherTypes::Lldp => "Lldp", //(0x88cc)
+                   EtherTypes::Ptp => "Ptp", //(0x88f7)
+                   EtherTypes::Cfm => "Cfm", //(0x8902)
+                   EtherTypes::QinQ => "QinQ", //(0x9100)
                    _ => "unknown",
                })
     }
 }
 
-impl Into<u16> for EtherType {
-    fn into(self) -> u16 {
-        self.0
+impl From<EtherType> for u16 {
+    fn from(e: EtherType) -> u16 {
+        e.0
     }
 }
 
@@ -102,14 +113,53 @@ impl From<u16> for EtherType {
     }
 }
 
-pub fn parse_ethtype <'a> (buf: &'a [u8]) -> EtherType {
-    EtherType(common::u16_parse(buf))
+/// Read the type/length field, see `EtherType::is_length` for 802.3 frames
+pub fn parse_ethtype (buf: &[u8]) -> Result<EtherType, ParseError> {
+    if buf.len() < 2 {
+        return Err(ParseError::truncated(Layer::EtherType, 0, 2, buf.len()));
+    }
+    Ok(EtherType(common::u16_parse(buf)))
+}
+
+impl Emit for EtherType {
+    fn emit_len(&self) -> usize {
+        2
+    }
+
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        if buf.len() < 2 {
+            return Err(ParseError::truncated(Layer::EtherType, 0, 2, buf.len()));
+        }
+        common::u16_write(buf, self.0);
+        Ok(2)
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::*;
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+    #[test]
+    fn check_parse() {
+        assert_eq!(parse_ethtype(&[0x08, 0x00, 0x45]), Ok(EtherTypes::Ipv4));
+        assert!(!EtherTypes::Ipv4.is_length());
+        assert!(parse_ethtype(&[0x05, 0xdc]).unwrap().is_length());
+        assert!(!parse_ethtype(&[0x05, 0xdd]).unwrap().is_length());
+    }
+    #[test]
+    fn check_truncated() {
+        for len in 0..2 {
+            assert_eq!(parse_ethtype(&[0x08, 0x06][..len]), Err(ParseError::truncated(Layer::EtherType, 0, 2, len)));
+        }
+    }
+    #[test]
+    fn check_emit() {
+        let mut buf = [0u8; 2];
+        assert_eq!(EtherTypes::Arp.emit(&mut buf), Ok(2));
+        assert_eq!(parse_ethtype(&buf), Ok(EtherTypes::Arp));
+        assert_eq!(EtherTypes::Arp.emit(&mut buf[..1]), Err(ParseError::truncated(Layer::EtherType, 0, 2, 1)));
+    }
 }
@@ -0,0 +1,536 @@
+extern crate common;
+extern crate vlan;
+extern crate ip;
+extern crate ipv6;
+extern crate tcp;
+
+use std::collections::{HashMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use common::ParseError;
+use ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+use tcp::TcpFlags;
+
+/// Addresses of a flow as sent by `src`. Both directions of a flow share the same
+/// `canonical` key.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FlowKey {
+    /// VLAN ids, outer first
+    pub vlans: Vec<u16>,
+    pub protocol: IpNextHeaderProtocol,
+    /// Port 0 for protocols without ports
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+}
+
+/// Source and destination ports of the transports that start with them
+fn ports(protocol: IpNextHeaderProtocol, payload: &[u8]) -> (u16, u16) {
+    use ip::IpNextHeaderProtocols as P;
+    match protocol {
+        P::Tcp | P::Udp | P::UdpLite | P::Sctp if payload.len() >= 4 =>
+            (common::u16_parse(&payload[0..2]), common::u16_parse(&payload[2..4])),
+        _ => (0, 0),
+    }
+}
+
+/// Control bits of a TCP header, `None` for other protocols or a header cut before them
+fn tcp_flags(protocol: IpNextHeaderProtocol, payload: &[u8]) -> Option<TcpFlags> {
+    match protocol == IpNextHeaderProtocols::Tcp && payload.len() >= 14 {
+        true => Some(TcpFlags(common::u16_parse(&payload[12..14]) & 0x1ff)),
+        false => None,
+    }
+}
+
+fn vids(vlans: &Option<Vec<vlan::Vlan>>) -> Vec<u16> {
+    vlans.as_ref().map(|v| v.iter().map(|v| v.vid).collect()).unwrap_or_default()
+}
+
+impl FlowKey {
+    /// Key of an IPv4 packet. Non-first fragments carry no ports, they get port 0.
+    pub fn from_ipv4(packet: &ip::IpV4Packet) -> FlowKey {
+        let hdr = &packet.ip_hdr;
+        let (src_port, dst_port) = match hdr.offset {
+            0 => ports(hdr.protocol, packet.payload),
+            _ => (0, 0),
+        };
+        FlowKey {
+            vlans: vids(&packet.vlans),
+            protocol: hdr.protocol,
+            src: SocketAddr::new(IpAddr::V4(hdr.src_addr()), src_port),
+            dst: SocketAddr::new(IpAddr::V4(hdr.dst_addr()), dst_port),
+        }
+    }
+
+    /// Key of an IPv6 packet, the protocol is the one after the extension headers
+    pub fn from_ipv6(packet: &ipv6::IpV6Packet) -> Result<FlowKey, ParseError> {
+        let (protocol, payload) = packet.upper_layer()?;
+        let (src_port, dst_port) = ports(protocol, payload);
+        Ok(FlowKey {
+            vlans: vids(&packet.vlans),
+            protocol,
+            src: SocketAddr::new(IpAddr::V6(packet.ip_hdr.src_addr()), src_port),
+            dst: SocketAddr::new(IpAddr::V6(packet.ip_hdr.dst_addr()), dst_port),
+        })
+    }
+
+    /// Same flow seen from `dst`
+    pub fn reversed(&self) -> FlowKey {
+        FlowKey { vlans: self.vlans.clone(), protocol: self.protocol, src: self.dst, dst: self.src }
+    }
+
+    /// Orientation shared by both directions, the lowest endpoint first
+    pub fn canonical(&self) -> FlowKey {
+        match self.src <= self.dst {
+            true => self.clone(),
+            false => self.reversed(),
+        }
+    }
+}
+
+/// Counters of one direction of a flow
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FlowCounters {
+    pub packets: u64,
+    /// IP bytes, headers included
+    pub bytes: u64,
+    /// Flags of the first TCP segment
+    pub initial_tcp_flags: TcpFlags,
+    /// Union of the flags of all TCP segments
+    pub tcp_flags: TcpFlags,
+}
+
+impl FlowCounters {
+    fn add(&mut self, bytes: usize, tcp_flags: Option<TcpFlags>) {
+        if let Some(flags) = tcp_flags {
+            if self.packets == 0 {
+                self.initial_tcp_flags = flags;
+            }
+            self.tcp_flags = self.tcp_flags | flags;
+        }
+        self.packets += 1;
+        self.bytes += bytes as u64;
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Flow {
+    /// Oriented from the sender of the first packet seen
+    pub key: FlowKey,
+    pub first_seen: Duration,
+    pub last_seen: Duration,
+    /// Sent from `key.src`
+    pub forward: FlowCounters,
+    /// Sent from `key.dst`
+    pub reverse: FlowCounters,
+    id: u64,
+}
+
+impl Flow {
+    /// RST seen or FIN sent by both sides
+    fn tcp_ended(&self) -> bool {
+        (self.forward.tcp_flags | self.reverse.tcp_flags).contains(TcpFlags::RST)
+            || (self.forward.tcp_flags.contains(TcpFlags::FIN) && self.reverse.tcp_flags.contains(TcpFlags::FIN))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ExpiryReason {
+    /// No packet within `FlowConfig::idle_timeout`
+    Idle,
+    /// The flow lasted `FlowConfig::active_timeout`, its next packets start a new record
+    Active,
+    /// RST seen or FIN sent by both sides, then `FlowConfig::tcp_close_timeout` passed
+    TcpEnd,
+    /// Removed to make room, the table reached `FlowConfig::max_flows`
+    Evicted,
+    /// `FlowTable::flush` was called, usually at the end of a capture
+    Flush,
+}
+
+/// Receives the flows leaving the table
+pub trait FlowSink {
+    fn expired(&mut self, flow: Flow, reason: ExpiryReason);
+}
+
+impl FlowSink for Vec<(Flow, ExpiryReason)> {
+    fn expired(&mut self, flow: Flow, reason: ExpiryReason) {
+        self.push((flow, reason));
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FlowConfig {
+    /// Flows without packets for longer are expired
+    pub idle_timeout: Duration,
+    /// Flows lasting longer are reported and restarted
+    pub active_timeout: Duration,
+    /// Flows tracked at once, the least recently active are evicted beyond it
+    pub max_flows: usize,
+    /// Ended TCP flows are kept this long after their last packet, so that the final ACK
+    /// and retransmitted FINs are accounted to them
+    pub tcp_close_timeout: Duration,
+}
+
+impl Default for FlowConfig {
+    fn default() -> FlowConfig {
+        FlowConfig {
+            idle_timeout: Duration::from_secs(15),
+            active_timeout: Duration::from_secs(1800),
+            max_flows: 1 << 16,
+            tcp_close_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Groups packets into bidirectional flows
+pub struct FlowTable<S: FlowSink> {
+    sink: S,
+    config: FlowConfig,
+    flows: HashMap<FlowKey, Flow>,
+    /// Canonical keys by the activity they had when queued. An entry is moved back
+    /// when its flow was active since, and dropped when the flow is gone.
+    queue: VecDeque<(FlowKey, u64, Duration)>,
+    /// Same for the ended TCP flows, which expire after `tcp_close_timeout`
+    closing: VecDeque<(FlowKey, u64, Duration)>,
+    next_id: u64,
+}
+
+impl <S: FlowSink> FlowTable<S> {
+    pub fn new(sink: S, config: FlowConfig) -> FlowTable<S> {
+        FlowTable { sink, config, flows: HashMap::new(), queue: VecDeque::new(), closing: VecDeque::new(), next_id: 0 }
+    }
+
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+
+    pub fn sink_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+
+    pub fn into_sink(self) -> S {
+        self.sink
+    }
+
+    /// Number of flows tracked
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+
+    /// Flow of `key`, in either direction
+    pub fn get(&self, key: &FlowKey) -> Option<&Flow> {
+        self.flows.get(&key.canonical())
+    }
+
+    fn remove(&mut self, key: &FlowKey, reason: ExpiryReason) {
+        if let Some(flow) = self.flows.remove(key) {
+            self.sink.expired(flow, reason);
+        }
+    }
+
+    /// Last activity of the flow queued as `key`, `None` if that flow is gone
+    fn last_seen(&self, key: &FlowKey, id: u64) -> Option<Duration> {
+        self.flows.get(key).filter(|f| f.id == id).map(|f| f.last_seen)
+    }
+
+    /// Expire the flows idle since more than `idle_timeout` before `now`, and the ended TCP
+    /// flows idle since more than `tcp_close_timeout`, returns their number
+    pub fn expire(&mut self, now: Duration) -> usize {
+        let close = self.config.tcp_close_timeout;
+        let mut expired = 0;
+        while self.closing.front().is_some_and(|&(_, _, stamp)| now.saturating_sub(stamp) > close) {
+            let (key, id, _) = self.closing.pop_front().unwrap();
+            match self.last_seen(&key, id) {
+                Some(last_seen) if now.saturating_sub(last_seen) > close => {
+                    self.remove(&key, ExpiryReason::TcpEnd);
+                    expired += 1;
+                },
+                Some(last_seen) => self.closing.push_back((key, id, last_seen)),
+                None => {},
+            }
+        }
+        let idle = self.config.idle_timeout;
+        while self.queue.front().is_some_and(|&(_, _, stamp)| now.saturating_sub(stamp) > idle) {
+            let (key, id, _) = self.queue.pop_front().unwrap();
+            match self.last_seen(&key, id) {
+                Some(last_seen) if now.saturating_sub(last_seen) > idle => {
+                    let reason = match self.flows[&key].tcp_ended() {
+                        true => ExpiryReason::TcpEnd,
+                        false => ExpiryReason::Idle,
+                    };
+                    self.remove(&key, reason);
+                    expired += 1;
+                },
+                Some(last_seen) => self.queue.push_back((key, id, last_seen)),
+                None => {},
+            }
+        }
+        expired
+    }
+
+    /// Evict the least recently active flows until one more fits
+    fn evict(&mut self) {
+        while self.flows.len() >= self.config.max_flows.max(1) {
+            let (key, id, stamp) = match self.queue.pop_front() {
+                Some(entry) => entry,
+                None => break,
+            };
+            match self.last_seen(&key, id) {
+                Some(last_seen) if last_seen > stamp => self.queue.push_back((key, id, last_seen)),
+                Some(_) => self.remove(&key, ExpiryReason::Evicted),
+                None => {},
+            }
+        }
+    }
+
+    /// Drop the entries of removed flows once they outnumber the live ones
+    fn compact(&mut self) {
+        if self.queue.len() > 2 * self.flows.len() + 64 {
+            let flows = &self.flows;
+            self.queue.retain(|&(ref key, id, _)| flows.get(key).is_some_and(|f| f.id == id));
+        }
+        if self.closing.len() > 2 * self.flows.len() + 64 {
+            let flows = &self.flows;
+            self.closing.retain(|&(ref key, id, _)| flows.get(key).is_some_and(|f| f.id == id));
+        }
+    }
+
+    /// Expire all flows, least recently active first
+    pub fn flush(&mut self) {
+        while let Some((key, id, _)) = self.queue.pop_front() {
+            if self.last_seen(&key, id).is_some() {
+                self.remove(&key, ExpiryReason::Flush);
+            }
+        }
+        self.closing.clear();
+    }
+
+    /// Account a packet of `bytes` sent as `key` at `now`, with its TCP flags if any.
+    /// A SYN without ACK on an ended TCP flow starts a new one.
+    pub fn update(&mut self, key: &FlowKey, bytes: usize, tcp_flags: Option<TcpFlags>, now: Duration) {
+        self.expire(now);
+        let canonical = key.canonical();
+        let syn = tcp_flags.is_some_and(|f| f.contains(TcpFlags::SYN) && !f.contains(TcpFlags::ACK));
+        if syn && self.flows.get(&canonical).is_some_and(|f| f.tcp_ended()) {
+            self.remove(&canonical, ExpiryReason::TcpEnd);
+        }
+        if !self.flows.contains_key(&canonical) {
+            self.evict();
+            let id = self.next_id;
+            self.next_id += 1;
+            let flow = Flow {
+                key: key.clone(),
+                first_seen: now,
+                last_seen: now,
+                forward: FlowCounters::default(),
+                reverse: FlowCounters::default(),
+                id,
+            };
+            self.flows.insert(canonical.clone(), flow);
+            self.queue.push_back((canonical.clone(), id, now));
+            self.compact();
+        }
+
+        let flow = self.flows.get_mut(&canonical).unwrap();
+        if now.saturating_sub(flow.first_seen) > self.config.active_timeout {
+            let record = flow.clone();
+            flow.first_seen = now;
+            flow.forward = FlowCounters::default();
+            flow.reverse = FlowCounters::default();
+            self.sink.expired(record, ExpiryReason::Active);
+        }
+        flow.last_seen = now;
+        let ended = flow.tcp_ended();
+        match flow.key.src == key.src {
+            true => flow.forward.add(bytes, tcp_flags),
+            false => flow.reverse.add(bytes, tcp_flags),
+        }
+        if !ended && flow.tcp_ended() {
+            let id = flow.id;
+            self.closing.push_back((canonical, id, now));
+            self.compact();
+        }
+    }
+
+    /// Account an IPv4 packet captured at `now`, by its captured length when the header
+    /// announces none (TSO/GRO)
+    pub fn process(&mut self, packet: &ip::IpV4Packet, now: Duration) {
+        let hdr = &packet.ip_hdr;
+        let flags = match hdr.offset {
+            0 => tcp_flags(hdr.protocol, packet.payload),
+            _ => None,
+        };
+        let bytes = match hdr.packet_length {
+            0 => hdr.hdr_length as usize * 4 + packet.payload.len(),
+            length => length as usize,
+        };
+        self.update(&FlowKey::from_ipv4(packet), bytes, flags, now);
+    }
+
+    /// Account an IPv6 packet captured at `now`, fails on malformed extension headers
+    pub fn process_ipv6(&mut self, packet: &ipv6::IpV6Packet, now: Duration) -> Result<(), ParseError> {
+        let key = FlowKey::from_ipv6(packet)?;
+        let flags = tcp_flags(key.protocol, packet.upper_layer()?.1);
+        let bytes = match packet.ip_hdr.payload_length {
+            0 => 40 + packet.payload.len(),
+            length => 40 + length as usize,
+        };
+        self.update(&key, bytes, flags, now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    type Records = Vec<(Flow, ExpiryReason)>;
+
+    fn key(src: &str, dst: &str) -> FlowKey {
+        FlowKey { vlans: vec!(10), protocol: IpNextHeaderProtocols::Tcp, src: src.parse().unwrap(), dst: dst.parse().unwrap() }
+    }
+
+    fn secs(s: u64) -> Duration {
+        Duration::from_secs(s)
+    }
+
+    const CLIENT: &str = "10.0.0.1:40000";
+    const SERVER: &str = "10.0.0.2:80";
+
+    #[test]
+    fn test_key() {
+        let frame = [
+            0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 0x81, 0x00, 0x00, 0x64, 0x08, 0x00,
+            0x45, 0x00, 0x00, 0x28, 0x00, 0x01, 0x40, 0x00, 0x40, 0x06, 0x00, 0x00,
+            192, 168, 0, 2, 192, 168, 0, 1,
+            0xc3, 0x50, 0x00, 0x50, 0, 0, 0, 1, 0, 0, 0, 0, 0x50, 0x12, 0xff, 0xff, 0, 0, 0, 0,
+        ];
+        let packet = ip::IpV4Packet::try_from(&frame[..]).unwrap();
+        let k = FlowKey::from_ipv4(&packet);
+        assert_eq!(k, FlowKey {
+            vlans: vec!(100), protocol: IpNextHeaderProtocols::Tcp,
+            src: "192.168.0.2:50000".parse().unwrap(), dst: "192.168.0.1:80".parse().unwrap(),
+        });
+        assert_eq!(k.canonical(), k.reversed());
+        assert_eq!(k.reversed().canonical(), k.reversed());
+
+        let mut t = FlowTable::new(Records::new(), FlowConfig::default());
+        t.process(&packet, secs(1));
+        let flow = t.get(&k.reversed()).unwrap();
+        assert_eq!(flow.key, k);
+        assert_eq!((flow.forward.packets, flow.forward.bytes), (1, 40));
+        assert_eq!(flow.forward.tcp_flags, TcpFlags::SYN | TcpFlags::ACK);
+
+        let mut tso = frame;
+        tso[20..22].copy_from_slice(&[0, 0]);
+        t.process(&ip::IpV4Packet::try_from(&tso[..]).unwrap(), secs(2));
+        assert_eq!(t.get(&k).unwrap().forward.bytes, 80);
+    }
+
+    #[test]
+    fn test_bidirectional() {
+        let mut t = FlowTable::new(Records::new(), FlowConfig::default());
+        let k = key(CLIENT, SERVER);
+        t.update(&k, 60, Some(TcpFlags::SYN), secs(1));
+        t.update(&k.reversed(), 60, Some(TcpFlags::SYN | TcpFlags::ACK), secs(2));
+        t.update(&k, 52, Some(TcpFlags::ACK), secs(3));
+        let mut other = k.clone();
+        other.vlans = vec!(20);
+        t.update(&other, 100, Some(TcpFlags::ACK), secs(3));
+        assert_eq!(t.len(), 2);
+
+        let flow = t.get(&k).unwrap();
+        assert_eq!(flow.key, k);
+        assert_eq!((flow.first_seen, flow.last_seen), (secs(1), secs(3)));
+        assert_eq!(flow.forward, FlowCounters {
+            packets: 2, bytes: 112, initial_tcp_flags: TcpFlags::SYN, tcp_flags: TcpFlags::SYN | TcpFlags::ACK,
+        });
+        assert_eq!((flow.reverse.packets, flow.reverse.bytes), (1, 60));
+
+        t.update(&k.reversed(), 40, Some(TcpFlags::FIN | TcpFlags::ACK), secs(4));
+        assert!(t.sink().is_empty());
+        t.update(&k, 40, Some(TcpFlags::FIN | TcpFlags::ACK), secs(5));
+        t.update(&k.reversed(), 40, Some(TcpFlags::ACK), secs(6));
+        t.update(&k, 40, Some(TcpFlags::FIN | TcpFlags::ACK), secs(7));
+        assert_eq!((t.len(), t.sink().len()), (2, 0));
+        assert_eq!(t.expire(secs(12)), 0);
+        assert_eq!(t.expire(secs(13)), 1);
+        assert_eq!(t.len(), 1);
+        t.update(&k, 60, Some(TcpFlags::SYN), secs(14));
+        let records = t.into_sink();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].1, ExpiryReason::TcpEnd);
+        assert_eq!((records[0].0.forward.packets, records[0].0.reverse.packets), (4, 3));
+
+        let mut t = FlowTable::new(Records::new(), FlowConfig::default());
+        t.update(&k, 40, Some(TcpFlags::RST), secs(1));
+        t.update(&k, 60, Some(TcpFlags::SYN), secs(2));
+        assert_eq!(t.get(&k).map(|f| f.first_seen), Some(secs(2)));
+        assert_eq!(t.sink().iter().map(|r| r.1).collect::<Vec<_>>(), vec!(ExpiryReason::TcpEnd));
+    }
+
+    #[test]
+    fn test_timeouts() {
+        let config = FlowConfig { idle_timeout: secs(10), active_timeout: secs(60), max_flows: 100, ..FlowConfig::default() };
+        let mut t = FlowTable::new(Records::new(), config);
+        let long = key(CLIENT, SERVER);
+        let short = key("10.0.0.1:40001", SERVER);
+        t.update(&long, 100, None, secs(0));
+        t.update(&short, 100, None, secs(1));
+        for s in 1..8 {
+            t.update(&long, 100, None, secs(s * 8));
+        }
+        assert_eq!(t.sink().len(), 1);
+        let (flow, reason) = t.sink_mut().pop().unwrap();
+        assert_eq!((flow.key, flow.first_seen, flow.last_seen, reason), (short, secs(1), secs(1), ExpiryReason::Idle));
+
+        t.update(&long.reversed(), 100, None, secs(64));
+        assert_eq!(t.sink().len(), 1);
+        let (flow, reason) = t.sink()[0].clone();
+        assert_eq!(reason, ExpiryReason::Active);
+        assert_eq!((flow.first_seen, flow.last_seen, flow.forward.packets, flow.reverse.packets), (secs(0), secs(56), 8, 0));
+        let flow = t.get(&long).unwrap();
+        assert_eq!((flow.first_seen, flow.forward.packets, flow.reverse.packets), (secs(64), 0, 1));
+
+        assert_eq!(t.expire(secs(74)), 0);
+        assert_eq!(t.expire(secs(75)), 1);
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    fn test_flood() {
+        let config = FlowConfig { max_flows: 4, ..FlowConfig::default() };
+        let mut t = FlowTable::new(Records::new(), config);
+        let kept = key(CLIENT, SERVER);
+        t.update(&kept, 60, None, secs(0));
+        for port in 0..1000u16 {
+            let now = Duration::from_millis(1000 + port as u64);
+            let k = key(&format!("10.0.1.1:{}", port), SERVER);
+            t.update(&k, 60, Some(TcpFlags::SYN), now);
+            t.update(&kept, 60, None, now);
+            assert!(t.len() <= 4 && t.queue.len() <= 4);
+        }
+        assert_eq!(t.get(&kept).unwrap().forward.packets, 1001);
+        assert_eq!(t.sink().len(), 997);
+        assert!(t.sink().iter().all(|r| r.1 == ExpiryReason::Evicted && r.0.key != kept));
+
+        for port in 0..1000u16 {
+            let now = Duration::from_millis(3000 + port as u64);
+            let k = key(&format!("10.0.1.1:{}", port), SERVER);
+            t.update(&k, 60, Some(TcpFlags::RST), now);
+            t.update(&kept, 60, None, now);
+            assert!(t.queue.len() <= 2 * 4 + 64 && t.closing.len() <= 2 * 4 + 64);
+        }
+        assert_eq!(t.len(), 4);
+        t.expire(secs(9));
+        assert_eq!(t.len(), 1);
+        t.flush();
+        assert!(t.is_empty() && t.queue.is_empty());
+        assert_eq!(t.sink().last().map(|r| (r.0.key.clone(), r.1)), Some((kept, ExpiryReason::Flush)));
+    }
+}
@@ -0,0 +1,418 @@
+extern crate common;
+extern crate ip;
+
+use std::convert::TryFrom;
+use std::net::Ipv4Addr;
+use common::{Emit, Layer, ParseError, Reason};
+use ip::ChecksumCheck;
+
+/// ICMP message type
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IcmpType(pub u8);
+
+#[allow(non_snake_case)]
+#[allow(non_upper_case_globals)]
+pub mod IcmpTypes {
+    use super::IcmpType;
+    /// Echo Reply [RFC 792]
+    pub const EchoReply: IcmpType = IcmpType(0);
+    /// Destination Unreachable [RFC 792]
+    pub const DestinationUnreachable: IcmpType = IcmpType(3);
+    /// Source Quench, deprecated [RFC 6633]
+    pub const SourceQuench: IcmpType = IcmpType(4);
+    /// Redirect [RFC 792]
+    pub const Redirect: IcmpType = IcmpType(5);
+    /// Echo [RFC 792]
+    pub const EchoRequest: IcmpType = IcmpType(8);
+    /// Router Advertisement [RFC 1256]
+    pub const RouterAdvertisement: IcmpType = IcmpType(9);
+    /// Router Solicitation [RFC 1256]
+    pub const RouterSolicitation: IcmpType = IcmpType(10);
+    /// Time Exceeded [RFC 792]
+    pub const TimeExceeded: IcmpType = IcmpType(11);
+    /// Parameter Problem [RFC 792]
+    pub const ParameterProblem: IcmpType = IcmpType(12);
+    /// Timestamp [RFC 792]
+    pub const Timestamp: IcmpType = IcmpType(13);
+    /// Timestamp Reply [RFC 792]
+    pub const TimestampReply: IcmpType = IcmpType(14);
+}
+
+/// Codes of Destination Unreachable
+#[allow(non_snake_case)]
+#[allow(non_upper_case_globals)]
+pub mod UnreachableCodes {
+    pub const Net: u8 = 0;
+    pub const Host: u8 = 1;
+    pub const Protocol: u8 = 2;
+    pub const Port: u8 = 3;
+    /// Fragmentation needed and DF set, `next_hop_mtu` is meaningful [RFC 1191]
+    pub const FragmentationNeeded: u8 = 4;
+    pub const SourceRouteFailed: u8 = 5;
+    pub const NetUnknown: u8 = 6;
+    pub const HostUnknown: u8 = 7;
+    pub const SourceHostIsolated: u8 = 8;
+    pub const NetProhibited: u8 = 9;
+    pub const HostProhibited: u8 = 10;
+    pub const NetUnreachableForTos: u8 = 11;
+    pub const HostUnreachableForTos: u8 = 12;
+    /// Communication administratively prohibited [RFC 1812]
+    pub const AdminProhibited: u8 = 13;
+    pub const HostPrecedenceViolation: u8 = 14;
+    pub const PrecedenceCutoff: u8 = 15;
+}
+
+/// Codes of Time Exceeded
+#[allow(non_snake_case)]
+#[allow(non_upper_case_globals)]
+pub mod TimeExceededCodes {
+    /// TTL exceeded in transit
+    pub const Ttl: u8 = 0;
+    /// Fragment reassembly time exceeded
+    pub const Reassembly: u8 = 1;
+}
+
+/// Codes of Redirect
+#[allow(non_snake_case)]
+#[allow(non_upper_case_globals)]
+pub mod RedirectCodes {
+    pub const Net: u8 = 0;
+    pub const Host: u8 = 1;
+    pub const TosNet: u8 = 2;
+    pub const TosHost: u8 = 3;
+}
+
+/// Header and leading L4 bytes of the datagram an error message refers to
+#[derive(Debug,PartialEq)]
+pub struct OriginalDatagram <'a> {
+    pub ip_hdr: ip::IpV4Header<'a>,
+    /// Bytes after the quoted header, at least 8 from conforming senders
+    pub data: &'a [u8],
+}
+
+impl <'a> OriginalDatagram<'a> {
+    /// Source and destination ports of a quoted TCP, UDP, UDP-Lite or SCTP datagram
+    pub fn ports(&self) -> Option<(u16, u16)> {
+        use ip::IpNextHeaderProtocols as P;
+        match self.ip_hdr.protocol {
+            P::Tcp | P::Udp | P::UdpLite | P::Sctp if self.data.len() >= 4 => {
+                Some((common::u16_parse(&self.data[0..2]), common::u16_parse(&self.data[2..4])))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl <'a> TryFrom<&'a [u8]> for OriginalDatagram<'a> {
+    type Error = ParseError;
+
+    fn try_from(buf: &'a [u8]) -> Result<OriginalDatagram<'a>, Self::Error> {
+        let ip_hdr = ip::IpV4Header::try_from(buf)?;
+        let data = &buf[ip_hdr.hdr_length as usize * 4..];
+        Ok(OriginalDatagram { ip_hdr, data })
+    }
+}
+
+/// Error messages keep the datagram they quote as captured in `quote`,
+/// `IcmpMessage::original` decodes it
+#[derive(Debug,PartialEq)]
+pub enum IcmpMessage <'a> {
+    EchoReply { id: u16, seq: u16, data: &'a [u8] },
+    EchoRequest { id: u16, seq: u16, data: &'a [u8] },
+    /// `next_hop_mtu` is set with `UnreachableCodes::FragmentationNeeded`, zero otherwise
+    DestinationUnreachable { next_hop_mtu: u16, quote: &'a [u8] },
+    Redirect { gateway: Ipv4Addr, quote: &'a [u8] },
+    TimeExceeded { quote: &'a [u8] },
+    /// `pointer` is the offset of the offending byte in the original header
+    ParameterProblem { pointer: u8, quote: &'a [u8] },
+    /// Times are milliseconds since midnight UT
+    Timestamp { id: u16, seq: u16, originate: u32, receive: u32, transmit: u32 },
+    TimestampReply { id: u16, seq: u16, originate: u32, receive: u32, transmit: u32 },
+    /// Any other type, `rest_of_header` is the 4 bytes following the checksum
+    Other { rest_of_header: &'a [u8], data: &'a [u8] },
+}
+
+impl <'a> IcmpMessage<'a> {
+    /// Quoted datagram of an error message, `None` for the other messages
+    pub fn quote(&self) -> Option<&'a [u8]> {
+        match *self {
+            IcmpMessage::DestinationUnreachable { quote, .. } | IcmpMessage::Redirect { quote, .. }
+            | IcmpMessage::TimeExceeded { quote } | IcmpMessage::ParameterProblem { quote, .. } => Some(quote),
+            _ => None,
+        }
+    }
+
+    /// Decoded quote of an error message, `None` for the other messages.
+    /// Offsets of errors count from the ICMP header.
+    pub fn original(&self) -> Option<Result<OriginalDatagram<'a>, ParseError>> {
+        self.quote().map(|quote| OriginalDatagram::try_from(quote).map_err(|e| e.shift(ICMP_HDR_LEN)))
+    }
+}
+
+#[derive(Debug,PartialEq)]
+pub struct IcmpPacket <'a> {
+    pub icmp_type: IcmpType,
+    pub code: u8,
+    pub checksum: u16,
+    /// Result of the checksum verification, `None` when it was skipped or when the message
+    /// is truncated or fragmented, see `IcmpPacket::from_ipv4`
+    pub checksum_ok: Option<bool>,
+    pub message: IcmpMessage<'a>,
+}
+
+const ICMP_HDR_LEN: usize = 8;
+const TIMESTAMP_LEN: usize = 20;
+
+/// Parse the message at the start of `buf`, the checksum is verified over all of `buf`
+impl <'a> TryFrom<&'a [u8]> for IcmpPacket<'a> {
+    type Error = ParseError;
+
+    fn try_from(buf: &'a [u8]) -> Result<IcmpPacket<'a>, Self::Error> {
+        if buf.len() < ICMP_HDR_LEN {
+            return Err(ParseError::truncated(Layer::Icmp, 0, ICMP_HDR_LEN, buf.len()));
+        }
+        let icmp_type = IcmpType(buf[0]);
+        let id = common::u16_parse(&buf[4..6]);
+        let seq = common::u16_parse(&buf[6..8]);
+        let quote = &buf[ICMP_HDR_LEN..];
+        let message = match icmp_type {
+            IcmpTypes::EchoReply => IcmpMessage::EchoReply { id, seq, data: &buf[ICMP_HDR_LEN..] },
+            IcmpTypes::EchoRequest => IcmpMessage::EchoRequest { id, seq, data: &buf[ICMP_HDR_LEN..] },
+            IcmpTypes::DestinationUnreachable => IcmpMessage::DestinationUnreachable { next_hop_mtu: seq, quote },
+            IcmpTypes::Redirect => IcmpMessage::Redirect { gateway: Ipv4Addr::new(buf[4], buf[5], buf[6], buf[7]), quote },
+            IcmpTypes::TimeExceeded => IcmpMessage::TimeExceeded { quote },
+            IcmpTypes::ParameterProblem => IcmpMessage::ParameterProblem { pointer: buf[4], quote },
+            IcmpTypes::Timestamp | IcmpTypes::TimestampReply => {
+                if buf.len() < TIMESTAMP_LEN {
+                    return Err(ParseError::truncated(Layer::Icmp, 0, TIMESTAMP_LEN, buf.len()));
+                }
+                let originate = common::u32_parse(&buf[8..12]);
+                let receive = common::u32_parse(&buf[12..16]);
+                let transmit = common::u32_parse(&buf[16..20]);
+                match icmp_type {
+                    IcmpTypes::Timestamp => IcmpMessage::Timestamp { id, seq, originate, receive, transmit },
+                    _ => IcmpMessage::TimestampReply { id, seq, originate, receive, transmit },
+                }
+            }
+            _ => IcmpMessage::Other { rest_of_header: &buf[4..ICMP_HDR_LEN], data: &buf[ICMP_HDR_LEN..] },
+        };
+        Ok(IcmpPacket {
+            icmp_type,
+            code: buf[1],
+            checksum: common::u16_parse(&buf[2..4]),
+            checksum_ok: Some(common::checksum(buf) == 0),
+            message,
+        })
+    }
+}
+
+impl <'a> IcmpPacket<'a> {
+    /// Message carried by an IPv4 packet. Offsets of errors count from the IPv4 header.
+    /// Non-first fragments fail with `BadField("offset")`.
+    pub fn from_ipv4(packet: &ip::IpV4Packet<'a>, check: ChecksumCheck) -> Result<IcmpPacket<'a>, ParseError> {
+        let hdr = &packet.ip_hdr;
+        if hdr.protocol != ip::IpNextHeaderProtocols::Icmp {
+            return Err(ParseError::new(Layer::Icmp, 9, Reason::WrongProtocol(hdr.protocol.0)));
+        }
+        if hdr.offset != 0 {
+            return Err(ParseError::new(Layer::Icmp, 6, Reason::BadField("offset")));
+        }
+        let start = hdr.hdr_length as usize * 4;
+        let mut icmp = IcmpPacket::try_from(packet.payload).map_err(|e| e.shift(start))?;
+        if check == ChecksumCheck::Skip || packet.missing_bytes() != 0 || hdr.flags & ip::Flags::MORE_FRAGMENTS != 0 {
+            icmp.checksum_ok = None;
+        } else if icmp.checksum_ok == Some(false) && check == ChecksumCheck::Reject {
+            let expected = common::checksum_update(common::checksum(packet.payload), icmp.checksum, 0);
+            return Err(ParseError::new(Layer::Icmp, start + 2, Reason::BadChecksum { expected, actual: icmp.checksum }));
+        }
+        Ok(icmp)
+    }
+}
+
+/// Same as `IcmpPacket::from_ipv4` with `ChecksumCheck::Flag`
+impl <'a, 'b> TryFrom<&'b ip::IpV4Packet<'a>> for IcmpPacket<'a> {
+    type Error = ParseError;
+
+    fn try_from(packet: &'b ip::IpV4Packet<'a>) -> Result<IcmpPacket<'a>, Self::Error> {
+        IcmpPacket::from_ipv4(packet, ChecksumCheck::Flag)
+    }
+}
+
+impl <'a> Emit for OriginalDatagram<'a> {
+    fn emit_len(&self) -> usize {
+        self.ip_hdr.emit_len() + self.data.len()
+    }
+
+    /// The quoted header is written with its `crc` as is, not a recomputed one
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        let ptr = self.ip_hdr.emit(buf)?;
+        common::u16_write(&mut buf[10..], self.ip_hdr.crc);
+        if buf.len() < ptr + self.data.len() {
+            return Err(ParseError::truncated(Layer::Icmp, ptr, self.data.len(), buf.len() - ptr));
+        }
+        buf[ptr..ptr + self.data.len()].copy_from_slice(self.data);
+        Ok(ptr + self.data.len())
+    }
+}
+
+impl <'a> Emit for IcmpPacket<'a> {
+    fn emit_len(&self) -> usize {
+        ICMP_HDR_LEN + match self.message {
+            IcmpMessage::EchoReply { data, .. } | IcmpMessage::EchoRequest { data, .. } | IcmpMessage::Other { data, .. }
+            | IcmpMessage::DestinationUnreachable { quote: data, .. } | IcmpMessage::Redirect { quote: data, .. }
+            | IcmpMessage::TimeExceeded { quote: data } | IcmpMessage::ParameterProblem { quote: data, .. } => data.len(),
+            IcmpMessage::Timestamp { .. } | IcmpMessage::TimestampReply { .. } => TIMESTAMP_LEN - ICMP_HDR_LEN,
+        }
+    }
+
+    /// Write the message, `checksum` is replaced by the computed one
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        let len = self.emit_len();
+        if buf.len() < len {
+            return Err(ParseError::truncated(Layer::Icmp, 0, len, buf.len()));
+        }
+        buf[0] = self.icmp_type.0;
+        buf[1] = self.code;
+        common::u16_write(&mut buf[2..], 0);
+        let (rest, data) = match self.message {
+            IcmpMessage::EchoReply { id, seq, data } | IcmpMessage::EchoRequest { id, seq, data } => ((id as u32) << 16 | seq as u32, data),
+            IcmpMessage::DestinationUnreachable { next_hop_mtu, quote } => (next_hop_mtu as u32, quote),
+            IcmpMessage::Redirect { gateway, quote } => (u32::from(gateway), quote),
+            IcmpMessage::TimeExceeded { quote } => (0, quote),
+            IcmpMessage::ParameterProblem { pointer, quote } => ((pointer as u32) << 24, quote),
+            IcmpMessage::Timestamp { id, seq, originate, receive, transmit }
+            | IcmpMessage::TimestampReply { id, seq, originate, receive, transmit } => {
+                common::u32_write(&mut buf[8..], originate);
+                common::u32_write(&mut buf[12..], receive);
+                common::u32_write(&mut buf[16..], transmit);
+                ((id as u32) << 16 | seq as u32, &[][..])
+            }
+            IcmpMessage::Other { rest_of_header, data } => {
+                if rest_of_header.len() != 4 {
+                    return Err(ParseError::new(Layer::Icmp, 4, Reason::BadField("rest_of_header")));
+                }
+                (common::u32_parse(rest_of_header), data)
+            }
+        };
+        common::u32_write(&mut buf[4..], rest);
+        buf[ICMP_HDR_LEN..ICMP_HDR_LEN + data.len()].copy_from_slice(data);
+        let crc = common::checksum(&buf[..len]);
+        common::u16_write(&mut buf[2..], crc);
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ECHO: [u8; 12] = [0x08, 0x00, 0x21, 0x04, 0x12, 0x34, 0x00, 0x01, b'a', b'b', b'c', b'd'];
+
+    /// Time Exceeded quoting a UDP datagram to 8.8.8.8:53
+    const TIME_EXCEEDED: [u8; 36] = [0x0b, 0x00, 0x0a, 0xd5, 0x00, 0x00, 0x00, 0x00,
+        0x45, 0x00, 0x00, 0x22, 0x00, 0x01, 0x00, 0x00, 0x01, 0x11, 0xe8, 0x08, 0xc0, 0xa8, 0x01, 0x0a, 0x08, 0x08, 0x08, 0x08,
+        0xd4, 0x31, 0x00, 0x35, 0x00, 0x0e, 0x15, 0xb6];
+
+    #[test]
+    fn test_echo() {
+        let res = IcmpPacket::try_from(&ECHO[..]).unwrap();
+        assert_eq!(res, IcmpPacket {
+            icmp_type: IcmpTypes::EchoRequest,
+            code: 0,
+            checksum: 0x2104,
+            checksum_ok: Some(true),
+            message: IcmpMessage::EchoRequest { id: 0x1234, seq: 1, data: b"abcd" },
+        });
+        let mut buf = ECHO;
+        buf[11] = b'e';
+        assert_eq!(IcmpPacket::try_from(&buf[..]).unwrap().checksum_ok, Some(false));
+    }
+    #[test]
+    fn test_errors_quote() {
+        let res = IcmpPacket::try_from(&TIME_EXCEEDED[..]).unwrap();
+        assert_eq!(res.checksum_ok, Some(true));
+        assert_eq!(res.code, TimeExceededCodes::Ttl);
+        match res.message {
+            IcmpMessage::TimeExceeded { quote } => assert_eq!(quote, &TIME_EXCEEDED[8..]),
+            ref m => panic!("unexpected {:?}", m),
+        }
+        let original = res.message.original().unwrap().unwrap();
+        assert_eq!(original.ip_hdr.ttl, 1);
+        assert_eq!(original.ip_hdr.dst_addr(), Ipv4Addr::new(8, 8, 8, 8));
+        assert_eq!(original.ports(), Some((0xd431, 53)));
+        assert_eq!(original.data.len(), 8);
+
+        let mut buf = TIME_EXCEEDED;
+        buf[0..8].copy_from_slice(&[0x03, UnreachableCodes::FragmentationNeeded, 0x0c, 0xf5, 0x00, 0x00, 0x05, 0xdc]);
+        let res = IcmpPacket::try_from(&buf[..]).unwrap();
+        assert_eq!(res.checksum_ok, Some(true));
+        match res.message {
+            IcmpMessage::DestinationUnreachable { next_hop_mtu, .. } => assert_eq!(next_hop_mtu, 1500),
+            ref m => panic!("unexpected {:?}", m),
+        }
+
+        buf[8] = 0x65;
+        let res = IcmpPacket::try_from(&buf[..]).unwrap();
+        assert_eq!(res.message.original(), Some(Err(ParseError::new(Layer::IpV4, 8, Reason::BadVersion(6)))));
+        assert_eq!(IcmpPacket::try_from(&ECHO[..]).unwrap().message.original(), None);
+    }
+    #[test]
+    fn test_truncated() {
+        for len in 0..8 {
+            assert_eq!(IcmpPacket::try_from(&ECHO[..len]), Err(ParseError::truncated(Layer::Icmp, 0, 8, len)));
+        }
+        let res = IcmpPacket::try_from(&TIME_EXCEEDED[..20]).unwrap();
+        assert_eq!(res.message.original(), Some(Err(ParseError::truncated(Layer::IpV4, 8, 20, 12))));
+        let timestamp = [0x0du8, 0, 0, 0, 0, 1, 0, 2, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(IcmpPacket::try_from(&timestamp[..]), Err(ParseError::truncated(Layer::Icmp, 0, 20, 19)));
+    }
+    #[test]
+    fn test_emit() {
+        for msg in [&ECHO[..], &TIME_EXCEEDED[..]].iter() {
+            let res = IcmpPacket::try_from(*msg).unwrap();
+            let mut out = vec!();
+            assert_eq!(res.emit_vec(&mut out), Ok(msg.len()));
+            assert_eq!(&out[..], *msg);
+        }
+        // the quote is written as captured, its bad header checksum included
+        let mut buf = TIME_EXCEEDED;
+        buf[18] ^= 0xff;
+        let res = IcmpPacket::try_from(&buf[..]).unwrap();
+        let mut out = vec!();
+        res.emit_vec(&mut out).unwrap();
+        assert_eq!(&out[4..], &buf[4..]);
+        let mut out = vec!();
+        res.message.original().unwrap().unwrap().emit_vec(&mut out).unwrap();
+        assert_eq!(&out[..], &buf[8..]);
+        let timestamp = IcmpPacket {
+            icmp_type: IcmpTypes::TimestampReply,
+            code: 0,
+            checksum: 0,
+            checksum_ok: None,
+            message: IcmpMessage::TimestampReply { id: 1, seq: 2, originate: 3, receive: 4, transmit: 5 },
+        };
+        let mut out = vec!();
+        assert_eq!(timestamp.emit_vec(&mut out), Ok(20));
+        let res = IcmpPacket::try_from(&out[..]).unwrap();
+        assert_eq!((res.checksum_ok, res.message), (Some(true), timestamp.message));
+    }
+    #[test]
+    fn test_from_ip() {
+        let mut frame = vec!(0x11u8, 0x12, 0x13, 0x14, 0x15, 0x16, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x08, 0x00,
+            0x45, 0x00, 0x00, 0x20, 0x00, 0x02, 0x00, 0x00, 0x40, 0x01, 0x00, 0x00, 0x08, 0x08, 0x08, 0x08, 0xc0, 0xa8, 0x01, 0x0a);
+        frame.extend_from_slice(&ECHO);
+        let packet = ip::IpV4Packet::try_from(&frame[..]).unwrap();
+        assert_eq!(IcmpPacket::try_from(&packet).unwrap().checksum_ok, Some(true));
+        let packet = ip::IpV4Packet::try_from(&frame[..44]).unwrap();
+        assert_eq!(IcmpPacket::try_from(&packet).unwrap().checksum_ok, None);
+        frame[45] = b'e';
+        let packet = ip::IpV4Packet::try_from(&frame[..]).unwrap();
+        assert_eq!(IcmpPacket::from_ipv4(&packet, ChecksumCheck::Flag).unwrap().checksum_ok, Some(false));
+        assert_eq!(IcmpPacket::from_ipv4(&packet, ChecksumCheck::Skip).unwrap().checksum_ok, None);
+        assert_eq!(IcmpPacket::from_ipv4(&packet, ChecksumCheck::Reject),
+                   Err(ParseError::new(Layer::Icmp, 22, Reason::BadChecksum { expected: 0x2103, actual: 0x2104 })));
+        frame[23] = 17;
+        let packet = ip::IpV4Packet::try_from(&frame[..]).unwrap();
+        assert_eq!(IcmpPacket::try_from(&packet), Err(ParseError::new(Layer::Icmp, 9, Reason::WrongProtocol(17))));
+    }
+}
@@ -0,0 +1,875 @@
+extern crate common;
+extern crate ip;
+extern crate ipv6;
+
+use std::convert::TryFrom;
+use std::net::Ipv6Addr;
+use common::{Emit, Layer, ParseError, Reason};
+use ip::{ChecksumCheck, IpNextHeaderProtocols};
+
+/// ICMPv6 message type
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Icmpv6Type(pub u8);
+
+#[allow(non_snake_case)]
+#[allow(non_upper_case_globals)]
+pub mod Icmpv6Types {
+    use super::Icmpv6Type;
+    /// Destination Unreachable [RFC 4443]
+    pub const DestinationUnreachable: Icmpv6Type = Icmpv6Type(1);
+    /// Packet Too Big [RFC 4443]
+    pub const PacketTooBig: Icmpv6Type = Icmpv6Type(2);
+    /// Time Exceeded [RFC 4443]
+    pub const TimeExceeded: Icmpv6Type = Icmpv6Type(3);
+    /// Parameter Problem [RFC 4443]
+    pub const ParameterProblem: Icmpv6Type = Icmpv6Type(4);
+    /// Echo Request [RFC 4443]
+    pub const EchoRequest: Icmpv6Type = Icmpv6Type(128);
+    /// Echo Reply [RFC 4443]
+    pub const EchoReply: Icmpv6Type = Icmpv6Type(129);
+    /// Multicast Listener Query [RFC 2710, RFC 3810]
+    pub const MldQuery: Icmpv6Type = Icmpv6Type(130);
+    /// Multicast Listener Report, version 1 [RFC 2710]
+    pub const MldReport: Icmpv6Type = Icmpv6Type(131);
+    /// Multicast Listener Done [RFC 2710]
+    pub const MldDone: Icmpv6Type = Icmpv6Type(132);
+    /// Router Solicitation [RFC 4861]
+    pub const RouterSolicitation: Icmpv6Type = Icmpv6Type(133);
+    /// Router Advertisement [RFC 4861]
+    pub const RouterAdvertisement: Icmpv6Type = Icmpv6Type(134);
+    /// Neighbor Solicitation [RFC 4861]
+    pub const NeighborSolicitation: Icmpv6Type = Icmpv6Type(135);
+    /// Neighbor Advertisement [RFC 4861]
+    pub const NeighborAdvertisement: Icmpv6Type = Icmpv6Type(136);
+    /// Redirect [RFC 4861]
+    pub const Redirect: Icmpv6Type = Icmpv6Type(137);
+    /// Multicast Listener Report, version 2 [RFC 3810]
+    pub const Mldv2Report: Icmpv6Type = Icmpv6Type(143);
+}
+
+/// Neighbor Discovery option types
+#[allow(non_snake_case)]
+#[allow(non_upper_case_globals)]
+pub mod NdpOptionTypes {
+    /// Source Link-Layer Address [RFC 4861]
+    pub const SourceLinkLayerAddress: u8 = 1;
+    /// Target Link-Layer Address [RFC 4861]
+    pub const TargetLinkLayerAddress: u8 = 2;
+    /// Prefix Information [RFC 4861]
+    pub const PrefixInformation: u8 = 3;
+    /// Redirected Header [RFC 4861]
+    pub const RedirectedHeader: u8 = 4;
+    /// MTU [RFC 4861]
+    pub const Mtu: u8 = 5;
+    /// Recursive DNS Server [RFC 8106]
+    pub const Rdnss: u8 = 25;
+}
+
+/// Record types of an MLDv2 report [RFC 3810]
+#[allow(non_snake_case)]
+#[allow(non_upper_case_globals)]
+pub mod MldRecordTypes {
+    pub const ModeIsInclude: u8 = 1;
+    pub const ModeIsExclude: u8 = 2;
+    pub const ChangeToInclude: u8 = 3;
+    pub const ChangeToExclude: u8 = 4;
+    pub const AllowNewSources: u8 = 5;
+    pub const BlockOldSources: u8 = 6;
+}
+
+#[derive(Debug,PartialEq)]
+pub struct PrefixInfo {
+    pub prefix_length: u8,
+    /// L flag, the prefix can be used for on-link determination
+    pub on_link: bool,
+    /// A flag, the prefix can be used for stateless address autoconfiguration
+    pub autonomous: bool,
+    /// Seconds, 0xffffffff meaning infinity
+    pub valid_lifetime: u32,
+    /// Seconds, 0xffffffff meaning infinity
+    pub preferred_lifetime: u32,
+    pub prefix: Ipv6Addr,
+}
+
+#[derive(Debug,PartialEq)]
+pub enum NdpOption <'a> {
+    /// Link-layer address, padding included
+    SourceLinkLayerAddress(&'a [u8]),
+    /// Link-layer address, padding included
+    TargetLinkLayerAddress(&'a [u8]),
+    PrefixInformation(PrefixInfo),
+    /// As much of the redirected packet as fits, starting with its IPv6 header
+    RedirectedHeader(&'a [u8]),
+    Mtu(u32),
+    Rdnss { lifetime: u32, servers: Vec<Ipv6Addr> },
+    Unknown { option_type: u8, data: &'a [u8] },
+}
+
+/// MLDv2 fields following the v1 part of a query
+#[derive(Debug,PartialEq)]
+pub struct MldV2Query {
+    /// S flag, routers suppress timer updates
+    pub suppress: bool,
+    /// Querier's Robustness Variable
+    pub robustness: u8,
+    /// Querier's Query Interval Code
+    pub qqic: u8,
+    pub sources: Vec<Ipv6Addr>,
+}
+
+#[derive(Debug,PartialEq)]
+pub struct MulticastRecord <'a> {
+    pub record_type: u8,
+    pub group: Ipv6Addr,
+    pub sources: Vec<Ipv6Addr>,
+    pub aux_data: &'a [u8],
+}
+
+/// Header and leading bytes of the packet an error message refers to
+#[derive(Debug,PartialEq)]
+pub struct OriginalDatagram <'a> {
+    pub ip_hdr: ipv6::IpV6Header<'a>,
+    /// Bytes after the quoted header, extension headers included
+    pub data: &'a [u8],
+}
+
+impl <'a> TryFrom<&'a [u8]> for OriginalDatagram<'a> {
+    type Error = ParseError;
+
+    fn try_from(buf: &'a [u8]) -> Result<OriginalDatagram<'a>, Self::Error> {
+        let ip_hdr = ipv6::IpV6Header::try_from(buf)?;
+        Ok(OriginalDatagram { ip_hdr, data: &buf[ipv6::IPV6_HDR_LEN..] })
+    }
+}
+
+/// Error messages keep the packet they quote as captured in `quote`,
+/// `Icmpv6Message::original` decodes it
+#[derive(Debug,PartialEq)]
+pub enum Icmpv6Message <'a> {
+    DestinationUnreachable { quote: &'a [u8] },
+    PacketTooBig { mtu: u32, quote: &'a [u8] },
+    TimeExceeded { quote: &'a [u8] },
+    /// `pointer` is the offset of the offending byte in the original packet
+    ParameterProblem { pointer: u32, quote: &'a [u8] },
+    EchoRequest { id: u16, seq: u16, data: &'a [u8] },
+    EchoReply { id: u16, seq: u16, data: &'a [u8] },
+    /// `max_response_code` is the delay in milliseconds for MLDv1, encoded as in RFC 3810
+    /// section 5.1.3 for MLDv2. `v2` is set for queries longer than 24 bytes.
+    MldQuery { max_response_code: u16, group: Ipv6Addr, v2: Option<MldV2Query> },
+    MldReport { group: Ipv6Addr },
+    MldDone { group: Ipv6Addr },
+    Mldv2Report { records: Vec<MulticastRecord<'a>> },
+    RouterSolicitation { options: Vec<NdpOption<'a>> },
+    RouterAdvertisement {
+        cur_hop_limit: u8,
+        /// M flag, addresses are available via DHCPv6
+        managed: bool,
+        /// O flag, other configuration is available via DHCPv6
+        other: bool,
+        /// Seconds, 0 when the router is not a default router
+        router_lifetime: u16,
+        /// Milliseconds
+        reachable_time: u32,
+        /// Milliseconds
+        retrans_timer: u32,
+        options: Vec<NdpOption<'a>>,
+    },
+    NeighborSolicitation { target: Ipv6Addr, options: Vec<NdpOption<'a>> },
+    NeighborAdvertisement {
+        /// R flag
+        router: bool,
+        /// S flag
+        solicited: bool,
+        /// O flag
+        override_flag: bool,
+        target: Ipv6Addr,
+        options: Vec<NdpOption<'a>>,
+    },
+    Redirect { target: Ipv6Addr, destination: Ipv6Addr, options: Vec<NdpOption<'a>> },
+    /// Any other type, `rest_of_header` is the 4 bytes following the checksum
+    Other { rest_of_header: &'a [u8], data: &'a [u8] },
+}
+
+impl <'a> Icmpv6Message<'a> {
+    /// Quoted packet of an error message, `None` for the other messages
+    pub fn quote(&self) -> Option<&'a [u8]> {
+        match *self {
+            Icmpv6Message::DestinationUnreachable { quote } | Icmpv6Message::PacketTooBig { quote, .. }
+            | Icmpv6Message::TimeExceeded { quote } | Icmpv6Message::ParameterProblem { quote, .. } => Some(quote),
+            _ => None,
+        }
+    }
+
+    /// Decoded quote of an error message, `None` for the other messages.
+    /// Offsets of errors count from the ICMPv6 header.
+    pub fn original(&self) -> Option<Result<OriginalDatagram<'a>, ParseError>> {
+        self.quote().map(|quote| OriginalDatagram::try_from(quote).map_err(|e| e.shift(ICMPV6_HDR_LEN)))
+    }
+
+    /// Neighbor Discovery options, `None` for messages other than RS, RA, NS, NA and Redirect
+    pub fn ndp_options(&self) -> Option<&[NdpOption<'a>]> {
+        match *self {
+            Icmpv6Message::RouterSolicitation { ref options }
+            | Icmpv6Message::RouterAdvertisement { ref options, .. }
+            | Icmpv6Message::NeighborSolicitation { ref options, .. }
+            | Icmpv6Message::NeighborAdvertisement { ref options, .. }
+            | Icmpv6Message::Redirect { ref options, .. } => Some(options),
+            _ => None,
+        }
+    }
+
+    /// Link-layer address carried by a Source or Target Link-Layer Address option,
+    /// the NDP counterpart of `ArpInfo::ar_ha`
+    pub fn link_layer_addr(&self) -> Option<&'a [u8]> {
+        self.ndp_options()?.iter().filter_map(|o| match *o {
+            NdpOption::SourceLinkLayerAddress(addr) | NdpOption::TargetLinkLayerAddress(addr) => Some(addr),
+            _ => None,
+        }).next()
+    }
+}
+
+#[derive(Debug,PartialEq)]
+pub struct Icmpv6Packet <'a> {
+    pub icmp_type: Icmpv6Type,
+    pub code: u8,
+    pub checksum: u16,
+    /// Result of the checksum verification. `None` when it was not requested
+    /// or when the capture is truncated.
+    pub checksum_ok: Option<bool>,
+    pub message: Icmpv6Message<'a>,
+}
+
+const ICMPV6_HDR_LEN: usize = 8;
+
+fn addr(buf: &[u8]) -> Ipv6Addr {
+    let mut a = [0u8; 16];
+    a.copy_from_slice(&buf[..16]);
+    Ipv6Addr::from(a)
+}
+
+fn addrs(buf: &[u8], count: usize) -> Vec<Ipv6Addr> {
+    (0..count).map(|i| addr(&buf[i * 16..])).collect()
+}
+
+fn pad8(len: usize) -> usize {
+    len.div_ceil(8) * 8
+}
+
+/// Parse the Neighbor Discovery options filling `buf`
+pub fn parse_ndp_options(buf: &[u8]) -> Result<Vec<NdpOption<'_>>, ParseError> {
+    let mut options = vec!();
+    let mut ptr = 0;
+    while ptr < buf.len() {
+        let option = NdpOption::try_from(&buf[ptr..]).map_err(|e| e.shift(ptr))?;
+        ptr += option.emit_len();
+        options.push(option);
+    }
+    Ok(options)
+}
+
+/// Parse the option at the start of `buf`
+impl <'a> TryFrom<&'a [u8]> for NdpOption<'a> {
+    type Error = ParseError;
+
+    fn try_from(buf: &'a [u8]) -> Result<NdpOption<'a>, Self::Error> {
+        if buf.len() < 2 {
+            return Err(ParseError::truncated(Layer::IcmpV6, 0, 2, buf.len()));
+        }
+        let len = buf[1] as usize * 8;
+        if len == 0 {
+            return Err(ParseError::new(Layer::IcmpV6, 1, Reason::BadField("length")));
+        } else if len > buf.len() {
+            return Err(ParseError::truncated(Layer::IcmpV6, 0, len, buf.len()));
+        }
+        let bad_length = Err(ParseError::new(Layer::IcmpV6, 1, Reason::BadField("length")));
+        Ok(match buf[0] {
+            NdpOptionTypes::SourceLinkLayerAddress => NdpOption::SourceLinkLayerAddress(&buf[2..len]),
+            NdpOptionTypes::TargetLinkLayerAddress => NdpOption::TargetLinkLayerAddress(&buf[2..len]),
+            NdpOptionTypes::PrefixInformation => {
+                if len != 32 {
+                    return bad_length;
+                }
+                NdpOption::PrefixInformation(PrefixInfo {
+                    prefix_length: buf[2],
+                    on_link: buf[3] & 0x80 != 0,
+                    autonomous: buf[3] & 0x40 != 0,
+                    valid_lifetime: common::u32_parse(&buf[4..8]),
+                    preferred_lifetime: common::u32_parse(&buf[8..12]),
+                    prefix: addr(&buf[16..32]),
+                })
+            }
+            NdpOptionTypes::RedirectedHeader => NdpOption::RedirectedHeader(&buf[8..len]),
+            NdpOptionTypes::Mtu => {
+                if len != 8 {
+                    return bad_length;
+                }
+                NdpOption::Mtu(common::u32_parse(&buf[4..8]))
+            }
+            NdpOptionTypes::Rdnss => {
+                if len < 24 || !(len - 8).is_multiple_of(16) {
+                    return bad_length;
+                }
+                NdpOption::Rdnss { lifetime: common::u32_parse(&buf[4..8]), servers: addrs(&buf[8..], (len - 8) / 16) }
+            }
+            option_type => NdpOption::Unknown { option_type, data: &buf[2..len] },
+        })
+    }
+}
+
+/// Source addresses starting at `start`, their number is read at `count_at`
+fn parse_sources(buf: &[u8], count_at: usize, start: usize) -> Result<Vec<Ipv6Addr>, ParseError> {
+    let count = common::u16_parse(&buf[count_at..count_at + 2]) as usize;
+    let len = start + count * 16;
+    if buf.len() < len {
+        return Err(ParseError::truncated(Layer::IcmpV6, 0, len, buf.len()));
+    }
+    Ok(addrs(&buf[start..], count))
+}
+
+fn parse_records(buf: &[u8]) -> Result<Vec<MulticastRecord<'_>>, ParseError> {
+    let count = common::u16_parse(&buf[6..8]) as usize;
+    let mut records = Vec::with_capacity(count.min(buf.len() / 20));
+    let mut ptr = ICMPV6_HDR_LEN;
+    for _ in 0..count {
+        if buf.len() < ptr + 20 {
+            return Err(ParseError::truncated(Layer::IcmpV6, ptr, 20, buf.len() - ptr));
+        }
+        let sources = parse_sources(&buf[ptr..], 2, 20).map_err(|e| e.shift(ptr))?;
+        let start = ptr + 20 + sources.len() * 16;
+        let end = start + buf[ptr + 1] as usize * 4;
+        if buf.len() < end {
+            return Err(ParseError::truncated(Layer::IcmpV6, ptr, end - ptr, buf.len() - ptr));
+        }
+        records.push(MulticastRecord {
+            record_type: buf[ptr],
+            group: addr(&buf[ptr + 4..]),
+            sources,
+            aux_data: &buf[start..end],
+        });
+        ptr = end;
+    }
+    Ok(records)
+}
+
+impl <'a> Icmpv6Packet<'a> {
+    /// Parse the message at the start of `buf` without checking the checksum,
+    /// `buf` holds the message only
+    pub fn parse(buf: &'a [u8]) -> Result<Icmpv6Packet<'a>, ParseError> {
+        if buf.len() < ICMPV6_HDR_LEN {
+            return Err(ParseError::truncated(Layer::IcmpV6, 0, ICMPV6_HDR_LEN, buf.len()));
+        }
+        let icmp_type = Icmpv6Type(buf[0]);
+        let min_len = match icmp_type {
+            Icmpv6Types::MldQuery | Icmpv6Types::MldReport | Icmpv6Types::MldDone
+            | Icmpv6Types::NeighborSolicitation | Icmpv6Types::NeighborAdvertisement => 24,
+            Icmpv6Types::RouterAdvertisement => 16,
+            Icmpv6Types::Redirect => 40,
+            _ => ICMPV6_HDR_LEN,
+        };
+        if buf.len() < min_len {
+            return Err(ParseError::truncated(Layer::IcmpV6, 0, min_len, buf.len()));
+        }
+        let options = || parse_ndp_options(&buf[min_len..]).map_err(|e| e.shift(min_len));
+        let quote = &buf[ICMPV6_HDR_LEN..];
+        let id = common::u16_parse(&buf[4..6]);
+        let seq = common::u16_parse(&buf[6..8]);
+        let rest = common::u32_parse(&buf[4..8]);
+        let message = match icmp_type {
+            Icmpv6Types::DestinationUnreachable => Icmpv6Message::DestinationUnreachable { quote },
+            Icmpv6Types::PacketTooBig => Icmpv6Message::PacketTooBig { mtu: rest, quote },
+            Icmpv6Types::TimeExceeded => Icmpv6Message::TimeExceeded { quote },
+            Icmpv6Types::ParameterProblem => Icmpv6Message::ParameterProblem { pointer: rest, quote },
+            Icmpv6Types::EchoRequest => Icmpv6Message::EchoRequest { id, seq, data: &buf[ICMPV6_HDR_LEN..] },
+            Icmpv6Types::EchoReply => Icmpv6Message::EchoReply { id, seq, data: &buf[ICMPV6_HDR_LEN..] },
+            Icmpv6Types::MldQuery => {
+                let v2 = match buf.len() {
+                    24 => None,
+                    len if len < 28 => return Err(ParseError::truncated(Layer::IcmpV6, 0, 28, len)),
+                    _ => Some(MldV2Query {
+                        suppress: buf[24] & 0x08 != 0,
+                        robustness: buf[24] & 0x07,
+                        qqic: buf[25],
+                        sources: parse_sources(buf, 26, 28)?,
+                    }),
+                };
+                Icmpv6Message::MldQuery { max_response_code: id, group: addr(&buf[8..]), v2 }
+            }
+            Icmpv6Types::MldReport => Icmpv6Message::MldReport { group: addr(&buf[8..]) },
+            Icmpv6Types::MldDone => Icmpv6Message::MldDone { group: addr(&buf[8..]) },
+            Icmpv6Types::Mldv2Report => Icmpv6Message::Mldv2Report { records: parse_records(buf)? },
+            Icmpv6Types::RouterSolicitation => Icmpv6Message::RouterSolicitation { options: options()? },
+            Icmpv6Types::RouterAdvertisement => Icmpv6Message::RouterAdvertisement {
+                cur_hop_limit: buf[4],
+                managed: buf[5] & 0x80 != 0,
+                other: buf[5] & 0x40 != 0,
+                router_lifetime: seq,
+                reachable_time: common::u32_parse(&buf[8..12]),
+                retrans_timer: common::u32_parse(&buf[12..16]),
+                options: options()?,
+            },
+            Icmpv6Types::NeighborSolicitation => Icmpv6Message::NeighborSolicitation { target: addr(&buf[8..]), options: options()? },
+            Icmpv6Types::NeighborAdvertisement => Icmpv6Message::NeighborAdvertisement {
+                router: buf[4] & 0x80 != 0,
+                solicited: buf[4] & 0x40 != 0,
+                override_flag: buf[4] & 0x20 != 0,
+                target: addr(&buf[8..]),
+                options: options()?,
+            },
+            Icmpv6Types::Redirect => Icmpv6Message::Redirect {
+                target: addr(&buf[8..]),
+                destination: addr(&buf[24..]),
+                options: options()?,
+            },
+            _ => Icmpv6Message::Other { rest_of_header: &buf[4..ICMPV6_HDR_LEN], data: &buf[ICMPV6_HDR_LEN..] },
+        };
+        Ok(Icmpv6Packet {
+            icmp_type,
+            code: buf[1],
+            checksum: common::u16_parse(&buf[2..4]),
+            checksum_ok: None,
+            message,
+        })
+    }
+
+    /// Message after the IPv6 extension headers. Offsets of errors count from the IPv6 header.
+    /// Non-first fragments fail with `BadField("offset")`.
+    pub fn from_ipv6(packet: &ipv6::IpV6Packet<'a>, check: ChecksumCheck) -> Result<Icmpv6Packet<'a>, ParseError> {
+        let chain = packet.extensions()?;
+        let start = ipv6::IPV6_HDR_LEN + chain.payload_offset;
+        if chain.protocol != IpNextHeaderProtocols::Icmpv6 {
+            return Err(ParseError::new(Layer::IcmpV6, start, Reason::WrongProtocol(chain.protocol.0)));
+        }
+        let fragment = chain.fragment();
+        if fragment.is_some_and(|f| f.offset != 0) {
+            return Err(ParseError::new(Layer::IcmpV6, start, Reason::BadField("offset")));
+        }
+        let buf = &packet.payload[chain.payload_offset..];
+        let mut icmp = Icmpv6Packet::parse(buf).map_err(|e| e.shift(start))?;
+        if check == ChecksumCheck::Skip || packet.missing_bytes() != 0 || fragment.is_some_and(|f| f.more_fragments) {
+            return Ok(icmp);
+        }
+        let pseudo = packet.ip_hdr.pseudo_header(chain.protocol, buf.len() as u32);
+        let sum = common::checksum_pseudo(&pseudo, buf);
+        if sum != 0 && check == ChecksumCheck::Reject {
+            let expected = common::checksum_update(sum, icmp.checksum, 0);
+            return Err(ParseError::new(Layer::IcmpV6, start + 2, Reason::BadChecksum { expected, actual: icmp.checksum }));
+        }
+        icmp.checksum_ok = Some(sum == 0);
+        Ok(icmp)
+    }
+
+    /// Checksum to store in the header, computed over `pseudo` (see `IpV6Header::pseudo_header`)
+    /// and the emitted message
+    pub fn compute_checksum(&self, pseudo: &[u8]) -> Result<u16, ParseError> {
+        let mut buf = vec!();
+        self.emit_vec(&mut buf)?;
+        common::u16_write(&mut buf[2..], 0);
+        Ok(common::checksum_pseudo(pseudo, &buf))
+    }
+}
+
+/// Parse the message at the start of `buf`, the checksum is not verified as it needs
+/// the IPv6 pseudo-header, see `Icmpv6Packet::from_ipv6`
+impl <'a> TryFrom<&'a [u8]> for Icmpv6Packet<'a> {
+    type Error = ParseError;
+
+    fn try_from(buf: &'a [u8]) -> Result<Icmpv6Packet<'a>, Self::Error> {
+        Icmpv6Packet::parse(buf)
+    }
+}
+
+/// Verifies the checksum and stores the result in `checksum_ok`
+impl <'a, 'b> TryFrom<&'b ipv6::IpV6Packet<'a>> for Icmpv6Packet<'a> {
+    type Error = ParseError;
+
+    fn try_from(packet: &'b ipv6::IpV6Packet<'a>) -> Result<Icmpv6Packet<'a>, Self::Error> {
+        Icmpv6Packet::from_ipv6(packet, ChecksumCheck::Flag)
+    }
+}
+
+impl <'a> Emit for NdpOption<'a> {
+    fn emit_len(&self) -> usize {
+        match *self {
+            NdpOption::SourceLinkLayerAddress(data) | NdpOption::TargetLinkLayerAddress(data)
+            | NdpOption::Unknown { data, .. } => pad8(2 + data.len()),
+            NdpOption::PrefixInformation(_) => 32,
+            NdpOption::RedirectedHeader(data) => pad8(8 + data.len()),
+            NdpOption::Mtu(_) => 8,
+            NdpOption::Rdnss { ref servers, .. } => 8 + 16 * servers.len(),
+        }
+    }
+
+    /// Write the option zero-padded to a multiple of 8 bytes
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        let len = self.emit_len();
+        if len > 255 * 8 {
+            return Err(ParseError::new(Layer::IcmpV6, 1, Reason::BadField("length")));
+        }
+        if buf.len() < len {
+            return Err(ParseError::truncated(Layer::IcmpV6, 0, len, buf.len()));
+        }
+        for b in &mut buf[..len] {
+            *b = 0;
+        }
+        buf[1] = (len / 8) as u8;
+        buf[0] = match *self {
+            NdpOption::SourceLinkLayerAddress(data) => {
+                buf[2..2 + data.len()].copy_from_slice(data);
+                NdpOptionTypes::SourceLinkLayerAddress
+            }
+            NdpOption::TargetLinkLayerAddress(data) => {
+                buf[2..2 + data.len()].copy_from_slice(data);
+                NdpOptionTypes::TargetLinkLayerAddress
+            }
+            NdpOption::PrefixInformation(ref info) => {
+                buf[2] = info.prefix_length;
+                buf[3] = (info.on_link as u8) << 7 | (info.autonomous as u8) << 6;
+                common::u32_write(&mut buf[4..], info.valid_lifetime);
+                common::u32_write(&mut buf[8..], info.preferred_lifetime);
+                buf[16..32].copy_from_slice(&info.prefix.octets());
+                NdpOptionTypes::PrefixInformation
+            }
+            NdpOption::RedirectedHeader(data) => {
+                buf[8..8 + data.len()].copy_from_slice(data);
+                NdpOptionTypes::RedirectedHeader
+            }
+            NdpOption::Mtu(mtu) => {
+                common::u32_write(&mut buf[4..], mtu);
+                NdpOptionTypes::Mtu
+            }
+            NdpOption::Rdnss { lifetime, ref servers } => {
+                common::u32_write(&mut buf[4..], lifetime);
+                for (i, server) in servers.iter().enumerate() {
+                    buf[8 + i * 16..24 + i * 16].copy_from_slice(&server.octets());
+                }
+                NdpOptionTypes::Rdnss
+            }
+            NdpOption::Unknown { option_type, data } => {
+                buf[2..2 + data.len()].copy_from_slice(data);
+                option_type
+            }
+        };
+        Ok(len)
+    }
+}
+
+impl <'a> Emit for MulticastRecord<'a> {
+    fn emit_len(&self) -> usize {
+        20 + 16 * self.sources.len() + self.aux_data.len()
+    }
+
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        let len = self.emit_len();
+        if !self.aux_data.len().is_multiple_of(4) || self.aux_data.len() > 255 * 4 {
+            return Err(ParseError::new(Layer::IcmpV6, 1, Reason::BadField("aux_data")));
+        }
+        if buf.len() < len {
+            return Err(ParseError::truncated(Layer::IcmpV6, 0, len, buf.len()));
+        }
+        buf[0] = self.record_type;
+        buf[1] = (self.aux_data.len() / 4) as u8;
+        common::u16_write(&mut buf[2..], self.sources.len() as u16);
+        buf[4..20].copy_from_slice(&self.group.octets());
+        for (i, source) in self.sources.iter().enumerate() {
+            buf[20 + i * 16..36 + i * 16].copy_from_slice(&source.octets());
+        }
+        buf[len - self.aux_data.len()..len].copy_from_slice(self.aux_data);
+        Ok(len)
+    }
+}
+
+impl <'a> Emit for OriginalDatagram<'a> {
+    fn emit_len(&self) -> usize {
+        ipv6::IPV6_HDR_LEN + self.data.len()
+    }
+
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        let ptr = self.ip_hdr.emit(buf)?;
+        if buf.len() < ptr + self.data.len() {
+            return Err(ParseError::truncated(Layer::IcmpV6, ptr, self.data.len(), buf.len() - ptr));
+        }
+        buf[ptr..ptr + self.data.len()].copy_from_slice(self.data);
+        Ok(ptr + self.data.len())
+    }
+}
+
+fn options_len(options: &[NdpOption]) -> usize {
+    options.iter().map(|o| o.emit_len()).sum()
+}
+
+impl <'a> Emit for Icmpv6Packet<'a> {
+    fn emit_len(&self) -> usize {
+        match self.message {
+            Icmpv6Message::DestinationUnreachable { quote: data } | Icmpv6Message::PacketTooBig { quote: data, .. }
+            | Icmpv6Message::TimeExceeded { quote: data } | Icmpv6Message::ParameterProblem { quote: data, .. }
+            | Icmpv6Message::EchoRequest { data, .. } | Icmpv6Message::EchoReply { data, .. }
+            | Icmpv6Message::Other { data, .. } => ICMPV6_HDR_LEN + data.len(),
+            Icmpv6Message::MldQuery { v2: None, .. } | Icmpv6Message::MldReport { .. } | Icmpv6Message::MldDone { .. } => 24,
+            Icmpv6Message::MldQuery { v2: Some(ref v2), .. } => 28 + 16 * v2.sources.len(),
+            Icmpv6Message::Mldv2Report { ref records } => ICMPV6_HDR_LEN + records.iter().map(|r| r.emit_len()).sum::<usize>(),
+            Icmpv6Message::RouterSolicitation { ref options } => ICMPV6_HDR_LEN + options_len(options),
+            Icmpv6Message::RouterAdvertisement { ref options, .. } => 16 + options_len(options),
+            Icmpv6Message::NeighborSolicitation { ref options, .. }
+            | Icmpv6Message::NeighborAdvertisement { ref options, .. } => 24 + options_len(options),
+            Icmpv6Message::Redirect { ref options, .. } => 40 + options_len(options),
+        }
+    }
+
+    /// Write the message with `checksum` as is, see `compute_checksum`
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        let len = self.emit_len();
+        if buf.len() < len {
+            return Err(ParseError::truncated(Layer::IcmpV6, 0, len, buf.len()));
+        }
+        for b in &mut buf[4..len] {
+            *b = 0;
+        }
+        buf[0] = self.icmp_type.0;
+        buf[1] = self.code;
+        common::u16_write(&mut buf[2..], self.checksum);
+        let options = match self.message {
+            Icmpv6Message::DestinationUnreachable { quote } | Icmpv6Message::TimeExceeded { quote } => {
+                buf[ICMPV6_HDR_LEN..len].copy_from_slice(quote);
+                None
+            }
+            Icmpv6Message::PacketTooBig { mtu: rest, quote } | Icmpv6Message::ParameterProblem { pointer: rest, quote } => {
+                common::u32_write(&mut buf[4..], rest);
+                buf[ICMPV6_HDR_LEN..len].copy_from_slice(quote);
+                None
+            }
+            Icmpv6Message::EchoRequest { id, seq, data } | Icmpv6Message::EchoReply { id, seq, data } => {
+                common::u16_write(&mut buf[4..], id);
+                common::u16_write(&mut buf[6..], seq);
+                buf[ICMPV6_HDR_LEN..len].copy_from_slice(data);
+                None
+            }
+            Icmpv6Message::MldQuery { max_response_code, group, ref v2 } => {
+                common::u16_write(&mut buf[4..], max_response_code);
+                buf[8..24].copy_from_slice(&group.octets());
+                if let Some(ref v2) = *v2 {
+                    if v2.robustness > 7 {
+                        return Err(ParseError::new(Layer::IcmpV6, 24, Reason::BadField("robustness")));
+                    }
+                    buf[24] = (v2.suppress as u8) << 3 | v2.robustness;
+                    buf[25] = v2.qqic;
+                    common::u16_write(&mut buf[26..], v2.sources.len() as u16);
+                    for (i, source) in v2.sources.iter().enumerate() {
+                        buf[28 + i * 16..44 + i * 16].copy_from_slice(&source.octets());
+                    }
+                }
+                None
+            }
+            Icmpv6Message::MldReport { group } | Icmpv6Message::MldDone { group } => {
+                buf[8..24].copy_from_slice(&group.octets());
+                None
+            }
+            Icmpv6Message::Mldv2Report { ref records } => {
+                common::u16_write(&mut buf[6..], records.len() as u16);
+                let mut ptr = ICMPV6_HDR_LEN;
+                for record in records {
+                    ptr += record.emit(&mut buf[ptr..len]).map_err(|e| e.shift(ptr))?;
+                }
+                None
+            }
+            Icmpv6Message::RouterSolicitation { ref options } => Some((ICMPV6_HDR_LEN, options)),
+            Icmpv6Message::RouterAdvertisement { cur_hop_limit, managed, other, router_lifetime, reachable_time, retrans_timer, ref options } => {
+                buf[4] = cur_hop_limit;
+                buf[5] = (managed as u8) << 7 | (other as u8) << 6;
+                common::u16_write(&mut buf[6..], router_lifetime);
+                common::u32_write(&mut buf[8..], reachable_time);
+                common::u32_write(&mut buf[12..], retrans_timer);
+                Some((16, options))
+            }
+            Icmpv6Message::NeighborSolicitation { target, ref options } => {
+                buf[8..24].copy_from_slice(&target.octets());
+                Some((24, options))
+            }
+            Icmpv6Message::NeighborAdvertisement { router, solicited, override_flag, target, ref options } => {
+                buf[4] = (router as u8) << 7 | (solicited as u8) << 6 | (override_flag as u8) << 5;
+                buf[8..24].copy_from_slice(&target.octets());
+                Some((24, options))
+            }
+            Icmpv6Message::Redirect { target, destination, ref options } => {
+                buf[8..24].copy_from_slice(&target.octets());
+                buf[24..40].copy_from_slice(&destination.octets());
+                Some((40, options))
+            }
+            Icmpv6Message::Other { rest_of_header, data } => {
+                if rest_of_header.len() != 4 {
+                    return Err(ParseError::new(Layer::IcmpV6, 4, Reason::BadField("rest_of_header")));
+                }
+                buf[4..ICMPV6_HDR_LEN].copy_from_slice(rest_of_header);
+                buf[ICMPV6_HDR_LEN..len].copy_from_slice(data);
+                None
+            }
+        };
+        if let Some((mut ptr, options)) = options {
+            for option in options {
+                ptr += option.emit(&mut buf[ptr..len]).map_err(|e| e.shift(ptr))?;
+            }
+        }
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Neighbor Solicitation for fe80::2 from fe80::1 with a Source Link-Layer Address option
+    const FRAME_NS: [u8; 86] = [0x33, 0x33, 0xff, 0x00, 0x00, 0x02, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x86, 0xdd,
+        0x60, 0x00, 0x00, 0x00, 0x00, 0x20, 0x3a, 0xff,
+        0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01,
+        0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01, 0xff, 0x00, 0x00, 0x02,
+        0x87, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x02,
+        0x01, 0x01, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55];
+
+    fn ns_frame() -> Vec<u8> {
+        let mut frame = FRAME_NS.to_vec();
+        let crc = {
+            let packet = ipv6::IpV6Packet::try_from(&frame[..]).unwrap();
+            let pseudo = packet.ip_hdr.pseudo_header(IpNextHeaderProtocols::Icmpv6, 32);
+            Icmpv6Packet::try_from(&packet).unwrap().compute_checksum(&pseudo).unwrap()
+        };
+        common::u16_write(&mut frame[56..], crc);
+        frame
+    }
+
+    #[test]
+    fn test_ns() {
+        let frame = ns_frame();
+        assert_eq!(&frame[56..58], &[0x15, 0xff]);
+        let packet = ipv6::IpV6Packet::try_from(&frame[..]).unwrap();
+        let res = Icmpv6Packet::try_from(&packet).unwrap();
+        assert_eq!(res.checksum_ok, Some(true));
+        assert_eq!(res.message, Icmpv6Message::NeighborSolicitation {
+            target: "fe80::2".parse().unwrap(),
+            options: vec!(NdpOption::SourceLinkLayerAddress(&[0x00, 0x11, 0x22, 0x33, 0x44, 0x55])),
+        });
+        assert_eq!(res.message.link_layer_addr(), Some(&frame[80..86]));
+
+        let mut out = vec!();
+        assert_eq!(res.emit_vec(&mut out), Ok(32));
+        assert_eq!(&out[..], &frame[54..]);
+    }
+    #[test]
+    fn test_checksum() {
+        let mut frame = ns_frame();
+        frame[85] = 0x56;
+        let packet = ipv6::IpV6Packet::try_from(&frame[..]).unwrap();
+        assert_eq!(Icmpv6Packet::try_from(&packet).unwrap().checksum_ok, Some(false));
+        assert_eq!(Icmpv6Packet::from_ipv6(&packet, ChecksumCheck::Skip).unwrap().checksum_ok, None);
+        assert_eq!(Icmpv6Packet::from_ipv6(&packet, ChecksumCheck::Reject),
+                   Err(ParseError::new(Layer::IcmpV6, 42, Reason::BadChecksum { expected: 0x15fe, actual: 0x15ff })));
+        let packet = ipv6::IpV6Packet::try_from(&frame[..80]).unwrap();
+        assert_eq!(Icmpv6Packet::try_from(&packet),
+                   Err(ParseError::truncated(Layer::IcmpV6, 64, 8, 2)));
+        frame[20] = 17;
+        let packet = ipv6::IpV6Packet::try_from(&frame[..]).unwrap();
+        assert_eq!(Icmpv6Packet::try_from(&packet), Err(ParseError::new(Layer::IcmpV6, 40, Reason::WrongProtocol(17))));
+    }
+    #[test]
+    fn test_ra() {
+        let ra = Icmpv6Packet {
+            icmp_type: Icmpv6Types::RouterAdvertisement,
+            code: 0,
+            checksum: 0,
+            checksum_ok: None,
+            message: Icmpv6Message::RouterAdvertisement {
+                cur_hop_limit: 64,
+                managed: false,
+                other: true,
+                router_lifetime: 1800,
+                reachable_time: 0,
+                retrans_timer: 0,
+                options: vec!(
+                    NdpOption::SourceLinkLayerAddress(&[0x00, 0x11, 0x22, 0x33, 0x44, 0x55]),
+                    NdpOption::Mtu(1500),
+                    NdpOption::PrefixInformation(PrefixInfo {
+                        prefix_length: 64,
+                        on_link: true,
+                        autonomous: true,
+                        valid_lifetime: 86400,
+                        preferred_lifetime: 14400,
+                        prefix: "2001:db8:1::".parse().unwrap(),
+                    }),
+                    NdpOption::Rdnss { lifetime: 600, servers: vec!("2001:db8::53".parse().unwrap(), "2001:db8::54".parse().unwrap()) },
+                ),
+            },
+        };
+        let mut out = vec!();
+        assert_eq!(ra.emit_vec(&mut out), Ok(16 + 8 + 8 + 32 + 40));
+        assert_eq!(&out[0..8], &[134, 0, 0, 0, 64, 0x40, 0x07, 0x08]);
+        assert_eq!(&out[24..32], &[5, 1, 0, 0, 0, 0, 0x05, 0xdc]);
+        assert_eq!(Icmpv6Packet::try_from(&out[..]), Ok(ra));
+
+        out[25] = 0;
+        assert_eq!(Icmpv6Packet::try_from(&out[..]), Err(ParseError::new(Layer::IcmpV6, 25, Reason::BadField("length"))));
+        out[25] = 2;
+        assert_eq!(Icmpv6Packet::try_from(&out[..]), Err(ParseError::new(Layer::IcmpV6, 25, Reason::BadField("length"))));
+    }
+    #[test]
+    fn test_mld() {
+        let query = Icmpv6Packet {
+            icmp_type: Icmpv6Types::MldQuery,
+            code: 0,
+            checksum: 0,
+            checksum_ok: None,
+            message: Icmpv6Message::MldQuery {
+                max_response_code: 10000,
+                group: Ipv6Addr::UNSPECIFIED,
+                v2: Some(MldV2Query { suppress: false, robustness: 2, qqic: 125, sources: vec!() }),
+            },
+        };
+        let mut out = vec!();
+        assert_eq!(query.emit_vec(&mut out), Ok(28));
+        assert_eq!(Icmpv6Packet::try_from(&out[..]), Ok(query));
+        assert_eq!(Icmpv6Packet::try_from(&out[..26]), Err(ParseError::truncated(Layer::IcmpV6, 0, 28, 26)));
+        match Icmpv6Packet::try_from(&out[..24]).unwrap().message {
+            Icmpv6Message::MldQuery { v2, .. } => assert_eq!(v2, None),
+            ref m => panic!("unexpected {:?}", m),
+        }
+
+        let report = Icmpv6Packet {
+            icmp_type: Icmpv6Types::Mldv2Report,
+            code: 0,
+            checksum: 0,
+            checksum_ok: None,
+            message: Icmpv6Message::Mldv2Report { records: vec!(
+                MulticastRecord { record_type: MldRecordTypes::ChangeToExclude, group: "ff02::fb".parse().unwrap(), sources: vec!(), aux_data: &[] },
+                MulticastRecord { record_type: MldRecordTypes::AllowNewSources, group: "ff3e::1".parse().unwrap(),
+                                  sources: vec!("2001:db8::1".parse().unwrap()), aux_data: &[1, 2, 3, 4] },
+            )},
+        };
+        let mut out = vec!();
+        assert_eq!(report.emit_vec(&mut out), Ok(8 + 20 + 40));
+        assert_eq!(&out[4..8], &[0, 0, 0, 2]);
+        assert_eq!(Icmpv6Packet::try_from(&out[..]), Ok(report));
+        assert_eq!(Icmpv6Packet::try_from(&out[..60]), Err(ParseError::truncated(Layer::IcmpV6, 28, 36, 32)));
+    }
+    #[test]
+    fn test_errors_quote() {
+        let frame = ns_frame();
+        let mut buf = vec!(2u8, 0, 0, 0, 0, 0, 0x05, 0x00);
+        buf.extend_from_slice(&frame[14..]);
+        let res = Icmpv6Packet::try_from(&buf[..]).unwrap();
+        match res.message {
+            Icmpv6Message::PacketTooBig { mtu, quote } => {
+                assert_eq!(mtu, 1280);
+                assert_eq!(quote, &buf[8..]);
+                let original = res.message.original().unwrap().unwrap();
+                assert_eq!(original.ip_hdr.dst_addr(), "ff02::1:ff00:2".parse::<Ipv6Addr>().unwrap());
+                assert_eq!(original.data.len(), 32);
+            }
+            ref m => panic!("unexpected {:?}", m),
+        }
+        let mut out = vec!();
+        assert_eq!(res.emit_vec(&mut out), Ok(buf.len()));
+        assert_eq!(out, buf);
+
+        let cut = Icmpv6Packet::try_from(&buf[..30]).unwrap();
+        assert_eq!(cut.message, Icmpv6Message::PacketTooBig { mtu: 1280, quote: &buf[8..30] });
+        assert_eq!(cut.message.original(), Some(Err(ParseError::truncated(Layer::IpV6, 8, 40, 22))));
+    }
+}
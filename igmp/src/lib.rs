@@ -0,0 +1,388 @@
+extern crate common;
+extern crate ip;
+
+use std::convert::TryFrom;
+use std::net::Ipv4Addr;
+use common::{Emit, Layer, ParseError, Reason};
+use ip::ChecksumCheck;
+
+/// IGMP message type
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IgmpType(pub u8);
+
+#[allow(non_snake_case)]
+#[allow(non_upper_case_globals)]
+pub mod IgmpTypes {
+    use super::IgmpType;
+    /// Membership Query, all versions [RFC 3376]
+    pub const MembershipQuery: IgmpType = IgmpType(0x11);
+    /// Version 1 Membership Report [RFC 1112]
+    pub const V1MembershipReport: IgmpType = IgmpType(0x12);
+    /// Version 2 Membership Report [RFC 2236]
+    pub const V2MembershipReport: IgmpType = IgmpType(0x16);
+    /// Leave Group [RFC 2236]
+    pub const LeaveGroup: IgmpType = IgmpType(0x17);
+    /// Version 3 Membership Report [RFC 3376]
+    pub const V3MembershipReport: IgmpType = IgmpType(0x22);
+}
+
+/// Record types of a version 3 report [RFC 3376]
+#[allow(non_snake_case)]
+#[allow(non_upper_case_globals)]
+pub mod GroupRecordTypes {
+    pub const ModeIsInclude: u8 = 1;
+    pub const ModeIsExclude: u8 = 2;
+    pub const ChangeToInclude: u8 = 3;
+    pub const ChangeToExclude: u8 = 4;
+    pub const AllowNewSources: u8 = 5;
+    pub const BlockOldSources: u8 = 6;
+}
+
+/// Version 3 fields following the version 2 part of a query
+#[derive(Debug,PartialEq)]
+pub struct IgmpV3Query {
+    /// S flag, routers suppress timer updates
+    pub suppress: bool,
+    /// Querier's Robustness Variable
+    pub robustness: u8,
+    /// Querier's Query Interval Code
+    pub qqic: u8,
+    pub sources: Vec<Ipv4Addr>,
+}
+
+#[derive(Debug,PartialEq)]
+pub struct GroupRecord <'a> {
+    pub record_type: u8,
+    pub group: Ipv4Addr,
+    pub sources: Vec<Ipv4Addr>,
+    pub aux_data: &'a [u8],
+}
+
+#[derive(Debug,PartialEq)]
+pub enum IgmpMessage <'a> {
+    /// `max_resp_code` is zero for version 1, tenths of a second for version 2,
+    /// encoded as in RFC 3376 section 4.1.1 for version 3. `v3` is set for queries
+    /// longer than 8 bytes.
+    MembershipQuery { max_resp_code: u8, group: Ipv4Addr, v3: Option<IgmpV3Query> },
+    V1MembershipReport { group: Ipv4Addr },
+    V2MembershipReport { group: Ipv4Addr },
+    LeaveGroup { group: Ipv4Addr },
+    V3MembershipReport { records: Vec<GroupRecord<'a>> },
+    /// Any other type, `code` is the byte following the type
+    Other { code: u8, data: &'a [u8] },
+}
+
+impl <'a> IgmpMessage<'a> {
+    /// Protocol version the message belongs to, `None` for unknown types
+    pub fn version(&self) -> Option<u8> {
+        match *self {
+            IgmpMessage::MembershipQuery { v3: Some(_), .. } | IgmpMessage::V3MembershipReport { .. } => Some(3),
+            IgmpMessage::MembershipQuery { max_resp_code: 0, .. } | IgmpMessage::V1MembershipReport { .. } => Some(1),
+            IgmpMessage::MembershipQuery { .. } | IgmpMessage::V2MembershipReport { .. } | IgmpMessage::LeaveGroup { .. } => Some(2),
+            IgmpMessage::Other { .. } => None,
+        }
+    }
+}
+
+#[derive(Debug,PartialEq)]
+pub struct IgmpPacket <'a> {
+    pub igmp_type: IgmpType,
+    pub checksum: u16,
+    /// Result of the checksum verification, `None` when it was skipped or when the message
+    /// is truncated or fragmented, see `IgmpPacket::from_ipv4`
+    pub checksum_ok: Option<bool>,
+    /// Router Alert option of the carrying IPv4 header, see `IpV4Header::router_alert`.
+    /// `None` when absent or when the message was parsed without its IPv4 header.
+    pub router_alert: Option<u16>,
+    pub message: IgmpMessage<'a>,
+}
+
+const IGMP_HDR_LEN: usize = 8;
+const IGMP_V3_QUERY_LEN: usize = 12;
+
+fn to_ipv4(buf: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3])
+}
+
+/// Source addresses starting at `start`, their number is read at `count_at`
+fn parse_sources(buf: &[u8], count_at: usize, start: usize) -> Result<Vec<Ipv4Addr>, ParseError> {
+    let count = common::u16_parse(&buf[count_at..count_at + 2]) as usize;
+    let len = start + count * 4;
+    if buf.len() < len {
+        return Err(ParseError::truncated(Layer::Igmp, 0, len, buf.len()));
+    }
+    Ok((0..count).map(|i| to_ipv4(&buf[start + i * 4..])).collect())
+}
+
+fn parse_records(buf: &[u8]) -> Result<Vec<GroupRecord<'_>>, ParseError> {
+    let count = common::u16_parse(&buf[6..8]) as usize;
+    let mut records = Vec::with_capacity(count.min(buf.len() / 8));
+    let mut ptr = IGMP_HDR_LEN;
+    for _ in 0..count {
+        if buf.len() < ptr + 8 {
+            return Err(ParseError::truncated(Layer::Igmp, ptr, 8, buf.len() - ptr));
+        }
+        let sources = parse_sources(&buf[ptr..], 2, 8).map_err(|e| e.shift(ptr))?;
+        let start = ptr + 8 + sources.len() * 4;
+        let end = start + buf[ptr + 1] as usize * 4;
+        if buf.len() < end {
+            return Err(ParseError::truncated(Layer::Igmp, ptr, end - ptr, buf.len() - ptr));
+        }
+        records.push(GroupRecord {
+            record_type: buf[ptr],
+            group: to_ipv4(&buf[ptr + 4..]),
+            sources,
+            aux_data: &buf[start..end],
+        });
+        ptr = end;
+    }
+    Ok(records)
+}
+
+/// Parse the message at the start of `buf`, the checksum is verified over all of `buf`
+impl <'a> TryFrom<&'a [u8]> for IgmpPacket<'a> {
+    type Error = ParseError;
+
+    fn try_from(buf: &'a [u8]) -> Result<IgmpPacket<'a>, Self::Error> {
+        if buf.len() < IGMP_HDR_LEN {
+            return Err(ParseError::truncated(Layer::Igmp, 0, IGMP_HDR_LEN, buf.len()));
+        }
+        let igmp_type = IgmpType(buf[0]);
+        let group = to_ipv4(&buf[4..8]);
+        let message = match igmp_type {
+            IgmpTypes::MembershipQuery => {
+                let v3 = match buf.len() {
+                    IGMP_HDR_LEN => None,
+                    len if len < IGMP_V3_QUERY_LEN => return Err(ParseError::truncated(Layer::Igmp, 0, IGMP_V3_QUERY_LEN, len)),
+                    _ => Some(IgmpV3Query {
+                        suppress: buf[8] & 0x08 != 0,
+                        robustness: buf[8] & 0x07,
+                        qqic: buf[9],
+                        sources: parse_sources(buf, 10, IGMP_V3_QUERY_LEN)?,
+                    }),
+                };
+                IgmpMessage::MembershipQuery { max_resp_code: buf[1], group, v3 }
+            }
+            IgmpTypes::V1MembershipReport => IgmpMessage::V1MembershipReport { group },
+            IgmpTypes::V2MembershipReport => IgmpMessage::V2MembershipReport { group },
+            IgmpTypes::LeaveGroup => IgmpMessage::LeaveGroup { group },
+            IgmpTypes::V3MembershipReport => IgmpMessage::V3MembershipReport { records: parse_records(buf)? },
+            _ => IgmpMessage::Other { code: buf[1], data: &buf[4..] },
+        };
+        Ok(IgmpPacket {
+            igmp_type,
+            checksum: common::u16_parse(&buf[2..4]),
+            checksum_ok: Some(common::checksum(buf) == 0),
+            router_alert: None,
+            message,
+        })
+    }
+}
+
+impl <'a> IgmpPacket<'a> {
+    /// Message carried by an IPv4 packet. Offsets of errors count from the IPv4 header.
+    /// Non-first fragments fail with `BadField("offset")`.
+    pub fn from_ipv4(packet: &ip::IpV4Packet<'a>, check: ChecksumCheck) -> Result<IgmpPacket<'a>, ParseError> {
+        let hdr = &packet.ip_hdr;
+        if hdr.protocol != ip::IpNextHeaderProtocols::Igmp {
+            return Err(ParseError::new(Layer::Igmp, 9, Reason::WrongProtocol(hdr.protocol.0)));
+        }
+        if hdr.offset != 0 {
+            return Err(ParseError::new(Layer::Igmp, 6, Reason::BadField("offset")));
+        }
+        let start = hdr.hdr_length as usize * 4;
+        let mut igmp = IgmpPacket::try_from(packet.payload).map_err(|e| e.shift(start))?;
+        if check == ChecksumCheck::Skip || packet.missing_bytes() != 0 || hdr.flags & ip::Flags::MORE_FRAGMENTS != 0 {
+            igmp.checksum_ok = None;
+        } else if igmp.checksum_ok == Some(false) && check == ChecksumCheck::Reject {
+            let expected = common::checksum_update(common::checksum(packet.payload), igmp.checksum, 0);
+            return Err(ParseError::new(Layer::Igmp, start + 2, Reason::BadChecksum { expected, actual: igmp.checksum }));
+        }
+        igmp.router_alert = hdr.router_alert();
+        Ok(igmp)
+    }
+}
+
+/// Same as `IgmpPacket::from_ipv4` with `ChecksumCheck::Flag`
+impl <'a, 'b> TryFrom<&'b ip::IpV4Packet<'a>> for IgmpPacket<'a> {
+    type Error = ParseError;
+
+    fn try_from(packet: &'b ip::IpV4Packet<'a>) -> Result<IgmpPacket<'a>, Self::Error> {
+        IgmpPacket::from_ipv4(packet, ChecksumCheck::Flag)
+    }
+}
+
+impl <'a> Emit for GroupRecord<'a> {
+    fn emit_len(&self) -> usize {
+        8 + 4 * self.sources.len() + self.aux_data.len()
+    }
+
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        let len = self.emit_len();
+        if !self.aux_data.len().is_multiple_of(4) || self.aux_data.len() > 255 * 4 {
+            return Err(ParseError::new(Layer::Igmp, 1, Reason::BadField("aux_data")));
+        }
+        if buf.len() < len {
+            return Err(ParseError::truncated(Layer::Igmp, 0, len, buf.len()));
+        }
+        buf[0] = self.record_type;
+        buf[1] = (self.aux_data.len() / 4) as u8;
+        common::u16_write(&mut buf[2..], self.sources.len() as u16);
+        buf[4..8].copy_from_slice(&self.group.octets());
+        for (i, source) in self.sources.iter().enumerate() {
+            buf[8 + i * 4..12 + i * 4].copy_from_slice(&source.octets());
+        }
+        buf[len - self.aux_data.len()..len].copy_from_slice(self.aux_data);
+        Ok(len)
+    }
+}
+
+impl <'a> Emit for IgmpPacket<'a> {
+    fn emit_len(&self) -> usize {
+        match self.message {
+            IgmpMessage::MembershipQuery { v3: Some(ref v3), .. } => IGMP_V3_QUERY_LEN + 4 * v3.sources.len(),
+            IgmpMessage::V3MembershipReport { ref records } => IGMP_HDR_LEN + records.iter().map(|r| r.emit_len()).sum::<usize>(),
+            IgmpMessage::Other { data, .. } => 4 + data.len(),
+            _ => IGMP_HDR_LEN,
+        }
+    }
+
+    /// Write the message, `checksum` is replaced by the computed one
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        let len = self.emit_len();
+        if buf.len() < len {
+            return Err(ParseError::truncated(Layer::Igmp, 0, len, buf.len()));
+        }
+        for b in &mut buf[..len] {
+            *b = 0;
+        }
+        buf[0] = self.igmp_type.0;
+        match self.message {
+            IgmpMessage::MembershipQuery { max_resp_code, group, ref v3 } => {
+                buf[1] = max_resp_code;
+                buf[4..8].copy_from_slice(&group.octets());
+                if let Some(ref v3) = *v3 {
+                    if v3.robustness > 7 {
+                        return Err(ParseError::new(Layer::Igmp, 8, Reason::BadField("robustness")));
+                    }
+                    buf[8] = (v3.suppress as u8) << 3 | v3.robustness;
+                    buf[9] = v3.qqic;
+                    common::u16_write(&mut buf[10..], v3.sources.len() as u16);
+                    for (i, source) in v3.sources.iter().enumerate() {
+                        buf[12 + i * 4..16 + i * 4].copy_from_slice(&source.octets());
+                    }
+                }
+            }
+            IgmpMessage::V1MembershipReport { group } | IgmpMessage::V2MembershipReport { group }
+            | IgmpMessage::LeaveGroup { group } => buf[4..8].copy_from_slice(&group.octets()),
+            IgmpMessage::V3MembershipReport { ref records } => {
+                common::u16_write(&mut buf[6..], records.len() as u16);
+                let mut ptr = IGMP_HDR_LEN;
+                for record in records {
+                    ptr += record.emit(&mut buf[ptr..len]).map_err(|e| e.shift(ptr))?;
+                }
+            }
+            IgmpMessage::Other { code, data } => {
+                buf[1] = code;
+                buf[4..len].copy_from_slice(data);
+            }
+        }
+        let crc = common::checksum(&buf[..len]);
+        common::u16_write(&mut buf[2..], crc);
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Version 2 report for 239.1.1.1 sent with the Router Alert option
+    const FRAME_V2: [u8; 46] = [0x01, 0x00, 0x5e, 0x01, 0x01, 0x01, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x08, 0x00,
+        0x46, 0xc0, 0x00, 0x20, 0x00, 0x01, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x01, 0xef, 0x01, 0x01, 0x01,
+        0x94, 0x04, 0x00, 0x00,
+        0x16, 0x00, 0xf9, 0xfc, 0xef, 0x01, 0x01, 0x01];
+
+    /// Version 3 report: exclude nothing for 239.1.1.1, include 10.0.0.9 for 232.1.1.1
+    const REPORT_V3: [u8; 32] = [0x22, 0x00, 0xf5, 0xed, 0x00, 0x00, 0x00, 0x02,
+        0x04, 0x00, 0x00, 0x00, 0xef, 0x01, 0x01, 0x01,
+        0x01, 0x00, 0x00, 0x01, 0xe8, 0x01, 0x01, 0x01, 0x0a, 0x00, 0x00, 0x09,
+        0x00, 0x00, 0x00, 0x00];
+
+    #[test]
+    fn test_v2_report() {
+        let packet = ip::IpV4Packet::try_from(&FRAME_V2[..]).unwrap();
+        let res = IgmpPacket::try_from(&packet).unwrap();
+        assert_eq!(res, IgmpPacket {
+            igmp_type: IgmpTypes::V2MembershipReport,
+            checksum: 0xf9fc,
+            checksum_ok: Some(true),
+            router_alert: Some(0),
+            message: IgmpMessage::V2MembershipReport { group: Ipv4Addr::new(239, 1, 1, 1) },
+        });
+        assert_eq!(res.message.version(), Some(2));
+        let mut out = vec!();
+        assert_eq!(res.emit_vec(&mut out), Ok(8));
+        assert_eq!(&out[..], &FRAME_V2[38..]);
+
+        let mut buf = FRAME_V2;
+        buf[45] = 2;
+        let packet = ip::IpV4Packet::try_from(&buf[..]).unwrap();
+        assert_eq!(IgmpPacket::try_from(&packet).unwrap().checksum_ok, Some(false));
+        assert_eq!(IgmpPacket::from_ipv4(&packet, ChecksumCheck::Skip).unwrap().checksum_ok, None);
+        assert_eq!(IgmpPacket::from_ipv4(&packet, ChecksumCheck::Reject),
+                   Err(ParseError::new(Layer::Igmp, 26, Reason::BadChecksum { expected: 0xf9fb, actual: 0xf9fc })));
+        buf[23] = 17;
+        let packet = ip::IpV4Packet::try_from(&buf[..]).unwrap();
+        assert_eq!(IgmpPacket::try_from(&packet), Err(ParseError::new(Layer::Igmp, 9, Reason::WrongProtocol(17))));
+    }
+    #[test]
+    fn test_v3_report() {
+        let res = IgmpPacket::try_from(&REPORT_V3[..]).unwrap();
+        assert_eq!(res.checksum_ok, Some(true));
+        assert_eq!(res.message, IgmpMessage::V3MembershipReport { records: vec!(
+            GroupRecord { record_type: GroupRecordTypes::ChangeToExclude, group: Ipv4Addr::new(239, 1, 1, 1), sources: vec!(), aux_data: &[] },
+            GroupRecord { record_type: GroupRecordTypes::ModeIsInclude, group: Ipv4Addr::new(232, 1, 1, 1),
+                          sources: vec!(Ipv4Addr::new(10, 0, 0, 9)), aux_data: &[] },
+        )});
+        let mut out = vec!();
+        assert_eq!(res.emit_vec(&mut out), Ok(28));
+        assert_eq!(&out[..], &REPORT_V3[..28]);
+
+        let mut buf = REPORT_V3;
+        buf[17] = 1;
+        assert_eq!(IgmpPacket::try_from(&buf[..28]), Err(ParseError::truncated(Layer::Igmp, 16, 16, 12)));
+        let res = IgmpPacket::try_from(&buf[..]).unwrap();
+        assert_eq!(res.checksum_ok, Some(false));
+        let mut out = vec!();
+        assert_eq!(res.emit_vec(&mut out), Ok(32));
+        assert_eq!(&out[4..], &buf[4..]);
+    }
+    #[test]
+    fn test_queries() {
+        let general = [0x11u8, 0x64, 0xee, 0x9b, 0, 0, 0, 0];
+        let res = IgmpPacket::try_from(&general[..]).unwrap();
+        assert_eq!(res.checksum_ok, Some(true));
+        assert_eq!(res.message.version(), Some(2));
+
+        let query = IgmpPacket {
+            igmp_type: IgmpTypes::MembershipQuery,
+            checksum: 0,
+            checksum_ok: None,
+            router_alert: None,
+            message: IgmpMessage::MembershipQuery {
+                max_resp_code: 100,
+                group: Ipv4Addr::new(232, 1, 1, 1),
+                v3: Some(IgmpV3Query { suppress: true, robustness: 2, qqic: 125, sources: vec!(Ipv4Addr::new(10, 0, 0, 9)) }),
+            },
+        };
+        let mut out = vec!();
+        assert_eq!(query.emit_vec(&mut out), Ok(16));
+        assert_eq!(&out[8..12], &[0x0a, 125, 0, 1]);
+        let res = IgmpPacket::try_from(&out[..]).unwrap();
+        assert_eq!((res.checksum_ok, res.message.version()), (Some(true), Some(3)));
+        assert_eq!(res.message, query.message);
+        assert_eq!(IgmpPacket::try_from(&out[..10]), Err(ParseError::truncated(Layer::Igmp, 0, 12, 10)));
+        assert_eq!(IgmpPacket::try_from(&out[..14]), Err(ParseError::truncated(Layer::Igmp, 0, 16, 14)));
+    }
+}
@@ -1,11 +1,13 @@
-#![feature(try_from)]
 use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 extern crate common;
 extern crate ethernet;
 extern crate vlan;
 extern crate ethertype;
 
+use common::{Emit, Layer, ParseError, Reason};
+
 
 #[allow(non_snake_case)]
 pub mod Flags {
@@ -15,6 +17,17 @@ pub mod Flags {
     pub const MORE_FRAGMENTS: u8 = 0b001;
 }
 
+/// Flag values of the Timestamp option [RFC 791]
+#[allow(non_snake_case)]
+pub mod TimestampFlags {
+    /// Timestamps only
+    pub const TIMESTAMP_ONLY: u8 = 0;
+    /// Each timestamp preceded by the address of the recording host
+    pub const ADDRESS_AND_TIMESTAMP: u8 = 1;
+    /// Timestamps recorded by prespecified addresses only
+    pub const PRESPECIFIED: u8 = 3;
+}
+
 /// IPv4 header options numbers as defined in
 /// http://www.iana.org/assignments/ip-parameters/ip-parameters.xhtml
 #[allow(non_snake_case)]
@@ -481,10 +494,10 @@ pub mod IpNextHeaderProtocols {
     /// Performance Transparency Protocol
     pub const Ptp: IpNextHeaderProtocol = IpNextHeaderProtocol(123);
 
-    ///
+    /// ISIS over IPv4
     pub const IsisOverIpv4: IpNextHeaderProtocol = IpNextHeaderProtocol(124);
 
-    ///
+    /// FIRE
     pub const Fire: IpNextHeaderProtocol = IpNextHeaderProtocol(125);
 
     /// Combat Radio Transport Protocol
@@ -493,10 +506,10 @@ pub mod IpNextHeaderProtocols {
     /// Combat Radio User Datagram
     pub const Crudp: IpNextHeaderProtocol = IpNextHeaderProtocol(127);
 
-    ///
+    /// SSCOPMCE
     pub const Sscopmce: IpNextHeaderProtocol = IpNextHeaderProtocol(128);
 
-    ///
+    /// IPLT
     pub const Iplt: IpNextHeaderProtocol = IpNextHeaderProtocol(129);
 
     /// Secure Packet Shield
@@ -544,7 +557,7 @@ pub mod IpNextHeaderProtocols {
     /// Use for experimentation and testing [RFC3692]
     pub const Test2: IpNextHeaderProtocol = IpNextHeaderProtocol(254);
 
-    ///
+    /// Reserved
     pub const Reserved: IpNextHeaderProtocol = IpNextHeaderProtocol(255);
 
 }
@@ -570,15 +583,132 @@ impl Ipv4OptionNumber {
     pub fn new(value: u8) -> Ipv4OptionNumber { Ipv4OptionNumber(value) }
 }
 
+/// Option as found in the header, see `value` for the decoded content.
+/// End of Options List and No Operation are single bytes with `option_size` 1.
 #[derive(Debug,PartialEq)]
 pub struct IpOption <'a> {
     pub copy: bool,
     pub class: u8,
     pub option_num: Ipv4OptionNumber,
+    /// Length of the whole option, type and length bytes included
     pub option_size: u8,
     pub val: &'a[u8]
 }
 
+#[derive(Debug,PartialEq)]
+pub struct TimestampEntry {
+    /// Recording host, `None` with `TimestampFlags::TIMESTAMP_ONLY`
+    pub addr: Option<Ipv4Addr>,
+    /// Milliseconds since midnight UT unless the high-order bit is set
+    pub timestamp: u32,
+}
+
+/// Decoded content of an option
+#[derive(Debug,PartialEq)]
+pub enum Ipv4OptionValue <'a> {
+    EndOfList,
+    Nop,
+    /// `pointer` is the 1-based offset in the option of the next free slot
+    RecordRoute { pointer: u8, route: Vec<Ipv4Addr> },
+    LooseSourceRoute { pointer: u8, route: Vec<Ipv4Addr> },
+    StrictSourceRoute { pointer: u8, route: Vec<Ipv4Addr> },
+    /// `flag` is one of `TimestampFlags`, `entries` holds every slot, filled or not
+    Timestamp { pointer: u8, overflow: u8, flag: u8, entries: Vec<TimestampEntry> },
+    /// 0 asks routers to examine the packet [RFC 2113]
+    RouterAlert(u16),
+    /// Basic Security Option [RFC 1108]
+    Security { classification: u8, protection_authority: &'a [u8] },
+    /// Commercial IP Security Option, `tags` left encoded
+    Cipso { doi: u32, tags: &'a [u8] },
+    /// Quick-Start request or report, `nonce` is 30 bits [RFC 4782]
+    QuickStart { function: u8, rate: u8, ttl: u8, nonce: u32 },
+    Unknown(&'a [u8]),
+}
+
+impl <'a> IpOption<'a> {
+    /// End of Options List and No Operation carry neither length nor value
+    pub fn is_single_byte(&self) -> bool {
+        !self.copy && self.class == 0 && (self.option_num == Ipv4OptionNumbers::EOL || self.option_num == Ipv4OptionNumbers::NOP)
+    }
+
+    /// Decode the value. Offsets of errors count from the option type byte.
+    pub fn value(&self) -> Result<Ipv4OptionValue<'a>, ParseError> {
+        let val = self.val;
+        let bad = |offset, field| ParseError::new(Layer::IpV4, offset, Reason::BadField(field));
+        let route = || -> Result<(u8, Vec<Ipv4Addr>), ParseError> {
+            if val.is_empty() || !(val.len() - 1).is_multiple_of(4) {
+                return Err(bad(1, "option_size"));
+            }
+            Ok((val[0], val[1..].chunks(4).map(to_ipv4).collect()))
+        };
+        Ok(match self.option_num {
+            Ipv4OptionNumbers::EOL if self.is_single_byte() => Ipv4OptionValue::EndOfList,
+            Ipv4OptionNumbers::NOP if self.is_single_byte() => Ipv4OptionValue::Nop,
+            Ipv4OptionNumbers::RR => {
+                let (pointer, route) = route()?;
+                Ipv4OptionValue::RecordRoute { pointer, route }
+            }
+            Ipv4OptionNumbers::LSR => {
+                let (pointer, route) = route()?;
+                Ipv4OptionValue::LooseSourceRoute { pointer, route }
+            }
+            Ipv4OptionNumbers::SSR => {
+                let (pointer, route) = route()?;
+                Ipv4OptionValue::StrictSourceRoute { pointer, route }
+            }
+            Ipv4OptionNumbers::TS => {
+                if val.len() < 2 {
+                    return Err(bad(1, "option_size"));
+                }
+                let flag = val[1] & 0xf;
+                let size = match flag {
+                    TimestampFlags::TIMESTAMP_ONLY => 4,
+                    TimestampFlags::ADDRESS_AND_TIMESTAMP | TimestampFlags::PRESPECIFIED => 8,
+                    _ => return Err(bad(3, "flag")),
+                };
+                if !(val.len() - 2).is_multiple_of(size) {
+                    return Err(bad(1, "option_size"));
+                }
+                let entries = val[2..].chunks(size).map(|e| match size {
+                    4 => TimestampEntry { addr: None, timestamp: common::u32_parse(e) },
+                    _ => TimestampEntry { addr: Some(to_ipv4(&e[..4])), timestamp: common::u32_parse(&e[4..]) },
+                }).collect();
+                Ipv4OptionValue::Timestamp { pointer: val[0], overflow: val[1] >> 4, flag, entries }
+            }
+            Ipv4OptionNumbers::RTRALT => {
+                if val.len() != 2 {
+                    return Err(bad(1, "option_size"));
+                }
+                Ipv4OptionValue::RouterAlert(common::u16_parse(val))
+            }
+            Ipv4OptionNumbers::SEC => {
+                if val.is_empty() {
+                    return Err(bad(1, "option_size"));
+                }
+                Ipv4OptionValue::Security { classification: val[0], protection_authority: &val[1..] }
+            }
+            Ipv4OptionNumbers::CIPSO => {
+                if val.len() < 4 {
+                    return Err(bad(1, "option_size"));
+                }
+                Ipv4OptionValue::Cipso { doi: common::u32_parse(val), tags: &val[4..] }
+            }
+            Ipv4OptionNumbers::QS => {
+                if val.len() != 6 {
+                    return Err(bad(1, "option_size"));
+                }
+                Ipv4OptionValue::QuickStart {
+                    function: val[0] >> 4,
+                    rate: val[0] & 0xf,
+                    ttl: val[1],
+                    nonce: common::u32_parse(&val[2..]) >> 2,
+                }
+            }
+            _ => Ipv4OptionValue::Unknown(val),
+        })
+    }
+}
+
 #[derive(Debug,PartialEq)]
 pub struct IpV4Header <'a>{
     pub version: u8,
@@ -592,24 +722,54 @@ pub struct IpV4Header <'a>{
     pub ttl: u8,
     pub protocol: IpNextHeaderProtocol,
     pub crc: u16,
-    pub src_ip: &'a[u8],
-    pub dst_ip: &'a[u8],
+    /// Result of the checksum verification, `None` when it was not requested.
+    /// Compared by `PartialEq` like the other fields: the same header parsed
+    /// with `ChecksumCheck::Skip` and `ChecksumCheck::Flag` is not equal.
+    pub checksum_ok: Option<bool>,
+    pub src_ip: &'a[u8; 4],
+    pub dst_ip: &'a[u8; 4],
     pub options: Option<Vec<IpOption<'a>>>
 }
 
 #[derive(Debug,PartialEq)]
 pub struct IpV4Packet <'a> {
-    pub eth_header: ethernet::EthHeader<'a>,
+    pub eth_header: ethernet::EthHeader,
     pub vlans : Option<Vec<vlan::Vlan>>,
     pub ip_hdr : IpV4Header<'a>,
+    /// Bytes after the IPv4 header up to `packet_length`, Ethernet padding excluded.
+    /// Shorter than announced when the frame was captured truncated, see `missing_bytes`.
+    /// A zero `packet_length`, as in captures of TSO/GRO packets, takes the whole captured rest.
+    pub payload: &'a [u8],
+}
+
+impl <'a> IpV4Packet<'a> {
+    /// Number of payload bytes announced by `packet_length` but absent from the buffer
+    pub fn missing_bytes(&self) -> usize {
+        if self.ip_hdr.packet_length == 0 {
+            return 0;
+        }
+        let announced = (self.ip_hdr.packet_length as usize).saturating_sub(self.ip_hdr.hdr_length as usize * 4);
+        announced.saturating_sub(self.payload.len())
+    }
+
+    /// Payload, or an error when the capture does not hold all of it
+    pub fn complete_payload(&self) -> Result<&'a [u8], ParseError> {
+        match self.missing_bytes() {
+            0 => Ok(self.payload),
+            missing => {
+                let start = self.ip_hdr.hdr_length as usize * 4;
+                Err(ParseError::truncated(Layer::IpV4, start, self.payload.len() + missing, self.payload.len()))
+            }
+        }
+    }
 }
 
 
-fn parse_options<'a>(buf: &'a[u8]) -> Result<Option<Vec<IpOption<'a>>>, ()> {
+fn parse_options(buf: &[u8]) -> Result<Option<Vec<IpOption<'_>>>, ParseError> {
     let len = buf.len();
     let check = len % 4;
     if check != 0 {
-        return Err(());
+        return Err(ParseError::new(Layer::IpV4, 0, Reason::TruncatedOptions));
     }
 
     let mut result: Vec<IpOption> = vec!();
@@ -617,41 +777,147 @@ fn parse_options<'a>(buf: &'a[u8]) -> Result<Option<Vec<IpOption<'a>>>, ()> {
     let mut offset = 0;
     while offset < len {
         let copy = buf[offset] >> 7;
-        let class = buf[offset] >> 5 & !(1 << 2);
-        let option_num = Ipv4OptionNumber(buf[offset] & !!(7 << 5));
+        let class = buf[offset] >> 5 & 0x3;
+        let option_num = Ipv4OptionNumber(buf[offset] & 0x1f);
 
-        if option_num == Ipv4OptionNumbers::EOL {
+        if buf[offset] == Ipv4OptionNumbers::EOL.0 {
             break;
         }
+        if buf[offset] == Ipv4OptionNumbers::NOP.0 {
+            result.push(IpOption{copy: false, class, option_num, option_size: 1, val: &[]});
+            offset += 1;
+            continue;
+        }
 
-        offset += 1;
-        let option_size = buf[offset];
-        offset += 1;
-        let val = &buf[offset..offset + option_size as usize];
+        if offset + 2 > len {
+            return Err(ParseError::new(Layer::IpV4, offset, Reason::TruncatedOptions));
+        }
+        let option_size = buf[offset + 1];
+        if option_size < 2 {
+            return Err(ParseError::new(Layer::IpV4, offset + 1, Reason::BadField("option_size")));
+        }
+        let end = offset + option_size as usize;
+        if end > len {
+            return Err(ParseError::new(Layer::IpV4, offset, Reason::TruncatedOptions));
+        }
+        let val = &buf[offset + 2..end];
         result.push(IpOption{copy: copy!=0, class, option_num, option_size, val});
-        offset += option_size  as usize;
+        offset = end;
     }
 
-    if result.len() > 0 {
+    if !result.is_empty() {
         Ok(Some(result))
     } else {
         Ok(None)
     }
 }
 
-impl <'a>TryFrom<&'a [u8]> for IpV4Header<'a> {
-    type Error = ();
+fn to_ipv4(buf: &[u8]) -> Ipv4Addr {
+    let mut a = [0u8; 4];
+    a.copy_from_slice(buf);
+    Ipv4Addr::from(a)
+}
 
-    fn try_from(buf: &'a [u8]) -> Result<IpV4Header, Self::Error> {
+/// Scope of an IPv4 or IPv6 address, for use in filters
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AddrClass {
+    /// 0.0.0.0, ::
+    Unspecified,
+    /// 127.0.0.0/8, ::1
+    Loopback,
+    /// 255.255.255.255
+    Broadcast,
+    /// 224.0.0.0/4, ff00::/8
+    Multicast,
+    /// 169.254.0.0/16, fe80::/10
+    LinkLocal,
+    /// RFC 1918 ranges, fc00::/7 unique local
+    Private,
+    /// Anything else
+    Global,
+}
+
+impl AddrClass {
+    pub fn of(addr: IpAddr) -> AddrClass {
+        match addr {
+            IpAddr::V4(a) => AddrClass::of_v4(a),
+            IpAddr::V6(a) => AddrClass::of_v6(a),
+        }
+    }
+
+    pub fn of_v4(addr: Ipv4Addr) -> AddrClass {
+        if addr.is_unspecified() {
+            AddrClass::Unspecified
+        } else if addr.is_loopback() {
+            AddrClass::Loopback
+        } else if addr.is_broadcast() {
+            AddrClass::Broadcast
+        } else if addr.is_multicast() {
+            AddrClass::Multicast
+        } else if addr.is_link_local() {
+            AddrClass::LinkLocal
+        } else if addr.is_private() {
+            AddrClass::Private
+        } else {
+            AddrClass::Global
+        }
+    }
+
+    /// IPv4-mapped addresses (::ffff:a.b.c.d) are classified as their IPv4 address
+    pub fn of_v6(addr: Ipv6Addr) -> AddrClass {
+        let seg = addr.segments();
+        if seg[..5] == [0; 5] && seg[5] == 0xffff {
+            let o = addr.octets();
+            return AddrClass::of_v4(Ipv4Addr::new(o[12], o[13], o[14], o[15]));
+        }
+        if addr.is_unspecified() {
+            AddrClass::Unspecified
+        } else if addr.is_loopback() {
+            AddrClass::Loopback
+        } else if addr.is_multicast() {
+            AddrClass::Multicast
+        } else if seg[0] & 0xffc0 == 0xfe80 {
+            AddrClass::LinkLocal
+        } else if seg[0] & 0xfe00 == 0xfc00 {
+            AddrClass::Private
+        } else {
+            AddrClass::Global
+        }
+    }
+}
+
+/// How `IpV4Header::parse` and `IpV4Packet::parse` treat the header checksum
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ChecksumCheck {
+    /// Do not verify, `checksum_ok` is `None`
+    Skip,
+    /// Verify and store the result in `checksum_ok`
+    Flag,
+    /// Verify and fail with `Reason::BadChecksum`
+    Reject,
+}
+
+/// Compute the checksum of the IPv4 header (options included) at the start of `buf`,
+/// the stored checksum field is taken as zero
+pub fn header_checksum(buf: &[u8]) -> Result<u16, ParseError> {
+    let len = IpV4Header::try_from(buf)?.hdr_length as usize * 4;
+    let stored = common::u16_parse(&buf[10..12]);
+    Ok(common::checksum_update(common::checksum(&buf[..len]), stored, 0))
+}
+
+impl <'a> IpV4Header<'a> {
+    /// Parse the header at the start of `buf`, verifying the checksum as `check` tells
+    pub fn parse(buf: &'a [u8], check: ChecksumCheck) -> Result<IpV4Header<'a>, ParseError> {
         if buf.len()>=20 {
             let version = buf[0] >> 4;
+            if version != 4 {
+                return Err(ParseError::new(Layer::IpV4, 0, Reason::BadVersion(version)));
+            }
             let hdr_length = buf[0] & 0xF;
-            if hdr_length < 5 || hdr_length > 15 {
-                return Err(());
-            } else {
-                if hdr_length as usize * 4 > buf.len() {
-                    return Err(());
-                }
+            if !(5..=15).contains(&hdr_length) {
+                return Err(ParseError::new(Layer::IpV4, 0, Reason::BadHeaderLength(hdr_length)));
+            } else if hdr_length as usize * 4 > buf.len() {
+                return Err(ParseError::truncated(Layer::IpV4, 0, hdr_length as usize * 4, buf.len()));
             }
             let dscp = buf[1] >> 2;
             let ecn = buf[1] & 0x3;
@@ -662,6 +928,20 @@ impl <'a>TryFrom<&'a [u8]> for IpV4Header<'a> {
             let ttl = buf[8];
             let protocol = IpNextHeaderProtocol(buf[9]);
             let crc = common::u16_parse(&buf[10..=11]);
+            let (src_ip, dst_ip) = match (buf[12..].first_chunk(), buf[16..].first_chunk()) {
+                (Some(src_ip), Some(dst_ip)) => (src_ip, dst_ip),
+                _ => return Err(ParseError::truncated(Layer::IpV4, 0, 20, buf.len())),
+            };
+            let checksum_ok = match check {
+                ChecksumCheck::Skip => None,
+                _ => {
+                    let expected = common::checksum_update(common::checksum(&buf[..hdr_length as usize * 4]), crc, 0);
+                    if check == ChecksumCheck::Reject && expected != crc {
+                        return Err(ParseError::new(Layer::IpV4, 10, Reason::BadChecksum { expected, actual: crc }));
+                    }
+                    Some(expected == crc)
+                }
+            };
 
             Ok(IpV4Header {
                 version,
@@ -675,50 +955,327 @@ impl <'a>TryFrom<&'a [u8]> for IpV4Header<'a> {
                 ttl,
                 protocol,
                 crc,
-                src_ip: &buf[12..=15],
-                dst_ip: &buf[16..=19],
+                checksum_ok,
+                src_ip,
+                dst_ip,
                 options: match hdr_length > 5 {
-                    true => match parse_options(&buf[20..hdr_length as usize * 4]) {
-                        Ok(x) => x,
-                        _ => {
-                            return Err(())
-                        }
-                    }
+                    true => parse_options(&buf[20..hdr_length as usize * 4]).map_err(|e| e.shift(20))?,
                     _ => None
                 },
             })
         } else {
-            Err(())
+            Err(ParseError::truncated(Layer::IpV4, 0, 20, buf.len()))
         }
     }
+
+    pub fn src_addr(&self) -> Ipv4Addr {
+        Ipv4Addr::from(*self.src_ip)
+    }
+
+    pub fn dst_addr(&self) -> Ipv4Addr {
+        Ipv4Addr::from(*self.dst_ip)
+    }
+
+    /// Pseudo-header of the upper-layer checksum (RFC 768) for `length` bytes of upper-layer data
+    pub fn pseudo_header(&self, length: u16) -> [u8; 12] {
+        let mut buf = [0u8; 12];
+        buf[0..4].copy_from_slice(self.src_ip);
+        buf[4..8].copy_from_slice(self.dst_ip);
+        buf[9] = self.protocol.0;
+        common::u16_write(&mut buf[10..], length);
+        buf
+    }
+
+    /// Value of the Router Alert option [RFC 2113], 0 asking routers to examine the packet.
+    /// `None` when the option is absent or malformed.
+    pub fn router_alert(&self) -> Option<u16> {
+        self.options.as_ref()?.iter().filter_map(|o| match o.value() {
+            Ok(Ipv4OptionValue::RouterAlert(value)) => Some(value),
+            _ => None,
+        }).next()
+    }
+
+    /// Checksum of the header as `emit` would write it
+    pub fn compute_checksum(&self) -> Result<u16, ParseError> {
+        let mut buf = [0u8; 60];
+        self.emit(&mut buf)?;
+        Ok(common::u16_parse(&buf[10..12]))
+    }
 }
 
-impl <'a>TryFrom<&'a [u8]> for IpV4Packet<'a> {
-    type Error = ();
+impl <'a>TryFrom<&'a [u8]> for IpV4Header<'a> {
+    type Error = ParseError;
 
-    fn try_from(buf: &'a [u8]) -> Result<IpV4Packet, Self::Error> {
+    fn try_from(buf: &'a [u8]) -> Result<IpV4Header<'a>, Self::Error> {
+        IpV4Header::parse(buf, ChecksumCheck::Skip)
+    }
+}
+
+impl <'a> IpV4Packet<'a> {
+    /// Parse a frame starting with the Ethernet header, verifying the IPv4 checksum as `check` tells
+    pub fn parse(buf: &'a [u8], check: ChecksumCheck) -> Result<IpV4Packet<'a>, ParseError> {
         let eth_end = 12;
 
-        let (v, ptr) = vlan::parse_vlans(&buf[eth_end..buf.len()]).unwrap();
+        let eth_header = ethernet::EthHeader::try_from(buf)?;
+
+        let (v, ptr) = vlan::parse_vlans(&buf[eth_end..buf.len()]).map_err(|e| e.shift(eth_end))?;
 
-        let ethtype = ethertype::parse_ethtype(&buf[eth_end + ptr..eth_end + ptr + 2]);
+        let ethtype = ethertype::parse_ethtype(&buf[eth_end + ptr..buf.len()]).map_err(|e| e.shift(eth_end + ptr))?;
         if ethtype == ethertype::EtherTypes::Ipv4 {
-            Ok(IpV4Packet { eth_header: ethernet::EthHeader::try_from(buf)?, vlans: v, ip_hdr: IpV4Header::try_from(&buf[eth_end + ptr + 2..buf.len()])?})
+            let ip_start = eth_end + ptr + 2;
+            IpV4Packet::from_parts(eth_header, v, &buf[ip_start..buf.len()], check).map_err(|e| e.shift(ip_start))
         } else {
-            Err(())
+            Err(ParseError::new(Layer::IpV4, eth_end + ptr, Reason::WrongEtherType(ethtype.0)))
         }
     }
+
+    /// Parse the IPv4 header at the start of `buf` that follows the already parsed
+    /// Ethernet header and tags, verifying the checksum as `check` tells
+    pub fn from_parts(eth_header: ethernet::EthHeader, vlans: Option<Vec<vlan::Vlan>>, buf: &'a [u8], check: ChecksumCheck) -> Result<IpV4Packet<'a>, ParseError> {
+        let ip_hdr = IpV4Header::parse(buf, check)?;
+        let start = ip_hdr.hdr_length as usize * 4;
+        let end = match ip_hdr.packet_length {
+            0 => buf.len(),
+            len => len as usize,
+        };
+        if end < start {
+            return Err(ParseError::new(Layer::IpV4, 2, Reason::BadField("packet_length")));
+        }
+        let payload = &buf[start..end.min(buf.len())];
+        Ok(IpV4Packet{eth_header, vlans, ip_hdr, payload})
+    }
 }
 
-impl <'a> TryFrom<(ethernet::EthHeader<'a>, Option<Vec<vlan::Vlan>>, &'a [u8])> for IpV4Packet<'a> {
-    type Error = ();
-    fn try_from(src: (ethernet::EthHeader<'a>, Option<Vec<vlan::Vlan>>, &'a [u8])) -> Result<IpV4Packet, Self::Error> {
-        let (eth_header, vlans, payload) = src;
-        Ok(IpV4Packet{eth_header, vlans, ip_hdr: IpV4Header::try_from(&payload[..])?})
+impl <'a>TryFrom<&'a [u8]> for IpV4Packet<'a> {
+    type Error = ParseError;
+
+    fn try_from(buf: &'a [u8]) -> Result<IpV4Packet<'a>, Self::Error> {
+        IpV4Packet::parse(buf, ChecksumCheck::Skip)
+    }
+}
+
+impl <'a> TryFrom<(ethernet::EthHeader, Option<Vec<vlan::Vlan>>, &'a [u8])> for IpV4Packet<'a> {
+    type Error = ParseError;
+    fn try_from(src: (ethernet::EthHeader, Option<Vec<vlan::Vlan>>, &'a [u8])) -> Result<IpV4Packet<'a>, Self::Error> {
+        let (eth_header, vlans, buf) = src;
+        IpV4Packet::from_parts(eth_header, vlans, buf, ChecksumCheck::Skip)
+    }
+}
+
+impl <'a> Emit for IpOption<'a> {
+    fn emit_len(&self) -> usize {
+        match self.is_single_byte() {
+            true => 1,
+            false => 2 + self.val.len(),
+        }
+    }
+
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        if self.class > 3 {
+            return Err(ParseError::new(Layer::IpV4, 0, Reason::BadField("class")));
+        }
+        if self.option_num.0 > 0x1f {
+            return Err(ParseError::new(Layer::IpV4, 0, Reason::BadField("option_num")));
+        }
+        if self.option_size as usize != self.emit_len() {
+            return Err(ParseError::new(Layer::IpV4, 1, Reason::BadField("option_size")));
+        }
+        if buf.len() < self.emit_len() {
+            return Err(ParseError::new(Layer::IpV4, 0, Reason::TruncatedOptions));
+        }
+        buf[0] = (self.copy as u8) << 7 | self.class << 5 | self.option_num.0;
+        if !self.is_single_byte() {
+            buf[1] = self.option_size;
+            buf[2..self.emit_len()].copy_from_slice(self.val);
+        }
+        Ok(self.emit_len())
+    }
+}
+
+impl <'a> Emit for IpV4Header<'a> {
+    fn emit_len(&self) -> usize {
+        self.hdr_length as usize * 4
+    }
+
+    /// Write the header, options are padded with End of Options List up to `hdr_length`
+    /// and `crc` is replaced by the computed checksum
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        let len = self.emit_len();
+        if !(5..=15).contains(&self.hdr_length) {
+            return Err(ParseError::new(Layer::IpV4, 0, Reason::BadHeaderLength(self.hdr_length)));
+        }
+        if self.version > 0xf {
+            return Err(ParseError::new(Layer::IpV4, 0, Reason::BadVersion(self.version)));
+        }
+        if self.dscp > 0x3f || self.ecn > 3 {
+            return Err(ParseError::new(Layer::IpV4, 1, Reason::BadField("dscp/ecn")));
+        }
+        if self.flags > 7 || self.offset > 0x1fff {
+            return Err(ParseError::new(Layer::IpV4, 6, Reason::BadField("flags/offset")));
+        }
+        if buf.len() < len {
+            return Err(ParseError::truncated(Layer::IpV4, 0, len, buf.len()));
+        }
+
+        buf[0] = self.version << 4 | self.hdr_length;
+        buf[1] = self.dscp << 2 | self.ecn;
+        common::u16_write(&mut buf[2..], self.packet_length);
+        common::u16_write(&mut buf[4..], self.id);
+        common::u16_write(&mut buf[6..], (self.flags as u16) << 13 | self.offset);
+        buf[8] = self.ttl;
+        buf[9] = self.protocol.0;
+        common::u16_write(&mut buf[10..], 0);
+        buf[12..16].copy_from_slice(self.src_ip);
+        buf[16..20].copy_from_slice(self.dst_ip);
+
+        let mut ptr = 20;
+        if let Some(ref options) = self.options {
+            for option in options {
+                ptr += option.emit(&mut buf[ptr..len]).map_err(|e| e.shift(ptr))?;
+            }
+        }
+        for b in &mut buf[ptr..len] {
+            *b = Ipv4OptionNumbers::EOL.0;
+        }
+
+        let crc = common::checksum(&buf[..len]);
+        common::u16_write(&mut buf[10..], crc);
+        Ok(len)
+    }
+}
+
+impl <'a> Emit for IpV4Packet<'a> {
+    fn emit_len(&self) -> usize {
+        let vlans = self.vlans.as_ref().map_or(0, |v| v.len());
+        12 + 4 * vlans + 2 + self.ip_hdr.emit_len() + self.payload.len()
+    }
+
+    /// Write the Ethernet header, tags, EtherType, the IPv4 header and the payload
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        let mut ptr = self.eth_header.emit(buf)?;
+        if let Some(ref vlans) = self.vlans {
+            ptr += vlan::emit_vlans(vlans, &mut buf[ptr..]).map_err(|e| e.shift(ptr))?;
+        }
+        ptr += ethertype::EtherTypes::Ipv4.emit(&mut buf[ptr..]).map_err(|e| e.shift(ptr))?;
+        ptr += self.ip_hdr.emit(&mut buf[ptr..]).map_err(|e| e.shift(ptr))?;
+        if buf.len() < ptr + self.payload.len() {
+            return Err(ParseError::truncated(Layer::IpV4, ptr, self.payload.len(), buf.len() - ptr));
+        }
+        buf[ptr..ptr + self.payload.len()].copy_from_slice(self.payload);
+        Ok(ptr + self.payload.len())
+    }
+}
+
+/// Mutable view of an IPv4 header inside a received frame.
+///
+/// Every setter updates the header checksum incrementally (RFC 1624).
+/// A header received with a bad checksum keeps a bad one, call
+/// `recompute_checksum` to fix it.
+#[derive(Debug)]
+pub struct IpV4HeaderMut <'a> {
+    buf: &'a mut [u8]
+}
+
+impl <'a> TryFrom<&'a mut [u8]> for IpV4HeaderMut<'a> {
+    type Error = ParseError;
+    fn try_from(buf: &'a mut [u8]) -> Result<IpV4HeaderMut<'a>, ParseError> {
+        IpV4Header::try_from(&buf[..])?;
+        Ok(IpV4HeaderMut { buf })
+    }
+}
+
+impl <'a> IpV4HeaderMut<'a> {
+    /// Read-only view of the current header, fails if a setter left
+    /// the header inconsistent (e.g. a too short packet length)
+    pub fn header(&self) -> Result<IpV4Header<'_>, ParseError> {
+        IpV4Header::try_from(&self.buf[..])
+    }
+
+    /// Recompute the checksum from scratch, fixes a header received with a bad one
+    pub fn recompute_checksum(&mut self) {
+        let len = (self.buf[0] & 0xf) as usize * 4;
+        common::u16_write(&mut self.buf[10..], 0);
+        let crc = common::checksum(&self.buf[..len]);
+        common::u16_write(&mut self.buf[10..], crc);
+    }
+
+    /// Replace the 16-bit word at `offset` and update the checksum incrementally
+    fn set_word(&mut self, offset: usize, val: u16) {
+        let old = common::u16_parse(&self.buf[offset..]);
+        let crc = common::checksum_update(common::u16_parse(&self.buf[10..]), old, val);
+        common::u16_write(&mut self.buf[offset..], val);
+        common::u16_write(&mut self.buf[10..], crc);
+    }
+
+    pub fn set_dscp(&mut self, dscp: u8) -> Result<(), ParseError> {
+        if dscp > 0x3f {
+            return Err(ParseError::new(Layer::IpV4, 1, Reason::BadField("dscp")));
+        }
+        let word = (dscp as u16) << 2 | common::u16_parse(&self.buf[0..]) & 0xff03;
+        self.set_word(0, word);
+        Ok(())
+    }
+
+    pub fn set_ecn(&mut self, ecn: u8) -> Result<(), ParseError> {
+        if ecn > 3 {
+            return Err(ParseError::new(Layer::IpV4, 1, Reason::BadField("ecn")));
+        }
+        let word = common::u16_parse(&self.buf[0..]) & !0x3 | ecn as u16;
+        self.set_word(0, word);
+        Ok(())
+    }
+
+    pub fn set_packet_length(&mut self, packet_length: u16) {
+        self.set_word(2, packet_length);
+    }
+
+    pub fn set_id(&mut self, id: u16) {
+        self.set_word(4, id);
+    }
+
+    pub fn set_flags(&mut self, flags: u8) -> Result<(), ParseError> {
+        if flags > 7 {
+            return Err(ParseError::new(Layer::IpV4, 6, Reason::BadField("flags")));
+        }
+        let word = (flags as u16) << 13 | common::u16_parse(&self.buf[6..]) & 0x1fff;
+        self.set_word(6, word);
+        Ok(())
+    }
+
+    pub fn set_offset(&mut self, offset: u16) -> Result<(), ParseError> {
+        if offset > 0x1fff {
+            return Err(ParseError::new(Layer::IpV4, 6, Reason::BadField("offset")));
+        }
+        let word = common::u16_parse(&self.buf[6..]) & 0xe000 | offset;
+        self.set_word(6, word);
+        Ok(())
+    }
+
+    pub fn set_ttl(&mut self, ttl: u8) {
+        let word = (ttl as u16) << 8 | self.buf[9] as u16;
+        self.set_word(8, word);
+    }
+
+    pub fn set_protocol(&mut self, protocol: IpNextHeaderProtocol) {
+        let word = (self.buf[8] as u16) << 8 | protocol.0 as u16;
+        self.set_word(8, word);
+    }
+
+    pub fn set_src_ip(&mut self, ip: Ipv4Addr) {
+        let ip = ip.octets();
+        self.set_word(12, (ip[0] as u16) << 8 | ip[1] as u16);
+        self.set_word(14, (ip[2] as u16) << 8 | ip[3] as u16);
+    }
+
+    pub fn set_dst_ip(&mut self, ip: Ipv4Addr) {
+        let ip = ip.octets();
+        self.set_word(16, (ip[0] as u16) << 8 | ip[1] as u16);
+        self.set_word(18, (ip[2] as u16) << 8 | ip[3] as u16);
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::unnecessary_cast)]
 mod tests {
     use super::*;
     #[test]
@@ -737,6 +1294,7 @@ mod tests {
             ttl: 62,
             protocol: IpNextHeaderProtocol(6),
             crc: 0xce9f,
+            checksum_ok: None,
             src_ip: &[0xac as u8, 0x10,0x00,0x53],
             dst_ip: &[0xc0 as u8, 0xa8,0x16,0x47],
             options: None
@@ -746,18 +1304,246 @@ mod tests {
     fn test_broken() {
         let buf = [0x45 as u8,0x00,0x05,0xbf,0xe5,0x46,0x40,0x00,0x3e,0x06,0xce,0x9f,0xac,0x10,0x00,0x53,0xc0,0xa8];
         let eth = IpV4Header::try_from(&buf[..]);
-        assert!(eth == Err(()));
+        assert!(eth == Err(ParseError::truncated(Layer::IpV4, 0, 20, 18)));
     }
     #[test]
     fn test_broken_hdr_len() {
         let buf = [0x46 as u8,0x00,0x05,0xbf,0xe5,0x46,0x40,0x00,0x3e,0x06,0xce,0x9f,0xac,0x10,0x00,0x53,0xc0,0xa8,0x16,0x47];
         let eth = IpV4Header::try_from(&buf[..]);
-        assert!(eth == Err(()));
+        assert!(eth == Err(ParseError::truncated(Layer::IpV4, 0, 24, 20)));
+    }
+    #[test]
+    fn test_small_hdr_len() {
+        let buf = [0x44u8,0x00,0x05,0xbf,0xe5,0x46,0x40,0x00,0x3e,0x06,0xce,0x9f,0xac,0x10,0x00,0x53,0xc0,0xa8,0x16,0x47];
+        let eth = IpV4Header::try_from(&buf[..]);
+        assert!(eth == Err(ParseError::new(Layer::IpV4, 0, Reason::BadHeaderLength(4))));
+    }
+    #[test]
+    fn test_bad_version() {
+        let buf = [0x65u8,0x00,0x05,0xbf,0xe5,0x46,0x40,0x00,0x3e,0x06,0xce,0x9f,0xac,0x10,0x00,0x53,0xc0,0xa8,0x16,0x47];
+        let eth = IpV4Header::try_from(&buf[..]);
+        assert!(eth == Err(ParseError::new(Layer::IpV4, 0, Reason::BadVersion(6))));
     }
     #[test]
     fn test_broken_options() {
         let buf = [0x46 as u8,0x00,0x05,0xbf,0xe5,0x46,0x40,0x00,0x3e,0x06,0xce,0x9f,0xac,0x10,0x00,0x53,0xc0,0xa8,0x16,0x47,0x22];
         let eth = IpV4Header::try_from(&buf[..]);
-        assert!(eth == Err(()));
+        assert!(eth == Err(ParseError::truncated(Layer::IpV4, 0, 24, 21)));
+    }
+    #[test]
+    fn test_truncated_options() {
+        let buf = [0x46u8,0x00,0x05,0xbf,0xe5,0x46,0x40,0x00,0x3e,0x06,0xce,0x9f,0xac,0x10,0x00,0x53,0xc0,0xa8,0x16,0x47,0x94,0x09,0x00,0x00];
+        let eth = IpV4Header::try_from(&buf[..]);
+        assert!(eth == Err(ParseError::new(Layer::IpV4, 20, Reason::TruncatedOptions)));
+    }
+    #[test]
+    fn test_router_alert() {
+        let mut buf = [0x46u8,0x00,0x00,0x20,0x00,0x01,0x00,0x00,0x01,0x02,0x00,0x00,0x0a,0x00,0x00,0x01,0xe0,0x00,0x00,0x16,0x94,0x04,0x00,0x00];
+        let hdr = IpV4Header::try_from(&buf[..]).unwrap();
+        assert_eq!(hdr.options, Some(vec!(IpOption{copy: true, class: 0, option_num: Ipv4OptionNumbers::RTRALT, option_size: 4, val: &[0, 0]})));
+        assert_eq!(hdr.router_alert(), Some(0));
+        buf[21] = 1;
+        assert_eq!(IpV4Header::try_from(&buf[..]), Err(ParseError::new(Layer::IpV4, 21, Reason::BadField("option_size"))));
+        buf[20..24].copy_from_slice(&[0x83, 0x03, 0x04, 0x00]);
+        assert_eq!(IpV4Header::try_from(&buf[..]).unwrap().router_alert(), None);
+    }
+    #[test]
+    fn test_typed_options() {
+        let mut buf = vec!(0x4du8,0x00,0x00,0x34,0x00,0x01,0x00,0x00,0x40,0x11,0x00,0x00,0x0a,0x00,0x00,0x01,0x0a,0x00,0x00,0x02,
+            0x01,
+            0x07,0x07,0x04,0x00,0x00,0x00,0x00,
+            0x44,0x0c,0x0d,0x11,0xc0,0xa8,0x01,0x01,0x00,0x00,0x00,0x64,
+            0x94,0x04,0x00,0x00,
+            0x19,0x08,0x05,0x40,0x00,0x00,0x00,0x04);
+        let crc = header_checksum(&buf).unwrap();
+        common::u16_write(&mut buf[10..], crc);
+        let hdr = IpV4Header::parse(&buf, ChecksumCheck::Reject).unwrap();
+        let values: Vec<_> = hdr.options.as_ref().unwrap().iter().map(|o| o.value().unwrap()).collect();
+        assert_eq!(values, vec!(
+            Ipv4OptionValue::Nop,
+            Ipv4OptionValue::RecordRoute { pointer: 4, route: vec!(Ipv4Addr::new(0, 0, 0, 0)) },
+            Ipv4OptionValue::Timestamp { pointer: 13, overflow: 1, flag: TimestampFlags::ADDRESS_AND_TIMESTAMP,
+                                         entries: vec!(TimestampEntry { addr: Some(Ipv4Addr::new(192, 168, 1, 1)), timestamp: 100 }) },
+            Ipv4OptionValue::RouterAlert(0),
+            Ipv4OptionValue::QuickStart { function: 0, rate: 5, ttl: 0x40, nonce: 1 },
+        ));
+        assert_eq!(hdr.router_alert(), Some(0));
+        let mut out = vec!();
+        assert_eq!(hdr.emit_vec(&mut out), Ok(52));
+        assert_eq!(out, buf);
+
+        buf[40..44].copy_from_slice(&[0x01, 0x01, 0x01, 0x01]);
+        let hdr = IpV4Header::try_from(&buf[..]).unwrap();
+        assert_eq!(hdr.options.as_ref().map(|o| o.len()), Some(8));
+        assert_eq!(hdr.router_alert(), None);
+        buf[28..30].copy_from_slice(&[0x44, 0x0b]);
+        buf[39] = 0x00;
+        let hdr = IpV4Header::try_from(&buf[..]).unwrap();
+        assert_eq!(hdr.options.as_ref().unwrap()[2].value(), Err(ParseError::new(Layer::IpV4, 1, Reason::BadField("option_size"))));
+    }
+    #[test]
+    fn test_option_values() {
+        let option = |num, val: &'static [u8]| IpOption{copy: true, class: 0, option_num: num, option_size: 2 + val.len() as u8, val};
+        assert_eq!(option(Ipv4OptionNumbers::LSR, &[8, 10, 0, 0, 1, 10, 0, 0, 2]).value(),
+                   Ok(Ipv4OptionValue::LooseSourceRoute { pointer: 8, route: vec!(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)) }));
+        assert_eq!(option(Ipv4OptionNumbers::SSR, &[4, 10, 0, 0]).value(), Err(ParseError::new(Layer::IpV4, 1, Reason::BadField("option_size"))));
+        assert_eq!(option(Ipv4OptionNumbers::SEC, &[0xab, 0x80]).value(),
+                   Ok(Ipv4OptionValue::Security { classification: 0xab, protection_authority: &[0x80] }));
+        assert_eq!(option(Ipv4OptionNumbers::CIPSO, &[0, 0, 0, 3, 1, 4, 0, 0]).value(),
+                   Ok(Ipv4OptionValue::Cipso { doi: 3, tags: &[1, 4, 0, 0] }));
+        assert_eq!(option(Ipv4OptionNumbers::TS, &[5, 0x02, 0, 0, 0, 1]).value(), Err(ParseError::new(Layer::IpV4, 3, Reason::BadField("flag"))));
+        assert_eq!(option(Ipv4OptionNumbers::TS, &[5, 0x00, 0, 0, 0, 1]).value(),
+                   Ok(Ipv4OptionValue::Timestamp { pointer: 5, overflow: 0, flag: 0, entries: vec!(TimestampEntry { addr: None, timestamp: 1 }) }));
+        assert_eq!(option(Ipv4OptionNumbers::EXP, &[1]).value(), Ok(Ipv4OptionValue::Unknown(&[1])));
+    }
+    #[test]
+    fn test_truncated_packet() {
+        let buf = [0x11u8,0x12,0x13,0x14,0x15,0x16,0x01,0x02,0x03,0x04,0x05,0x06,0x81,0x00,0x00,0x01,0x08,0x00,
+            0x46,0x00,0x05,0xbf,0xe5,0x46,0x40,0x00,0x3e,0x06,0xce,0x9f,0xac,0x10,0x00,0x53,0xc0,0xa8,0x16,0x47,0x94,0x02,0x00,0x00];
+        assert!(IpV4Packet::try_from(&buf[..]).is_ok());
+        for len in 0..buf.len() {
+            assert!(IpV4Packet::try_from(&buf[..len]).is_err(), "len {}", len);
+        }
     }
-}
\ No newline at end of file
+    #[test]
+    fn test_emit() {
+        let buf = [0x45u8,0x00,0x05,0xbf,0xe5,0x46,0x40,0x00,0x3e,0x06,0xce,0x9f,0xac,0x10,0x00,0x53,0xc0,0xa8,0x16,0x47];
+        let hdr = IpV4Header::try_from(&buf[..]).unwrap();
+        let mut out = vec!();
+        assert_eq!(hdr.emit_vec(&mut out), Ok(20));
+        assert_eq!(&out[..], &buf[..]);
+        assert_eq!(IpV4Header::try_from(&out[..]), Ok(hdr));
+    }
+    #[test]
+    fn test_emit_options() {
+        let hdr = IpV4Header{
+            version: 4,
+            hdr_length: 7,
+            dscp: 0,
+            ecn: 0,
+            packet_length: 28,
+            id: 1,
+            flags: 0,
+            offset: 0,
+            ttl: 1,
+            protocol: IpNextHeaderProtocols::Igmp,
+            crc: 0,
+            checksum_ok: None,
+            src_ip: &[10, 0, 0, 1],
+            dst_ip: &[224, 0, 0, 22],
+            options: Some(vec!(IpOption{copy: true, class: 0, option_num: Ipv4OptionNumbers::RTRALT, option_size: 4, val: &[0, 0]}))
+        };
+        let mut out = vec!();
+        assert_eq!(hdr.emit_vec(&mut out), Ok(28));
+        assert_eq!(&out[20..], &[0x94, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+        assert_eq!(common::checksum(&out), 0);
+
+        let short = IpV4Header{hdr_length: 5, ..hdr};
+        assert_eq!(short.emit(&mut [0; 20]), Err(ParseError::new(Layer::IpV4, 20, Reason::TruncatedOptions)));
+    }
+    #[test]
+    fn test_emit_packet() {
+        let buf = [0x11u8,0x12,0x13,0x14,0x15,0x16,0x01,0x02,0x03,0x04,0x05,0x06,0x81,0x00,0x00,0x01,0x08,0x00,
+            0x45,0x00,0x05,0xbf,0xe5,0x46,0x40,0x00,0x3e,0x06,0xce,0x9f,0xac,0x10,0x00,0x53,0xc0,0xa8,0x16,0x47];
+        let packet = IpV4Packet::try_from(&buf[..]).unwrap();
+        let mut out = [0u8; 38];
+        assert_eq!(packet.emit(&mut out), Ok(38));
+        assert_eq!(&out[..], &buf[..]);
+        assert_eq!(IpV4Packet::try_from(&out[..]), Ok(packet));
+    }
+    #[test]
+    fn test_mut() {
+        let mut buf = [0x45u8,0x00,0x05,0xbf,0xe5,0x46,0x40,0x00,0x3e,0x06,0xce,0x9f,0xac,0x10,0x00,0x53,0xc0,0xa8,0x16,0x47,0x01];
+        {
+            let mut hdr = IpV4HeaderMut::try_from(&mut buf[..]).unwrap();
+            hdr.set_ttl(61);
+            hdr.set_dscp(46).unwrap();
+            hdr.set_ecn(1).unwrap();
+            hdr.set_src_ip(Ipv4Addr::new(10, 0, 0, 1));
+            hdr.set_dst_ip(Ipv4Addr::new(10, 0, 0, 2));
+            hdr.set_offset(0x10).unwrap();
+            hdr.set_flags(Flags::MORE_FRAGMENTS).unwrap();
+            assert_eq!(hdr.set_dscp(64), Err(ParseError::new(Layer::IpV4, 1, Reason::BadField("dscp"))));
+            let h = hdr.header().unwrap();
+            assert_eq!((h.ttl, h.dscp, h.ecn, h.flags, h.offset), (61, 46, 1, Flags::MORE_FRAGMENTS, 0x10));
+            assert_eq!((h.src_ip, h.dst_ip), (&[10u8, 0, 0, 1], &[10u8, 0, 0, 2]));
+        }
+        assert_eq!(common::checksum(&buf[..20]), 0);
+        assert_eq!(buf[20], 0x01);
+    }
+    #[test]
+    fn test_checksum() {
+        let mut buf = [0x45u8,0x00,0x05,0xbf,0xe5,0x46,0x40,0x00,0x3e,0x06,0xce,0x9f,0xac,0x10,0x00,0x53,0xc0,0xa8,0x16,0x47];
+        assert_eq!(header_checksum(&buf), Ok(0xce9f));
+        assert_eq!(IpV4Header::parse(&buf, ChecksumCheck::Flag).unwrap().checksum_ok, Some(true));
+        assert_eq!(IpV4Header::try_from(&buf[..]).unwrap().compute_checksum(), Ok(0xce9f));
+
+        buf[11] = 0x9e;
+        assert_eq!(IpV4Header::parse(&buf, ChecksumCheck::Skip).unwrap().checksum_ok, None);
+        assert_eq!(IpV4Header::parse(&buf, ChecksumCheck::Flag).unwrap().checksum_ok, Some(false));
+        assert_eq!(IpV4Header::parse(&buf, ChecksumCheck::Reject),
+                   Err(ParseError::new(Layer::IpV4, 10, Reason::BadChecksum { expected: 0xce9f, actual: 0xce9e })));
+        assert_eq!(header_checksum(&buf), Ok(0xce9f));
+    }
+    #[test]
+    fn test_mut_keeps_bad_checksum() {
+        let mut buf = [0x45u8,0x00,0x05,0xbf,0xe5,0x46,0x40,0x00,0x3e,0x06,0xce,0x9e,0xac,0x10,0x00,0x53,0xc0,0xa8,0x16,0x47];
+        let mut hdr = IpV4HeaderMut::try_from(&mut buf[..]).unwrap();
+        hdr.set_ttl(1);
+        assert_eq!(hdr.header().unwrap().compute_checksum().unwrap(), hdr.header().unwrap().crc + 1);
+        hdr.recompute_checksum();
+        assert_eq!(hdr.header().unwrap().compute_checksum().unwrap(), hdr.header().unwrap().crc);
+    }
+    #[test]
+    fn test_payload() {
+        let mut buf = [0x11u8,0x12,0x13,0x14,0x15,0x16,0x01,0x02,0x03,0x04,0x05,0x06,0x08,0x00,
+            0x45,0x00,0x00,0x1a,0x00,0x01,0x00,0x00,0x40,0x11,0x00,0x00,0x0a,0x00,0x00,0x01,0x0a,0x00,0x00,0x02,
+            0xaa,0xbb,0xcc,0xdd,0xee,0xff,0x00,0x00,0x00,0x00];
+        let packet = IpV4Packet::try_from(&buf[..]).unwrap();
+        assert_eq!(packet.payload, &[0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff]);
+        assert_eq!(packet.complete_payload(), Ok(&[0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff][..]));
+
+        let packet = IpV4Packet::try_from(&buf[..38]).unwrap();
+        assert_eq!(packet.payload, &[0xaa, 0xbb, 0xcc, 0xdd]);
+        assert_eq!(packet.missing_bytes(), 2);
+        assert_eq!(packet.complete_payload(), Err(ParseError::truncated(Layer::IpV4, 20, 6, 4)));
+
+        buf[17] = 0x10;
+        assert_eq!(IpV4Packet::try_from(&buf[..]), Err(ParseError::new(Layer::IpV4, 16, Reason::BadField("packet_length"))));
+
+        buf[17] = 0;
+        let mut packet = IpV4Packet::try_from(&buf[..]).unwrap();
+        assert_eq!((packet.payload, packet.missing_bytes()), (&buf[34..], 0));
+        packet.ip_hdr.packet_length = 8;
+        assert_eq!(packet.missing_bytes(), 0);
+    }
+    #[test]
+    fn test_addr() {
+        let buf = [0x45u8,0x00,0x05,0xbf,0xe5,0x46,0x40,0x00,0x3e,0x06,0xce,0x9f,0xac,0x10,0x00,0x53,0xc0,0xa8,0x16,0x47];
+        let hdr = IpV4Header::try_from(&buf[..]).unwrap();
+        assert_eq!(hdr.src_addr(), Ipv4Addr::new(172, 16, 0, 83));
+        assert_eq!(hdr.dst_addr(), Ipv4Addr::new(192, 168, 22, 71));
+        assert_eq!(AddrClass::of(IpAddr::V4(hdr.src_addr())), AddrClass::Private);
+    }
+    #[test]
+    fn test_addr_class() {
+        let cases = [("0.0.0.0", AddrClass::Unspecified),
+                     ("127.0.0.1", AddrClass::Loopback),
+                     ("255.255.255.255", AddrClass::Broadcast),
+                     ("239.1.2.3", AddrClass::Multicast),
+                     ("169.254.10.1", AddrClass::LinkLocal),
+                     ("10.1.2.3", AddrClass::Private),
+                     ("192.168.1.1", AddrClass::Private),
+                     ("8.8.8.8", AddrClass::Global),
+                     ("::", AddrClass::Unspecified),
+                     ("::1", AddrClass::Loopback),
+                     ("ff02::1", AddrClass::Multicast),
+                     ("fe80::1", AddrClass::LinkLocal),
+                     ("fd00::1", AddrClass::Private),
+                     ("::ffff:10.0.0.1", AddrClass::Private),
+                     ("2001:db8::1", AddrClass::Global)];
+        for &(addr, class) in cases.iter() {
+            assert_eq!(AddrClass::of(addr.parse().unwrap()), class, "{}", addr);
+        }
+    }
+}
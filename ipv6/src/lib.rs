@@ -0,0 +1,518 @@
+extern crate common;
+extern crate ethernet;
+extern crate vlan;
+extern crate ethertype;
+extern crate ip;
+
+use std::convert::TryFrom;
+use std::net::Ipv6Addr;
+use common::{Emit, Layer, ParseError, Reason};
+use ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
+
+/// Length of the fixed IPv6 header
+pub const IPV6_HDR_LEN: usize = 40;
+
+#[derive(Debug,PartialEq)]
+pub struct IpV6Header <'a> {
+    pub version: u8,
+    pub traffic_class: u8,
+    pub flow_label: u32,
+    pub payload_length: u16,
+    pub next_header: IpNextHeaderProtocol,
+    pub hop_limit: u8,
+    pub src_ip: &'a [u8; 16],
+    pub dst_ip: &'a [u8; 16],
+}
+
+#[derive(Debug,PartialEq)]
+pub struct IpV6Packet <'a> {
+    pub eth_header: ethernet::EthHeader,
+    pub vlans : Option<Vec<vlan::Vlan>>,
+    pub ip_hdr : IpV6Header<'a>,
+    /// Bytes after the fixed header up to `payload_length`, Ethernet padding excluded.
+    /// Extension headers are part of it. Shorter than announced when the frame
+    /// was captured truncated, see `missing_bytes`.
+    pub payload: &'a [u8],
+}
+
+impl <'a> IpV6Header<'a> {
+    /// Differentiated services code point, the upper 6 bits of `traffic_class`
+    pub fn dscp(&self) -> u8 {
+        self.traffic_class >> 2
+    }
+
+    /// Explicit congestion notification, the lower 2 bits of `traffic_class`
+    pub fn ecn(&self) -> u8 {
+        self.traffic_class & 0x3
+    }
+
+    pub fn src_addr(&self) -> Ipv6Addr {
+        Ipv6Addr::from(*self.src_ip)
+    }
+
+    pub fn dst_addr(&self) -> Ipv6Addr {
+        Ipv6Addr::from(*self.dst_ip)
+    }
+
+    /// Pseudo-header of the upper-layer checksum (RFC 8200, 8.1). `protocol` is the
+    /// upper-layer one, not `next_header`. The final destination of a routing header is ignored.
+    pub fn pseudo_header(&self, protocol: IpNextHeaderProtocol, length: u32) -> [u8; 40] {
+        let mut buf = [0u8; 40];
+        buf[0..16].copy_from_slice(self.src_ip);
+        buf[16..32].copy_from_slice(self.dst_ip);
+        common::u32_write(&mut buf[32..], length);
+        buf[39] = protocol.0;
+        buf
+    }
+}
+
+impl <'a> TryFrom<&'a [u8]> for IpV6Header<'a> {
+    type Error = ParseError;
+
+    fn try_from(buf: &'a [u8]) -> Result<IpV6Header<'a>, Self::Error> {
+        if buf.len() < IPV6_HDR_LEN {
+            return Err(ParseError::truncated(Layer::IpV6, 0, IPV6_HDR_LEN, buf.len()));
+        }
+        let version = buf[0] >> 4;
+        if version != 6 {
+            return Err(ParseError::new(Layer::IpV6, 0, Reason::BadVersion(version)));
+        }
+        let (src_ip, dst_ip) = match (buf[8..].first_chunk(), buf[24..].first_chunk()) {
+            (Some(src_ip), Some(dst_ip)) => (src_ip, dst_ip),
+            _ => return Err(ParseError::truncated(Layer::IpV6, 0, IPV6_HDR_LEN, buf.len())),
+        };
+        let first = common::u16_parse(&buf[0..2]) as u32;
+        Ok(IpV6Header {
+            version,
+            traffic_class: (first >> 4) as u8,
+            flow_label: (first & 0xf) << 16 | common::u16_parse(&buf[2..4]) as u32,
+            payload_length: common::u16_parse(&buf[4..6]),
+            next_header: IpNextHeaderProtocol(buf[6]),
+            hop_limit: buf[7],
+            src_ip,
+            dst_ip,
+        })
+    }
+}
+
+impl <'a> IpV6Packet<'a> {
+    /// Number of payload bytes announced by `payload_length` but absent from the buffer
+    pub fn missing_bytes(&self) -> usize {
+        if self.ip_hdr.payload_length == 0 {
+            return 0;
+        }
+        (self.ip_hdr.payload_length as usize).saturating_sub(self.payload.len())
+    }
+
+    /// Payload, or an error when the capture does not hold all of it
+    pub fn complete_payload(&self) -> Result<&'a [u8], ParseError> {
+        match self.missing_bytes() {
+            0 => Ok(self.payload),
+            missing => Err(ParseError::truncated(Layer::IpV6, IPV6_HDR_LEN, self.payload.len() + missing, self.payload.len())),
+        }
+    }
+
+    /// Extension headers of the packet, see `parse_extensions`
+    pub fn extensions(&self) -> Result<ExtensionChain<'a>, ParseError> {
+        parse_extensions(self.ip_hdr.next_header, self.payload).map_err(|e| e.shift(IPV6_HDR_LEN))
+    }
+
+    /// Upper-layer protocol and its data, after the extension headers
+    pub fn upper_layer(&self) -> Result<(IpNextHeaderProtocol, &'a [u8]), ParseError> {
+        let chain = self.extensions()?;
+        Ok((chain.protocol, &self.payload[chain.payload_offset..]))
+    }
+}
+
+impl <'a> TryFrom<&'a [u8]> for IpV6Packet<'a> {
+    type Error = ParseError;
+
+    fn try_from(buf: &'a [u8]) -> Result<IpV6Packet<'a>, Self::Error> {
+        let eth_end = 12;
+
+        let eth_header = ethernet::EthHeader::try_from(buf)?;
+
+        let (v, ptr) = vlan::parse_vlans(&buf[eth_end..buf.len()]).map_err(|e| e.shift(eth_end))?;
+
+        let ethtype = ethertype::parse_ethtype(&buf[eth_end + ptr..buf.len()]).map_err(|e| e.shift(eth_end + ptr))?;
+        if ethtype == ethertype::EtherTypes::Ipv6 {
+            let ip_start = eth_end + ptr + 2;
+            IpV6Packet::try_from((eth_header, v, &buf[ip_start..buf.len()])).map_err(|e| e.shift(ip_start))
+        } else {
+            Err(ParseError::new(Layer::IpV6, eth_end + ptr, Reason::WrongEtherType(ethtype.0)))
+        }
+    }
+}
+
+/// Jumbograms (RFC 2675) and captures of TSO/GRO packets announce a zero `payload_length`,
+/// the payload is then the whole captured rest
+impl <'a> TryFrom<(ethernet::EthHeader, Option<Vec<vlan::Vlan>>, &'a [u8])> for IpV6Packet<'a> {
+    type Error = ParseError;
+    fn try_from(src: (ethernet::EthHeader, Option<Vec<vlan::Vlan>>, &'a [u8])) -> Result<IpV6Packet<'a>, Self::Error> {
+        let (eth_header, vlans, buf) = src;
+        let ip_hdr = IpV6Header::try_from(buf)?;
+        let end = match ip_hdr.payload_length {
+            0 => buf.len(),
+            len => IPV6_HDR_LEN + len as usize,
+        };
+        let payload = &buf[IPV6_HDR_LEN..end.min(buf.len())];
+        Ok(IpV6Packet{eth_header, vlans, ip_hdr, payload})
+    }
+}
+
+/// Pad1 option, a single zero byte
+pub const OPTION_PAD1: u8 = 0;
+/// PadN option
+pub const OPTION_PADN: u8 = 1;
+/// Router Alert option [RFC 2711]
+pub const OPTION_ROUTER_ALERT: u8 = 5;
+/// Jumbo Payload option [RFC 2675]
+pub const OPTION_JUMBO: u8 = 0xc2;
+
+/// Segment Routing Header [RFC 8754]
+pub const ROUTING_SRH: u8 = 4;
+
+/// Option of a Hop-by-Hop or Destination Options header
+#[derive(Debug,PartialEq)]
+pub struct Ipv6Option <'a> {
+    pub option_type: u8,
+    pub data: &'a [u8],
+}
+
+#[derive(Debug,PartialEq)]
+pub struct RoutingHeader <'a> {
+    pub routing_type: u8,
+    pub segments_left: u8,
+    /// Type specific data following `segments_left`
+    pub data: &'a [u8],
+}
+
+impl <'a> RoutingHeader<'a> {
+    /// Segment list of an SRv6 header, `None` for other routing types.
+    /// Segment 0 is the final destination, the list is in reverse order of visit.
+    pub fn segments(&self) -> Option<Vec<Ipv6Addr>> {
+        if self.routing_type != ROUTING_SRH {
+            return None;
+        }
+        let count = *self.data.first()? as usize + 1;
+        let segments = self.data.get(4..4 + 16 * count)?;
+        Some(segments.as_chunks::<16>().0.iter().map(|a| Ipv6Addr::from(*a)).collect())
+    }
+}
+
+#[derive(Debug,PartialEq)]
+pub struct FragmentHeader {
+    pub offset: u16,
+    pub more_fragments: bool,
+    pub id: u32,
+}
+
+/// Authentication Header [RFC 4302]
+#[derive(Debug,PartialEq)]
+pub struct AuthHeader <'a> {
+    pub spi: u32,
+    pub seq: u32,
+    pub icv: &'a [u8],
+}
+
+#[derive(Debug,PartialEq)]
+pub enum ExtensionHeader <'a> {
+    HopByHop(Vec<Ipv6Option<'a>>),
+    Routing(RoutingHeader<'a>),
+    Fragment(FragmentHeader),
+    DestinationOptions(Vec<Ipv6Option<'a>>),
+    Auth(AuthHeader<'a>),
+}
+
+/// Result of walking the extension headers of a packet
+#[derive(Debug,PartialEq)]
+pub struct ExtensionChain <'a> {
+    pub headers: Vec<ExtensionHeader<'a>>,
+    /// Upper-layer protocol: `Ipv6NoNxt` when nothing follows, `Esp` for encrypted data
+    pub protocol: IpNextHeaderProtocol,
+    /// Offset of the upper-layer data from the start of the IPv6 payload
+    pub payload_offset: usize,
+}
+
+impl <'a> ExtensionChain<'a> {
+    pub fn fragment(&self) -> Option<&FragmentHeader> {
+        self.headers.iter().filter_map(|h| match *h {
+            ExtensionHeader::Fragment(ref f) => Some(f),
+            _ => None,
+        }).next()
+    }
+}
+
+/// Parse the TLV options of a Hop-by-Hop or Destination Options header, padding is dropped
+fn parse_ipv6_options(buf: &[u8]) -> Result<Vec<Ipv6Option<'_>>, ParseError> {
+    let mut result = vec!();
+    let mut ptr = 0;
+    while ptr < buf.len() {
+        let option_type = buf[ptr];
+        if option_type == OPTION_PAD1 {
+            ptr += 1;
+            continue;
+        }
+        if ptr + 2 > buf.len() || ptr + 2 + buf[ptr + 1] as usize > buf.len() {
+            return Err(ParseError::new(Layer::IpV6, ptr, Reason::TruncatedOptions));
+        }
+        let end = ptr + 2 + buf[ptr + 1] as usize;
+        if option_type != OPTION_PADN {
+            result.push(Ipv6Option { option_type, data: &buf[ptr + 2..end] });
+        }
+        ptr = end;
+    }
+    Ok(result)
+}
+
+/// Walk the extension headers at the start of `buf` (the IPv6 payload), `next_header`
+/// is the value from the fixed header. Stops at the first upper-layer protocol, at ESP,
+/// and after a Fragment header with a non-zero offset since the rest is not in this fragment.
+pub fn parse_extensions(next_header: IpNextHeaderProtocol, buf: &[u8]) -> Result<ExtensionChain<'_>, ParseError> {
+    let mut headers = vec!();
+    let mut protocol = next_header;
+    let mut ptr = 0;
+    loop {
+        let hdr_len = match protocol {
+            IpNextHeaderProtocols::Hopopt | IpNextHeaderProtocols::Ipv6Route | IpNextHeaderProtocols::Ipv6Opts => {
+                if buf.len() < ptr + 8 {
+                    return Err(ParseError::truncated(Layer::IpV6, ptr, 8, buf.len() - ptr));
+                }
+                (buf[ptr + 1] as usize + 1) * 8
+            }
+            IpNextHeaderProtocols::Ipv6Frag => 8,
+            IpNextHeaderProtocols::Ah => {
+                if buf.len() < ptr + 12 {
+                    return Err(ParseError::truncated(Layer::IpV6, ptr, 12, buf.len() - ptr));
+                }
+                if buf[ptr + 1] < 1 {
+                    return Err(ParseError::new(Layer::IpV6, ptr + 1, Reason::BadField("ah length")));
+                }
+                (buf[ptr + 1] as usize + 2) * 4
+            }
+            _ => break,
+        };
+        if buf.len() < ptr + hdr_len {
+            return Err(ParseError::truncated(Layer::IpV6, ptr, hdr_len, buf.len() - ptr));
+        }
+        let hdr = &buf[ptr..ptr + hdr_len];
+        let mut last = false;
+        let header = match protocol {
+            IpNextHeaderProtocols::Hopopt => {
+                if ptr != 0 {
+                    return Err(ParseError::new(Layer::IpV6, ptr, Reason::BadField("hop-by-hop position")));
+                }
+                ExtensionHeader::HopByHop(parse_ipv6_options(&hdr[2..]).map_err(|e| e.shift(ptr + 2))?)
+            }
+            IpNextHeaderProtocols::Ipv6Opts => {
+                ExtensionHeader::DestinationOptions(parse_ipv6_options(&hdr[2..]).map_err(|e| e.shift(ptr + 2))?)
+            }
+            IpNextHeaderProtocols::Ipv6Route => {
+                let routing = RoutingHeader { routing_type: hdr[2], segments_left: hdr[3], data: &hdr[4..] };
+                if routing.routing_type == ROUTING_SRH && 8 + 16 * (hdr[4] as usize + 1) > hdr_len {
+                    return Err(ParseError::new(Layer::IpV6, ptr + 4, Reason::BadField("last_entry")));
+                }
+                ExtensionHeader::Routing(routing)
+            }
+            IpNextHeaderProtocols::Ipv6Frag => {
+                let word = common::u16_parse(&hdr[2..4]);
+                let fragment = FragmentHeader { offset: word >> 3, more_fragments: word & 1 != 0, id: common::u32_parse(&hdr[4..8]) };
+                last = fragment.offset != 0;
+                ExtensionHeader::Fragment(fragment)
+            }
+            _ => ExtensionHeader::Auth(AuthHeader { spi: common::u32_parse(&hdr[4..8]), seq: common::u32_parse(&hdr[8..12]), icv: &hdr[12..] }),
+        };
+        headers.push(header);
+        protocol = IpNextHeaderProtocol(hdr[0]);
+        ptr += hdr_len;
+        if last {
+            break;
+        }
+    }
+    Ok(ExtensionChain { headers, protocol, payload_offset: ptr })
+}
+
+impl <'a> Emit for IpV6Header<'a> {
+    fn emit_len(&self) -> usize {
+        IPV6_HDR_LEN
+    }
+
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        if self.version > 0xf {
+            return Err(ParseError::new(Layer::IpV6, 0, Reason::BadVersion(self.version)));
+        }
+        if self.flow_label > 0xfffff {
+            return Err(ParseError::new(Layer::IpV6, 1, Reason::BadField("flow_label")));
+        }
+        if buf.len() < IPV6_HDR_LEN {
+            return Err(ParseError::truncated(Layer::IpV6, 0, IPV6_HDR_LEN, buf.len()));
+        }
+        common::u16_write(&mut buf[0..], (self.version as u16) << 12 | (self.traffic_class as u16) << 4 | (self.flow_label >> 16) as u16);
+        common::u16_write(&mut buf[2..], self.flow_label as u16);
+        common::u16_write(&mut buf[4..], self.payload_length);
+        buf[6] = self.next_header.0;
+        buf[7] = self.hop_limit;
+        buf[8..24].copy_from_slice(self.src_ip);
+        buf[24..40].copy_from_slice(self.dst_ip);
+        Ok(IPV6_HDR_LEN)
+    }
+}
+
+impl <'a> Emit for IpV6Packet<'a> {
+    fn emit_len(&self) -> usize {
+        let vlans = self.vlans.as_ref().map_or(0, |v| v.len());
+        12 + 4 * vlans + 2 + IPV6_HDR_LEN + self.payload.len()
+    }
+
+    /// Write the Ethernet header, tags, EtherType, the IPv6 header and the payload
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        let mut ptr = self.eth_header.emit(buf)?;
+        if let Some(ref vlans) = self.vlans {
+            ptr += vlan::emit_vlans(vlans, &mut buf[ptr..]).map_err(|e| e.shift(ptr))?;
+        }
+        ptr += ethertype::EtherTypes::Ipv6.emit(&mut buf[ptr..]).map_err(|e| e.shift(ptr))?;
+        ptr += self.ip_hdr.emit(&mut buf[ptr..]).map_err(|e| e.shift(ptr))?;
+        if buf.len() < ptr + self.payload.len() {
+            return Err(ParseError::truncated(Layer::IpV6, ptr, self.payload.len(), buf.len() - ptr));
+        }
+        buf[ptr..ptr + self.payload.len()].copy_from_slice(self.payload);
+        Ok(ptr + self.payload.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: [u8; 64] = [0x33, 0x33, 0x00, 0x00, 0x00, 0x01, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x86, 0xdd,
+        0x6b, 0x8f, 0xab, 0xcd, 0x00, 0x08, 0x3a, 0xff,
+        0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01,
+        0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01,
+        0x80, 0x00, 0x7f, 0xff, 0x00, 0x01, 0x00, 0x01,
+        0x00, 0x00];
+
+    #[test]
+    fn test_ok() {
+        let res = IpV6Packet::try_from(&FRAME[..]).unwrap();
+        assert_eq!(res.ip_hdr, IpV6Header {
+            version: 6,
+            traffic_class: 0xb8,
+            flow_label: 0xfabcd,
+            payload_length: 8,
+            next_header: ip::IpNextHeaderProtocols::Icmpv6,
+            hop_limit: 255,
+            src_ip: FRAME[22..].first_chunk().unwrap(),
+            dst_ip: FRAME[38..].first_chunk().unwrap(),
+        });
+        assert_eq!((res.ip_hdr.dscp(), res.ip_hdr.ecn()), (46, 0));
+        assert_eq!(res.ip_hdr.src_addr(), Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1));
+        assert_eq!(res.ip_hdr.dst_addr(), Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1));
+        assert_eq!(res.payload, &FRAME[54..62]);
+        assert_eq!(res.complete_payload(), Ok(&FRAME[54..62]));
+    }
+    #[test]
+    fn test_errors() {
+        assert_eq!(IpV6Header::try_from(&FRAME[14..53]), Err(ParseError::truncated(Layer::IpV6, 0, 40, 39)));
+        let mut buf = FRAME;
+        buf[14] = 0x45;
+        assert_eq!(IpV6Packet::try_from(&buf[..]), Err(ParseError::new(Layer::IpV6, 14, Reason::BadVersion(4))));
+        buf[12..14].copy_from_slice(&[0x08, 0x00]);
+        assert_eq!(IpV6Packet::try_from(&buf[..]), Err(ParseError::new(Layer::IpV6, 12, Reason::WrongEtherType(0x0800))));
+        for len in 0..FRAME.len() {
+            let _ = IpV6Packet::try_from(&FRAME[..len]);
+        }
+    }
+    #[test]
+    fn test_truncated_packet() {
+        let res = IpV6Packet::try_from(&FRAME[..58]).unwrap();
+        assert_eq!(res.missing_bytes(), 4);
+        assert_eq!(res.complete_payload(), Err(ParseError::truncated(Layer::IpV6, 40, 8, 4)));
+
+        let mut buf = FRAME.to_vec();
+        buf[18..20].copy_from_slice(&[0, 0]);
+        let mut res = IpV6Packet::try_from(&buf[..]).unwrap();
+        assert_eq!((res.payload, res.missing_bytes()), (&buf[54..], 0));
+        res.ip_hdr.payload_length = 2;
+        assert_eq!(res.missing_bytes(), 0);
+    }
+    #[test]
+    fn test_emit() {
+        let res = IpV6Packet::try_from(&FRAME[..]).unwrap();
+        let mut out = vec!();
+        assert_eq!(res.emit_vec(&mut out), Ok(62));
+        assert_eq!(&out[..], &FRAME[..62]);
+        assert_eq!(res.emit(&mut [0u8; 60]), Err(ParseError::truncated(Layer::IpV6, 54, 8, 6)));
+
+        let hdr = IpV6Header { flow_label: 0x100000, ..IpV6Header::try_from(&FRAME[14..]).unwrap() };
+        assert_eq!(hdr.emit(&mut [0u8; 40]), Err(ParseError::new(Layer::IpV6, 1, Reason::BadField("flow_label"))));
+    }
+    #[test]
+    fn test_extensions() {
+        let mut payload = vec!();
+        // Hop-by-Hop: Router Alert, PadN
+        payload.extend_from_slice(&[43, 0, 5, 2, 0, 0, 1, 0]);
+        // SRv6 routing header with two segments
+        payload.extend_from_slice(&[44, 4, ROUTING_SRH, 1, 1, 0, 0, 0]);
+        payload.extend_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 2).octets());
+        payload.extend_from_slice(&Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).octets());
+        // first fragment, more follow
+        payload.extend_from_slice(&[60, 0, 0x00, 0x01, 0x12, 0x34, 0x56, 0x78]);
+        // Destination Options: Pad1 x6
+        payload.extend_from_slice(&[51, 0, 0, 0, 0, 0, 0, 0]);
+        // AH with a 4 byte ICV
+        payload.extend_from_slice(&[6, 2, 0, 0, 0, 0, 0x10, 0x01, 0, 0, 0, 7, 0xaa, 0xbb, 0xcc, 0xdd]);
+        payload.extend_from_slice(&[0x1f, 0x90, 0xaa, 0x5c]);
+
+        let chain = parse_extensions(IpNextHeaderProtocols::Hopopt, &payload).unwrap();
+        assert_eq!(chain.protocol, IpNextHeaderProtocols::Tcp);
+        assert_eq!(chain.payload_offset, payload.len() - 4);
+        assert_eq!(chain.headers.len(), 5);
+        assert_eq!(chain.headers[0], ExtensionHeader::HopByHop(vec!(Ipv6Option { option_type: OPTION_ROUTER_ALERT, data: &[0, 0] })));
+        match chain.headers[1] {
+            ExtensionHeader::Routing(ref r) => {
+                assert_eq!(r.segments_left, 1);
+                assert_eq!(r.segments(), Some(vec!("2001:db8::2".parse().unwrap(), "2001:db8::1".parse().unwrap())));
+            }
+            ref h => panic!("unexpected {:?}", h),
+        }
+        assert_eq!(chain.fragment(), Some(&FragmentHeader { offset: 0, more_fragments: true, id: 0x12345678 }));
+        assert_eq!(chain.headers[3], ExtensionHeader::DestinationOptions(vec!()));
+        assert_eq!(chain.headers[4], ExtensionHeader::Auth(AuthHeader { spi: 0x1001, seq: 7, icv: &[0xaa, 0xbb, 0xcc, 0xdd] }));
+
+        for len in 0..payload.len() - 4 {
+            assert!(parse_extensions(IpNextHeaderProtocols::Hopopt, &payload[..len]).is_err(), "len {}", len);
+        }
+    }
+    #[test]
+    fn test_extension_stops() {
+        let later_fragment = [6u8, 0, 0x05, 0xa8, 0, 0, 0, 1, 0xde, 0xad];
+        let chain = parse_extensions(IpNextHeaderProtocols::Ipv6Frag, &later_fragment).unwrap();
+        assert_eq!((chain.protocol, chain.payload_offset), (IpNextHeaderProtocols::Tcp, 8));
+        assert_eq!(chain.fragment(), Some(&FragmentHeader { offset: 181, more_fragments: false, id: 1 }));
+
+        let no_next = [59u8, 0, 1, 4, 0, 0, 0, 0];
+        let chain = parse_extensions(IpNextHeaderProtocols::Ipv6Opts, &no_next).unwrap();
+        assert_eq!((chain.protocol, chain.payload_offset), (IpNextHeaderProtocols::Ipv6NoNxt, 8));
+
+        let late_hbh = [0u8, 0, 1, 4, 0, 0, 0, 0, 6, 0, 1, 4, 0, 0, 0, 0];
+        assert_eq!(parse_extensions(IpNextHeaderProtocols::Ipv6Opts, &late_hbh),
+                   Err(ParseError::new(Layer::IpV6, 8, Reason::BadField("hop-by-hop position"))));
+        let bad_srh = [6u8, 2, ROUTING_SRH, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(parse_extensions(IpNextHeaderProtocols::Ipv6Route, &bad_srh),
+                   Err(ParseError::new(Layer::IpV6, 4, Reason::BadField("last_entry"))));
+        let bad_ah = [6u8, 0, 0, 0, 0, 0, 0x10, 0x01, 0, 0, 0, 7];
+        assert_eq!(parse_extensions(IpNextHeaderProtocols::Ah, &bad_ah),
+                   Err(ParseError::new(Layer::IpV6, 1, Reason::BadField("ah length"))));
+        let bad_option = [6u8, 0, 5, 7, 0, 0, 0, 0];
+        assert_eq!(parse_extensions(IpNextHeaderProtocols::Ipv6Opts, &bad_option),
+                   Err(ParseError::new(Layer::IpV6, 2, Reason::TruncatedOptions)));
+    }
+    #[test]
+    fn test_upper_layer() {
+        let res = IpV6Packet::try_from(&FRAME[..]).unwrap();
+        assert_eq!(res.upper_layer(), Ok((ip::IpNextHeaderProtocols::Icmpv6, &FRAME[54..62])));
+        let mut buf = FRAME;
+        buf[20] = 0;
+        assert_eq!(IpV6Packet::try_from(&buf[..]).unwrap().upper_layer(), Err(ParseError::new(Layer::IpV6, 42, Reason::TruncatedOptions)));
+    }
+}
@@ -0,0 +1,348 @@
+extern crate common;
+extern crate ethernet;
+extern crate vlan;
+extern crate ethertype;
+
+use std::convert::TryFrom;
+use common::{Emit, Layer, ParseError, Reason};
+
+/// IEEE 802.2 service access point
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Sap(pub u8);
+
+impl Sap {
+    pub fn new(value: u8) -> Sap {
+        Sap(value)
+    }
+
+    /// Address without the I/G (DSAP) or C/R (SSAP) bit
+    pub fn address(&self) -> Sap {
+        Sap(self.0 & 0xfe)
+    }
+}
+
+#[allow(non_snake_case)]
+#[allow(non_upper_case_globals)]
+pub mod Saps {
+    use super::Sap;
+    /// Null SAP
+    pub const Null: Sap = Sap(0x00);
+    /// Spanning Tree BPDU [IEEE 802.1D]
+    pub const Stp: Sap = Sap(0x42);
+    /// SubNetwork Access Protocol [RFC 1042]
+    pub const Snap: Sap = Sap(0xaa);
+    /// Novell IPX
+    pub const Ipx: Sap = Sap(0xe0);
+    /// NetBIOS
+    pub const NetBios: Sap = Sap(0xf0);
+    /// ISO network layer: CLNP, ES-IS, IS-IS
+    pub const Iso: Sap = Sap(0xfe);
+    /// Global DSAP
+    pub const Global: Sap = Sap(0xff);
+}
+
+/// SNAP OUI whose PID is an EtherType [RFC 1042]
+pub const OUI_ENCAPSULATED: [u8; 3] = [0x00, 0x00, 0x00];
+/// Cisco SNAP OUI, carries CDP, VTP, DTP and PVST+
+pub const OUI_CISCO: [u8; 3] = [0x00, 0x00, 0x0c];
+
+/// ISO network layer protocol identifier of IS-IS
+const NLPID_ISIS: u8 = 0x83;
+
+/// LLC control field
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Control {
+    /// U-format PDU (UI, XID, TEST...), one byte
+    Unnumbered(u8),
+    /// I- or S-format PDU, two bytes in wire order
+    Sequenced(u16),
+}
+
+/// Unnumbered information, the control field of connectionless traffic
+pub const CONTROL_UI: Control = Control::Unnumbered(0x03);
+
+/// SNAP extension of the LLC header
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Snap {
+    pub oui: [u8; 3],
+    pub pid: u16,
+}
+
+/// IEEE 802.2 LLC header, with the SNAP extension when both SAPs are 0xaa
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LlcHeader {
+    pub dsap: Sap,
+    pub ssap: Sap,
+    pub control: Control,
+    pub snap: Option<Snap>,
+}
+
+impl LlcHeader {
+    /// Response PDU, the C/R bit of SSAP is set
+    pub fn is_response(&self) -> bool {
+        self.ssap.0 & 0x01 != 0
+    }
+}
+
+impl <'a> TryFrom<&'a [u8]> for LlcHeader {
+    type Error = ParseError;
+    fn try_from(buf: &'a [u8]) -> Result<LlcHeader, ParseError> {
+        if buf.len() < 3 {
+            return Err(ParseError::truncated(Layer::Llc, 0, 3, buf.len()));
+        }
+        let dsap = Sap(buf[0]);
+        let ssap = Sap(buf[1]);
+        let (control, ptr) = if buf[2] & 0x03 == 0x03 {
+            (Control::Unnumbered(buf[2]), 3)
+        } else if buf.len() < 4 {
+            return Err(ParseError::truncated(Layer::Llc, 0, 4, buf.len()));
+        } else {
+            (Control::Sequenced(common::u16_parse(&buf[2..4])), 4)
+        };
+
+        let mut snap = None;
+        if dsap.address() == Saps::Snap && ssap.address() == Saps::Snap {
+            if buf.len() < ptr + 5 {
+                return Err(ParseError::truncated(Layer::Llc, 0, ptr + 5, buf.len()));
+            }
+            snap = Some(Snap { oui: [buf[ptr], buf[ptr + 1], buf[ptr + 2]], pid: common::u16_parse(&buf[ptr + 3..ptr + 5]) });
+        }
+        Ok(LlcHeader { dsap, ssap, control, snap })
+    }
+}
+
+impl Emit for LlcHeader {
+    fn emit_len(&self) -> usize {
+        let control = match self.control {
+            Control::Unnumbered(_) => 1,
+            Control::Sequenced(_) => 2,
+        };
+        2 + control + self.snap.map_or(0, |_| 5)
+    }
+
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        let len = self.emit_len();
+        if buf.len() < len {
+            return Err(ParseError::truncated(Layer::Llc, 0, len, buf.len()));
+        }
+        buf[0] = self.dsap.0;
+        buf[1] = self.ssap.0;
+        let mut ptr = match self.control {
+            Control::Unnumbered(c) if c & 0x03 == 0x03 => {
+                buf[2] = c;
+                3
+            }
+            Control::Sequenced(c) if (c >> 8) & 0x03 != 0x03 => {
+                common::u16_write(&mut buf[2..], c);
+                4
+            }
+            _ => return Err(ParseError::new(Layer::Llc, 2, Reason::BadField("control"))),
+        };
+        if let Some(snap) = self.snap {
+            buf[ptr..ptr + 3].copy_from_slice(&snap.oui);
+            common::u16_write(&mut buf[ptr + 3..], snap.pid);
+            ptr += 5;
+        }
+        Ok(ptr)
+    }
+}
+
+/// Protocol carried by an LLC frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum LlcProtocol {
+    /// Spanning Tree BPDU
+    Stp,
+    /// IS-IS over the ISO network layer SAP
+    IsIs,
+    /// Cisco Discovery Protocol
+    Cdp,
+    /// Cisco VLAN Trunking Protocol
+    Vtp,
+    /// Cisco Dynamic Trunking Protocol
+    Dtp,
+    /// Cisco per-VLAN Spanning Tree (PVST+)
+    Pvst,
+    /// SNAP with a zero OUI, the PID is an EtherType [RFC 1042]
+    Encapsulated(ethertype::EtherType),
+    /// Anything else
+    Unknown,
+}
+
+/// IEEE 802.3 frame: the type field is a length followed by an LLC header
+#[derive(Debug,PartialEq)]
+pub struct LlcPacket <'a> {
+    pub eth_header: ethernet::EthHeader,
+    pub vlans : Option<Vec<vlan::Vlan>>,
+    /// 802.3 length field, counts the LLC header and the payload
+    pub length: u16,
+    pub llc: LlcHeader,
+    /// Bytes after the LLC/SNAP header up to `length`, Ethernet padding excluded.
+    /// Shorter than announced when the frame was captured truncated, see `missing_bytes`.
+    pub payload: &'a [u8],
+}
+
+impl <'a> LlcPacket<'a> {
+    /// Number of payload bytes announced by `length` but absent from the buffer
+    pub fn missing_bytes(&self) -> usize {
+        (self.length as usize).saturating_sub(self.llc.emit_len()).saturating_sub(self.payload.len())
+    }
+
+    /// Dispatch on the SAPs and the SNAP OUI/PID
+    pub fn protocol(&self) -> LlcProtocol {
+        match self.llc.snap {
+            Some(Snap { oui: OUI_ENCAPSULATED, pid }) => LlcProtocol::Encapsulated(ethertype::EtherType(pid)),
+            Some(Snap { oui: OUI_CISCO, pid: 0x2000 }) => LlcProtocol::Cdp,
+            Some(Snap { oui: OUI_CISCO, pid: 0x2003 }) => LlcProtocol::Vtp,
+            Some(Snap { oui: OUI_CISCO, pid: 0x2004 }) => LlcProtocol::Dtp,
+            Some(Snap { oui: OUI_CISCO, pid: 0x010b }) => LlcProtocol::Pvst,
+            Some(_) => LlcProtocol::Unknown,
+            None => match self.llc.dsap.address() {
+                Saps::Stp => LlcProtocol::Stp,
+                Saps::Iso if self.payload.first() == Some(&NLPID_ISIS) => LlcProtocol::IsIs,
+                _ => LlcProtocol::Unknown,
+            },
+        }
+    }
+}
+
+impl <'a> TryFrom<&'a [u8]> for LlcPacket<'a> {
+    type Error = ParseError;
+    fn try_from(buf: &'a [u8]) -> Result<Self, ParseError> {
+        let eth_end = 12;
+
+        let eth_header = ethernet::EthHeader::try_from(buf)?;
+
+        let (v, ptr) = vlan::parse_vlans(&buf[eth_end..buf.len()]).map_err(|e| e.shift(eth_end))?;
+
+        let len_start = eth_end + ptr;
+        LlcPacket::try_from((eth_header, v, &buf[len_start..buf.len()])).map_err(|e| e.shift(len_start))
+    }
+}
+
+/// Unlike the other layers `buf` starts at the 802.3 length field, not after it
+impl <'a> TryFrom<(ethernet::EthHeader, Option<Vec<vlan::Vlan>>, &'a [u8])> for LlcPacket<'a> {
+    type Error = ParseError;
+    fn try_from(src: (ethernet::EthHeader, Option<Vec<vlan::Vlan>>, &'a [u8])) -> Result<LlcPacket<'a>, ParseError> {
+        let (eth_header, vlans, buf) = src;
+        let length = ethertype::parse_ethtype(buf)?;
+        if !length.is_length() {
+            return Err(ParseError::new(Layer::Llc, 0, Reason::WrongEtherType(length.0)));
+        }
+        let llc = LlcHeader::try_from(&buf[2..]).map_err(|e| e.shift(2))?;
+        let llc_end = 2 + llc.emit_len();
+        if (length.0 as usize) < llc.emit_len() {
+            return Err(ParseError::new(Layer::Llc, 0, Reason::BadField("length")));
+        }
+        let end = std::cmp::min(2 + length.0 as usize, buf.len());
+        Ok(LlcPacket { eth_header, vlans, length: length.0, llc, payload: &buf[llc_end..end] })
+    }
+}
+
+impl <'a> Emit for LlcPacket<'a> {
+    fn emit_len(&self) -> usize {
+        let vlans = self.vlans.as_ref().map_or(0, |v| v.len());
+        12 + 4 * vlans + 2 + self.llc.emit_len() + self.payload.len()
+    }
+
+    /// Write the whole frame, `length` is recomputed from the LLC header and the payload
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        let mut ptr = self.eth_header.emit(buf)?;
+        if let Some(ref vlans) = self.vlans {
+            ptr += vlan::emit_vlans(vlans, &mut buf[ptr..]).map_err(|e| e.shift(ptr))?;
+        }
+        let length = self.llc.emit_len() + self.payload.len();
+        if length > ethertype::MAX_LENGTH as usize {
+            return Err(ParseError::new(Layer::Llc, ptr, Reason::BadField("length")));
+        }
+        ptr += ethertype::EtherType(length as u16).emit(&mut buf[ptr..]).map_err(|e| e.shift(ptr))?;
+        ptr += self.llc.emit(&mut buf[ptr..]).map_err(|e| e.shift(ptr))?;
+        if buf.len() < ptr + self.payload.len() {
+            return Err(ParseError::truncated(Layer::Llc, ptr, self.payload.len(), buf.len() - ptr));
+        }
+        buf[ptr..ptr + self.payload.len()].copy_from_slice(self.payload);
+        Ok(ptr + self.payload.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STP_FRAME: [u8; 60] = [0x01, 0x80, 0xc2, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x00, 0x07,
+        0x42, 0x42, 0x03, 0x00, 0x00, 0x00, 0x80,
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+    const CDP_FRAME: [u8; 30] = [0x01, 0x00, 0x0c, 0xcc, 0xcc, 0xcc, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x00, 0x10,
+        0xaa, 0xaa, 0x03, 0x00, 0x00, 0x0c, 0x20, 0x00, 0x02, 0xb4, 0x12, 0x34, 0x00, 0x01, 0x00, 0x04];
+
+    #[test]
+    fn check_stp() {
+        let res = LlcPacket::try_from(&STP_FRAME[..]).unwrap();
+        assert_eq!(res, LlcPacket {
+            eth_header: ethernet::EthHeader {
+                dst_mac: ethernet::MacAddr([0x01, 0x80, 0xc2, 0, 0, 0]), src_mac: ethernet::MacAddr([1, 2, 3, 4, 5, 6]) },
+            vlans: None,
+            length: 7,
+            llc: LlcHeader { dsap: Saps::Stp, ssap: Saps::Stp, control: CONTROL_UI, snap: None },
+            payload: &[0x00, 0x00, 0x00, 0x80],
+        });
+        assert_eq!(res.protocol(), LlcProtocol::Stp);
+        assert_eq!(res.missing_bytes(), 0);
+    }
+    #[test]
+    fn check_snap() {
+        let res = LlcPacket::try_from(&CDP_FRAME[..]).unwrap();
+        assert_eq!(res.llc, LlcHeader { dsap: Saps::Snap, ssap: Saps::Snap, control: CONTROL_UI, snap: Some(Snap { oui: OUI_CISCO, pid: 0x2000 }) });
+        assert_eq!(res.payload, &CDP_FRAME[22..]);
+        assert_eq!(res.protocol(), LlcProtocol::Cdp);
+
+        let mut buf = CDP_FRAME;
+        buf[19..22].copy_from_slice(&[0x00, 0x08, 0x00]);
+        assert_eq!(LlcPacket::try_from(&buf[..]).unwrap().protocol(), LlcProtocol::Encapsulated(ethertype::EtherTypes::Ipv4));
+    }
+    #[test]
+    fn check_isis() {
+        let buf = [0x01u8, 0x80, 0xc2, 0x00, 0x00, 0x14, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x00, 0x05,
+            0xfe, 0xfe, 0x03, 0x83, 0x1b];
+        let res = LlcPacket::try_from(&buf[..]).unwrap();
+        assert_eq!(res.protocol(), LlcProtocol::IsIs);
+        assert_eq!(res.payload, &[0x83, 0x1b]);
+    }
+    #[test]
+    fn check_sequenced() {
+        let buf = [0xf0u8, 0xf1, 0x0a, 0x0c, 0x01];
+        assert_eq!(LlcHeader::try_from(&buf[..]), Ok(LlcHeader { dsap: Saps::NetBios, ssap: Sap(0xf1), control: Control::Sequenced(0x0a0c), snap: None }));
+        assert!(LlcHeader::try_from(&buf[..]).unwrap().is_response());
+        assert_eq!(LlcHeader::try_from(&buf[..3]), Err(ParseError::truncated(Layer::Llc, 0, 4, 3)));
+    }
+    #[test]
+    fn check_errors() {
+        assert_eq!(LlcPacket::try_from(&CDP_FRAME[..20]), Err(ParseError::truncated(Layer::Llc, 14, 8, 6)));
+        let mut buf = CDP_FRAME;
+        buf[12..14].copy_from_slice(&[0x08, 0x00]);
+        assert_eq!(LlcPacket::try_from(&buf[..]), Err(ParseError::new(Layer::Llc, 12, Reason::WrongEtherType(0x0800))));
+        buf[12..14].copy_from_slice(&[0x00, 0x04]);
+        assert_eq!(LlcPacket::try_from(&buf[..]), Err(ParseError::new(Layer::Llc, 12, Reason::BadField("length"))));
+        buf[12..14].copy_from_slice(&[0x00, 0x20]);
+        let mut res = LlcPacket::try_from(&buf[..]).unwrap();
+        assert_eq!(res.missing_bytes(), 16);
+        res.length = 2;
+        assert_eq!(res.missing_bytes(), 0);
+        for len in 0..CDP_FRAME.len() {
+            let _ = LlcPacket::try_from(&CDP_FRAME[..len]);
+        }
+    }
+    #[test]
+    fn check_emit() {
+        for frame in [&STP_FRAME[..21], &CDP_FRAME[..]].iter() {
+            let res = LlcPacket::try_from(*frame).unwrap();
+            let mut out = vec!();
+            assert_eq!(res.emit_vec(&mut out), Ok(frame.len()));
+            assert_eq!(&out[..], *frame);
+        }
+        let res = LlcPacket::try_from(&STP_FRAME[..]).unwrap();
+        assert_eq!(res.emit(&mut [0u8; 20]), Err(ParseError::truncated(Layer::Llc, 17, 4, 3)));
+        let hdr = LlcHeader { dsap: Saps::Stp, ssap: Saps::Stp, control: Control::Unnumbered(0x04), snap: None };
+        assert_eq!(hdr.emit(&mut [0u8; 3]), Err(ParseError::new(Layer::Llc, 2, Reason::BadField("control"))));
+    }
+}
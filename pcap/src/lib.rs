@@ -0,0 +1,444 @@
+extern crate byteorder;
+extern crate common;
+
+use std::convert::TryFrom;
+use std::io::{self, Read, Write};
+use std::time::Duration;
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use common::{Emit, Layer, ParseError, Reason};
+
+/// Link-layer header type of the captured frames, see `LinkTypes`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LinkType(pub u32);
+
+/// Values from https://www.tcpdump.org/linktypes.html
+#[allow(non_snake_case)]
+#[allow(non_upper_case_globals)]
+pub mod LinkTypes {
+    use ::LinkType;
+    /// BSD loopback, a 4-byte address family in the byte order of the capturing host
+    pub const Null: LinkType = LinkType(0);
+    /// IEEE 802.3 Ethernet
+    pub const Ethernet: LinkType = LinkType(1);
+    /// Raw IPv4 or IPv6, told apart by the version field
+    pub const Raw: LinkType = LinkType(101);
+    /// Linux cooked capture
+    pub const LinuxSll: LinkType = LinkType(113);
+}
+
+/// Magic number of files with microsecond timestamps
+pub const MAGIC_MICROS: u32 = 0xa1b2c3d4;
+/// Magic number of files with nanosecond timestamps
+pub const MAGIC_NANOS: u32 = 0xa1b23c4d;
+/// Records longer than this are taken as corrupt, whatever the snaplen
+pub const MAX_SNAPLEN: u32 = 262144;
+
+pub const HEADER_LEN: usize = 24;
+pub const RECORD_HEADER_LEN: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Precision {
+    Micros,
+    Nanos,
+}
+
+/// Global header of a pcap file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PcapHeader {
+    /// Fields are stored big endian, the file was written on such a host
+    pub big_endian: bool,
+    pub precision: Precision,
+    pub version_major: u16,
+    pub version_minor: u16,
+    /// Offset of the timestamps from UTC in seconds, 0 in practice
+    pub thiszone: i32,
+    pub sigfigs: u32,
+    /// Maximum number of bytes captured per packet
+    pub snaplen: u32,
+    pub link_type: LinkType,
+}
+
+impl PcapHeader {
+    /// Header of a version 2.4 file in the byte order of this host, a snaplen of 0
+    /// stands for `MAX_SNAPLEN`
+    pub fn new(link_type: LinkType, snaplen: u32, precision: Precision) -> PcapHeader {
+        PcapHeader {
+            big_endian: cfg!(target_endian = "big"),
+            precision,
+            version_major: 2,
+            version_minor: 4,
+            thiszone: 0,
+            sigfigs: 0,
+            snaplen: match snaplen {
+                0 => MAX_SNAPLEN,
+                snaplen => snaplen,
+            },
+            link_type,
+        }
+    }
+
+    fn write_u16(&self, buf: &mut [u8], val: u16) {
+        match self.big_endian {
+            true => BigEndian::write_u16(buf, val),
+            false => LittleEndian::write_u16(buf, val),
+        }
+    }
+
+    fn write_u32(&self, buf: &mut [u8], val: u32) {
+        match self.big_endian {
+            true => BigEndian::write_u32(buf, val),
+            false => LittleEndian::write_u32(buf, val),
+        }
+    }
+
+    fn read_u16(&self, buf: &[u8]) -> u16 {
+        match self.big_endian {
+            true => BigEndian::read_u16(buf),
+            false => LittleEndian::read_u16(buf),
+        }
+    }
+
+    fn read_u32(&self, buf: &[u8]) -> u32 {
+        match self.big_endian {
+            true => BigEndian::read_u32(buf),
+            false => LittleEndian::read_u32(buf),
+        }
+    }
+}
+
+impl <'a> TryFrom<&'a [u8]> for PcapHeader {
+    type Error = ParseError;
+
+    /// The upper 16 bits of the link type field, FCS information, are ignored
+    fn try_from(buf: &'a [u8]) -> Result<PcapHeader, ParseError> {
+        if buf.len() < HEADER_LEN {
+            return Err(ParseError::truncated(Layer::Pcap, 0, HEADER_LEN, buf.len()));
+        }
+        let (big_endian, precision) = match (LittleEndian::read_u32(buf), BigEndian::read_u32(buf)) {
+            (MAGIC_MICROS, _) => (false, Precision::Micros),
+            (MAGIC_NANOS, _) => (false, Precision::Nanos),
+            (_, MAGIC_MICROS) => (true, Precision::Micros),
+            (_, MAGIC_NANOS) => (true, Precision::Nanos),
+            _ => return Err(ParseError::new(Layer::Pcap, 0, Reason::BadField("magic"))),
+        };
+        let mut hdr = PcapHeader {
+            big_endian,
+            precision,
+            version_major: 0,
+            version_minor: 0,
+            thiszone: 0,
+            sigfigs: 0,
+            snaplen: 0,
+            link_type: LinkType(0),
+        };
+        hdr.version_major = hdr.read_u16(&buf[4..6]);
+        if hdr.version_major != 2 {
+            return Err(ParseError::new(Layer::Pcap, 4, Reason::BadVersion(hdr.version_major as u8)));
+        }
+        hdr.version_minor = hdr.read_u16(&buf[6..8]);
+        hdr.thiszone = hdr.read_u32(&buf[8..12]) as i32;
+        hdr.sigfigs = hdr.read_u32(&buf[12..16]);
+        hdr.snaplen = hdr.read_u32(&buf[16..20]);
+        hdr.link_type = LinkType(hdr.read_u32(&buf[20..24]) & 0xffff);
+        Ok(hdr)
+    }
+}
+
+impl Emit for PcapHeader {
+    fn emit_len(&self) -> usize {
+        HEADER_LEN
+    }
+
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        if buf.len() < HEADER_LEN {
+            return Err(ParseError::truncated(Layer::Pcap, 0, HEADER_LEN, buf.len()));
+        }
+        let magic = match self.precision {
+            Precision::Micros => MAGIC_MICROS,
+            Precision::Nanos => MAGIC_NANOS,
+        };
+        self.write_u32(&mut buf[0..4], magic);
+        self.write_u16(&mut buf[4..6], self.version_major);
+        self.write_u16(&mut buf[6..8], self.version_minor);
+        self.write_u32(&mut buf[8..12], self.thiszone as u32);
+        self.write_u32(&mut buf[12..16], self.sigfigs);
+        self.write_u32(&mut buf[16..20], self.snaplen);
+        self.write_u32(&mut buf[20..24], self.link_type.0);
+        Ok(HEADER_LEN)
+    }
+}
+
+/// Captured packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Record<'a> {
+    /// Time since the Unix epoch
+    pub timestamp: Duration,
+    /// Length of the packet on the wire
+    pub orig_len: u32,
+    /// Captured bytes, at most the snaplen
+    pub data: &'a [u8],
+}
+
+impl <'a> Record<'a> {
+    /// Number of bytes of the packet that were not captured
+    pub fn missing_bytes(&self) -> usize {
+        (self.orig_len as usize).saturating_sub(self.data.len())
+    }
+}
+
+/// Read into `buf` until it is full or the input ends, returns the number of bytes read
+pub fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut n = 0;
+    while n < buf.len() {
+        match reader.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(len) => n += len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(n)
+}
+
+/// Malformed files are reported as `InvalidData` errors wrapping the `ParseError`,
+/// its offset counts from the start of the file
+pub fn invalid(e: ParseError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Reads the records of a pcap file one at a time
+pub struct PcapReader<R: Read> {
+    reader: R,
+    header: PcapHeader,
+    buf: Vec<u8>,
+    /// File offset of the next record
+    offset: usize,
+}
+
+impl <R: Read> PcapReader<R> {
+    /// Read the file header
+    pub fn new(mut reader: R) -> io::Result<PcapReader<R>> {
+        let mut buf = [0u8; HEADER_LEN];
+        let n = read_full(&mut reader, &mut buf)?;
+        let header = PcapHeader::try_from(&buf[..n]).map_err(invalid)?;
+        Ok(PcapReader { reader, header, buf: Vec::new(), offset: HEADER_LEN })
+    }
+
+    pub fn header(&self) -> &PcapHeader {
+        &self.header
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Next record, `None` at the end of the file. A record cut by the end of the
+    /// file is an error.
+    pub fn next_record(&mut self) -> io::Result<Option<Record<'_>>> {
+        let mut buf = [0u8; RECORD_HEADER_LEN];
+        match read_full(&mut self.reader, &mut buf)? {
+            0 => return Ok(None),
+            n if n < RECORD_HEADER_LEN => return Err(invalid(ParseError::truncated(Layer::Pcap, self.offset, RECORD_HEADER_LEN, n))),
+            _ => {},
+        }
+        let hdr = &self.header;
+        let ts_sec = hdr.read_u32(&buf[0..4]);
+        let ts_frac = hdr.read_u32(&buf[4..8]);
+        let caplen = hdr.read_u32(&buf[8..12]);
+        let orig_len = hdr.read_u32(&buf[12..16]);
+        if caplen > MAX_SNAPLEN {
+            return Err(invalid(ParseError::new(Layer::Pcap, self.offset + 8, Reason::BadField("caplen"))));
+        }
+        let nanos = match hdr.precision {
+            Precision::Micros => ts_frac as u64 * 1000,
+            Precision::Nanos => ts_frac as u64,
+        };
+        let timestamp = Duration::from_secs(ts_sec as u64) + Duration::from_nanos(nanos);
+
+        let caplen = caplen as usize;
+        self.buf.resize(caplen, 0);
+        let n = read_full(&mut self.reader, &mut self.buf)?;
+        if n < caplen {
+            return Err(invalid(ParseError::truncated(Layer::Pcap, self.offset + RECORD_HEADER_LEN, caplen, n)));
+        }
+        self.offset += RECORD_HEADER_LEN + caplen;
+        Ok(Some(Record { timestamp, orig_len, data: &self.buf }))
+    }
+}
+
+/// Writes pcap files
+pub struct PcapWriter<W: Write> {
+    writer: W,
+    header: PcapHeader,
+}
+
+impl <W: Write> PcapWriter<W> {
+    /// Write the file header, its precision and snaplen apply to every record.
+    /// A snaplen of 0 is written as `MAX_SNAPLEN`.
+    pub fn new(mut writer: W, mut header: PcapHeader) -> io::Result<PcapWriter<W>> {
+        if header.snaplen == 0 {
+            header.snaplen = MAX_SNAPLEN;
+        }
+        let mut buf = [0u8; HEADER_LEN];
+        header.emit(&mut buf).map_err(invalid)?;
+        writer.write_all(&buf)?;
+        Ok(PcapWriter { writer, header })
+    }
+
+    pub fn header(&self) -> &PcapHeader {
+        &self.header
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Write a frame captured at `timestamp` that was `orig_len` bytes long on the wire.
+    /// `data` beyond the snaplen or `MAX_SNAPLEN` is cut, `orig_len` below its length is raised to it.
+    pub fn write_packet(&mut self, timestamp: Duration, orig_len: u32, data: &[u8]) -> io::Result<()> {
+        let hdr = &self.header;
+        if timestamp.as_secs() > u32::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, ParseError::new(Layer::Pcap, 0, Reason::BadField("timestamp"))));
+        }
+        let caplen = data.len().min(hdr.snaplen.min(MAX_SNAPLEN) as usize);
+        let frac = match hdr.precision {
+            Precision::Micros => timestamp.subsec_micros(),
+            Precision::Nanos => timestamp.subsec_nanos(),
+        };
+        let mut buf = [0u8; RECORD_HEADER_LEN];
+        hdr.write_u32(&mut buf[0..4], timestamp.as_secs() as u32);
+        hdr.write_u32(&mut buf[4..8], frac);
+        hdr.write_u32(&mut buf[8..12], caplen as u32);
+        hdr.write_u32(&mut buf[12..16], orig_len.max(data.len() as u32));
+        self.writer.write_all(&buf)?;
+        self.writer.write_all(&data[..caplen])
+    }
+
+    /// Write a record read from another file
+    pub fn write_record(&mut self, record: &Record) -> io::Result<()> {
+        self.write_packet(record.timestamp, record.orig_len, record.data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// File with the given magic and records of (seconds, fraction, orig_len, data)
+    fn file(big_endian: bool, magic: u32, records: &[(u32, u32, u32, &[u8])]) -> Vec<u8> {
+        let version = match big_endian {
+            true => 0x0002_0004,
+            false => 0x0004_0002,
+        };
+        let mut buf = Vec::new();
+        let put = |buf: &mut Vec<u8>, v: u32| match big_endian {
+            true => buf.extend_from_slice(&v.to_be_bytes()),
+            false => buf.extend_from_slice(&v.to_le_bytes()),
+        };
+        for &w in &[magic, version, 0, 0, 96, 1] {
+            put(&mut buf, w);
+        }
+        for &(sec, frac, orig_len, data) in records {
+            for &w in &[sec, frac, data.len() as u32, orig_len] {
+                put(&mut buf, w);
+            }
+            buf.extend_from_slice(data);
+        }
+        buf
+    }
+
+    #[test]
+    fn test_header() {
+        let buf = file(false, MAGIC_MICROS, &[]);
+        let hdr = PcapHeader::try_from(&buf[..]).unwrap();
+        assert_eq!(hdr, PcapHeader {
+            big_endian: false, precision: Precision::Micros, version_major: 2, version_minor: 4,
+            thiszone: 0, sigfigs: 0, snaplen: 96, link_type: LinkTypes::Ethernet,
+        });
+        let buf = file(true, MAGIC_NANOS, &[]);
+        let hdr = PcapHeader::try_from(&buf[..]).unwrap();
+        assert_eq!((hdr.big_endian, hdr.precision, hdr.version_minor, hdr.snaplen), (true, Precision::Nanos, 4, 96));
+
+        assert_eq!(PcapHeader::try_from(&buf[..20]), Err(ParseError::truncated(Layer::Pcap, 0, 24, 20)));
+        let mut bad = buf.clone();
+        bad[0] = 0x0a;
+        assert_eq!(PcapHeader::try_from(&bad[..]), Err(ParseError::new(Layer::Pcap, 0, Reason::BadField("magic"))));
+        bad = buf.clone();
+        bad[5] = 1;
+        assert_eq!(PcapHeader::try_from(&bad[..]), Err(ParseError::new(Layer::Pcap, 4, Reason::BadVersion(1))));
+    }
+
+    #[test]
+    fn test_records() {
+        for &(big_endian, magic, frac) in &[(false, MAGIC_MICROS, 250_000), (true, MAGIC_MICROS, 250_000), (true, MAGIC_NANOS, 250_000_000)] {
+            let buf = file(big_endian, magic, &[(10, frac, 3, b"abc"), (11, 0, 1514, b"defg")]);
+            let mut r = PcapReader::new(&buf[..]).unwrap();
+            assert_eq!(r.header().link_type, LinkTypes::Ethernet);
+            assert_eq!(r.next_record().unwrap(), Some(Record { timestamp: Duration::from_millis(10250), orig_len: 3, data: b"abc" }));
+            let rec = r.next_record().unwrap().unwrap();
+            assert_eq!((rec.timestamp, rec.data, rec.missing_bytes()), (Duration::from_secs(11), &b"defg"[..], 1510));
+            assert_eq!(r.next_record().unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_write() {
+        for &(big_endian, precision) in &[(false, Precision::Micros), (true, Precision::Nanos)] {
+            let header = PcapHeader { big_endian, ..PcapHeader::new(LinkTypes::Raw, 4, precision) };
+            let mut w = PcapWriter::new(Vec::new(), header).unwrap();
+            w.write_packet(Duration::new(7, 123_456_789), 60, b"abcdef").unwrap();
+            w.write_record(&Record { timestamp: Duration::from_secs(8), orig_len: 0, data: b"gh" }).unwrap();
+            let err = w.write_packet(Duration::from_secs(1 << 32), 1, b"i").unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            let file = w.into_inner();
+            assert_eq!(file.len(), HEADER_LEN + 2 * RECORD_HEADER_LEN + 6);
+
+            let mut r = PcapReader::new(&file[..]).unwrap();
+            assert_eq!(r.header(), &header);
+            let nanos = match precision {
+                Precision::Micros => 123_456_000,
+                Precision::Nanos => 123_456_789,
+            };
+            assert_eq!(r.next_record().unwrap(), Some(Record { timestamp: Duration::new(7, nanos), orig_len: 60, data: b"abcd" }));
+            assert_eq!(r.next_record().unwrap(), Some(Record { timestamp: Duration::from_secs(8), orig_len: 2, data: b"gh" }));
+            assert_eq!(r.next_record().unwrap(), None);
+        }
+        let header = PcapHeader::new(LinkTypes::Ethernet, 65535, Precision::Micros);
+        assert_eq!(header.emit(&mut [0u8; 23]), Err(ParseError::truncated(Layer::Pcap, 0, 24, 23)));
+        let mut buf = Vec::new();
+        header.emit_vec(&mut buf).unwrap();
+        assert_eq!(PcapHeader::try_from(&buf[..]), Ok(header));
+
+        assert_eq!(PcapHeader::new(LinkTypes::Ethernet, 0, Precision::Micros).snaplen, MAX_SNAPLEN);
+        let w = PcapWriter::new(Vec::new(), PcapHeader { snaplen: 0, ..header }).unwrap();
+        assert_eq!(PcapReader::new(&w.into_inner()[..]).unwrap().header().snaplen, MAX_SNAPLEN);
+    }
+
+    #[test]
+    fn test_malformed() {
+        let err = PcapReader::new(&[0xd4u8, 0xc3, 0xb2][..]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.get_ref().unwrap().downcast_ref(), Some(&ParseError::truncated(Layer::Pcap, 0, 24, 3)));
+
+        let buf = file(false, MAGIC_MICROS, &[(1, 0, 3, b"abc"), (2, 0, 3, b"def")]);
+        let parse_error = |len: usize| {
+            let mut r = PcapReader::new(&buf[..len]).unwrap();
+            r.next_record().unwrap();
+            let err = r.next_record().unwrap_err();
+            *err.get_ref().unwrap().downcast_ref::<ParseError>().unwrap()
+        };
+        assert_eq!(parse_error(50), ParseError::truncated(Layer::Pcap, 43, 16, 7));
+        assert_eq!(parse_error(61), ParseError::truncated(Layer::Pcap, 59, 3, 2));
+
+        let mut buf = file(false, MAGIC_MICROS, &[(1, 0, 3, b"abc")]);
+        buf[32..36].copy_from_slice(&(MAX_SNAPLEN + 1).to_le_bytes());
+        buf[16..20].copy_from_slice(&u32::MAX.to_le_bytes());
+        let mut r = PcapReader::new(&buf[..]).unwrap();
+        let err = r.next_record().unwrap_err();
+        assert_eq!(err.get_ref().unwrap().downcast_ref(), Some(&ParseError::new(Layer::Pcap, 32, Reason::BadField("caplen"))));
+    }
+}
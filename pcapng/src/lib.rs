@@ -0,0 +1,849 @@
+extern crate byteorder;
+extern crate common;
+extern crate pcap;
+
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::time::Duration;
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use common::{Layer, ParseError, Reason};
+use pcap::{invalid, read_full, LinkType};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BlockType(pub u32);
+
+#[allow(non_snake_case)]
+#[allow(non_upper_case_globals)]
+pub mod BlockTypes {
+    use ::BlockType;
+    pub const SectionHeader: BlockType = BlockType(0x0a0d0d0a);
+    pub const InterfaceDescription: BlockType = BlockType(1);
+    pub const SimplePacket: BlockType = BlockType(3);
+    pub const NameResolution: BlockType = BlockType(4);
+    pub const InterfaceStatistics: BlockType = BlockType(5);
+    pub const EnhancedPacket: BlockType = BlockType(6);
+}
+
+/// Option codes, the meaning of most depends on the block type
+#[allow(non_snake_case)]
+pub mod OptionCodes {
+    pub const END_OF_OPT: u16 = 0;
+    /// UTF-8 comment, allowed in every block
+    pub const COMMENT: u16 = 1;
+    pub const SHB_HARDWARE: u16 = 2;
+    pub const SHB_OS: u16 = 3;
+    pub const SHB_USERAPPL: u16 = 4;
+    pub const IF_NAME: u16 = 2;
+    pub const IF_DESCRIPTION: u16 = 3;
+    /// Timestamp resolution, a negative power of 10, or of 2 when the top bit is set
+    pub const IF_TSRESOL: u16 = 9;
+    pub const EPB_FLAGS: u16 = 2;
+}
+
+const BYTE_ORDER_MAGIC: u32 = 0x1a2b3c4d;
+/// Blocks longer than this are taken as corrupt
+pub const MAX_BLOCK_LEN: usize = 16 << 20;
+
+/// Option of a block, the value is kept in the byte order of the file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockOption {
+    pub code: u16,
+    pub value: Vec<u8>,
+}
+
+impl BlockOption {
+    pub fn comment(text: &str) -> BlockOption {
+        BlockOption { code: OptionCodes::COMMENT, value: text.as_bytes().to_vec() }
+    }
+
+    /// Value of a string option, `None` if it is not UTF-8
+    pub fn as_str(&self) -> Option<&str> {
+        std::str::from_utf8(&self.value).ok()
+    }
+}
+
+/// Comment options of a block
+pub fn comments(options: &[BlockOption]) -> Vec<&str> {
+    options.iter().filter(|o| o.code == OptionCodes::COMMENT).filter_map(|o| o.as_str()).collect()
+}
+
+fn find_str(options: &[BlockOption], code: u16) -> Option<&str> {
+    options.iter().find(|o| o.code == code).and_then(|o| o.as_str())
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SectionHeader {
+    /// Fields of the section are stored big endian
+    pub big_endian: bool,
+    pub version_major: u16,
+    pub version_minor: u16,
+    /// Bytes of the section after this block, -1 when unknown
+    pub section_length: i64,
+    pub options: Vec<BlockOption>,
+}
+
+impl Default for SectionHeader {
+    fn default() -> SectionHeader {
+        SectionHeader { big_endian: false, version_major: 1, version_minor: 0, section_length: -1, options: Vec::new() }
+    }
+}
+
+impl SectionHeader {
+    /// Capture application, `shb_userappl`
+    pub fn application(&self) -> Option<&str> {
+        find_str(&self.options, OptionCodes::SHB_USERAPPL)
+    }
+}
+
+/// Interface Description block, interfaces are numbered from 0 in each section
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Interface {
+    pub link_type: LinkType,
+    /// Maximum number of bytes captured per packet, 0 for no limit
+    pub snaplen: u32,
+    pub options: Vec<BlockOption>,
+}
+
+impl Interface {
+    pub fn new(link_type: LinkType, snaplen: u32) -> Interface {
+        Interface { link_type, snaplen, options: Vec::new() }
+    }
+
+    /// `if_name`
+    pub fn name(&self) -> Option<&str> {
+        find_str(&self.options, OptionCodes::IF_NAME)
+    }
+
+    /// Timestamp units per second given by `if_tsresol`, microseconds by default
+    pub fn units_per_second(&self) -> u64 {
+        let resol = self.options.iter().find(|o| o.code == OptionCodes::IF_TSRESOL && o.value.len() == 1).map(|o| o.value[0]);
+        match resol {
+            Some(r) if r & 0x80 != 0 && r & 0x7f < 64 => 1 << (r & 0x7f),
+            Some(r) if r < 20 => 10u64.pow(r as u32),
+            _ => 1_000_000,
+        }
+    }
+
+    /// Time since the Unix epoch of a timestamp in the units of the interface
+    pub fn timestamp(&self, ts: u64) -> Duration {
+        let units = self.units_per_second();
+        let nanos = (ts % units) as u128 * 1_000_000_000 / units as u128;
+        Duration::new(ts / units, nanos as u32)
+    }
+
+    /// Timestamp in the units of the interface, sub-unit time is truncated
+    pub fn units(&self, time: Duration) -> u64 {
+        let units = self.units_per_second() as u128;
+        (time.as_secs() as u128 * units + time.subsec_nanos() as u128 * units / 1_000_000_000) as u64
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NameRecord {
+    Ipv4 { addr: Ipv4Addr, names: Vec<String> },
+    Ipv6 { addr: Ipv6Addr, names: Vec<String> },
+    Other { record_type: u16, value: Vec<u8> },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NameResolution {
+    pub records: Vec<NameRecord>,
+    pub options: Vec<BlockOption>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EnhancedPacket<'a> {
+    pub interface_id: u32,
+    /// In the units of the interface, see `Interface::timestamp`
+    pub timestamp: u64,
+    /// Length of the packet on the wire
+    pub orig_len: u32,
+    pub data: &'a [u8],
+    pub options: Vec<BlockOption>,
+}
+
+/// Packet of interface 0 without timestamp
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SimplePacket<'a> {
+    pub orig_len: u32,
+    pub data: &'a [u8],
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Block<'a> {
+    SectionHeader(SectionHeader),
+    InterfaceDescription(Interface),
+    EnhancedPacket(EnhancedPacket<'a>),
+    SimplePacket(SimplePacket<'a>),
+    NameResolution(NameResolution),
+    /// Interface Statistics and other blocks, the body in the byte order of the section
+    Other { block_type: BlockType, body: &'a [u8] },
+}
+
+/// Packet of an Enhanced or Simple Packet block with its interface resolved
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record<'a> {
+    pub interface_id: u32,
+    pub link_type: LinkType,
+    /// Time since the Unix epoch, `None` for Simple Packet blocks
+    pub timestamp: Option<Duration>,
+    pub orig_len: u32,
+    pub data: &'a [u8],
+    pub options: Vec<BlockOption>,
+}
+
+impl <'a> Record<'a> {
+    pub fn comments(&self) -> Vec<&str> {
+        comments(&self.options)
+    }
+}
+
+fn read_u16(big_endian: bool, buf: &[u8]) -> u16 {
+    match big_endian {
+        true => BigEndian::read_u16(buf),
+        false => LittleEndian::read_u16(buf),
+    }
+}
+
+fn read_u32(big_endian: bool, buf: &[u8]) -> u32 {
+    match big_endian {
+        true => BigEndian::read_u32(buf),
+        false => LittleEndian::read_u32(buf),
+    }
+}
+
+fn put_u16(big_endian: bool, out: &mut Vec<u8>, v: u16) {
+    match big_endian {
+        true => out.extend_from_slice(&v.to_be_bytes()),
+        false => out.extend_from_slice(&v.to_le_bytes()),
+    }
+}
+
+fn put_u32(big_endian: bool, out: &mut Vec<u8>, v: u32) {
+    match big_endian {
+        true => out.extend_from_slice(&v.to_be_bytes()),
+        false => out.extend_from_slice(&v.to_le_bytes()),
+    }
+}
+
+/// `len` rounded up to the 32-bit alignment of pcapng fields
+fn padded(len: usize) -> usize {
+    len.div_ceil(4) * 4
+}
+
+fn pad(out: &mut Vec<u8>) {
+    let len = padded(out.len());
+    out.resize(len, 0);
+}
+
+/// Options up to `opt_endofopt` or the end of `buf`, `offset` is the file offset of `buf`
+fn parse_options(big_endian: bool, buf: &[u8], offset: usize) -> Result<Vec<BlockOption>, ParseError> {
+    let mut options = Vec::new();
+    let mut pos = 0;
+    while pos + 4 <= buf.len() {
+        let code = read_u16(big_endian, &buf[pos..]);
+        let len = read_u16(big_endian, &buf[pos + 2..]) as usize;
+        if code == OptionCodes::END_OF_OPT {
+            break;
+        }
+        if pos + 4 + len > buf.len() {
+            return Err(ParseError::truncated(Layer::Pcap, offset + pos, 4 + len, buf.len() - pos));
+        }
+        options.push(BlockOption { code, value: buf[pos + 4..pos + 4 + len].to_vec() });
+        pos += 4 + padded(len);
+    }
+    Ok(options)
+}
+
+fn put_options(big_endian: bool, out: &mut Vec<u8>, options: &[BlockOption]) -> Result<(), ParseError> {
+    if options.is_empty() {
+        return Ok(());
+    }
+    for option in options {
+        if option.code == OptionCodes::END_OF_OPT || option.value.len() > u16::MAX as usize {
+            return Err(ParseError::new(Layer::Pcap, 0, Reason::BadField("options")));
+        }
+        put_u16(big_endian, out, option.code);
+        put_u16(big_endian, out, option.value.len() as u16);
+        out.extend_from_slice(&option.value);
+        pad(out);
+    }
+    put_u32(big_endian, out, 0);
+    Ok(())
+}
+
+/// Zero-terminated names following the address of a name record
+fn parse_names(buf: &[u8]) -> Vec<String> {
+    buf.split(|&b| b == 0).filter(|n| !n.is_empty()).map(|n| String::from_utf8_lossy(n).into_owned()).collect()
+}
+
+fn parse_section_header(big_endian: bool, body: &[u8], offset: usize) -> Result<SectionHeader, ParseError> {
+    if body.len() < 16 {
+        return Err(ParseError::truncated(Layer::Pcap, offset, 16, body.len()));
+    }
+    let version_major = read_u16(big_endian, &body[4..]);
+    if version_major != 1 {
+        return Err(ParseError::new(Layer::Pcap, offset + 4, Reason::BadVersion(version_major as u8)));
+    }
+    let section_length = match big_endian {
+        true => BigEndian::read_i64(&body[8..16]),
+        false => LittleEndian::read_i64(&body[8..16]),
+    };
+    Ok(SectionHeader {
+        big_endian,
+        version_major,
+        version_minor: read_u16(big_endian, &body[6..]),
+        section_length,
+        options: parse_options(big_endian, &body[16..], offset + 16)?,
+    })
+}
+
+fn parse_interface(big_endian: bool, body: &[u8], offset: usize) -> Result<Interface, ParseError> {
+    if body.len() < 8 {
+        return Err(ParseError::truncated(Layer::Pcap, offset, 8, body.len()));
+    }
+    Ok(Interface {
+        link_type: LinkType(read_u16(big_endian, body) as u32),
+        snaplen: read_u32(big_endian, &body[4..]),
+        options: parse_options(big_endian, &body[8..], offset + 8)?,
+    })
+}
+
+fn parse_name_resolution(big_endian: bool, body: &[u8], offset: usize) -> Result<NameResolution, ParseError> {
+    let mut records = Vec::new();
+    let mut pos = 0;
+    loop {
+        if pos + 4 > body.len() {
+            return Err(ParseError::truncated(Layer::Pcap, offset + pos, 4, body.len() - pos));
+        }
+        let record_type = read_u16(big_endian, &body[pos..]);
+        let len = read_u16(big_endian, &body[pos + 2..]) as usize;
+        if record_type == 0 {
+            break;
+        }
+        if pos + 4 + len > body.len() {
+            return Err(ParseError::truncated(Layer::Pcap, offset + pos, 4 + len, body.len() - pos));
+        }
+        let value = &body[pos + 4..pos + 4 + len];
+        records.push(match record_type {
+            1 if len >= 4 => {
+                let mut a = [0u8; 4];
+                a.copy_from_slice(&value[..4]);
+                NameRecord::Ipv4 { addr: Ipv4Addr::from(a), names: parse_names(&value[4..]) }
+            },
+            2 if len >= 16 => {
+                let mut a = [0u8; 16];
+                a.copy_from_slice(&value[..16]);
+                NameRecord::Ipv6 { addr: Ipv6Addr::from(a), names: parse_names(&value[16..]) }
+            },
+            1 | 2 => return Err(ParseError::new(Layer::Pcap, offset + pos + 2, Reason::BadField("record_length"))),
+            _ => NameRecord::Other { record_type, value: value.to_vec() },
+        });
+        pos += 4 + padded(len);
+    }
+    let options = parse_options(big_endian, &body[pos + 4..], offset + pos + 4)?;
+    Ok(NameResolution { records, options })
+}
+
+fn parse_enhanced_packet(big_endian: bool, body: &[u8], offset: usize) -> Result<EnhancedPacket<'_>, ParseError> {
+    if body.len() < 20 {
+        return Err(ParseError::truncated(Layer::Pcap, offset, 20, body.len()));
+    }
+    let caplen = read_u32(big_endian, &body[12..]) as usize;
+    if caplen > body.len() - 20 {
+        return Err(ParseError::truncated(Layer::Pcap, offset + 20, caplen, body.len() - 20));
+    }
+    let options_at = (20 + padded(caplen)).min(body.len());
+    Ok(EnhancedPacket {
+        interface_id: read_u32(big_endian, body),
+        timestamp: (read_u32(big_endian, &body[4..]) as u64) << 32 | read_u32(big_endian, &body[8..]) as u64,
+        orig_len: read_u32(big_endian, &body[16..]),
+        data: &body[20..20 + caplen],
+        options: parse_options(big_endian, &body[options_at..], offset + options_at)?,
+    })
+}
+
+/// Reads the blocks of a pcapng file one at a time, keeping track of the current
+/// section and of its interfaces
+pub struct PcapNgReader<R: Read> {
+    reader: R,
+    section: SectionHeader,
+    interfaces: Vec<Interface>,
+    /// Last block read, its body is in `buf`
+    block_type: BlockType,
+    buf: Vec<u8>,
+    /// File offset of the body of the last block
+    body_offset: usize,
+    /// File offset of the next block
+    offset: usize,
+}
+
+impl <R: Read> PcapNgReader<R> {
+    /// Read the Section Header block the file starts with
+    pub fn new(reader: R) -> io::Result<PcapNgReader<R>> {
+        let mut r = PcapNgReader {
+            reader,
+            section: SectionHeader::default(),
+            interfaces: Vec::new(),
+            block_type: BlockType(0),
+            buf: Vec::new(),
+            body_offset: 0,
+            offset: 0,
+        };
+        let first = r.read_block()?;
+        if first != Some(BlockTypes::SectionHeader) {
+            let e = match first {
+                Some(_) => ParseError::new(Layer::Pcap, 0, Reason::BadField("block_type")),
+                None => ParseError::truncated(Layer::Pcap, 0, 28, 0),
+            };
+            return Err(invalid(e));
+        }
+        Ok(r)
+    }
+
+    /// Section Header of the current section
+    pub fn section(&self) -> &SectionHeader {
+        &self.section
+    }
+
+    /// Interfaces of the current section described so far
+    pub fn interfaces(&self) -> &[Interface] {
+        &self.interfaces
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Read the next block into `buf`, updating the section and interfaces.
+    /// Returns its type, `None` at the end of the file.
+    fn read_block(&mut self) -> io::Result<Option<BlockType>> {
+        let offset = self.offset;
+        let mut hdr = [0u8; 12];
+        match read_full(&mut self.reader, &mut hdr[..8])? {
+            0 => return Ok(None),
+            n if n < 8 => return Err(invalid(ParseError::truncated(Layer::Pcap, offset, 12, n))),
+            _ => {},
+        }
+        // The type of Section Header blocks reads the same in both byte orders,
+        // their byte-order magic tells the order of the new section
+        let mut big_endian = self.section.big_endian;
+        let shb = LittleEndian::read_u32(&hdr) == BlockTypes::SectionHeader.0;
+        let mut read = 0;
+        if shb {
+            read = read_full(&mut self.reader, &mut hdr[8..12])?;
+            if read < 4 {
+                return Err(invalid(ParseError::truncated(Layer::Pcap, offset, 12, 8 + read)));
+            }
+            big_endian = match LittleEndian::read_u32(&hdr[8..12]) {
+                BYTE_ORDER_MAGIC => false,
+                m if m.swap_bytes() == BYTE_ORDER_MAGIC => true,
+                _ => return Err(invalid(ParseError::new(Layer::Pcap, offset + 8, Reason::BadField("byte_order_magic")))),
+            };
+        }
+        let total = read_u32(big_endian, &hdr[4..8]) as usize;
+        if total < 12 + read || !total.is_multiple_of(4) || total > MAX_BLOCK_LEN {
+            return Err(invalid(ParseError::new(Layer::Pcap, offset + 4, Reason::BadField("block_length"))));
+        }
+
+        self.buf.clear();
+        self.buf.extend_from_slice(&hdr[8..8 + read]);
+        self.buf.resize(total - 8, 0);
+        let n = read_full(&mut self.reader, &mut self.buf[read..])?;
+        if read + n < total - 8 {
+            return Err(invalid(ParseError::truncated(Layer::Pcap, offset, total, 8 + read + n)));
+        }
+        let trailer = self.buf.len() - 4;
+        if read_u32(big_endian, &self.buf[trailer..]) as usize != total {
+            return Err(invalid(ParseError::new(Layer::Pcap, offset + total - 4, Reason::BadField("block_length"))));
+        }
+        self.buf.truncate(trailer);
+        self.block_type = BlockType(read_u32(big_endian, &hdr));
+        self.body_offset = offset + 8;
+        self.offset += total;
+
+        if shb {
+            self.section = parse_section_header(big_endian, &self.buf, self.body_offset).map_err(invalid)?;
+            self.interfaces.clear();
+        } else if self.block_type == BlockTypes::InterfaceDescription {
+            let interface = parse_interface(big_endian, &self.buf, self.body_offset).map_err(invalid)?;
+            self.interfaces.push(interface);
+        }
+        Ok(Some(self.block_type))
+    }
+
+    /// Decode the block in `buf`
+    fn block(&self) -> Result<Block<'_>, ParseError> {
+        let (big_endian, body, offset) = (self.section.big_endian, &self.buf[..], self.body_offset);
+        match self.block_type {
+            BlockTypes::SectionHeader => Ok(Block::SectionHeader(self.section.clone())),
+            BlockTypes::InterfaceDescription => Ok(Block::InterfaceDescription(self.interfaces[self.interfaces.len() - 1].clone())),
+            BlockTypes::EnhancedPacket => {
+                let packet = parse_enhanced_packet(big_endian, body, offset)?;
+                if packet.interface_id as usize >= self.interfaces.len() {
+                    return Err(ParseError::new(Layer::Pcap, offset, Reason::BadField("interface_id")));
+                }
+                Ok(Block::EnhancedPacket(packet))
+            },
+            BlockTypes::SimplePacket => {
+                let interface = match self.interfaces.first() {
+                    Some(interface) => interface,
+                    None => return Err(ParseError::new(Layer::Pcap, offset, Reason::BadField("interface_id"))),
+                };
+                if body.len() < 4 {
+                    return Err(ParseError::truncated(Layer::Pcap, offset, 4, body.len()));
+                }
+                let orig_len = read_u32(big_endian, body);
+                let mut caplen = (orig_len as usize).min(body.len() - 4);
+                if interface.snaplen != 0 {
+                    caplen = caplen.min(interface.snaplen as usize);
+                }
+                Ok(Block::SimplePacket(SimplePacket { orig_len, data: &body[4..4 + caplen] }))
+            },
+            BlockTypes::NameResolution => Ok(Block::NameResolution(parse_name_resolution(big_endian, body, offset)?)),
+            block_type => Ok(Block::Other { block_type, body }),
+        }
+    }
+
+    /// Next block, `None` at the end of the file. The first Section Header block is
+    /// read by `new`, see `section`.
+    pub fn next_block(&mut self) -> io::Result<Option<Block<'_>>> {
+        match self.read_block()? {
+            Some(_) => self.block().map(Some).map_err(invalid),
+            None => Ok(None),
+        }
+    }
+
+    /// Next Enhanced or Simple Packet block, `None` at the end of the file.
+    /// The other blocks are skipped, their metadata is available from `section` and `interfaces`.
+    pub fn next_packet(&mut self) -> io::Result<Option<Record<'_>>> {
+        loop {
+            match self.read_block()? {
+                Some(t) if t == BlockTypes::EnhancedPacket || t == BlockTypes::SimplePacket => break,
+                Some(_) => {},
+                None => return Ok(None),
+            }
+        }
+        match self.block().map_err(invalid)? {
+            Block::EnhancedPacket(p) => {
+                let interface = &self.interfaces[p.interface_id as usize];
+                Ok(Some(Record {
+                    interface_id: p.interface_id,
+                    link_type: interface.link_type,
+                    timestamp: Some(interface.timestamp(p.timestamp)),
+                    orig_len: p.orig_len,
+                    data: p.data,
+                    options: p.options,
+                }))
+            },
+            Block::SimplePacket(p) => Ok(Some(Record {
+                interface_id: 0,
+                link_type: self.interfaces[0].link_type,
+                timestamp: None,
+                orig_len: p.orig_len,
+                data: p.data,
+                options: Vec::new(),
+            })),
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// Body of `block` in the given byte order
+fn encode(big_endian: bool, block: &Block) -> Result<Vec<u8>, ParseError> {
+    let mut out = Vec::new();
+    match *block {
+        Block::SectionHeader(ref s) => {
+            put_u32(big_endian, &mut out, BYTE_ORDER_MAGIC);
+            put_u16(big_endian, &mut out, s.version_major);
+            put_u16(big_endian, &mut out, s.version_minor);
+            match big_endian {
+                true => out.extend_from_slice(&s.section_length.to_be_bytes()),
+                false => out.extend_from_slice(&s.section_length.to_le_bytes()),
+            }
+            put_options(big_endian, &mut out, &s.options)?;
+        },
+        Block::InterfaceDescription(ref i) => {
+            if i.link_type.0 > u16::MAX as u32 {
+                return Err(ParseError::new(Layer::Pcap, 0, Reason::BadField("link_type")));
+            }
+            put_u16(big_endian, &mut out, i.link_type.0 as u16);
+            put_u16(big_endian, &mut out, 0);
+            put_u32(big_endian, &mut out, i.snaplen);
+            put_options(big_endian, &mut out, &i.options)?;
+        },
+        Block::EnhancedPacket(ref p) => {
+            put_u32(big_endian, &mut out, p.interface_id);
+            put_u32(big_endian, &mut out, (p.timestamp >> 32) as u32);
+            put_u32(big_endian, &mut out, p.timestamp as u32);
+            put_u32(big_endian, &mut out, p.data.len() as u32);
+            put_u32(big_endian, &mut out, p.orig_len);
+            out.extend_from_slice(p.data);
+            pad(&mut out);
+            put_options(big_endian, &mut out, &p.options)?;
+        },
+        Block::SimplePacket(ref p) => {
+            put_u32(big_endian, &mut out, p.orig_len);
+            out.extend_from_slice(p.data);
+            pad(&mut out);
+        },
+        Block::NameResolution(ref n) => {
+            for record in &n.records {
+                let (record_type, mut value, names): (u16, Vec<u8>, &[String]) = match *record {
+                    NameRecord::Ipv4 { ref addr, ref names } => (1, addr.octets().to_vec(), names),
+                    NameRecord::Ipv6 { ref addr, ref names } => (2, addr.octets().to_vec(), names),
+                    NameRecord::Other { record_type, ref value } => (record_type, value.clone(), &[]),
+                };
+                for name in names {
+                    value.extend_from_slice(name.as_bytes());
+                    value.push(0);
+                }
+                if record_type == 0 || value.len() > u16::MAX as usize {
+                    return Err(ParseError::new(Layer::Pcap, 0, Reason::BadField("records")));
+                }
+                put_u16(big_endian, &mut out, record_type);
+                put_u16(big_endian, &mut out, value.len() as u16);
+                out.extend_from_slice(&value);
+                pad(&mut out);
+            }
+            put_u32(big_endian, &mut out, 0);
+            put_options(big_endian, &mut out, &n.options)?;
+        },
+        Block::Other { body, .. } => {
+            out.extend_from_slice(body);
+            pad(&mut out);
+        },
+    }
+    Ok(out)
+}
+
+fn block_type(block: &Block) -> BlockType {
+    match *block {
+        Block::SectionHeader(_) => BlockTypes::SectionHeader,
+        Block::InterfaceDescription(_) => BlockTypes::InterfaceDescription,
+        Block::EnhancedPacket(_) => BlockTypes::EnhancedPacket,
+        Block::SimplePacket(_) => BlockTypes::SimplePacket,
+        Block::NameResolution(_) => BlockTypes::NameResolution,
+        Block::Other { block_type, .. } => block_type,
+    }
+}
+
+/// Writes pcapng blocks. Blocks read by `PcapNgReader` can be written back as they are,
+/// keeping the interfaces, their options and the packet comments.
+pub struct PcapNgWriter<W: Write> {
+    writer: W,
+    big_endian: bool,
+    interfaces: Vec<Interface>,
+}
+
+impl <W: Write> PcapNgWriter<W> {
+    /// Start the file with `section`, its `big_endian` sets the byte order
+    pub fn new(writer: W, section: &SectionHeader) -> io::Result<PcapNgWriter<W>> {
+        let mut w = PcapNgWriter { writer, big_endian: section.big_endian, interfaces: Vec::new() };
+        w.write_block(&Block::SectionHeader(section.clone()))?;
+        Ok(w)
+    }
+
+    /// Interfaces of the current section
+    pub fn interfaces(&self) -> &[Interface] {
+        &self.interfaces
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    /// Write a block. A Section Header starts a new section, packets must refer to
+    /// an interface of the current section.
+    pub fn write_block(&mut self, block: &Block) -> io::Result<()> {
+        let invalid_input = |e: ParseError| io::Error::new(io::ErrorKind::InvalidInput, e);
+        match *block {
+            Block::SectionHeader(ref s) => {
+                self.big_endian = s.big_endian;
+                self.interfaces.clear();
+            },
+            Block::InterfaceDescription(ref i) => self.interfaces.push(i.clone()),
+            Block::EnhancedPacket(ref p) if p.interface_id as usize >= self.interfaces.len() => {
+                return Err(invalid_input(ParseError::new(Layer::Pcap, 0, Reason::BadField("interface_id"))));
+            },
+            Block::SimplePacket(_) if self.interfaces.is_empty() => {
+                return Err(invalid_input(ParseError::new(Layer::Pcap, 0, Reason::BadField("interface_id"))));
+            },
+            _ => {},
+        }
+        let body = encode(self.big_endian, block).map_err(invalid_input)?;
+        let total = body.len() + 12;
+        if total > MAX_BLOCK_LEN {
+            return Err(invalid_input(ParseError::new(Layer::Pcap, 0, Reason::BadField("block_length"))));
+        }
+        let mut out = Vec::with_capacity(total);
+        put_u32(self.big_endian, &mut out, block_type(block).0);
+        put_u32(self.big_endian, &mut out, total as u32);
+        out.extend_from_slice(&body);
+        put_u32(self.big_endian, &mut out, total as u32);
+        self.writer.write_all(&out)
+    }
+
+    /// Describe an interface of the current section, returns its id
+    pub fn add_interface(&mut self, interface: &Interface) -> io::Result<u32> {
+        self.write_block(&Block::InterfaceDescription(interface.clone()))?;
+        Ok(self.interfaces.len() as u32 - 1)
+    }
+
+    /// Write an Enhanced Packet block, `timestamp` is converted to the units of the interface
+    pub fn write_packet(&mut self, interface_id: u32, timestamp: Duration, orig_len: u32, data: &[u8], options: &[BlockOption]) -> io::Result<()> {
+        let timestamp = match self.interfaces.get(interface_id as usize) {
+            Some(interface) => interface.units(timestamp),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, ParseError::new(Layer::Pcap, 0, Reason::BadField("interface_id")))),
+        };
+        self.write_block(&Block::EnhancedPacket(EnhancedPacket { interface_id, timestamp, orig_len, data, options: options.to_vec() }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pcap::LinkTypes;
+
+    /// Little-endian file: section, Ethernet interface with nanosecond timestamps,
+    /// a 5-byte packet with a comment and a Simple Packet block
+    const FILE: [u8; 148] = [
+        0x0a, 0x0d, 0x0d, 0x0a, 0x2c, 0x00, 0x00, 0x00, 0x4d, 0x3c, 0x2b, 0x1a, 0x01, 0x00, 0x00, 0x00,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x04, 0x00, 0x05, 0x00, 0x74, 0x65, 0x73, 0x74,
+        0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x2c, 0x00, 0x00, 0x00,
+        0x01, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x00,
+        0x09, 0x00, 0x01, 0x00, 0x09, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x20, 0x00, 0x00, 0x00,
+        0x06, 0x00, 0x00, 0x00, 0x34, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x05, 0xca, 0x9a, 0x3b, 0x05, 0x00, 0x00, 0x00, 0x3c, 0x00, 0x00, 0x00, 0x01, 0x02, 0x03, 0x04,
+        0x05, 0x00, 0x00, 0x00, 0x01, 0x00, 0x02, 0x00, 0x68, 0x69, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x34, 0x00, 0x00, 0x00,
+        0x03, 0x00, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x07, 0x08, 0x09, 0x00,
+        0x14, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn test_read() {
+        let mut r = PcapNgReader::new(&FILE[..]).unwrap();
+        assert_eq!(r.section().application(), Some("test!"));
+        assert_eq!(r.section().section_length, -1);
+        let rec = r.next_packet().unwrap().unwrap();
+        assert_eq!(rec, Record {
+            interface_id: 0,
+            link_type: LinkTypes::Ethernet,
+            timestamp: Some(Duration::new(1, 5)),
+            orig_len: 60,
+            data: &[1, 2, 3, 4, 5],
+            options: vec!(BlockOption::comment("hi")),
+        });
+        assert_eq!(rec.comments(), vec!("hi"));
+        assert_eq!(r.interfaces()[0].units_per_second(), 1_000_000_000);
+        let rec = r.next_packet().unwrap().unwrap();
+        assert_eq!((rec.timestamp, rec.orig_len, rec.data), (None, 3, &[7u8, 8, 9][..]));
+        assert_eq!(r.next_packet().unwrap(), None);
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for &big_endian in &[false, true] {
+            let section = SectionHeader { big_endian, options: vec!(BlockOption { code: OptionCodes::SHB_OS, value: b"Linux".to_vec() }), ..SectionHeader::default() };
+            let mut w = PcapNgWriter::new(Vec::new(), &section).unwrap();
+            let mut eth = Interface::new(LinkTypes::Ethernet, 65535);
+            eth.options.push(BlockOption { code: OptionCodes::IF_NAME, value: b"eth0".to_vec() });
+            let mut raw = Interface::new(LinkTypes::Raw, 0);
+            raw.options.push(BlockOption { code: OptionCodes::IF_TSRESOL, value: vec!(0x8a) });
+            assert_eq!((w.add_interface(&eth).unwrap(), w.add_interface(&raw).unwrap()), (0, 1));
+            let names = NameResolution {
+                records: vec!(
+                    NameRecord::Ipv4 { addr: Ipv4Addr::new(10, 0, 0, 1), names: vec!("a.example".to_string(), "b".to_string()) },
+                    NameRecord::Ipv6 { addr: "::1".parse().unwrap(), names: vec!("localhost".to_string()) },
+                    NameRecord::Other { record_type: 7, value: vec!(1, 2, 3) },
+                ),
+                options: vec!(),
+            };
+            w.write_block(&Block::NameResolution(names.clone())).unwrap();
+            w.write_packet(1, Duration::from_millis(1500), 3, b"abc", &[BlockOption::comment("first")]).unwrap();
+            w.write_packet(0, Duration::new(2, 999), 100, b"defgh", &[]).unwrap();
+            w.write_block(&Block::Other { block_type: BlockTypes::InterfaceStatistics, body: &[1, 2, 3, 4] }).unwrap();
+            assert_eq!(w.write_packet(2, Duration::new(0, 0), 1, b"x", &[]).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+            let file = w.into_inner();
+
+            let mut r = PcapNgReader::new(&file[..]).unwrap();
+            assert_eq!(r.section(), &section);
+            assert_eq!(r.next_block().unwrap(), Some(Block::InterfaceDescription(eth.clone())));
+            assert_eq!(r.next_block().unwrap(), Some(Block::InterfaceDescription(raw.clone())));
+            assert_eq!(r.interfaces()[0].name(), Some("eth0"));
+            assert_eq!(r.next_block().unwrap(), Some(Block::NameResolution(names)));
+            let rec = r.next_packet().unwrap().unwrap();
+            assert_eq!((rec.link_type, rec.timestamp, rec.data), (LinkTypes::Raw, Some(Duration::from_millis(1500)), &b"abc"[..]));
+            assert_eq!(rec.comments(), vec!("first"));
+            let rec = r.next_packet().unwrap().unwrap();
+            assert_eq!((rec.link_type, rec.timestamp, rec.orig_len), (LinkTypes::Ethernet, Some(Duration::new(2, 0)), 100));
+            assert_eq!(r.next_block().unwrap(), Some(Block::Other { block_type: BlockTypes::InterfaceStatistics, body: &[1, 2, 3, 4] }));
+            assert_eq!(r.next_block().unwrap(), None);
+
+            // copy block by block
+            let mut r = PcapNgReader::new(&file[..]).unwrap();
+            let mut w = PcapNgWriter::new(Vec::new(), r.section()).unwrap();
+            while let Some(block) = r.next_block().unwrap() {
+                w.write_block(&block).unwrap();
+            }
+            assert_eq!(w.into_inner(), file);
+        }
+    }
+
+    #[test]
+    fn test_sections() {
+        let mut w = PcapNgWriter::new(Vec::new(), &SectionHeader::default()).unwrap();
+        w.add_interface(&Interface::new(LinkTypes::Ethernet, 0)).unwrap();
+        w.write_packet(0, Duration::new(1, 0), 1, b"a", &[]).unwrap();
+        w.write_block(&Block::SectionHeader(SectionHeader { big_endian: true, ..SectionHeader::default() })).unwrap();
+        assert!(w.interfaces().is_empty());
+        w.add_interface(&Interface::new(LinkTypes::LinuxSll, 0)).unwrap();
+        w.write_packet(0, Duration::new(2, 0), 1, b"b", &[]).unwrap();
+        let file = w.into_inner();
+
+        let mut r = PcapNgReader::new(&file[..]).unwrap();
+        assert_eq!(r.next_packet().unwrap().unwrap().link_type, LinkTypes::Ethernet);
+        let rec = r.next_packet().unwrap().unwrap();
+        assert_eq!((rec.link_type, rec.data), (LinkTypes::LinuxSll, &b"b"[..]));
+        assert!(r.section().big_endian);
+        assert_eq!(r.interfaces().len(), 1);
+    }
+
+    #[test]
+    fn test_malformed() {
+        let error = |file: &[u8]| -> ParseError {
+            let mut r = match PcapNgReader::new(file) {
+                Ok(r) => r,
+                Err(e) => return *e.get_ref().unwrap().downcast_ref::<ParseError>().unwrap(),
+            };
+            loop {
+                match r.next_block() {
+                    Ok(Some(_)) => {},
+                    Ok(None) => panic!("no error"),
+                    Err(e) => return *e.get_ref().unwrap().downcast_ref::<ParseError>().unwrap(),
+                }
+            }
+        };
+        let mut file = FILE.to_vec();
+        file[8] = 0x4e;
+        assert_eq!(error(&file), ParseError::new(Layer::Pcap, 8, Reason::BadField("byte_order_magic")));
+        assert_eq!(error(&FILE[44..]), ParseError::new(Layer::Pcap, 0, Reason::BadField("block_type")));
+        assert_eq!(error(&FILE[..30]), ParseError::truncated(Layer::Pcap, 0, 44, 30));
+        assert_eq!(error(&FILE[..100]), ParseError::truncated(Layer::Pcap, 76, 52, 24));
+
+        file = FILE.to_vec();
+        file[72] = 0x24;
+        assert_eq!(error(&file), ParseError::new(Layer::Pcap, 72, Reason::BadField("block_length")));
+        file = FILE.to_vec();
+        file[84] = 1;
+        assert_eq!(error(&file), ParseError::new(Layer::Pcap, 84, Reason::BadField("interface_id")));
+        file = FILE.to_vec();
+        file[96] = 33;
+        assert_eq!(error(&file), ParseError::truncated(Layer::Pcap, 104, 33, 20));
+    }
+}
@@ -1,40 +1,292 @@
-#![feature(try_from)]
-
-extern crate common;
-extern crate ethernet;
-extern crate ethertype;
-extern crate vlan;
-extern crate arp;
-extern crate ip;
+pub extern crate common;
+pub extern crate ethernet;
+pub extern crate ethertype;
+pub extern crate llc;
+pub extern crate vlan;
+pub extern crate arp;
+pub extern crate ip;
+pub extern crate ipv6;
+pub extern crate tcp;
+pub extern crate udp;
+pub extern crate icmp;
+pub extern crate icmpv6;
+pub extern crate igmp;
+pub extern crate defrag;
+pub extern crate tcpstream;
+pub extern crate flow;
+pub extern crate pcap;
+pub extern crate pcapng;
 
 use std::convert::TryFrom;
+use common::ParseError;
 
+/// A dissected Ethernet frame.
+///
+/// Each variant holds every layer from Ethernet up to the highest one
+/// that was decoded. New protocols are added as new variants, so matches
+/// need a wildcard arm; the accessors below work for any variant.
 #[derive(Debug, PartialEq)]
-enum Packet<'a> {
+#[non_exhaustive]
+pub enum Packet<'a> {
     Arp (arp::ArpPacket<'a>),
-    IpV4 (ip::IpV4Packet<'a>),
-    Unknown { ethernet: ethernet::EthHeader<'a>, vlans: Option<Vec<vlan::Vlan>>, ethertype: ethertype::EtherType },
+    /// IPv4 packet and its transport layer
+    IpV4 (ip::IpV4Packet<'a>, Transport<'a>),
+    /// IPv6 packet and the transport layer after its extension headers
+    IpV6 (ipv6::IpV6Packet<'a>, Transport<'a>),
+    /// IEEE 802.3 frame with an LLC header, see `LlcPacket::protocol`
+    Llc (llc::LlcPacket<'a>),
+    /// Frame with an EtherType we do not decode, `payload` is everything after it
+    Unknown { ethernet: ethernet::EthHeader, vlans: Option<Vec<vlan::Vlan>>, ethertype: ethertype::EtherType, payload: &'a [u8] },
+}
+
+/// Transport layer of an IP packet.
+///
+/// A transport header that fails to parse does not fail the whole frame,
+/// it is kept as `Malformed` next to the IP layer.
+#[derive(Debug, PartialEq)]
+#[non_exhaustive]
+pub enum Transport<'a> {
+    Tcp (tcp::TcpSegment<'a>),
+    /// UDP or UDP-Lite datagram, see `UdpDatagram::lite`
+    Udp (udp::UdpDatagram<'a>),
+    Icmp (icmp::IcmpPacket<'a>),
+    Icmpv6 (icmpv6::Icmpv6Packet<'a>),
+    Igmp (igmp::IgmpPacket<'a>),
+    /// Protocol we do not decode or non-first fragment, `payload` is the rest of the packet
+    Unknown { protocol: ip::IpNextHeaderProtocol, payload: &'a [u8] },
+    /// The transport header failed to parse, the offset counts from the start of the frame
+    Malformed (ParseError),
 }
 
-fn parse_packet<'a>(buf: &'a [u8]) -> Result<Packet, ()> {
-    const ETH_END: usize = 12;
+impl <'a> Packet<'a> {
+    pub fn eth_header(&self) -> &ethernet::EthHeader {
+        match *self {
+            Packet::Arp(ref p) => &p.eth_header,
+            Packet::IpV4(ref p, _) => &p.eth_header,
+            Packet::IpV6(ref p, _) => &p.eth_header,
+            Packet::Llc(ref p) => &p.eth_header,
+            Packet::Unknown { ref ethernet, .. } => ethernet,
+        }
+    }
+
+    pub fn vlans(&self) -> Option<&[vlan::Vlan]> {
+        let vlans = match *self {
+            Packet::Arp(ref p) => &p.vlans,
+            Packet::IpV4(ref p, _) => &p.vlans,
+            Packet::IpV6(ref p, _) => &p.vlans,
+            Packet::Llc(ref p) => &p.vlans,
+            Packet::Unknown { ref vlans, .. } => vlans,
+        };
+        vlans.as_ref().map(|v| &v[..])
+    }
+
+    /// VID of the outermost tag, see `vlan::outer_vid`
+    pub fn outer_vid(&self) -> Option<u16> {
+        self.vlans().and_then(vlan::outer_vid)
+    }
+
+    /// VID of the innermost tag of a stacked frame, see `vlan::inner_vid`
+    pub fn inner_vid(&self) -> Option<u16> {
+        self.vlans().and_then(vlan::inner_vid)
+    }
+
+    /// EtherType following the VLAN tags, the 802.3 length for LLC frames
+    pub fn ethertype(&self) -> ethertype::EtherType {
+        match *self {
+            Packet::Arp(_) => ethertype::EtherTypes::Arp,
+            Packet::IpV4(..) => ethertype::EtherTypes::Ipv4,
+            Packet::IpV6(..) => ethertype::EtherTypes::Ipv6,
+            Packet::Llc(ref p) => ethertype::EtherType(p.length),
+            Packet::Unknown { ethertype, .. } => ethertype,
+        }
+    }
+
+    /// Transport layer of IP packets
+    pub fn transport(&self) -> Option<&Transport<'a>> {
+        match *self {
+            Packet::IpV4(_, ref t) | Packet::IpV6(_, ref t) => Some(t),
+            _ => None,
+        }
+    }
+}
+
+/// How `parse_packet_with` and `parse_link_with` dissect a frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParseOptions<'b> {
+    /// EtherTypes also taken as VLAN tags, see `vlan::parse_vlans_with_tpids`
+    pub extra_tpids: &'b [ethertype::EtherType],
+    /// How the IPv4 header, UDP, ICMP, ICMPv6 and IGMP checksums are verified
+    pub checksum: ip::ChecksumCheck,
+}
+
+impl <'b> Default for ParseOptions<'b> {
+    fn default() -> ParseOptions<'b> {
+        ParseOptions { extra_tpids: &[], checksum: ip::ChecksumCheck::Skip }
+    }
+}
+
+/// Dissect an Ethernet frame, dispatching on the EtherType after the VLAN tags.
+/// Type values up to 1500 are 802.3 lengths and decode as LLC.
+pub fn parse_packet(buf: &[u8]) -> Result<Packet<'_>, ParseError> {
+    parse_packet_with(buf, &ParseOptions::default())
+}
+
+/// `parse_packet` that also takes `extra_tpids` as VLAN tags, see `vlan::parse_vlans_with_tpids`
+pub fn parse_packet_with_tpids<'a>(buf: &'a [u8], extra_tpids: &[ethertype::EtherType]) -> Result<Packet<'a>, ParseError> {
+    parse_packet_with(buf, &ParseOptions { extra_tpids, ..ParseOptions::default() })
+}
+
+/// `parse_packet` with explicit `options`
+pub fn parse_packet_with<'a>(buf: &'a [u8], options: &ParseOptions) -> Result<Packet<'a>, ParseError> {
     let ethernet = ethernet::EthHeader::try_from(buf)?;
-    let (v, ptr) = vlan::parse_vlans(&buf[ETH_END..buf.len()]).unwrap();
+    parse_ethertype_at(ethernet, buf, 12, options, true)
+}
+
+/// Dispatch on the type field found at `start`, after the VLAN tags that follow it.
+/// Type values up to 1500 decode as LLC when `llc` is set.
+fn parse_ethertype_at<'a>(ethernet: ethernet::EthHeader, buf: &'a [u8], start: usize, options: &ParseOptions, llc: bool) -> Result<Packet<'a>, ParseError> {
+    let (v, ptr) = vlan::parse_vlans_with_tpids(&buf[start..buf.len()], options.extra_tpids).map_err(|e| e.shift(start))?;
+    let payload = start + ptr + 2;
 
-    match ethertype::parse_ethtype(&buf[ETH_END + ptr..ETH_END + ptr + 2]) {
+    match ethertype::parse_ethtype(&buf[start + ptr..buf.len()]).map_err(|e| e.shift(start + ptr))? {
         ethertype::EtherTypes::Arp => {
-            Ok(Packet::Arp(arp::ArpPacket::try_from((ethernet, v, &buf[ETH_END + ptr + 2 .. buf.len()]))?))
+            Ok(Packet::Arp(arp::ArpPacket::try_from((ethernet, v, &buf[payload..buf.len()])).map_err(|e| e.shift(payload))?))
         }
-        ethertype::EtherTypes::Ipv4 => {
-            Ok(Packet::IpV4(ip::IpV4Packet::try_from((ethernet, v, &buf[ETH_END + ptr + 2 .. buf.len()]))?))
+        ethertype::EtherTypes::Ipv4 => parse_ipv4_at(ethernet, v, buf, payload, options),
+        ethertype::EtherTypes::Ipv6 => parse_ipv6_at(ethernet, v, buf, payload, options),
+        eth if llc && eth.is_length() => {
+            let start = start + ptr;
+            Ok(Packet::Llc(llc::LlcPacket::try_from((ethernet, v, &buf[start..buf.len()])).map_err(|e| e.shift(start))?))
         }
         eth => {
-            Ok(Packet::Unknown { ethernet, vlans: v, ethertype: eth })
+            Ok(Packet::Unknown { ethernet, vlans: v, ethertype: eth, payload: &buf[payload..buf.len()] })
         }
     }
 }
 
+fn parse_ipv4_at<'a>(ethernet: ethernet::EthHeader, vlans: Option<Vec<vlan::Vlan>>, buf: &'a [u8], start: usize, options: &ParseOptions) -> Result<Packet<'a>, ParseError> {
+    use ip::IpNextHeaderProtocols as P;
+    let packet = ip::IpV4Packet::from_parts(ethernet, vlans, &buf[start..], options.checksum).map_err(|e| e.shift(start))?;
+    let hdr = &packet.ip_hdr;
+    let transport = match hdr.protocol {
+        _ if hdr.offset != 0 => Ok(Transport::Unknown { protocol: hdr.protocol, payload: packet.payload }),
+        P::Tcp => tcp::TcpSegment::try_from(&packet).map(Transport::Tcp),
+        P::Udp | P::UdpLite => udp::UdpDatagram::from_ipv4(&packet, options.checksum).map(Transport::Udp),
+        P::Icmp => icmp::IcmpPacket::from_ipv4(&packet, options.checksum).map(Transport::Icmp),
+        P::Igmp => igmp::IgmpPacket::from_ipv4(&packet, options.checksum).map(Transport::Igmp),
+        protocol => Ok(Transport::Unknown { protocol, payload: packet.payload }),
+    }.unwrap_or_else(|e| Transport::Malformed(e.shift(start)));
+    Ok(Packet::IpV4(packet, transport))
+}
+
+fn parse_ipv6_at<'a>(ethernet: ethernet::EthHeader, vlans: Option<Vec<vlan::Vlan>>, buf: &'a [u8], start: usize, options: &ParseOptions) -> Result<Packet<'a>, ParseError> {
+    use ip::IpNextHeaderProtocols as P;
+    let packet = ipv6::IpV6Packet::try_from((ethernet, vlans, &buf[start..])).map_err(|e| e.shift(start))?;
+    let transport = packet.extensions().and_then(|chain| {
+        let payload = &packet.payload[chain.payload_offset..];
+        match chain.protocol {
+            _ if chain.fragment().is_some_and(|f| f.offset != 0) => Ok(Transport::Unknown { protocol: chain.protocol, payload }),
+            P::Tcp => tcp::TcpSegment::try_from(&packet).map(Transport::Tcp),
+            P::Udp | P::UdpLite => udp::UdpDatagram::from_ipv6(&packet, options.checksum).map(Transport::Udp),
+            P::Icmpv6 => icmpv6::Icmpv6Packet::from_ipv6(&packet, options.checksum).map(Transport::Icmpv6),
+            protocol => Ok(Transport::Unknown { protocol, payload }),
+        }
+    }).unwrap_or_else(|e| Transport::Malformed(e.shift(start)));
+    Ok(Packet::IpV6(packet, transport))
+}
+
+/// Dissect the raw IP packet at `start`, the version field tells IPv4 from IPv6
+fn parse_raw_ip<'a>(buf: &'a [u8], start: usize, options: &ParseOptions) -> Result<Packet<'a>, ParseError> {
+    let ethernet = ethernet::EthHeader { dst_mac: ethernet::MacAddr::zero(), src_mac: ethernet::MacAddr::zero() };
+    match buf.get(start).map(|b| b >> 4) {
+        Some(4) => parse_ipv4_at(ethernet, None, buf, start, options),
+        Some(6) => parse_ipv6_at(ethernet, None, buf, start, options),
+        Some(v) => Err(ParseError::new(common::Layer::Link, start, common::Reason::BadVersion(v))),
+        None => Err(ParseError::truncated(common::Layer::Link, start, 1, 0)),
+    }
+}
+
+/// Dissect a frame captured with link type `link`.
+///
+/// `Packet` always has an Ethernet header: frames of other link types get one with
+/// zero addresses, Linux cooked captures put the 6-byte sender address as source.
+pub fn parse_link(link: pcap::LinkType, buf: &[u8]) -> Result<Packet<'_>, ParseError> {
+    parse_link_with(link, buf, &ParseOptions::default())
+}
+
+/// `parse_link` with explicit `options`
+pub fn parse_link_with<'a>(link: pcap::LinkType, buf: &'a [u8], options: &ParseOptions) -> Result<Packet<'a>, ParseError> {
+    use common::{Layer, Reason};
+    use pcap::LinkTypes as L;
+    match link {
+        L::Ethernet => parse_packet_with(buf, options),
+        L::Raw => parse_raw_ip(buf, 0, options),
+        L::Null => {
+            if buf.len() < 4 {
+                return Err(ParseError::truncated(Layer::Link, 0, 4, buf.len()));
+            }
+            // Host byte order of the capturing machine, families fit in the low byte
+            let family = match buf[0] {
+                0 => buf[3],
+                b => b,
+            };
+            match family {
+                // AF_INET, then AF_INET6 of Linux, BSDs, FreeBSD and macOS
+                2 | 10 | 24 | 28 | 30 => parse_raw_ip(buf, 4, options),
+                _ => Err(ParseError::new(Layer::Link, 0, Reason::BadField("family"))),
+            }
+        }
+        L::LinuxSll => {
+            if buf.len() < 16 {
+                return Err(ParseError::truncated(Layer::Link, 0, 16, buf.len()));
+            }
+            let mut ethernet = ethernet::EthHeader { dst_mac: ethernet::MacAddr::zero(), src_mac: ethernet::MacAddr::zero() };
+            if common::u16_parse(&buf[4..6]) == 6 {
+                ethernet.src_mac.0.copy_from_slice(&buf[6..12]);
+            }
+            parse_ethertype_at(ethernet, buf, 14, options, false)
+        }
+        _ => Err(ParseError::new(Layer::Link, 0, Reason::BadField("link_type"))),
+    }
+}
+
+/// Record of a capture file with its frame dissected
+#[derive(Debug, PartialEq)]
+pub struct Captured<'a> {
+    /// Time since the Unix epoch, zero for pcapng Simple Packet blocks that carry none
+    pub timestamp: std::time::Duration,
+    /// Length of the frame on the wire
+    pub orig_len: u32,
+    /// Captured bytes
+    pub data: &'a [u8],
+    /// `parse_link` of `data`, files hold frames we cannot decode
+    pub packet: Result<Packet<'a>, ParseError>,
+}
+
+/// Read and dissect the next record of a pcap file, `None` at the end of the file
+pub fn next_packet<R: std::io::Read>(reader: &mut pcap::PcapReader<R>) -> std::io::Result<Option<Captured<'_>>> {
+    let link = reader.header().link_type;
+    Ok(reader.next_record()?.map(|r| Captured {
+        timestamp: r.timestamp,
+        orig_len: r.orig_len,
+        data: r.data,
+        packet: parse_link(link, r.data),
+    }))
+}
+
+/// Read and dissect the next packet of a pcapng file, each with the link type of its
+/// interface. `None` at the end of the file.
+pub fn next_packet_ng<R: std::io::Read>(reader: &mut pcapng::PcapNgReader<R>) -> std::io::Result<Option<Captured<'_>>> {
+    Ok(reader.next_packet()?.map(|r| Captured {
+        timestamp: r.timestamp.unwrap_or_default(),
+        orig_len: r.orig_len,
+        data: r.data,
+        packet: parse_link(r.link_type, r.data),
+    }))
+}
+
 #[cfg(test)]
+#[allow(clippy::unnecessary_cast)]
 mod tests {
     use super::*;
 
@@ -44,10 +296,10 @@ mod tests {
         let res = parse_packet(&test_buf).unwrap();
         assert_eq!(res, Packet::Arp (arp::ArpPacket{
             eth_header: ethernet::EthHeader {
-                dst_mac: &[17, 18, 19, 20, 21, 22],
-                src_mac: &[1, 2, 3, 4, 5, 6],
+                dst_mac: ethernet::MacAddr([17, 18, 19, 20, 21, 22]),
+                src_mac: ethernet::MacAddr([1, 2, 3, 4, 5, 6]),
             },
-            vlans: Some(vec!(vlan::Vlan{drop_eligible_indicator: false, priority_code_point:vlan::ClassesOfService::BE, vid:1})),
+            vlans: Some(vec!(vlan::Vlan{tpid: ethertype::EtherTypes::Vlan, drop_eligible_indicator: false, priority_code_point:vlan::ClassesOfService::BE, vid:1})),
             arp_hdr: arp::ArpHdr {
                 ar_hrd: 1,
                 ar_pro: 2048,
@@ -57,11 +309,11 @@ mod tests {
             },
             arp_body: arp::ArpBody {
                 source: arp::ArpInfo {
-                    ar_ha: &[1, 2, 3, 4, 5, 6],
+                    ar_ha: ethernet::MacAddr([1, 2, 3, 4, 5, 6]),
                     addr: &[1, 1, 1, 1],
                 },
                 target: arp::ArpInfo {
-                    ar_ha: &[0, 0, 0, 0, 0, 0],
+                    ar_ha: ethernet::MacAddr([0, 0, 0, 0, 0, 0]),
                     addr: &[2, 2, 2, 2],
                 },
             },
@@ -74,12 +326,16 @@ mod tests {
         let res = parse_packet(&test_buf).unwrap();
         assert_eq!(res, Packet::Unknown {
             ethernet: ethernet::EthHeader {
-                dst_mac: &[17, 18, 19, 20, 21, 22],
-                src_mac: &[1, 2, 3, 4, 5, 6],
+                dst_mac: ethernet::MacAddr([17, 18, 19, 20, 21, 22]),
+                src_mac: ethernet::MacAddr([1, 2, 3, 4, 5, 6]),
             },
-            vlans: Some(vec!(vlan::Vlan{drop_eligible_indicator: false, priority_code_point:vlan::ClassesOfService::BE, vid:1})),
+            vlans: Some(vec!(vlan::Vlan{tpid: ethertype::EtherTypes::Vlan, drop_eligible_indicator: false, priority_code_point:vlan::ClassesOfService::BE, vid:1})),
             ethertype: ethertype::EtherType(0x0808),
+            payload: &[0x0, 0x1, 0x08, 0, 6, 4, 0, 1, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x1, 0x1, 0x1, 0x1, 0, 0, 0, 0, 0, 0, 0x2, 0x2, 0x2, 0x2],
         });
+        assert_eq!(res.ethertype(), ethertype::EtherType(0x0808));
+        assert_eq!(res.eth_header().src_mac, ethernet::MacAddr([1, 2, 3, 4, 5, 6]));
+        assert_eq!(res.vlans().map(|v| v[0].vid), Some(1));
     }
 
     #[test]
@@ -181,8 +437,8 @@ mod tests {
         let res = parse_packet(&test_buf).unwrap();
         assert_eq!(res, Packet::IpV4 (ip::IpV4Packet {
             eth_header: ethernet::EthHeader {
-                dst_mac: &[0xd0, 0x17, 0xc2, 0x97, 0x48, 0xb2],
-                src_mac: &[0xa8, 0xf9, 0x4b, 0x80, 0xe7, 0x00],
+                dst_mac: ethernet::MacAddr([0xd0, 0x17, 0xc2, 0x97, 0x48, 0xb2]),
+                src_mac: ethernet::MacAddr([0xa8, 0xf9, 0x4b, 0x80, 0xe7, 0x00]),
             },
             vlans: None,
             ip_hdr: ip::IpV4Header{
@@ -197,10 +453,432 @@ mod tests {
                 ttl: 62,
                 protocol: ip::IpNextHeaderProtocols::Tcp,
                 crc: 0xce9f,
+                checksum_ok: None,
                 src_ip: &[0xac as u8, 0x10,0x00,0x53],
                 dst_ip: &[0xc0 as u8, 0xa8,0x16,0x47],
                 options: None
+            },
+            payload: &test_buf[34..],
+        }, Transport::Tcp(tcp::TcpSegment::try_from(&test_buf[34..]).unwrap())));
+        if let Packet::IpV4(_, Transport::Tcp(ref segment)) = res {
+            assert_eq!((segment.tcp_hdr.src_port, segment.tcp_hdr.dst_port), (8080, 43612));
+            assert_eq!(segment.tcp_hdr.options, Some(vec!(tcp::TcpOption::Timestamps { value: 0x3287ea70, echo_reply: 0x622f450d })));
+            assert_eq!(segment.payload, &test_buf[66..]);
+        }
+    }
+
+    #[test]
+    fn check_tcp_stream() {
+        use std::time::Duration;
+        use common::Emit;
+        use tcpstream::{ConnectionKey, Direction, StreamConfig, StreamHandler, StreamReassembler};
+
+        struct Collect(Vec<u8>);
+        impl StreamHandler for Collect {
+            fn data(&mut self, _key: &ConnectionKey, _direction: Direction, data: &[u8]) {
+                self.0.extend_from_slice(data);
             }
-        }));
+        }
+
+        // a response of the HTTP session of `check_ip` cut in three, the last two swapped
+        let data = b"{\"_links\":{\"self\":{\"href\":\"/job/GPON/\"}},\"id\":\"42\",\"status\":\"SUCCESS\"}";
+        let mut frames = vec!();
+        for &(start, end) in [(0, 16), (40, data.len()), (16, 40)].iter() {
+            let ip_hdr = ip::IpV4Header {
+                version: 4, hdr_length: 5, dscp: 0, ecn: 0, packet_length: (40 + end - start) as u16, id: 1, flags: 2, offset: 0, ttl: 62,
+                protocol: ip::IpNextHeaderProtocols::Tcp, crc: 0, checksum_ok: None,
+                src_ip: &[172, 16, 0, 83], dst_ip: &[192, 168, 22, 71], options: None,
+            };
+            let segment = tcp::TcpSegment {
+                tcp_hdr: tcp::TcpHeader {
+                    src_port: 8080, dst_port: 43612, seq: 0xa21f25d1 + start as u32, ack: 0xf5438157, data_offset: 5,
+                    flags: tcp::TcpFlags::ACK, window: 249, checksum: 0, urgent_ptr: 0, options: None,
+                },
+                payload: &data[start..end],
+            };
+            let mut frame = vec!(0xd0u8, 0x17, 0xc2, 0x97, 0x48, 0xb2, 0xa8, 0xf9, 0x4b, 0x80, 0xe7, 0x00, 0x08, 0x00);
+            ip_hdr.emit_vec(&mut frame).unwrap();
+            segment.emit_vec(&mut frame).unwrap();
+            frames.push(frame);
+        }
+
+        let mut streams = StreamReassembler::new(Collect(vec!()), StreamConfig::default());
+        for (i, frame) in frames.iter().enumerate() {
+            match parse_packet(frame).unwrap() {
+                Packet::IpV4(ref ip, _) => streams.process(ip, Duration::from_secs(1)).unwrap(),
+                ref p => panic!("unexpected {:?}", p),
+            }
+            if i == 1 {
+                assert_eq!(&streams.handler().0[..], &data[..16]);
+            }
+        }
+        assert_eq!(&streams.into_handler().0[..], &data[..]);
+    }
+
+    #[test]
+    fn check_emit_roundtrip() {
+        use common::Emit;
+        let ip_frame = [0x11u8, 0x12, 0x13, 0x14, 0x15, 0x16, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x08, 0x00,
+            0x45, 0x00, 0x05, 0xbf, 0xe5, 0x46, 0x40, 0x00, 0x3e, 0x06, 0xce, 0x9f, 0xac, 0x10, 0x00, 0x53, 0xc0, 0xa8, 0x16, 0x47];
+        for frame in [&ARP_FRAME[..], &ip_frame[..], &CDP_FRAME[..], &IPV6_FRAME[..]].iter() {
+            let mut out = vec!();
+            match parse_packet(frame).unwrap() {
+                Packet::Arp(ref arp) => arp.emit_vec(&mut out).unwrap(),
+                Packet::IpV4(ref ip, _) => ip.emit_vec(&mut out).unwrap(),
+                Packet::IpV6(ref ip, _) => ip.emit_vec(&mut out).unwrap(),
+                Packet::Llc(ref llc) => llc.emit_vec(&mut out).unwrap(),
+                p => panic!("unexpected {:?}", p),
+            };
+            assert_eq!(&out[..], *frame);
+            assert_eq!(parse_packet(&out), parse_packet(frame));
+        }
+    }
+
+    /// Small xorshift generator, keeps the fuzz tests deterministic
+    struct XorShift(u32);
+
+    impl XorShift {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+    }
+
+    const ARP_FRAME: [u8; 46] = [0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x81, 0x00, 0x00, 0x01, 0x08, 0x06, 0x0, 0x1, 0x08, 0, 6, 4, 0, 1, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x1, 0x1, 0x1, 0x1, 0, 0, 0, 0, 0, 0, 0x2, 0x2, 0x2, 0x2];
+
+    const IP_FRAME: [u8; 42] = [0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x81, 0x00, 0x00, 0x01, 0x08, 0x00,
+        0x46, 0x00, 0x00, 0x18, 0xe5, 0x46, 0x40, 0x00, 0x3e, 0x06, 0xce, 0x9f, 0xac, 0x10, 0x00, 0x53, 0xc0, 0xa8, 0x16, 0x47, 0x94, 0x02, 0x00, 0x00];
+
+    const CDP_FRAME: [u8; 34] = [0x01, 0x00, 0x0c, 0xcc, 0xcc, 0xcc, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x81, 0x00, 0x00, 0x01, 0x00, 0x10,
+        0xaa, 0xaa, 0x03, 0x00, 0x00, 0x0c, 0x20, 0x00, 0x02, 0xb4, 0x12, 0x34, 0x00, 0x01, 0x00, 0x04];
+
+    const IPV6_FRAME: [u8; 66] = [0x33, 0x33, 0x00, 0x00, 0x00, 0x01, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x81, 0x00, 0x00, 0x01, 0x86, 0xdd,
+        0x60, 0x00, 0x00, 0x00, 0x00, 0x08, 0x3a, 0xff,
+        0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01,
+        0xff, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01,
+        0x80, 0x00, 0x7f, 0xff, 0x00, 0x01, 0x00, 0x01];
+
+    #[test]
+    fn check_ipv6() {
+        let res = parse_packet(&IPV6_FRAME).unwrap();
+        match res {
+            Packet::IpV6(ref p, ref t) => {
+                assert_eq!(p.ip_hdr.next_header, ip::IpNextHeaderProtocols::Icmpv6);
+                assert_eq!(p.ip_hdr.dst_addr(), "ff02::1".parse::<std::net::Ipv6Addr>().unwrap());
+                assert_eq!(p.payload, &IPV6_FRAME[58..]);
+                assert_eq!(t, &Transport::Icmpv6(icmpv6::Icmpv6Packet::from_ipv6(p, ip::ChecksumCheck::Skip).unwrap()));
+            }
+            ref p => panic!("unexpected {:?}", p),
+        }
+        assert_eq!(res.ethertype(), ethertype::EtherTypes::Ipv6);
+        assert_eq!(res.outer_vid(), Some(1));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn check_llc() {
+        let res = parse_packet(&CDP_FRAME).unwrap();
+        match res {
+            Packet::Llc(ref p) => {
+                assert_eq!(p.protocol(), llc::LlcProtocol::Cdp);
+                assert_eq!(p.payload, &CDP_FRAME[26..]);
+            }
+            ref p => panic!("unexpected {:?}", p),
+        }
+        assert_eq!(res.ethertype(), ethertype::EtherType(16));
+        assert_eq!(res.vlans().map(|v| v[0].vid), Some(1));
+    }
+
+    #[test]
+    fn check_link_types() {
+        use common::{Layer, Reason};
+        use pcap::{LinkType, LinkTypes};
+        let zero = ethernet::EthHeader { dst_mac: ethernet::MacAddr::zero(), src_mac: ethernet::MacAddr::zero() };
+        let ip = match parse_packet(&IP_FRAME).unwrap() {
+            Packet::IpV4(p, t) => {
+                assert_eq!(t, Transport::Malformed(ParseError::truncated(Layer::Tcp, 42, 20, 0)));
+                p
+            }
+            p => panic!("unexpected {:?}", p),
+        };
+        let raw = ip::IpV4Packet { eth_header: zero, vlans: None, ..ip };
+        let transport = Transport::Malformed(ParseError::truncated(Layer::Tcp, 24, 20, 0));
+        assert_eq!(parse_link(LinkTypes::Raw, &IP_FRAME[18..]), Ok(Packet::IpV4(raw, transport)));
+        assert_eq!(parse_link(LinkTypes::Ethernet, &IP_FRAME), parse_packet(&IP_FRAME));
+
+        let mut null = vec!(2u8, 0, 0, 0);
+        null.extend_from_slice(&IP_FRAME[18..]);
+        match parse_link(LinkTypes::Null, &null) {
+            Ok(Packet::IpV4(ref p, ref t)) => {
+                assert_eq!(p.ip_hdr, ip::IpV4Header::try_from(&IP_FRAME[18..]).unwrap());
+                assert_eq!(t, &Transport::Malformed(ParseError::truncated(Layer::Tcp, 28, 20, 0)));
+            }
+            ref p => panic!("unexpected {:?}", p),
+        }
+        let mut null = vec!(0u8, 0, 0, 30);
+        null.extend_from_slice(&IPV6_FRAME[18..]);
+        assert_eq!(parse_link(LinkTypes::Null, &null).unwrap().ethertype(), ethertype::EtherTypes::Ipv6);
+        null[3] = 7;
+        assert_eq!(parse_link(LinkTypes::Null, &null), Err(ParseError::new(Layer::Link, 0, Reason::BadField("family"))));
+        null[3] = 28;
+        assert_eq!(parse_link(LinkTypes::Null, &null[..24]).unwrap_err().offset, 4);
+
+        let mut sll = vec!(0u8, 0, 0, 1, 0, 6, 1, 2, 3, 4, 5, 6, 0, 0);
+        sll.extend_from_slice(&ARP_FRAME[16..]);
+        let res = parse_link(LinkTypes::LinuxSll, &sll).unwrap();
+        assert_eq!(res.ethertype(), ethertype::EtherTypes::Arp);
+        assert_eq!(res.eth_header().src_mac, ethernet::MacAddr::new(1, 2, 3, 4, 5, 6));
+        assert_eq!(res.vlans(), None);
+        sll[14..16].copy_from_slice(&[0x00, 0x04]);
+        assert_eq!(parse_link(LinkTypes::LinuxSll, &sll).unwrap().ethertype(), ethertype::EtherType(4));
+
+        assert_eq!(parse_link(LinkTypes::LinuxSll, &sll[..10]), Err(ParseError::truncated(Layer::Link, 0, 16, 10)));
+        assert_eq!(parse_link(LinkTypes::Raw, &[0x50]), Err(ParseError::new(Layer::Link, 0, Reason::BadVersion(5))));
+        assert_eq!(parse_link(LinkType(105), &IP_FRAME), Err(ParseError::new(Layer::Link, 0, Reason::BadField("link_type"))));
+    }
+
+    #[test]
+    fn check_pcap() {
+        let mut file = Vec::new();
+        for &w in &[pcap::MAGIC_MICROS, 0x0004_0002, 0, 0, 65535, 1] {
+            file.extend_from_slice(&u32::to_le_bytes(w));
+        }
+        for &(sec, usec, orig_len, data) in &[(1u32, 500_000u32, 42u32, &IP_FRAME[..]), (2, 0, 46, &ARP_FRAME[..20])] {
+            for &w in &[sec, usec, data.len() as u32, orig_len] {
+                file.extend_from_slice(&u32::to_le_bytes(w));
+            }
+            file.extend_from_slice(data);
+        }
+
+        let mut reader = pcap::PcapReader::new(&file[..]).unwrap();
+        let first = next_packet(&mut reader).unwrap().unwrap();
+        assert_eq!(first, Captured {
+            timestamp: std::time::Duration::from_millis(1500),
+            orig_len: 42,
+            data: &IP_FRAME,
+            packet: parse_packet(&IP_FRAME),
+        });
+        let second = next_packet(&mut reader).unwrap().unwrap();
+        assert_eq!((second.orig_len, second.data.len()), (46, 20));
+        assert_eq!(second.packet.unwrap_err().layer, common::Layer::Arp);
+        assert_eq!(next_packet(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn check_pcap_rewrite() {
+        use pcap::{LinkTypes, PcapHeader, PcapReader, PcapWriter, Precision};
+        use std::time::Duration;
+        let header = PcapHeader::new(LinkTypes::Ethernet, 65535, Precision::Nanos);
+        let mut w = PcapWriter::new(Vec::new(), header).unwrap();
+        for (i, frame) in [&ARP_FRAME[..], &IP_FRAME[..], &IPV6_FRAME[..]].iter().enumerate() {
+            w.write_packet(Duration::new(i as u64, 1), frame.len() as u32, frame).unwrap();
+        }
+        let input = w.into_inner();
+
+        // keep the IPv6 frames with a cleared source MAC, cut inside the ICMPv6 message
+        let mut reader = PcapReader::new(&input[..]).unwrap();
+        let mut w = PcapWriter::new(Vec::new(), PcapHeader { snaplen: 62, ..header }).unwrap();
+        while let Some(captured) = next_packet(&mut reader).unwrap() {
+            if let Ok(Packet::IpV6(..)) = captured.packet {
+                let mut frame = captured.data.to_vec();
+                ethernet::EthHeaderMut::try_from(&mut frame[..]).unwrap().set_src_mac(ethernet::MacAddr::zero());
+                w.write_packet(captured.timestamp, captured.orig_len, &frame).unwrap();
+            }
+        }
+        let output = w.into_inner();
+
+        let mut reader = PcapReader::new(&output[..]).unwrap();
+        let captured = next_packet(&mut reader).unwrap().unwrap();
+        assert_eq!((captured.timestamp, captured.orig_len, captured.data.len()), (Duration::new(2, 1), 66, 62));
+        assert_eq!(captured.data[12..], IPV6_FRAME[12..62]);
+        match captured.packet {
+            Ok(Packet::IpV6(ref p, _)) => {
+                assert_eq!(p.eth_header.src_mac, ethernet::MacAddr::zero());
+                assert_eq!(p.missing_bytes(), 4);
+            }
+            ref p => panic!("unexpected {:?}", p),
+        }
+        assert_eq!(next_packet(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn check_pcapng() {
+        use pcap::LinkTypes;
+        use pcapng::{BlockOption, Interface, PcapNgReader, PcapNgWriter, SectionHeader};
+        use std::time::Duration;
+        let mut w = PcapNgWriter::new(Vec::new(), &SectionHeader::default()).unwrap();
+        w.add_interface(&Interface::new(LinkTypes::Ethernet, 0)).unwrap();
+        w.add_interface(&Interface::new(LinkTypes::Raw, 0)).unwrap();
+        w.write_packet(1, Duration::from_secs(3), 24, &IP_FRAME[18..], &[BlockOption::comment("raw")]).unwrap();
+        w.write_packet(0, Duration::from_secs(4), 66, &IPV6_FRAME, &[]).unwrap();
+        let file = w.into_inner();
+
+        let mut reader = PcapNgReader::new(&file[..]).unwrap();
+        let first = next_packet_ng(&mut reader).unwrap().unwrap();
+        assert_eq!((first.timestamp, first.data), (Duration::from_secs(3), &IP_FRAME[18..]));
+        assert_eq!(first.packet, parse_link(LinkTypes::Raw, &IP_FRAME[18..]));
+        let second = next_packet_ng(&mut reader).unwrap().unwrap();
+        assert_eq!(second.packet, parse_packet(&IPV6_FRAME));
+        assert_eq!(next_packet_ng(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn check_qinq() {
+        let mut frame = vec!(0x11u8, 0x12, 0x13, 0x14, 0x15, 0x16, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x92, 0x00, 0x00, 0x64);
+        frame.extend_from_slice(&ARP_FRAME[12..]);
+        assert_eq!(parse_packet(&frame).unwrap().ethertype(), ethertype::EtherType(0x9200));
+        let res = parse_packet_with_tpids(&frame, &[ethertype::EtherType(0x9200)]).unwrap();
+        assert_eq!(res.ethertype(), ethertype::EtherTypes::Arp);
+        assert_eq!((res.outer_vid(), res.inner_vid()), (Some(100), Some(1)));
+        assert_eq!(parse_packet(&ARP_FRAME).unwrap().inner_vid(), None);
+    }
+
+    #[test]
+    fn check_truncated_frames() {
+        for frame in [&ARP_FRAME[..], &IP_FRAME[..], &CDP_FRAME[..], &IPV6_FRAME[..]].iter() {
+            assert!(parse_packet(frame).is_ok());
+            for len in 0..frame.len() {
+                let _ = parse_packet(&frame[..len]);
+            }
+        }
+    }
+
+    #[test]
+    fn check_mutated_frames() {
+        let mut rng = XorShift(0x2545_f491);
+        for frame in [&ARP_FRAME[..], &IP_FRAME[..], &CDP_FRAME[..], &IPV6_FRAME[..]].iter() {
+            let mut buf = frame.to_vec();
+            for pos in 0..buf.len() {
+                for &val in [0u8, 0x01, 0x7f, 0x80, 0xff, rng.next() as u8].iter() {
+                    let orig = buf[pos];
+                    buf[pos] = val;
+                    for len in pos..=buf.len() {
+                        let _ = parse_packet(&buf[..len]);
+                    }
+                    buf[pos] = orig;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn check_random_frames() {
+        let mut rng = XorShift(0x9e37_79b9);
+        for _ in 0..20000 {
+            let len = (rng.next() % 96) as usize;
+            let mut buf: Vec<u8> = (0..len).map(|_| rng.next() as u8).collect();
+            // steer most of the frames past the Ethernet layer
+            let mut pos = 12;
+            while pos + 4 <= buf.len() && rng.next().is_multiple_of(3) {
+                buf[pos..pos + 2].copy_from_slice(&[0x81, 0x00]);
+                pos += 4;
+            }
+            if pos + 3 <= buf.len() && !rng.next().is_multiple_of(4) {
+                let ethtype: &[u8] = match rng.next() % 3 {
+                    0 => &[0x08, 0x00, 0x40],
+                    1 => &[0x86, 0xdd, 0x60],
+                    _ => &[0x08, 0x06, 0x00],
+                };
+                buf[pos..pos + 3].copy_from_slice(ethtype);
+                buf[pos + 2] |= rng.next() as u8 & 0x0f;
+            }
+            let _ = parse_packet(&buf);
+        }
+    }
+
+    #[test]
+    fn check_transport() {
+        let mut frame = vec!(0x11u8, 0x12, 0x13, 0x14, 0x15, 0x16, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x08, 0x00,
+            0x45, 0x00, 0x00, 0x20, 0x00, 0x01, 0x00, 0x00, 0x40, 0xfd, 0x00, 0x00, 10, 0, 0, 1, 10, 0, 0, 2,
+            0x00, 0x35, 0x04, 0x00, 0x00, 0x0c, 0x00, 0x00, 1, 2, 3, 4);
+        assert_eq!(parse_packet(&frame).unwrap().transport(),
+                   Some(&Transport::Unknown { protocol: ip::IpNextHeaderProtocol(0xfd), payload: &frame[34..] }));
+        frame[23] = 0x06;
+        assert_eq!(parse_packet(&frame).unwrap().transport(),
+                   Some(&Transport::Malformed(ParseError::truncated(common::Layer::Tcp, 34, 20, 12))));
+        frame[21] = 1;
+        assert_eq!(parse_packet(&frame).unwrap().transport(),
+                   Some(&Transport::Unknown { protocol: ip::IpNextHeaderProtocols::Tcp, payload: &frame[34..] }));
+        frame[21] = 0;
+        frame[23] = 0x11;
+        match parse_packet(&frame).unwrap().transport() {
+            Some(Transport::Udp(udp)) => {
+                assert_eq!((udp.udp_hdr.src_port, udp.udp_hdr.dst_port), (53, 1024));
+                assert_eq!(udp.payload, &[1, 2, 3, 4]);
+            }
+            t => panic!("unexpected {:?}", t),
+        }
+        assert_eq!(parse_packet(&ARP_FRAME).unwrap().transport(), None);
+    }
+
+    #[test]
+    fn check_checksum_options() {
+        use common::{Layer, Reason};
+        use ip::ChecksumCheck;
+        let checksum_ok = |frame: &[u8], checksum| match parse_packet_with(frame, &ParseOptions { checksum, ..ParseOptions::default() }) {
+            Ok(Packet::IpV4(ref ip, _)) => ip.ip_hdr.checksum_ok,
+            ref p => panic!("unexpected {:?}", p),
+        };
+        let mut frame = [0x11u8, 0x12, 0x13, 0x14, 0x15, 0x16, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x81, 0x00, 0x00, 0x01, 0x08, 0x00,
+            0x46, 0x00, 0x00, 0x18, 0xe5, 0x46, 0x40, 0x00, 0x3e, 0x06, 0xce, 0x9f, 0xac, 0x10, 0x00, 0x53, 0xc0, 0xa8, 0x16, 0x47, 0x94, 0x02, 0x00, 0x00];
+        let crc = ip::header_checksum(&frame[18..]).unwrap();
+        common::u16_write(&mut frame[28..], crc);
+        assert_eq!(checksum_ok(&frame, ChecksumCheck::Skip), None);
+        assert_eq!(checksum_ok(&frame, ChecksumCheck::Flag), Some(true));
+
+        frame[28] ^= 1;
+        assert_eq!(checksum_ok(&frame, ChecksumCheck::Flag), Some(false));
+        let reject = ParseOptions { checksum: ChecksumCheck::Reject, ..ParseOptions::default() };
+        let err = ParseError::new(Layer::IpV4, 10, Reason::BadChecksum { expected: crc, actual: crc ^ 0x100 });
+        assert_eq!(parse_packet_with(&frame, &reject), Err(err.shift(18)));
+        assert_eq!(parse_link_with(pcap::LinkTypes::Raw, &frame[18..], &reject), Err(err));
+        assert!(parse_packet(&frame).is_ok());
+    }
+
+    #[test]
+    fn check_random_extension_chains() {
+        let kinds = [0u8, 43, 44, 51, 60];
+        let mut rng = XorShift(0x1b87_3593);
+        for _ in 0..20000 {
+            let first = kinds[rng.next() as usize % kinds.len()];
+            let mut chain = vec!();
+            let mut kind = first;
+            for _ in 0..1 + rng.next() % 4 {
+                let next = match rng.next() % 8 {
+                    0 => rng.next() as u8,
+                    1 => 6,
+                    2 => 17,
+                    n => kinds[n as usize % kinds.len()],
+                };
+                let len_field = (rng.next() % 4) as u8;
+                let len = match kind {
+                    44 if rng.next().is_multiple_of(2) => 8 + 16 * (len_field as usize / 2),
+                    0 | 43 | 60 => (len_field as usize + 1) * 8,
+                    44 => 8,
+                    _ => (len_field as usize + 2) * 4,
+                };
+                chain.extend_from_slice(&[next, len_field]);
+                chain.extend((2..len).map(|_| rng.next() as u8));
+                kind = next;
+            }
+            chain.extend((0..rng.next() % 16).map(|_| rng.next() as u8));
+
+            let mut buf = IPV6_FRAME[..58].to_vec();
+            buf[22..24].copy_from_slice(&(chain.len() as u16).to_be_bytes());
+            buf[24] = first;
+            buf.extend_from_slice(&chain);
+            if rng.next().is_multiple_of(4) {
+                let pos = rng.next() as usize % buf.len();
+                buf[pos] = rng.next() as u8;
+            }
+            let len = match rng.next().is_multiple_of(2) {
+                true => buf.len(),
+                false => rng.next() as usize % (buf.len() + 1),
+            };
+            if let Ok(Packet::IpV6(ref p, _)) = parse_packet(&buf[..len]) {
+                let _ = p.upper_layer();
+            }
+        }
+    }
+}
@@ -0,0 +1,467 @@
+extern crate common;
+extern crate ip;
+extern crate ipv6;
+
+use std::convert::TryFrom;
+use common::{Emit, Layer, ParseError, Reason};
+
+/// TCP option kinds, see `TcpOption` for the decoded forms
+#[allow(non_snake_case)]
+#[allow(non_upper_case_globals)]
+pub mod TcpOptionKinds {
+    /// End of Option List [RFC 793]
+    pub const EOL: u8 = 0;
+    /// No-Operation [RFC 793]
+    pub const NOP: u8 = 1;
+    /// Maximum Segment Size [RFC 793]
+    pub const MSS: u8 = 2;
+    /// Window Scale [RFC 7323]
+    pub const WSCALE: u8 = 3;
+    /// SACK Permitted [RFC 2018]
+    pub const SACK_PERMITTED: u8 = 4;
+    /// SACK [RFC 2018]
+    pub const SACK: u8 = 5;
+    /// Timestamps [RFC 7323]
+    pub const TIMESTAMPS: u8 = 8;
+    /// TCP Authentication Option [RFC 5925]
+    pub const AO: u8 = 29;
+    /// Multipath TCP [RFC 8684]
+    pub const MPTCP: u8 = 30;
+}
+
+/// MPTCP option subtypes [RFC 8684]
+#[allow(non_snake_case)]
+#[allow(non_upper_case_globals)]
+pub mod MptcpSubtypes {
+    pub const MP_CAPABLE: u8 = 0;
+    pub const MP_JOIN: u8 = 1;
+    pub const DSS: u8 = 2;
+    pub const ADD_ADDR: u8 = 3;
+    pub const REMOVE_ADDR: u8 = 4;
+    pub const MP_PRIO: u8 = 5;
+    pub const MP_FAIL: u8 = 6;
+    pub const MP_FASTCLOSE: u8 = 7;
+    pub const MP_TCPRST: u8 = 8;
+}
+
+/// Control bits of the TCP header, NS included
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct TcpFlags(pub u16);
+
+impl TcpFlags {
+    pub const FIN: TcpFlags = TcpFlags(0x001);
+    pub const SYN: TcpFlags = TcpFlags(0x002);
+    pub const RST: TcpFlags = TcpFlags(0x004);
+    pub const PSH: TcpFlags = TcpFlags(0x008);
+    pub const ACK: TcpFlags = TcpFlags(0x010);
+    pub const URG: TcpFlags = TcpFlags(0x020);
+    pub const ECE: TcpFlags = TcpFlags(0x040);
+    pub const CWR: TcpFlags = TcpFlags(0x080);
+    pub const NS: TcpFlags = TcpFlags(0x100);
+
+    /// All the bits of `other` are set
+    pub fn contains(&self, other: TcpFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for TcpFlags {
+    type Output = TcpFlags;
+    fn bitor(self, other: TcpFlags) -> TcpFlags {
+        TcpFlags(self.0 | other.0)
+    }
+}
+
+#[derive(Debug,PartialEq)]
+pub enum TcpOption <'a> {
+    Mss(u16),
+    WindowScale(u8),
+    SackPermitted,
+    /// Left and right edges of the SACK blocks
+    Sack(Vec<(u32, u32)>),
+    Timestamps { value: u32, echo_reply: u32 },
+    /// TCP Authentication Option
+    Ao { key_id: u8, rnext_key_id: u8, mac: &'a [u8] },
+    /// Multipath TCP, `data` is everything after the length byte, the subtype nibble included
+    Mptcp { subtype: u8, data: &'a [u8] },
+    Unknown { kind: u8, data: &'a [u8] },
+}
+
+#[derive(Debug,PartialEq)]
+pub struct TcpHeader <'a> {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub seq: u32,
+    pub ack: u32,
+    /// Header length in 32-bit words
+    pub data_offset: u8,
+    pub flags: TcpFlags,
+    pub window: u16,
+    pub checksum: u16,
+    pub urgent_ptr: u16,
+    pub options: Option<Vec<TcpOption<'a>>>,
+}
+
+#[derive(Debug,PartialEq)]
+pub struct TcpSegment <'a> {
+    pub tcp_hdr: TcpHeader<'a>,
+    pub payload: &'a [u8],
+}
+
+fn parse_option(kind: u8, data: &[u8]) -> Result<TcpOption<'_>, Reason> {
+    let expect = |len: usize, name: &'static str| if data.len() == len { Ok(()) } else { Err(Reason::BadField(name)) };
+    Ok(match kind {
+        TcpOptionKinds::MSS => {
+            expect(2, "mss")?;
+            TcpOption::Mss(common::u16_parse(data))
+        }
+        TcpOptionKinds::WSCALE => {
+            expect(1, "window scale")?;
+            TcpOption::WindowScale(data[0])
+        }
+        TcpOptionKinds::SACK_PERMITTED => {
+            expect(0, "sack permitted")?;
+            TcpOption::SackPermitted
+        }
+        TcpOptionKinds::SACK => {
+            if data.is_empty() || !data.len().is_multiple_of(8) {
+                return Err(Reason::BadField("sack"));
+            }
+            TcpOption::Sack(data.chunks(8).map(|b| (common::u32_parse(b), common::u32_parse(&b[4..]))).collect())
+        }
+        TcpOptionKinds::TIMESTAMPS => {
+            expect(8, "timestamps")?;
+            TcpOption::Timestamps { value: common::u32_parse(data), echo_reply: common::u32_parse(&data[4..]) }
+        }
+        TcpOptionKinds::AO => {
+            if data.len() < 2 {
+                return Err(Reason::BadField("ao"));
+            }
+            TcpOption::Ao { key_id: data[0], rnext_key_id: data[1], mac: &data[2..] }
+        }
+        TcpOptionKinds::MPTCP => {
+            if data.is_empty() {
+                return Err(Reason::BadField("mptcp"));
+            }
+            TcpOption::Mptcp { subtype: data[0] >> 4, data }
+        }
+        _ => TcpOption::Unknown { kind, data },
+    })
+}
+
+fn parse_options(buf: &[u8]) -> Result<Option<Vec<TcpOption<'_>>>, ParseError> {
+    let mut result = vec!();
+    let mut ptr = 0;
+    while ptr < buf.len() {
+        match buf[ptr] {
+            TcpOptionKinds::EOL => break,
+            TcpOptionKinds::NOP => {
+                ptr += 1;
+                continue;
+            }
+            _ => {}
+        }
+        if ptr + 2 > buf.len() || buf[ptr + 1] < 2 || ptr + buf[ptr + 1] as usize > buf.len() {
+            return Err(ParseError::new(Layer::Tcp, ptr, Reason::TruncatedOptions));
+        }
+        let end = ptr + buf[ptr + 1] as usize;
+        result.push(parse_option(buf[ptr], &buf[ptr + 2..end]).map_err(|r| ParseError::new(Layer::Tcp, ptr, r))?);
+        ptr = end;
+    }
+    match result.len() {
+        0 => Ok(None),
+        _ => Ok(Some(result)),
+    }
+}
+
+impl <'a> TryFrom<&'a [u8]> for TcpHeader<'a> {
+    type Error = ParseError;
+
+    fn try_from(buf: &'a [u8]) -> Result<TcpHeader<'a>, Self::Error> {
+        if buf.len() < 20 {
+            return Err(ParseError::truncated(Layer::Tcp, 0, 20, buf.len()));
+        }
+        let data_offset = buf[12] >> 4;
+        if data_offset < 5 {
+            return Err(ParseError::new(Layer::Tcp, 12, Reason::BadHeaderLength(data_offset)));
+        }
+        let hdr_len = data_offset as usize * 4;
+        if hdr_len > buf.len() {
+            return Err(ParseError::truncated(Layer::Tcp, 0, hdr_len, buf.len()));
+        }
+        Ok(TcpHeader {
+            src_port: common::u16_parse(&buf[0..2]),
+            dst_port: common::u16_parse(&buf[2..4]),
+            seq: common::u32_parse(&buf[4..8]),
+            ack: common::u32_parse(&buf[8..12]),
+            data_offset,
+            flags: TcpFlags(common::u16_parse(&buf[12..14]) & 0x1ff),
+            window: common::u16_parse(&buf[14..16]),
+            checksum: common::u16_parse(&buf[16..18]),
+            urgent_ptr: common::u16_parse(&buf[18..20]),
+            options: match data_offset > 5 {
+                true => parse_options(&buf[20..hdr_len]).map_err(|e| e.shift(20))?,
+                _ => None
+            },
+        })
+    }
+}
+
+impl <'a> TryFrom<&'a [u8]> for TcpSegment<'a> {
+    type Error = ParseError;
+
+    fn try_from(buf: &'a [u8]) -> Result<TcpSegment<'a>, Self::Error> {
+        let tcp_hdr = TcpHeader::try_from(buf)?;
+        let payload = &buf[tcp_hdr.data_offset as usize * 4..];
+        Ok(TcpSegment { tcp_hdr, payload })
+    }
+}
+
+/// Segment carried by an IPv4 packet. Offsets of errors count from the IPv4 header.
+/// Non-first fragments fail with `BadField("offset")`, they carry no TCP header.
+impl <'a, 'b> TryFrom<&'b ip::IpV4Packet<'a>> for TcpSegment<'a> {
+    type Error = ParseError;
+
+    fn try_from(packet: &'b ip::IpV4Packet<'a>) -> Result<TcpSegment<'a>, Self::Error> {
+        let hdr = &packet.ip_hdr;
+        if hdr.protocol != ip::IpNextHeaderProtocols::Tcp {
+            return Err(ParseError::new(Layer::Tcp, 9, Reason::WrongProtocol(hdr.protocol.0)));
+        }
+        if hdr.offset != 0 {
+            return Err(ParseError::new(Layer::Tcp, 6, Reason::BadField("offset")));
+        }
+        TcpSegment::try_from(packet.payload).map_err(|e| e.shift(hdr.hdr_length as usize * 4))
+    }
+}
+
+/// Segment after the IPv6 extension headers. Offsets of errors count from the IPv6 header.
+impl <'a, 'b> TryFrom<&'b ipv6::IpV6Packet<'a>> for TcpSegment<'a> {
+    type Error = ParseError;
+
+    fn try_from(packet: &'b ipv6::IpV6Packet<'a>) -> Result<TcpSegment<'a>, Self::Error> {
+        let chain = packet.extensions()?;
+        let start = ipv6::IPV6_HDR_LEN + chain.payload_offset;
+        if chain.protocol != ip::IpNextHeaderProtocols::Tcp {
+            return Err(ParseError::new(Layer::Tcp, start, Reason::WrongProtocol(chain.protocol.0)));
+        }
+        if chain.fragment().is_some_and(|f| f.offset != 0) {
+            return Err(ParseError::new(Layer::Tcp, start, Reason::BadField("offset")));
+        }
+        TcpSegment::try_from(&packet.payload[chain.payload_offset..]).map_err(|e| e.shift(start))
+    }
+}
+
+impl <'a> Emit for TcpOption<'a> {
+    fn emit_len(&self) -> usize {
+        2 + match *self {
+            TcpOption::Mss(_) => 2,
+            TcpOption::WindowScale(_) => 1,
+            TcpOption::SackPermitted => 0,
+            TcpOption::Sack(ref blocks) => 8 * blocks.len(),
+            TcpOption::Timestamps { .. } => 8,
+            TcpOption::Ao { mac, .. } => 2 + mac.len(),
+            TcpOption::Mptcp { data, .. } => data.len(),
+            TcpOption::Unknown { data, .. } => data.len(),
+        }
+    }
+
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        let len = self.emit_len();
+        if len > 0xff {
+            return Err(ParseError::new(Layer::Tcp, 1, Reason::BadField("option length")));
+        }
+        if buf.len() < len {
+            return Err(ParseError::new(Layer::Tcp, 0, Reason::TruncatedOptions));
+        }
+        buf[1] = len as u8;
+        buf[0] = match *self {
+            TcpOption::Mss(mss) => {
+                common::u16_write(&mut buf[2..], mss);
+                TcpOptionKinds::MSS
+            }
+            TcpOption::WindowScale(shift) => {
+                buf[2] = shift;
+                TcpOptionKinds::WSCALE
+            }
+            TcpOption::SackPermitted => TcpOptionKinds::SACK_PERMITTED,
+            TcpOption::Sack(ref blocks) => {
+                for (i, &(left, right)) in blocks.iter().enumerate() {
+                    common::u32_write(&mut buf[2 + 8 * i..], left);
+                    common::u32_write(&mut buf[6 + 8 * i..], right);
+                }
+                TcpOptionKinds::SACK
+            }
+            TcpOption::Timestamps { value, echo_reply } => {
+                common::u32_write(&mut buf[2..], value);
+                common::u32_write(&mut buf[6..], echo_reply);
+                TcpOptionKinds::TIMESTAMPS
+            }
+            TcpOption::Ao { key_id, rnext_key_id, mac } => {
+                buf[2] = key_id;
+                buf[3] = rnext_key_id;
+                buf[4..len].copy_from_slice(mac);
+                TcpOptionKinds::AO
+            }
+            TcpOption::Mptcp { data, .. } => {
+                buf[2..len].copy_from_slice(data);
+                TcpOptionKinds::MPTCP
+            }
+            TcpOption::Unknown { kind, data } => {
+                buf[2..len].copy_from_slice(data);
+                kind
+            }
+        };
+        Ok(len)
+    }
+}
+
+impl <'a> Emit for TcpHeader<'a> {
+    fn emit_len(&self) -> usize {
+        self.data_offset as usize * 4
+    }
+
+    /// Write the header, options are padded with End of Option List up to `data_offset`.
+    /// `checksum` is written as is, it covers the IP pseudo-header and the payload.
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        let len = self.emit_len();
+        if !(5..=15).contains(&self.data_offset) {
+            return Err(ParseError::new(Layer::Tcp, 12, Reason::BadHeaderLength(self.data_offset)));
+        }
+        if self.flags.0 > 0x1ff {
+            return Err(ParseError::new(Layer::Tcp, 12, Reason::BadField("flags")));
+        }
+        if buf.len() < len {
+            return Err(ParseError::truncated(Layer::Tcp, 0, len, buf.len()));
+        }
+        common::u16_write(&mut buf[0..], self.src_port);
+        common::u16_write(&mut buf[2..], self.dst_port);
+        common::u32_write(&mut buf[4..], self.seq);
+        common::u32_write(&mut buf[8..], self.ack);
+        common::u16_write(&mut buf[12..], (self.data_offset as u16) << 12 | self.flags.0);
+        common::u16_write(&mut buf[14..], self.window);
+        common::u16_write(&mut buf[16..], self.checksum);
+        common::u16_write(&mut buf[18..], self.urgent_ptr);
+
+        let mut ptr = 20;
+        if let Some(ref options) = self.options {
+            for option in options {
+                ptr += option.emit(&mut buf[ptr..len]).map_err(|e| e.shift(ptr))?;
+            }
+        }
+        for b in &mut buf[ptr..len] {
+            *b = TcpOptionKinds::EOL;
+        }
+        Ok(len)
+    }
+}
+
+impl <'a> Emit for TcpSegment<'a> {
+    fn emit_len(&self) -> usize {
+        self.tcp_hdr.emit_len() + self.payload.len()
+    }
+
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        let ptr = self.tcp_hdr.emit(buf)?;
+        if buf.len() < ptr + self.payload.len() {
+            return Err(ParseError::truncated(Layer::Tcp, ptr, self.payload.len(), buf.len() - ptr));
+        }
+        buf[ptr..ptr + self.payload.len()].copy_from_slice(self.payload);
+        Ok(ptr + self.payload.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEGMENT: [u8; 36] = [0x1f, 0x90, 0xaa, 0x5c, 0xa2, 0x1f, 0x25, 0xd1, 0xf5, 0x43, 0x81, 0x57, 0x80, 0x18,
+        0x00, 0xf9, 0x70, 0x6d, 0x00, 0x00, 0x01, 0x01, 0x08, 0x0a, 0x32, 0x87, 0xea, 0x70, 0x62, 0x2f,
+        0x45, 0x0d, 0x73, 0x74, 0x61, 0x72];
+
+    #[test]
+    fn test_ok() {
+        let res = TcpSegment::try_from(&SEGMENT[..]).unwrap();
+        assert_eq!(res.tcp_hdr, TcpHeader {
+            src_port: 8080,
+            dst_port: 43612,
+            seq: 0xa21f25d1,
+            ack: 0xf5438157,
+            data_offset: 8,
+            flags: TcpFlags::PSH | TcpFlags::ACK,
+            window: 249,
+            checksum: 0x706d,
+            urgent_ptr: 0,
+            options: Some(vec!(TcpOption::Timestamps { value: 0x3287ea70, echo_reply: 0x622f450d })),
+        });
+        assert_eq!(res.payload, b"star");
+        assert!(res.tcp_hdr.flags.contains(TcpFlags::ACK) && !res.tcp_hdr.flags.contains(TcpFlags::SYN));
+    }
+    #[test]
+    fn test_options() {
+        let buf = [2u8, 4, 0x05, 0xb4, 1, 3, 3, 7, 4, 2, 5, 10, 0, 0, 0, 1, 0, 0, 0, 2,
+                   29, 6, 1, 2, 0xaa, 0xbb, 30, 4, 0x01, 0x81, 0];
+        assert_eq!(parse_options(&buf), Ok(Some(vec!(
+            TcpOption::Mss(1460),
+            TcpOption::WindowScale(7),
+            TcpOption::SackPermitted,
+            TcpOption::Sack(vec!((1, 2))),
+            TcpOption::Ao { key_id: 1, rnext_key_id: 2, mac: &[0xaa, 0xbb] },
+            TcpOption::Mptcp { subtype: MptcpSubtypes::MP_CAPABLE, data: &[0x01, 0x81] },
+        ))));
+        assert_eq!(parse_options(&[1, 2, 3, 2, 0x05]), Err(ParseError::new(Layer::Tcp, 1, Reason::BadField("mss"))));
+        assert_eq!(parse_options(&[1, 8, 10, 0, 0]), Err(ParseError::new(Layer::Tcp, 1, Reason::TruncatedOptions)));
+        assert_eq!(parse_options(&[1, 8, 1]), Err(ParseError::new(Layer::Tcp, 1, Reason::TruncatedOptions)));
+        assert_eq!(parse_options(&[0x22, 0]), Err(ParseError::new(Layer::Tcp, 0, Reason::TruncatedOptions)));
+    }
+    #[test]
+    fn test_errors() {
+        for len in 0..32 {
+            assert!(TcpSegment::try_from(&SEGMENT[..len]).is_err(), "len {}", len);
+        }
+        assert_eq!(TcpHeader::try_from(&SEGMENT[..19]), Err(ParseError::truncated(Layer::Tcp, 0, 20, 19)));
+        assert_eq!(TcpHeader::try_from(&SEGMENT[..24]), Err(ParseError::truncated(Layer::Tcp, 0, 32, 24)));
+        let mut buf = SEGMENT;
+        buf[12] = 0x40;
+        assert_eq!(TcpHeader::try_from(&buf[..]), Err(ParseError::new(Layer::Tcp, 12, Reason::BadHeaderLength(4))));
+        buf[12] = 0x80;
+        buf[23] = 0x0b;
+        assert_eq!(TcpHeader::try_from(&buf[..]), Err(ParseError::new(Layer::Tcp, 22, Reason::TruncatedOptions)));
+    }
+    #[test]
+    fn test_emit() {
+        let res = TcpSegment::try_from(&SEGMENT[..]).unwrap();
+        let mut out = vec!();
+        assert_eq!(res.emit_vec(&mut out), Ok(36));
+        // the NOPs come back as trailing EOL padding
+        assert_eq!(&out[20..32], &[0x08, 0x0a, 0x32, 0x87, 0xea, 0x70, 0x62, 0x2f, 0x45, 0x0d, 0, 0]);
+        assert_eq!(TcpSegment::try_from(&out[..]), Ok(res));
+
+        let buf = [2u8, 4, 0x05, 0xb4, 1, 3, 3, 7, 4, 2, 5, 10, 0, 0, 0, 1, 0, 0, 0, 2,
+                   29, 6, 1, 2, 0xaa, 0xbb, 30, 4, 0x01, 0x81];
+        for option in parse_options(&buf).unwrap().unwrap() {
+            let mut out = vec!();
+            option.emit_vec(&mut out).unwrap();
+            assert_eq!(parse_options(&out), Ok(Some(vec!(option))));
+        }
+        let hdr = TcpHeader { data_offset: 5, ..TcpHeader::try_from(&SEGMENT[..]).unwrap() };
+        assert_eq!(hdr.emit(&mut [0u8; 20]), Err(ParseError::new(Layer::Tcp, 20, Reason::TruncatedOptions)));
+    }
+    #[test]
+    fn test_from_ip() {
+        let mut frame = vec!(0x11u8, 0x12, 0x13, 0x14, 0x15, 0x16, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x08, 0x00,
+            0x45, 0x00, 0x00, 0x38, 0xe5, 0x46, 0x40, 0x00, 0x3e, 0x06, 0x00, 0x00, 0xac, 0x10, 0x00, 0x53, 0xc0, 0xa8, 0x16, 0x47);
+        frame.extend_from_slice(&SEGMENT);
+        let packet = ip::IpV4Packet::try_from(&frame[..]).unwrap();
+        let res = TcpSegment::try_from(&packet).unwrap();
+        assert_eq!(res.tcp_hdr.dst_port, 43612);
+        assert_eq!(res.payload, b"star");
+
+        frame[23] = 17;
+        let packet = ip::IpV4Packet::try_from(&frame[..]).unwrap();
+        assert_eq!(TcpSegment::try_from(&packet), Err(ParseError::new(Layer::Tcp, 9, Reason::WrongProtocol(17))));
+        frame[23] = 6;
+        frame[46] = 0x40;
+        let packet = ip::IpV4Packet::try_from(&frame[..]).unwrap();
+        assert_eq!(TcpSegment::try_from(&packet), Err(ParseError::new(Layer::Tcp, 32, Reason::BadHeaderLength(4))));
+    }
+}
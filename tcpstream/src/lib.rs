@@ -0,0 +1,699 @@
+extern crate common;
+extern crate ip;
+extern crate ipv6;
+extern crate tcp;
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use common::ParseError;
+use tcp::{TcpFlags, TcpOption, TcpSegment};
+
+/// Connection seen from its initiator. When the handshake was missed, the sender
+/// of the first segment seen is taken as the client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ConnectionKey {
+    pub client: SocketAddr,
+    pub server: SocketAddr,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Direction {
+    ToServer,
+    ToClient,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CloseReason {
+    /// Both sides sent FIN and all data up to it was delivered
+    Fin,
+    Reset,
+    /// No segment within `StreamConfig::timeout`
+    Timeout,
+    /// Removed to make room, `StreamConfig::max_connections` was reached
+    Evicted,
+    /// `StreamReassembler::flush` was called, usually at the end of a capture
+    Flush,
+}
+
+/// Receives the reassembled streams
+pub trait StreamHandler {
+    /// A connection starts being tracked
+    fn open(&mut self, _key: &ConnectionKey) {}
+
+    /// Next bytes of the stream in `direction`, in sequence order and each delivered once
+    fn data(&mut self, key: &ConnectionKey, direction: Direction, data: &[u8]);
+
+    /// `len` bytes of the stream were never captured, the data that follows comes after them
+    fn gap(&mut self, _key: &ConnectionKey, _direction: Direction, _len: usize) {}
+
+    /// The connection is no longer tracked, no callback for it follows
+    fn close(&mut self, _key: &ConnectionKey, _reason: CloseReason) {}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamConfig {
+    /// Connections idle for longer are closed
+    pub timeout: Duration,
+    /// Out-of-order bytes held per direction, beyond it the stream skips to the data held
+    /// and reports a gap
+    pub max_buffered: usize,
+    /// Out-of-order segments held per direction, merged where they touch. Beyond it the
+    /// stream skips as with `max_buffered`.
+    pub max_segments: usize,
+    /// Connections tracked at once, the least recently active are evicted beyond it
+    pub max_connections: usize,
+    /// Connections closed by FIN or RST are remembered this long, their late segments
+    /// are ignored instead of opening a new connection
+    pub linger: Duration,
+    /// Open connections on data segments when the handshake was missed, otherwise only on SYN
+    pub midstream: bool,
+}
+
+impl Default for StreamConfig {
+    fn default() -> StreamConfig {
+        StreamConfig {
+            timeout: Duration::from_secs(300),
+            max_buffered: 1 << 20,
+            max_segments: 1 << 10,
+            max_connections: 1 << 16,
+            linger: Duration::from_secs(60),
+            midstream: true,
+        }
+    }
+}
+
+/// Signed distance from `b` to `a` in sequence space
+fn seq_diff(a: u32, b: u32) -> i32 {
+    a.wrapping_sub(b) as i32
+}
+
+#[derive(Default)]
+struct HalfStream {
+    /// Sequence number of the first byte seen, offsets count from it. `None` until the first segment
+    base: Option<u32>,
+    /// Offset of the next byte to deliver
+    next: u64,
+    /// Out-of-order data by offset. Entries never overlap, new data touching the end
+    /// of an entry is appended to it.
+    pending: BTreeMap<u64, Vec<u8>>,
+    buffered: usize,
+    /// Offset of the FIN
+    fin: Option<u64>,
+    closed: bool,
+    /// Window scale the sender announced in its SYN
+    wscale: Option<u8>,
+    /// Receive window the peer last advertised for this direction
+    window: Option<u32>,
+}
+
+impl HalfStream {
+    /// Sequence number of the next byte to deliver
+    fn next_seq(&self) -> Option<u32> {
+        self.base.map(|base| base.wrapping_add(self.next as u32))
+    }
+
+    /// Offset of `seq`, taken within 2 GiB of the next byte, negative before the first byte seen
+    fn offset(&self, seq: u32) -> Option<i64> {
+        self.next_seq().map(|next| self.next as i64 + seq_diff(seq, next) as i64)
+    }
+
+    /// Deliver `data` starting at `seq` if it is the next in sequence, keep it otherwise
+    fn add<H: StreamHandler>(&mut self, handler: &mut H, key: &ConnectionKey, direction: Direction, seq: u32, data: &[u8]) {
+        let start = match self.offset(seq) {
+            Some(start) => start,
+            None => return,
+        };
+        if self.closed || data.is_empty() || start + data.len() as i64 <= self.next as i64 {
+            return;
+        }
+        let (start, data) = match start < self.next as i64 {
+            true => (self.next, &data[(self.next as i64 - start) as usize..]),
+            false => (start as u64, data),
+        };
+        if start == self.next && self.pending.is_empty() {
+            handler.data(key, direction, data);
+            self.next += data.len() as u64;
+        } else {
+            self.hold(start, data);
+        }
+        self.deliver(handler, key, direction);
+    }
+
+    /// Keep the bytes of `data` that no held entry covers, the first copy received wins
+    fn hold(&mut self, start: u64, data: &[u8]) {
+        let end = start + data.len() as u64;
+        let mut pos = start;
+        while pos < end {
+            let limit = self.pending.range(pos + 1..).next().map_or(end, |(&k, _)| k.min(end));
+            let before = self.pending.range_mut(..=pos).next_back();
+            let at = match before {
+                Some((&k, held)) if k + held.len() as u64 > pos => {
+                    pos = k + held.len() as u64;
+                    continue;
+                }
+                Some((&k, held)) if k + held.len() as u64 == pos => {
+                    held.extend_from_slice(&data[(pos - start) as usize..(limit - start) as usize]);
+                    k
+                }
+                _ => {
+                    self.pending.insert(pos, data[(pos - start) as usize..(limit - start) as usize].to_vec());
+                    pos
+                }
+            };
+            self.buffered += (limit - pos) as usize;
+            pos = limit;
+            self.merge_next(at);
+        }
+    }
+
+    /// Append the entry touching the end of the one at `at` when it is not longer,
+    /// so that each byte is copied a logarithmic number of times
+    fn merge_next(&mut self, at: u64) {
+        let end = at + self.pending[&at].len() as u64;
+        if self.pending.get(&end).is_some_and(|next| next.len() <= self.pending[&at].len()) {
+            let next = self.pending.remove(&end).unwrap();
+            self.pending.get_mut(&at).unwrap().extend_from_slice(&next);
+        }
+    }
+
+    /// Whether a RST sent at `seq` falls in the receive window [RFC 793 3.4], any at or
+    /// after the next byte does while the window is unknown
+    fn accepts_reset(&self, seq: u32) -> bool {
+        let next = match self.next_seq() {
+            Some(next) => next,
+            None => return true,
+        };
+        let offset = seq_diff(seq, next);
+        offset == 0 || offset > 0 && self.window.is_none_or(|window| (offset as u32) < window)
+    }
+
+    /// Deliver the pending data that became contiguous, then FIN if reached
+    fn deliver<H: StreamHandler>(&mut self, handler: &mut H, key: &ConnectionKey, direction: Direction) {
+        while let Some(entry) = self.pending.first_entry() {
+            if *entry.key() > self.next {
+                break;
+            }
+            let (start, data) = entry.remove_entry();
+            self.buffered -= data.len();
+            let skip = (self.next - start) as usize;
+            if skip < data.len() {
+                handler.data(key, direction, &data[skip..]);
+                self.next += (data.len() - skip) as u64;
+            }
+        }
+        if !self.closed && self.fin == Some(self.next) {
+            self.closed = true;
+            self.next += 1;
+        }
+    }
+
+    /// Skip to the earliest pending data, reporting the bytes in between as a gap
+    fn skip<H: StreamHandler>(&mut self, handler: &mut H, key: &ConnectionKey, direction: Direction) {
+        if let Some(&first) = self.pending.keys().next() {
+            handler.gap(key, direction, (first - self.next) as usize);
+            self.next = first;
+            self.deliver(handler, key, direction);
+        }
+    }
+
+    /// Deliver everything held, holes reported as gaps
+    fn flush<H: StreamHandler>(&mut self, handler: &mut H, key: &ConnectionKey, direction: Direction) {
+        while !self.pending.is_empty() {
+            self.skip(handler, key, direction);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Connection {
+    id: u64,
+    last_seen: Duration,
+    to_server: HalfStream,
+    to_client: HalfStream,
+}
+
+impl Connection {
+    /// Streams of the sender and of the receiver of a segment sent in `direction`
+    fn halves(&mut self, direction: Direction) -> (&mut HalfStream, &mut HalfStream) {
+        match direction {
+            Direction::ToServer => (&mut self.to_server, &mut self.to_client),
+            Direction::ToClient => (&mut self.to_client, &mut self.to_server),
+        }
+    }
+
+    fn flush<H: StreamHandler>(&mut self, handler: &mut H, key: &ConnectionKey) {
+        self.to_server.flush(handler, key, Direction::ToServer);
+        self.to_client.flush(handler, key, Direction::ToClient);
+    }
+}
+
+/// Tracks the sequence space of each connection and delivers its byte streams in order.
+/// Retransmitted and overlapping bytes are delivered once, the first copy received wins.
+pub struct StreamReassembler<H: StreamHandler> {
+    handler: H,
+    config: StreamConfig,
+    connections: HashMap<ConnectionKey, Connection>,
+    /// Keys by the activity they had when queued. An entry is moved back when its
+    /// connection was active since, and dropped when the connection is gone.
+    queue: VecDeque<(ConnectionKey, u64, Duration)>,
+    next_id: u64,
+    /// Connections closed by FIN or RST within `linger`, with the time they closed
+    closed: HashMap<ConnectionKey, Duration>,
+    closed_queue: VecDeque<(ConnectionKey, Duration)>,
+}
+
+impl <H: StreamHandler> StreamReassembler<H> {
+    pub fn new(handler: H, config: StreamConfig) -> StreamReassembler<H> {
+        StreamReassembler {
+            handler,
+            config,
+            connections: HashMap::new(),
+            queue: VecDeque::new(),
+            next_id: 0,
+            closed: HashMap::new(),
+            closed_queue: VecDeque::new(),
+        }
+    }
+
+    pub fn handler(&self) -> &H {
+        &self.handler
+    }
+
+    pub fn handler_mut(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    pub fn into_handler(self) -> H {
+        self.handler
+    }
+
+    /// Number of connections tracked
+    pub fn connections(&self) -> usize {
+        self.connections.len()
+    }
+
+    fn close(&mut self, key: &ConnectionKey, reason: CloseReason) {
+        if let Some(mut connection) = self.connections.remove(key) {
+            connection.flush(&mut self.handler, key);
+            self.handler.close(key, reason);
+        }
+    }
+
+    /// Last activity of the connection queued as `key`, `None` if that connection is gone
+    fn last_seen(&self, key: &ConnectionKey, id: u64) -> Option<Duration> {
+        self.connections.get(key).filter(|c| c.id == id).map(|c| c.last_seen)
+    }
+
+    /// Close the connections idle since more than `timeout` before `now`, returns their number.
+    /// Closed connections are forgotten `linger` after they closed.
+    pub fn expire(&mut self, now: Duration) -> usize {
+        let linger = self.config.linger;
+        while self.closed_queue.front().is_some_and(|&(_, closed)| now.saturating_sub(closed) > linger) {
+            self.forget_oldest();
+        }
+        let timeout = self.config.timeout;
+        let mut expired = 0;
+        while self.queue.front().is_some_and(|&(_, _, stamp)| now.saturating_sub(stamp) > timeout) {
+            let (key, id, _) = self.queue.pop_front().unwrap();
+            match self.last_seen(&key, id) {
+                Some(last_seen) if now.saturating_sub(last_seen) > timeout => {
+                    self.close(&key, CloseReason::Timeout);
+                    expired += 1;
+                },
+                Some(last_seen) => self.queue.push_back((key, id, last_seen)),
+                None => {},
+            }
+        }
+        expired
+    }
+
+    /// Evict the least recently active connections until one more fits
+    fn evict(&mut self) {
+        while self.connections.len() >= self.config.max_connections.max(1) {
+            let (key, id, stamp) = match self.queue.pop_front() {
+                Some(entry) => entry,
+                None => break,
+            };
+            match self.last_seen(&key, id) {
+                Some(last_seen) if last_seen > stamp => self.queue.push_back((key, id, last_seen)),
+                Some(_) => self.close(&key, CloseReason::Evicted),
+                None => {},
+            }
+        }
+    }
+
+    /// Drop the entries of removed connections once they outnumber the live ones
+    fn compact(&mut self) {
+        if self.queue.len() > 2 * self.connections.len() + 64 {
+            let connections = &self.connections;
+            self.queue.retain(|&(ref key, id, _)| connections.get(key).is_some_and(|c| c.id == id));
+        }
+        if self.closed_queue.len() > 2 * self.closed.len() + 64 {
+            let closed = &self.closed;
+            self.closed_queue.retain(|&(ref key, at)| closed.get(key) == Some(&at));
+        }
+    }
+
+    /// Forget the connection that closed first, false if there is none
+    fn forget_oldest(&mut self) -> bool {
+        while let Some((key, at)) = self.closed_queue.pop_front() {
+            if self.closed.get(&key) == Some(&at) {
+                self.closed.remove(&key);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Remember a connection closed by FIN or RST at `now`
+    fn linger(&mut self, key: ConnectionKey, now: Duration) {
+        while self.closed.len() >= self.config.max_connections.max(1) && self.forget_oldest() {}
+        self.closed.insert(key, now);
+        self.closed_queue.push_back((key, now));
+        self.compact();
+    }
+
+    fn open(&mut self, key: ConnectionKey, now: Duration) {
+        self.evict();
+        let id = self.next_id;
+        self.next_id += 1;
+        self.connections.insert(key, Connection { id, last_seen: now, ..Connection::default() });
+        self.queue.push_back((key, id, now));
+        self.compact();
+        self.handler.open(&key);
+    }
+
+    /// Close all connections, least recently active first, delivering the data held
+    pub fn flush(&mut self) {
+        while let Some((key, id, _)) = self.queue.pop_front() {
+            if self.last_seen(&key, id).is_some() {
+                self.close(&key, CloseReason::Flush);
+            }
+        }
+    }
+
+    /// Add a segment sent from `src` to `dst`, captured at `now`. Connections open on SYN,
+    /// or on data with `midstream`. A RST closes them when it falls in the receive window.
+    pub fn process_segment(&mut self, src: SocketAddr, dst: SocketAddr, segment: &TcpSegment, now: Duration) {
+        self.expire(now);
+        let hdr = &segment.tcp_hdr;
+        let syn = hdr.flags.contains(TcpFlags::SYN);
+        let ack = hdr.flags.contains(TcpFlags::ACK);
+        let rst = hdr.flags.contains(TcpFlags::RST);
+        let forward = ConnectionKey { client: src, server: dst };
+        let backward = ConnectionKey { client: dst, server: src };
+        let (key, direction) = if self.connections.contains_key(&forward) {
+            (forward, Direction::ToServer)
+        } else if self.connections.contains_key(&backward) {
+            (backward, Direction::ToClient)
+        } else {
+            let lingering = self.closed.contains_key(&forward) || self.closed.contains_key(&backward);
+            let opens = !rst && match (syn, ack) {
+                (true, false) => true,
+                (true, true) => !lingering,
+                (false, _) => self.config.midstream && !lingering && !segment.payload.is_empty(),
+            };
+            if !opens {
+                return;
+            }
+            let (key, direction) = match syn && ack {
+                true => (backward, Direction::ToClient),
+                false => (forward, Direction::ToServer),
+            };
+            self.closed.remove(&forward);
+            self.closed.remove(&backward);
+            self.open(key, now);
+            (key, direction)
+        };
+        if rst {
+            if self.connections.get_mut(&key).unwrap().halves(direction).0.accepts_reset(hdr.seq) {
+                self.close(&key, CloseReason::Reset);
+                self.linger(key, now);
+            }
+            return;
+        }
+
+        let max_buffered = self.config.max_buffered;
+        let max_segments = self.config.max_segments;
+        let handler = &mut self.handler;
+        let connection = self.connections.get_mut(&key).unwrap();
+        connection.last_seen = now;
+        let (half, peer) = connection.halves(direction);
+        if syn {
+            half.wscale = hdr.options.iter().flatten().find_map(|option| match *option {
+                TcpOption::WindowScale(shift) => Some(shift.min(14)),
+                _ => None,
+            });
+        }
+        let shift = match (syn, half.wscale, peer.wscale) {
+            (false, Some(shift), Some(_)) => shift,
+            _ => 0,
+        };
+        peer.window = Some(u32::from(hdr.window) << shift);
+        let seq = match syn {
+            true => hdr.seq.wrapping_add(1),
+            false => hdr.seq,
+        };
+        if half.base.is_none() {
+            half.base = Some(seq);
+        }
+        if hdr.flags.contains(TcpFlags::FIN) && half.fin.is_none() {
+            half.fin = half.offset(seq.wrapping_add(segment.payload.len() as u32)).map(|fin| fin.max(0) as u64);
+        }
+        half.add(handler, &key, direction, seq, segment.payload);
+        half.deliver(handler, &key, direction);
+        while half.buffered > max_buffered || half.pending.len() > max_segments {
+            half.skip(handler, &key, direction);
+        }
+        if connection.to_server.closed && connection.to_client.closed {
+            self.close(&key, CloseReason::Fin);
+            self.linger(key, now);
+        }
+    }
+
+    /// Add the TCP segment of an IPv4 packet, other packets are ignored.
+    /// Fails on non-first fragments and malformed segments.
+    pub fn process(&mut self, packet: &ip::IpV4Packet, now: Duration) -> Result<(), ParseError> {
+        let hdr = &packet.ip_hdr;
+        if hdr.protocol != ip::IpNextHeaderProtocols::Tcp {
+            return Ok(());
+        }
+        let segment = TcpSegment::try_from(packet)?;
+        let src = SocketAddr::new(IpAddr::V4(hdr.src_addr()), segment.tcp_hdr.src_port);
+        let dst = SocketAddr::new(IpAddr::V4(hdr.dst_addr()), segment.tcp_hdr.dst_port);
+        self.process_segment(src, dst, &segment, now);
+        Ok(())
+    }
+
+    /// Add the TCP segment of an IPv6 packet, other packets are ignored
+    pub fn process_ipv6(&mut self, packet: &ipv6::IpV6Packet, now: Duration) -> Result<(), ParseError> {
+        if packet.upper_layer()?.0 != ip::IpNextHeaderProtocols::Tcp {
+            return Ok(());
+        }
+        let segment = TcpSegment::try_from(packet)?;
+        let src = SocketAddr::new(IpAddr::V6(packet.ip_hdr.src_addr()), segment.tcp_hdr.src_port);
+        let dst = SocketAddr::new(IpAddr::V6(packet.ip_hdr.dst_addr()), segment.tcp_hdr.dst_port);
+        self.process_segment(src, dst, &segment, now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Default, PartialEq)]
+    struct Collect {
+        to_server: Vec<u8>,
+        to_client: Vec<u8>,
+        gaps: Vec<(Direction, usize)>,
+        opened: usize,
+        closed: Vec<CloseReason>,
+    }
+
+    impl StreamHandler for Collect {
+        fn open(&mut self, _key: &ConnectionKey) {
+            self.opened += 1;
+        }
+        fn data(&mut self, _key: &ConnectionKey, direction: Direction, data: &[u8]) {
+            match direction {
+                Direction::ToServer => self.to_server.extend_from_slice(data),
+                Direction::ToClient => self.to_client.extend_from_slice(data),
+            }
+        }
+        fn gap(&mut self, _key: &ConnectionKey, direction: Direction, len: usize) {
+            self.gaps.push((direction, len));
+        }
+        fn close(&mut self, _key: &ConnectionKey, reason: CloseReason) {
+            self.closed.push(reason);
+        }
+    }
+
+    const CLIENT: &str = "10.0.0.1:40000";
+    const SERVER: &str = "10.0.0.2:80";
+
+    fn segment(seq: u32, flags: TcpFlags, payload: &[u8]) -> TcpSegment<'_> {
+        TcpSegment {
+            tcp_hdr: tcp::TcpHeader {
+                src_port: 0, dst_port: 0, seq, ack: 0, data_offset: 5, flags, window: 100, checksum: 0, urgent_ptr: 0, options: None,
+            },
+            payload,
+        }
+    }
+
+    fn send_at(r: &mut StreamReassembler<Collect>, secs: u64, to_server: bool, seq: u32, flags: TcpFlags, payload: &[u8]) {
+        let (client, server) = (CLIENT.parse().unwrap(), SERVER.parse().unwrap());
+        let (src, dst) = if to_server { (client, server) } else { (server, client) };
+        r.process_segment(src, dst, &segment(seq, flags, payload), Duration::from_secs(secs));
+    }
+
+    fn send(r: &mut StreamReassembler<Collect>, to_server: bool, seq: u32, flags: TcpFlags, payload: &[u8]) {
+        send_at(r, 1, to_server, seq, flags, payload);
+    }
+
+    #[test]
+    fn test_session() {
+        let mut r = StreamReassembler::new(Collect::default(), StreamConfig::default());
+        send(&mut r, true, 0xfffffff0, TcpFlags::SYN, b"");
+        send(&mut r, false, 1000, TcpFlags::SYN | TcpFlags::ACK, b"");
+        assert_eq!(r.connections(), 1);
+        send(&mut r, true, 0xfffffff9, TcpFlags::ACK, b"TP/1.0\r\n");
+        send(&mut r, true, 0xfffffff1, TcpFlags::ACK, b"GET / HT");
+        send(&mut r, true, 0xfffffff1, TcpFlags::ACK, b"GET / HT");
+        send(&mut r, false, 1001, TcpFlags::ACK, b"HTTP/1.0 200 OK\r\n");
+        send(&mut r, false, 1018, TcpFlags::ACK | TcpFlags::FIN, b"\r\n");
+        assert_eq!(r.handler().to_client, b"HTTP/1.0 200 OK\r\n\r\n");
+        send(&mut r, true, 0xfffffff1, TcpFlags::ACK | TcpFlags::PSH, b"GET / HTTP/1.0\r\n\r\n");
+        assert_eq!(r.handler().closed, vec!());
+        send(&mut r, true, 3, TcpFlags::ACK | TcpFlags::FIN, b"");
+        assert_eq!(r.connections(), 0);
+        let res = r.into_handler();
+        assert_eq!(res.to_server, b"GET / HTTP/1.0\r\n\r\n");
+        assert_eq!((res.gaps, res.closed), (vec!(), vec!(CloseReason::Fin)));
+    }
+    #[test]
+    fn test_gaps() {
+        let mut r = StreamReassembler::new(Collect::default(), StreamConfig { max_buffered: 8, ..StreamConfig::default() });
+        send(&mut r, true, 100, TcpFlags::ACK, b"abcd");
+        send(&mut r, true, 110, TcpFlags::ACK, b"klmn");
+        send(&mut r, true, 120, TcpFlags::ACK, b"uvw");
+        assert_eq!(r.handler().to_server, b"abcd");
+        send(&mut r, true, 116, TcpFlags::ACK, b"qrst");
+        assert_eq!(r.handler().to_server, b"abcdklmn");
+        assert_eq!(r.handler().gaps, vec!((Direction::ToServer, 6)));
+        send(&mut r, false, 5000, TcpFlags::ACK, b"xy");
+        send(&mut r, false, 5004, TcpFlags::ACK, b"z");
+        r.flush();
+        let res = r.into_handler();
+        assert_eq!(res.to_server, b"abcdklmnqrstuvw");
+        assert_eq!(res.to_client, b"xyz");
+        assert_eq!(res.gaps, vec!((Direction::ToServer, 6), (Direction::ToServer, 2), (Direction::ToClient, 2)));
+        assert_eq!(res.closed, vec!(CloseReason::Flush));
+    }
+    #[test]
+    fn test_reset_and_timeout() {
+        let mut r = StreamReassembler::new(Collect::default(), StreamConfig::default());
+        send(&mut r, true, 1, TcpFlags::RST, b"");
+        assert_eq!(r.connections(), 0);
+        send(&mut r, true, 1, TcpFlags::ACK, b"a");
+        send(&mut r, false, 1, TcpFlags::RST, b"");
+        assert_eq!(r.connections(), 0);
+        send(&mut r, true, 2, TcpFlags::ACK, b"b");
+        assert_eq!(r.connections(), 0);
+        send_at(&mut r, 62, true, 2, TcpFlags::ACK, b"b");
+        assert_eq!(r.expire(Duration::from_secs(362)), 0);
+        assert_eq!(r.expire(Duration::from_secs(363)), 1);
+        let res = r.into_handler();
+        assert_eq!(res.to_server, b"ab");
+        assert_eq!(res.closed, vec!(CloseReason::Reset, CloseReason::Timeout));
+    }
+    #[test]
+    fn test_linger() {
+        let mut r = StreamReassembler::new(Collect::default(), StreamConfig::default());
+        send(&mut r, true, 100, TcpFlags::SYN, b"");
+        send(&mut r, false, 500, TcpFlags::SYN | TcpFlags::ACK, b"");
+        send(&mut r, true, 101, TcpFlags::ACK | TcpFlags::FIN, b"");
+        send(&mut r, false, 501, TcpFlags::ACK | TcpFlags::FIN, b"");
+        assert_eq!(r.handler().closed, vec!(CloseReason::Fin));
+        send(&mut r, true, 102, TcpFlags::ACK, b"");
+        send(&mut r, false, 501, TcpFlags::ACK | TcpFlags::FIN, b"");
+        send_at(&mut r, 61, true, 102, TcpFlags::ACK, b"late");
+        assert_eq!((r.connections(), r.handler().opened), (0, 1));
+
+        send_at(&mut r, 62, true, 102, TcpFlags::ACK, b"data");
+        assert_eq!((r.connections(), r.handler().opened), (1, 2));
+        send_at(&mut r, 62, false, 501, TcpFlags::RST, b"");
+        send_at(&mut r, 63, true, 1000, TcpFlags::SYN, b"");
+        assert_eq!((r.connections(), r.handler().opened), (1, 3));
+
+        let config = StreamConfig { midstream: false, ..StreamConfig::default() };
+        let mut r = StreamReassembler::new(Collect::default(), config);
+        send(&mut r, true, 100, TcpFlags::ACK, b"data");
+        assert_eq!(r.connections(), 0);
+        send(&mut r, false, 500, TcpFlags::SYN | TcpFlags::ACK, b"");
+        send(&mut r, true, 101, TcpFlags::ACK, b"data");
+        assert_eq!((r.connections(), r.handler().to_server.as_slice()), (1, &b"data"[..]));
+    }
+    #[test]
+    fn test_reset_window() {
+        let mut r = StreamReassembler::new(Collect::default(), StreamConfig::default());
+        send(&mut r, true, 100, TcpFlags::ACK, b"abcd");
+        send(&mut r, false, 500, TcpFlags::ACK, b"");
+        send(&mut r, true, 90, TcpFlags::RST, b"");
+        send(&mut r, true, 204, TcpFlags::RST, b"");
+        assert_eq!(r.connections(), 1);
+        send(&mut r, true, 150, TcpFlags::RST, b"");
+        assert_eq!(r.handler().closed, vec!(CloseReason::Reset));
+    }
+    #[test]
+    fn test_duplicates() {
+        let mut r = StreamReassembler::new(Collect::default(), StreamConfig { max_buffered: 8, ..StreamConfig::default() });
+        send(&mut r, true, 100, TcpFlags::ACK, b"ab");
+        for _ in 0..3 {
+            send(&mut r, true, 110, TcpFlags::ACK, b"klmn");
+        }
+        send(&mut r, true, 110, TcpFlags::ACK, b"klmnop");
+        send(&mut r, true, 110, TcpFlags::ACK, b"kl");
+        send(&mut r, true, 102, TcpFlags::ACK, b"cdefghij");
+        let res = r.into_handler();
+        assert_eq!((res.to_server.as_slice(), res.gaps), (&b"abcdefghijklmnop"[..], vec!()));
+    }
+    #[test]
+    fn test_many_segments() {
+        let mut r = StreamReassembler::new(Collect::default(), StreamConfig::default());
+        send(&mut r, true, 99, TcpFlags::ACK, b"a");
+        for i in 0..100_000u32 {
+            send(&mut r, true, 101 + i, TcpFlags::ACK, &[i as u8]);
+            send(&mut r, true, 101 + i / 2, TcpFlags::ACK, b"?");
+        }
+        assert_eq!(r.handler().to_server.len(), 1);
+        send(&mut r, true, 100, TcpFlags::ACK, b"b");
+        assert_eq!(r.handler().to_server.len(), 100_002);
+        assert!(r.handler().to_server[2..].iter().enumerate().all(|(i, &b)| b == i as u8));
+
+        let config = StreamConfig { max_segments: 4, ..StreamConfig::default() };
+        let mut r = StreamReassembler::new(Collect::default(), config);
+        send(&mut r, true, 100, TcpFlags::ACK, b"a");
+        for seq in [103, 105, 107, 109, 111].iter() {
+            send(&mut r, true, *seq, TcpFlags::ACK, b"x");
+        }
+        let res = r.into_handler();
+        assert_eq!((res.to_server.as_slice(), res.gaps), (&b"ax"[..], vec!((Direction::ToServer, 2))));
+    }
+    #[test]
+    fn test_max_connections() {
+        let config = StreamConfig { max_connections: 2, ..StreamConfig::default() };
+        let mut r = StreamReassembler::new(Collect::default(), config);
+        let server: SocketAddr = SERVER.parse().unwrap();
+        for port in 0..100 {
+            let client = SocketAddr::new(server.ip(), 1000 + port);
+            r.process_segment(client, server, &segment(0, TcpFlags::SYN, b""), Duration::from_secs(port as u64));
+            assert!(r.connections() <= 2 && r.queue.len() <= 2 * 2 + 64);
+        }
+        assert_eq!(r.handler().closed, vec!(CloseReason::Evicted; 98));
+        r.flush();
+        assert!(r.queue.is_empty());
+    }
+}
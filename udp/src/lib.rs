@@ -0,0 +1,326 @@
+extern crate common;
+extern crate ip;
+extern crate ipv6;
+
+use std::convert::TryFrom;
+use common::{Emit, Layer, ParseError, Reason};
+use ip::{ChecksumCheck, IpNextHeaderProtocols};
+
+pub const UDP_HDR_LEN: usize = 8;
+
+#[derive(Debug,PartialEq)]
+pub struct UdpHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    /// Datagram length. For UDP-Lite the checksum coverage, 0 meaning the whole datagram.
+    pub length: u16,
+    pub checksum: u16,
+}
+
+#[derive(Debug,PartialEq)]
+pub struct UdpDatagram <'a> {
+    pub udp_hdr: UdpHeader,
+    /// UDP-Lite datagram [RFC 3828]
+    pub lite: bool,
+    /// Result of the checksum verification. `None` when it was not requested,
+    /// when the sender left it out (zero over IPv4) or when the capture is truncated.
+    pub checksum_ok: Option<bool>,
+    /// Bytes after the header. Bounded by `length` for UDP, by the IP payload for UDP-Lite.
+    pub payload: &'a [u8],
+}
+
+impl <'a> TryFrom<&'a [u8]> for UdpHeader {
+    type Error = ParseError;
+
+    fn try_from(buf: &'a [u8]) -> Result<UdpHeader, Self::Error> {
+        if buf.len() < UDP_HDR_LEN {
+            return Err(ParseError::truncated(Layer::Udp, 0, UDP_HDR_LEN, buf.len()));
+        }
+        Ok(UdpHeader {
+            src_port: common::u16_parse(&buf[0..2]),
+            dst_port: common::u16_parse(&buf[2..4]),
+            length: common::u16_parse(&buf[4..6]),
+            checksum: common::u16_parse(&buf[6..8]),
+        })
+    }
+}
+
+impl <'a> UdpDatagram<'a> {
+    /// Parse the datagram at the start of `buf` without checking the checksum,
+    /// `buf` is the IP payload
+    pub fn parse(buf: &'a [u8], lite: bool) -> Result<UdpDatagram<'a>, ParseError> {
+        let udp_hdr = UdpHeader::try_from(buf)?;
+        let length = udp_hdr.length as usize;
+        let end = if lite {
+            if length != 0 && (length < UDP_HDR_LEN || length > buf.len()) {
+                return Err(ParseError::new(Layer::Udp, 4, Reason::BadField("coverage")));
+            }
+            buf.len()
+        } else {
+            if length < UDP_HDR_LEN {
+                return Err(ParseError::new(Layer::Udp, 4, Reason::BadField("length")));
+            }
+            length.min(buf.len())
+        };
+        Ok(UdpDatagram { udp_hdr, lite, checksum_ok: None, payload: &buf[UDP_HDR_LEN..end] })
+    }
+
+    /// Number of UDP payload bytes announced by `length` but absent from the buffer
+    pub fn missing_bytes(&self) -> usize {
+        match self.lite {
+            true => 0,
+            false => (self.udp_hdr.length as usize).saturating_sub(UDP_HDR_LEN + self.payload.len()),
+        }
+    }
+
+    /// Number of bytes the checksum covers, the header included
+    fn coverage(&self) -> usize {
+        match (self.lite, self.udp_hdr.length) {
+            (true, 0) => UDP_HDR_LEN + self.payload.len(),
+            (_, length) => length as usize,
+        }
+    }
+
+    /// Verify the checksum of the datagram at the start of `buf` as `check` tells.
+    /// `length` is the datagram length announced by the IP layer, UDP-Lite with a zero
+    /// coverage covers all of it. `checksum_ok` stays `None` when the covered bytes
+    /// were not all captured.
+    fn verify(mut self, buf: &[u8], length: usize, pseudo: &[u8], check: ChecksumCheck, zero_allowed: bool) -> Result<UdpDatagram<'a>, ParseError> {
+        let stored = self.udp_hdr.checksum;
+        let coverage = match (self.lite, self.udp_hdr.length) {
+            (true, 0) => length,
+            (_, length) => length as usize,
+        };
+        if check == ChecksumCheck::Skip || (stored == 0 && zero_allowed && !self.lite) || coverage > buf.len() {
+            return Ok(self);
+        }
+        let sum = common::checksum_pseudo(pseudo, &buf[..coverage]);
+        if sum != 0 && check == ChecksumCheck::Reject {
+            let expected = match common::checksum_update(sum, stored, 0) {
+                0 => 0xffff,
+                crc => crc,
+            };
+            return Err(ParseError::new(Layer::Udp, 6, Reason::BadChecksum { expected, actual: stored }));
+        }
+        self.checksum_ok = Some(sum == 0);
+        Ok(self)
+    }
+
+    /// Datagram carried by an IPv4 packet. Offsets of errors count from the IPv4 header.
+    /// Non-first fragments fail with `BadField("offset")`, they carry no UDP header.
+    pub fn from_ipv4(packet: &ip::IpV4Packet<'a>, check: ChecksumCheck) -> Result<UdpDatagram<'a>, ParseError> {
+        let hdr = &packet.ip_hdr;
+        let lite = match hdr.protocol {
+            IpNextHeaderProtocols::Udp => false,
+            IpNextHeaderProtocols::UdpLite => true,
+            p => return Err(ParseError::new(Layer::Udp, 9, Reason::WrongProtocol(p.0))),
+        };
+        if hdr.offset != 0 {
+            return Err(ParseError::new(Layer::Udp, 6, Reason::BadField("offset")));
+        }
+        let start = hdr.hdr_length as usize * 4;
+        let datagram = UdpDatagram::parse(packet.payload, lite).map_err(|e| e.shift(start))?;
+        let announced = packet.payload.len() + packet.missing_bytes();
+        let length = match lite {
+            true => announced as u16,
+            false => datagram.udp_hdr.length,
+        };
+        datagram.verify(packet.payload, announced, &hdr.pseudo_header(length), check, true).map_err(|e| e.shift(start))
+    }
+
+    /// Datagram after the IPv6 extension headers. Offsets of errors count from the IPv6 header.
+    pub fn from_ipv6(packet: &ipv6::IpV6Packet<'a>, check: ChecksumCheck) -> Result<UdpDatagram<'a>, ParseError> {
+        let chain = packet.extensions()?;
+        let start = ipv6::IPV6_HDR_LEN + chain.payload_offset;
+        let lite = match chain.protocol {
+            IpNextHeaderProtocols::Udp => false,
+            IpNextHeaderProtocols::UdpLite => true,
+            p => return Err(ParseError::new(Layer::Udp, start, Reason::WrongProtocol(p.0))),
+        };
+        if chain.fragment().is_some_and(|f| f.offset != 0) {
+            return Err(ParseError::new(Layer::Udp, start, Reason::BadField("offset")));
+        }
+        let buf = &packet.payload[chain.payload_offset..];
+        let datagram = UdpDatagram::parse(buf, lite).map_err(|e| e.shift(start))?;
+        let announced = (packet.payload.len() + packet.missing_bytes()).saturating_sub(chain.payload_offset);
+        let length = match lite {
+            true => announced as u32,
+            false => datagram.udp_hdr.length as u32,
+        };
+        let pseudo = packet.ip_hdr.pseudo_header(chain.protocol, length);
+        datagram.verify(buf, announced, &pseudo, check, false).map_err(|e| e.shift(start))
+    }
+
+    /// Checksum to store in the header, computed over `pseudo` (see `IpV4Header::pseudo_header`
+    /// and `IpV6Header::pseudo_header`) and the emitted datagram
+    pub fn compute_checksum(&self, pseudo: &[u8]) -> Result<u16, ParseError> {
+        let mut buf = vec!();
+        self.emit_vec(&mut buf)?;
+        common::u16_write(&mut buf[6..], 0);
+        let coverage = self.coverage().min(buf.len());
+        Ok(match common::checksum_pseudo(pseudo, &buf[..coverage]) {
+            0 => 0xffff,
+            crc => crc,
+        })
+    }
+}
+
+/// Verifies the checksum and stores the result in `checksum_ok`
+impl <'a, 'b> TryFrom<&'b ip::IpV4Packet<'a>> for UdpDatagram<'a> {
+    type Error = ParseError;
+
+    fn try_from(packet: &'b ip::IpV4Packet<'a>) -> Result<UdpDatagram<'a>, Self::Error> {
+        UdpDatagram::from_ipv4(packet, ChecksumCheck::Flag)
+    }
+}
+
+/// Verifies the checksum and stores the result in `checksum_ok`
+impl <'a, 'b> TryFrom<&'b ipv6::IpV6Packet<'a>> for UdpDatagram<'a> {
+    type Error = ParseError;
+
+    fn try_from(packet: &'b ipv6::IpV6Packet<'a>) -> Result<UdpDatagram<'a>, Self::Error> {
+        UdpDatagram::from_ipv6(packet, ChecksumCheck::Flag)
+    }
+}
+
+impl Emit for UdpHeader {
+    fn emit_len(&self) -> usize {
+        UDP_HDR_LEN
+    }
+
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        if buf.len() < UDP_HDR_LEN {
+            return Err(ParseError::truncated(Layer::Udp, 0, UDP_HDR_LEN, buf.len()));
+        }
+        common::u16_write(&mut buf[0..], self.src_port);
+        common::u16_write(&mut buf[2..], self.dst_port);
+        common::u16_write(&mut buf[4..], self.length);
+        common::u16_write(&mut buf[6..], self.checksum);
+        Ok(UDP_HDR_LEN)
+    }
+}
+
+impl <'a> Emit for UdpDatagram<'a> {
+    fn emit_len(&self) -> usize {
+        UDP_HDR_LEN + self.payload.len()
+    }
+
+    /// Write the header as is and the payload, see `compute_checksum`
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        let ptr = self.udp_hdr.emit(buf)?;
+        if buf.len() < ptr + self.payload.len() {
+            return Err(ParseError::truncated(Layer::Udp, ptr, self.payload.len(), buf.len() - ptr));
+        }
+        buf[ptr..ptr + self.payload.len()].copy_from_slice(self.payload);
+        Ok(ptr + self.payload.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME: [u8; 48] = [0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x08, 0x00,
+        0x45, 0x00, 0x00, 0x22, 0x00, 0x01, 0x00, 0x00, 0x40, 0x11, 0xa9, 0x08, 0xc0, 0xa8, 0x01, 0x0a, 0x08, 0x08, 0x08, 0x08,
+        0xd4, 0x31, 0x00, 0x35, 0x00, 0x0e, 0x15, 0xb6, b'h', b'e', b'l', b'l', b'o', b'!'];
+
+    const FRAME_V6: [u8; 68] = [0x33, 0x33, 0x00, 0x00, 0x00, 0x01, 0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x86, 0xdd,
+        0x60, 0x00, 0x00, 0x00, 0x00, 0x0e, 0x11, 0x40,
+        0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01,
+        0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x02,
+        0xd4, 0x31, 0x00, 0x35, 0x00, 0x0e, 0xea, 0x73, b'h', b'e', b'l', b'l', b'o', b'!'];
+
+    #[test]
+    fn test_ok() {
+        let packet = ip::IpV4Packet::try_from(&FRAME[..]).unwrap();
+        let res = UdpDatagram::try_from(&packet).unwrap();
+        assert_eq!(res, UdpDatagram {
+            udp_hdr: UdpHeader { src_port: 0xd431, dst_port: 53, length: 14, checksum: 0x15b6 },
+            lite: false,
+            checksum_ok: Some(true),
+            payload: b"hello!",
+        });
+        assert_eq!(res.compute_checksum(&packet.ip_hdr.pseudo_header(14)), Ok(0x15b6));
+
+        let packet = ipv6::IpV6Packet::try_from(&FRAME_V6[..]).unwrap();
+        let res = UdpDatagram::try_from(&packet).unwrap();
+        assert_eq!(res.checksum_ok, Some(true));
+        assert_eq!(res.payload, b"hello!");
+    }
+    #[test]
+    fn test_bad_checksum() {
+        let mut buf = FRAME;
+        buf[47] = b'?';
+        let packet = ip::IpV4Packet::try_from(&buf[..]).unwrap();
+        assert_eq!(UdpDatagram::try_from(&packet).unwrap().checksum_ok, Some(false));
+        assert_eq!(UdpDatagram::from_ipv4(&packet, ChecksumCheck::Skip).unwrap().checksum_ok, None);
+        assert_eq!(UdpDatagram::from_ipv4(&packet, ChecksumCheck::Reject),
+                   Err(ParseError::new(Layer::Udp, 26, Reason::BadChecksum { expected: 0x1598, actual: 0x15b6 })));
+
+        buf[40..42].copy_from_slice(&[0, 0]);
+        let packet = ip::IpV4Packet::try_from(&buf[..]).unwrap();
+        assert_eq!(UdpDatagram::from_ipv4(&packet, ChecksumCheck::Reject).unwrap().checksum_ok, None);
+
+        let mut buf = FRAME_V6;
+        buf[60..62].copy_from_slice(&[0, 0]);
+        let packet = ipv6::IpV6Packet::try_from(&buf[..]).unwrap();
+        assert_eq!(UdpDatagram::try_from(&packet).unwrap().checksum_ok, Some(false));
+    }
+    #[test]
+    fn test_lite() {
+        let mut buf = FRAME;
+        buf[23] = 136;
+        buf[24..26].copy_from_slice(&[0xa8, 0x91]);
+        buf[38..42].copy_from_slice(&[0x00, 0x08, 0x59, 0x38]);
+        buf[47] = b'?';
+        let packet = ip::IpV4Packet::try_from(&buf[..]).unwrap();
+        let res = UdpDatagram::try_from(&packet).unwrap();
+        assert!(res.lite);
+        assert_eq!(res.checksum_ok, Some(true));
+        assert_eq!(res.payload, b"hello?");
+        assert_eq!(res.compute_checksum(&packet.ip_hdr.pseudo_header(14)), Ok(0x5938));
+
+        buf[38..40].copy_from_slice(&[0x00, 0x07]);
+        let packet = ip::IpV4Packet::try_from(&buf[..]).unwrap();
+        assert_eq!(UdpDatagram::try_from(&packet), Err(ParseError::new(Layer::Udp, 24, Reason::BadField("coverage"))));
+
+        // a zero coverage covers the whole datagram, checksum_ok is None when it is cut
+        buf[38..42].copy_from_slice(&[0, 0, 0, 0]);
+        let packet = ip::IpV4Packet::try_from(&buf[..]).unwrap();
+        let crc = UdpDatagram::try_from(&packet).unwrap().compute_checksum(&packet.ip_hdr.pseudo_header(14)).unwrap();
+        common::u16_write(&mut buf[40..], crc);
+        let packet = ip::IpV4Packet::try_from(&buf[..]).unwrap();
+        assert_eq!(UdpDatagram::try_from(&packet).unwrap().checksum_ok, Some(true));
+        let packet = ip::IpV4Packet::try_from(&buf[..45]).unwrap();
+        assert_eq!(UdpDatagram::try_from(&packet).unwrap().checksum_ok, None);
+    }
+    #[test]
+    fn test_errors() {
+        for len in 0..8 {
+            assert_eq!(UdpDatagram::parse(&FRAME[34..34 + len], false), Err(ParseError::truncated(Layer::Udp, 0, 8, len)));
+        }
+        let mut res = UdpDatagram::parse(&FRAME[34..45], false).unwrap();
+        assert_eq!((res.payload, res.missing_bytes()), (&b"hel"[..], 3));
+        res.udp_hdr.length = 8;
+        assert_eq!(res.missing_bytes(), 0);
+
+        let packet = ip::IpV4Packet::try_from(&FRAME[..45]).unwrap();
+        assert_eq!(UdpDatagram::try_from(&packet).unwrap().checksum_ok, None);
+
+        let mut buf = FRAME;
+        buf[39] = 7;
+        let packet = ip::IpV4Packet::try_from(&buf[..]).unwrap();
+        assert_eq!(UdpDatagram::try_from(&packet), Err(ParseError::new(Layer::Udp, 24, Reason::BadField("length"))));
+        buf[23] = 6;
+        let packet = ip::IpV4Packet::try_from(&buf[..]).unwrap();
+        assert_eq!(UdpDatagram::try_from(&packet), Err(ParseError::new(Layer::Udp, 9, Reason::WrongProtocol(6))));
+    }
+    #[test]
+    fn test_emit() {
+        let res = UdpDatagram::parse(&FRAME[34..], false).unwrap();
+        let mut out = vec!();
+        assert_eq!(res.emit_vec(&mut out), Ok(14));
+        assert_eq!(&out[..], &FRAME[34..]);
+        assert_eq!(res.emit(&mut [0u8; 10]), Err(ParseError::truncated(Layer::Udp, 8, 6, 2)));
+    }
+}
@@ -1,6 +1,10 @@
 extern crate common;
 extern crate ethertype;
 
+use std::convert::TryFrom;
+
+use common::{Emit, Layer, ParseError, Reason};
+
 /// Represents an IEEE 802.1p class of service
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct ClassOfService(pub u8);
@@ -44,29 +48,55 @@ pub mod ClassesOfService {
     pub const NC: ClassOfService = ClassOfService(7);
 }
 
-#[derive(Debug, PartialEq)]
+/// TPIDs recognized as tags without configuration: 802.1Q C-tag,
+/// 802.1ad S-tag and the legacy QinQ S-tag
+pub const TPIDS: [ethertype::EtherType; 3] = [ethertype::EtherTypes::Vlan, ethertype::EtherTypes::PBridge, ethertype::EtherTypes::QinQ];
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Vlan {
+    /// Tag protocol identifier the tag was found with
+    pub tpid: ethertype::EtherType,
     pub priority_code_point: ClassOfService,
     pub drop_eligible_indicator: bool,
     pub vid: u16,
 }
 
-fn parse_vlan(buf: &[u8]) -> Vlan {
+impl Vlan {
+    /// Service tag of a provider network, any TPID other than 0x8100
+    pub fn is_s_tag(&self) -> bool {
+        self.tpid != ethertype::EtherTypes::Vlan
+    }
+}
+
+fn parse_vlan(tpid: ethertype::EtherType, buf: &[u8]) -> Vlan {
     let vid = common::u16_parse(buf);
 
     let prio = (vid >> 13) as u8;
     let drop = (vid >> 12) as u8 & 1;
 
-    Vlan{priority_code_point:ClassOfService(prio), drop_eligible_indicator: drop!=0, vid: vid & !(((prio as u16) << 13) | ((drop as u16) << 12))}
+    Vlan{tpid, priority_code_point:ClassOfService(prio), drop_eligible_indicator: drop!=0, vid: vid & !(((prio as u16) << 13) | ((drop as u16) << 12))}
 }
 
-pub fn parse_vlans <'a> (buf: &'a [u8]) -> Result<(Option<Vec<Vlan>>, usize), ()> {
+/// Parse the 802.1Q/802.1ad tags following the MAC addresses.
+/// Returns the tags and the offset of the EtherType that follows them.
+pub fn parse_vlans (buf: &[u8]) -> Result<(Option<Vec<Vlan>>, usize), ParseError> {
+    parse_vlans_with_tpids(buf, &[])
+}
+
+/// `parse_vlans` that also takes `extra_tpids` as tags, for vendors using 0x9200 and similar
+pub fn parse_vlans_with_tpids (buf: &[u8], extra_tpids: &[ethertype::EtherType]) -> Result<(Option<Vec<Vlan>>, usize), ParseError> {
     let mut result : Vec<Vlan> = vec!();
     let mut ptr = 0;
-    while ethertype::parse_ethtype(&buf[ptr..ptr+2]) == ethertype::EtherTypes::Vlan {
-        ptr += 2;
-        let vlan = parse_vlan(&buf[ptr..ptr+2]);
-        ptr += 2;
+    loop {
+        let tpid = ethertype::parse_ethtype(&buf[ptr..]).map_err(|e| e.shift(ptr))?;
+        if !TPIDS.contains(&tpid) && !extra_tpids.contains(&tpid) {
+            break;
+        }
+        if buf.len() < ptr + 4 {
+            return Err(ParseError::truncated(Layer::Vlan, ptr, 4, buf.len() - ptr));
+        }
+        let vlan = parse_vlan(tpid, &buf[ptr + 2..ptr + 4]);
+        ptr += 4;
         result.push(vlan);
     }
     match result.len() {
@@ -75,25 +105,190 @@ pub fn parse_vlans <'a> (buf: &'a [u8]) -> Result<(Option<Vec<Vlan>>, usize), ()
     }
 }
 
+/// VID of the outermost tag: the S-tag of a QinQ stack, the only tag otherwise
+pub fn outer_vid (vlans: &[Vlan]) -> Option<u16> {
+    vlans.first().map(|v| v.vid)
+}
+
+/// VID of the innermost tag (the C-tag), `None` unless the frame is stacked
+pub fn inner_vid (vlans: &[Vlan]) -> Option<u16> {
+    match vlans.len() {
+        0 | 1 => None,
+        n => Some(vlans[n - 1].vid),
+    }
+}
+
+impl Emit for Vlan {
+    fn emit_len(&self) -> usize {
+        4
+    }
+
+    /// Write the TPID and the tag control information
+    fn emit(&self, buf: &mut [u8]) -> Result<usize, ParseError> {
+        if self.vid > 0xfff {
+            return Err(ParseError::new(Layer::Vlan, 2, Reason::BadField("vid")));
+        }
+        if self.priority_code_point.0 > 7 {
+            return Err(ParseError::new(Layer::Vlan, 2, Reason::BadField("priority_code_point")));
+        }
+        if buf.len() < 4 {
+            return Err(ParseError::truncated(Layer::Vlan, 0, 4, buf.len()));
+        }
+        let tci = (self.priority_code_point.0 as u16) << 13 | (self.drop_eligible_indicator as u16) << 12 | self.vid;
+        self.tpid.emit(buf)?;
+        common::u16_write(&mut buf[2..4], tci);
+        Ok(4)
+    }
+}
+
+/// Write a tag stack, the counterpart of `parse_vlans`.
+/// Returns the offset where the EtherType goes.
+pub fn emit_vlans (vlans: &[Vlan], buf: &mut [u8]) -> Result<usize, ParseError> {
+    let mut ptr = 0;
+    for vlan in vlans {
+        ptr += vlan.emit(&mut buf[ptr..]).map_err(|e| e.shift(ptr))?;
+    }
+    Ok(ptr)
+}
+
+/// Mutable view of one tag (TPID and TCI) inside a received frame,
+/// the TPID must be one of `TPIDS`
+#[derive(Debug)]
+pub struct VlanMut <'a> {
+    buf: &'a mut [u8]
+}
+
+impl <'a> TryFrom<&'a mut [u8]> for VlanMut<'a> {
+    type Error = ParseError;
+    fn try_from(buf: &'a mut [u8]) -> Result<VlanMut<'a>, ParseError> {
+        if buf.len() < 4 {
+            return Err(ParseError::truncated(Layer::Vlan, 0, 4, buf.len()));
+        }
+        let tpid = ethertype::parse_ethtype(buf)?;
+        if !TPIDS.contains(&tpid) {
+            return Err(ParseError::new(Layer::Vlan, 0, Reason::WrongEtherType(tpid.0)));
+        }
+        Ok(VlanMut { buf })
+    }
+}
+
+impl <'a> VlanMut<'a> {
+    /// Current value of the tag
+    pub fn vlan(&self) -> Vlan {
+        parse_vlan(ethertype::EtherType(common::u16_parse(self.buf)), &self.buf[2..4])
+    }
+
+    fn set_tci(&mut self, vlan: &Vlan) -> Result<(), ParseError> {
+        vlan.emit(self.buf).map(|_| ())
+    }
+
+    pub fn set_vid(&mut self, vid: u16) -> Result<(), ParseError> {
+        let vlan = Vlan { vid, ..self.vlan() };
+        self.set_tci(&vlan)
+    }
+
+    pub fn set_priority_code_point(&mut self, priority_code_point: ClassOfService) -> Result<(), ParseError> {
+        let vlan = Vlan { priority_code_point, ..self.vlan() };
+        self.set_tci(&vlan)
+    }
+
+    pub fn set_drop_eligible_indicator(&mut self, drop_eligible_indicator: bool) {
+        self.buf[2] = (self.buf[2] & !0x10) | (drop_eligible_indicator as u8) << 4;
+    }
+}
+
 #[cfg(test)]
+#[allow(clippy::unnecessary_cast)]
 mod tests {
     use super::*;
     #[test]
     fn check_simplest_vlan() {
         let buf = [0 as u8, 1];
-        let v = parse_vlan(&buf);
-        assert!(v == Vlan{drop_eligible_indicator:false, priority_code_point:ClassesOfService::BE, vid:1})
+        let v = parse_vlan(ethertype::EtherTypes::Vlan, &buf);
+        assert!(v == Vlan{tpid:ethertype::EtherTypes::Vlan, drop_eligible_indicator:false, priority_code_point:ClassesOfService::BE, vid:1})
     }
     #[test]
     fn check_vlan() {
         let buf = [0xC0 as u8, 1];
-        let v = parse_vlan(&buf);
-        assert!(v == Vlan{drop_eligible_indicator:false, priority_code_point:ClassesOfService::IC, vid:1})
+        let v = parse_vlan(ethertype::EtherTypes::Vlan, &buf);
+        assert!(v == Vlan{tpid:ethertype::EtherTypes::Vlan, drop_eligible_indicator:false, priority_code_point:ClassesOfService::IC, vid:1})
     }
     #[test]
     fn check_full_vlan() {
         let buf = [0xBD as u8, 0x6B];
-        let v = parse_vlan(&buf);
-        assert!(v == Vlan{drop_eligible_indicator:true, priority_code_point:ClassOfService(5), vid:3435})
+        let v = parse_vlan(ethertype::EtherTypes::Vlan, &buf);
+        assert!(v == Vlan{tpid:ethertype::EtherTypes::Vlan, drop_eligible_indicator:true, priority_code_point:ClassOfService(5), vid:3435})
+    }
+    #[test]
+    fn check_vlans() {
+        let buf = [0x81u8, 0x00, 0x00, 0x01, 0x81, 0x00, 0x00, 0x02, 0x08, 0x00];
+        let (v, ptr) = parse_vlans(&buf).unwrap();
+        assert_eq!(v, Some(vec!(Vlan{tpid:ethertype::EtherTypes::Vlan, drop_eligible_indicator:false, priority_code_point:ClassesOfService::BE, vid:1},
+                                Vlan{tpid:ethertype::EtherTypes::Vlan, drop_eligible_indicator:false, priority_code_point:ClassesOfService::BE, vid:2})));
+        assert_eq!(ptr, 8);
+    }
+    #[test]
+    fn check_truncated_vlans() {
+        let buf = [0x81u8, 0x00, 0x00, 0x01, 0x81, 0x00, 0x00, 0x02, 0x08, 0x00];
+        for len in 0..buf.len() {
+            assert!(parse_vlans(&buf[..len]).is_err(), "len {}", len);
+        }
+        assert_eq!(parse_vlans(&buf[..6]), Err(ParseError::truncated(Layer::Vlan, 4, 4, 2)));
+        assert_eq!(parse_vlans(&buf[..5]), Err(ParseError::truncated(Layer::EtherType, 4, 2, 1)));
+    }
+    #[test]
+    fn check_emit_vlans() {
+        let vlans = vec!(Vlan{tpid:ethertype::EtherTypes::Vlan, drop_eligible_indicator:true, priority_code_point:ClassOfService(5), vid:3435},
+                         Vlan{tpid:ethertype::EtherTypes::Vlan, drop_eligible_indicator:false, priority_code_point:ClassesOfService::BE, vid:1});
+        let mut buf = [0u8; 10];
+        assert_eq!(emit_vlans(&vlans, &mut buf), Ok(8));
+        assert_eq!(buf, [0x81, 0x00, 0xBD, 0x6B, 0x81, 0x00, 0x00, 0x01, 0x00, 0x00]);
+        buf[8..10].copy_from_slice(&[0x08, 0x00]);
+        assert_eq!(parse_vlans(&buf), Ok((Some(vlans), 8)));
+    }
+    #[test]
+    fn check_emit_errors() {
+        let vlans = [Vlan{tpid:ethertype::EtherTypes::Vlan, drop_eligible_indicator:false, priority_code_point:ClassesOfService::BE, vid:1},
+                     Vlan{tpid:ethertype::EtherTypes::Vlan, drop_eligible_indicator:false, priority_code_point:ClassesOfService::BE, vid:0x1000}];
+        assert_eq!(emit_vlans(&vlans, &mut [0; 8]), Err(ParseError::new(Layer::Vlan, 6, Reason::BadField("vid"))));
+        assert_eq!(emit_vlans(&vlans[..1], &mut [0; 3]), Err(ParseError::truncated(Layer::Vlan, 0, 4, 3)));
+    }
+    #[test]
+    fn check_mut() {
+        let mut buf = [0x81u8, 0x00, 0xBD, 0x6B, 0x08, 0x00];
+        {
+            let mut v = VlanMut::try_from(&mut buf[..]).unwrap();
+            v.set_vid(100).unwrap();
+            v.set_priority_code_point(ClassesOfService::NC).unwrap();
+            v.set_drop_eligible_indicator(true);
+            assert!(v.vlan().drop_eligible_indicator);
+            v.set_drop_eligible_indicator(false);
+            assert_eq!(v.set_vid(0x1000), Err(ParseError::new(Layer::Vlan, 2, Reason::BadField("vid"))));
+            assert_eq!(v.vlan(), Vlan{tpid:ethertype::EtherTypes::Vlan, drop_eligible_indicator:false, priority_code_point:ClassesOfService::NC, vid:100});
+        }
+        assert_eq!(buf, [0x81, 0x00, 0xE0, 0x64, 0x08, 0x00]);
+        assert_eq!(VlanMut::try_from(&mut buf[2..]).unwrap_err(), ParseError::new(Layer::Vlan, 0, Reason::WrongEtherType(0xE064)));
+    }
+    #[test]
+    fn check_qinq() {
+        let buf = [0x88u8, 0xa8, 0x00, 0x64, 0x81, 0x00, 0x00, 0x0a, 0x08, 0x00];
+        let (v, ptr) = parse_vlans(&buf).unwrap();
+        let v = v.unwrap();
+        assert_eq!(ptr, 8);
+        assert_eq!(v[0], Vlan{tpid:ethertype::EtherTypes::PBridge, drop_eligible_indicator:false, priority_code_point:ClassesOfService::BE, vid:100});
+        assert!(v[0].is_s_tag() && !v[1].is_s_tag());
+        assert_eq!((outer_vid(&v), inner_vid(&v)), (Some(100), Some(10)));
+        assert_eq!((outer_vid(&v[1..]), inner_vid(&v[1..])), (Some(10), None));
+        assert_eq!(outer_vid(&[]), None);
+
+        let mut out = [0u8; 8];
+        assert_eq!(emit_vlans(&v, &mut out), Ok(8));
+        assert_eq!(out, buf[..8]);
+
+        let buf = [0x91u8, 0x00, 0x00, 0x64, 0x92, 0x00, 0x00, 0x0a, 0x08, 0x00];
+        assert_eq!(parse_vlans(&buf).unwrap().1, 4);
+        let (v, ptr) = parse_vlans_with_tpids(&buf, &[ethertype::EtherType(0x9200)]).unwrap();
+        assert_eq!(ptr, 8);
+        assert_eq!(v.unwrap()[1].tpid, ethertype::EtherType(0x9200));
     }
 }